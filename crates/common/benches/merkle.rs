@@ -0,0 +1,62 @@
+//! `merkle_root` against the per-level allocating version it replaced, and against
+//! `merkle_root_par`, for 2^16 leaves.
+
+use common::crypto::Hasher;
+use common::merkle::{empty_root, leaf_hash, merkle_root, merkle_root_par};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+
+const LEAVES: u32 = 1 << 16;
+
+fn node(h: Hasher, a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let mut buf = [0u8; 64];
+    buf[..32].copy_from_slice(a);
+    buf[32..].copy_from_slice(b);
+    h.hash(&buf)
+}
+
+/// `merkle_root` before levels were hashed in place: a new `Vec` per level.
+fn merkle_root_allocating(h: Hasher, leaves: Vec<[u8; 32]>) -> [u8; 32] {
+    if leaves.is_empty() {
+        return empty_root(h);
+    }
+    let mut level = leaves;
+    while level.len() > 1 {
+        let mut next = Vec::with_capacity(level.len().div_ceil(2));
+        for pair in level.chunks(2) {
+            next.push(node(h, &pair[0], pair.get(1).unwrap_or(&pair[0])));
+        }
+        level = next;
+    }
+    level[0]
+}
+
+fn bench_merkle_root(c: &mut Criterion) {
+    let h = Hasher::Sha256;
+    let leaves: Vec<[u8; 32]> = (0..LEAVES).map(|i| leaf_hash(h, &i.to_be_bytes())).collect();
+    let root = merkle_root(h, leaves.clone());
+    assert_eq!(merkle_root_allocating(h, leaves.clone()), root);
+    assert_eq!(merkle_root_par(h, leaves.clone()), root);
+
+    let mut group = c.benchmark_group("merkle_root_2^16");
+    group.bench_function("in_place", |b| {
+        b.iter_batched(
+            || leaves.clone(),
+            |l| merkle_root(h, black_box(l)),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("allocating", |b| {
+        b.iter_batched(
+            || leaves.clone(),
+            |l| merkle_root_allocating(h, black_box(l)),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("parallel", |b| {
+        b.iter_batched(|| leaves.clone(), |l| merkle_root_par(h, black_box(l)), BatchSize::LargeInput)
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_merkle_root);
+criterion_main!(benches);
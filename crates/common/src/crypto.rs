@@ -1,7 +1,9 @@
 use anyhow::{anyhow, Result};
+use bincode::Options;
 use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
 use sha2::{Digest, Sha256};
 use ed25519_dalek::Signer;
+use rayon::prelude::*;
 
 /// Hash bytes with SHA-256.
 pub fn sha256(data: &[u8]) -> [u8; 32] {
@@ -10,30 +12,451 @@ pub fn sha256(data: &[u8]) -> [u8; 32] {
     hasher.finalize().into()
 }
 
+/// Hash function for the Merkle trees (the log tree and the sparse tree). A snapshot records
+/// its tag in `SnapshotMessage::hash_alg`, so verifiers recompute roots with the same one.
+/// Signing digests always use SHA-256.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Hasher {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl Hasher {
+    /// Tag recorded in snapshots to select this hash.
+    pub fn tag(self) -> u8 {
+        match self {
+            Hasher::Sha256 => 0,
+            Hasher::Blake3 => 1,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Result<Self> {
+        match tag {
+            0 => Ok(Hasher::Sha256),
+            1 => Ok(Hasher::Blake3),
+            other => Err(anyhow!("unknown hash_alg tag: {other}")),
+        }
+    }
+
+    pub fn hash(self, data: &[u8]) -> [u8; 32] {
+        match self {
+            Hasher::Sha256 => sha256(data),
+            Hasher::Blake3 => blake3::hash(data).into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Hasher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Hasher::Sha256 => "sha256",
+            Hasher::Blake3 => "blake3",
+        })
+    }
+}
+
+impl std::str::FromStr for Hasher {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "sha256" => Ok(Hasher::Sha256),
+            "blake3" => Ok(Hasher::Blake3),
+            other => Err(format!("unknown hash {other:?} (expected sha256 or blake3)")),
+        }
+    }
+}
+
 /// Deterministic encoding for signing: bincode over the struct.
+/// Signed messages carry a leading `version` field, so it is covered by the encoding.
+///
+/// Canonical encoding requirement: `enc` is bincode 1.x with its default options
+/// (little-endian, fixed-width integers, u64 length prefixes, no trailing bytes).
+/// Every implementation must produce byte-identical output for the same value, so
+/// signed/hashed types must not contain maps or other unordered containers.
+/// Values that would encode to more than `MAX_ENCODED_LEN` bytes are rejected.
+/// Verifiers encode with `enc_canonical`, which also checks the bytes decode back.
 pub fn enc<T: serde::Serialize>(value: &T) -> Result<Vec<u8>> {
-    Ok(bincode::serialize(value)?)
+    Ok(bincode_options().serialize(value)?)
+}
+
+/// Upper bound on the `enc` size of any signed or hashed value. Real messages are a few
+/// hundred bytes; the bound keeps a hostile length prefix from forcing a huge allocation.
+pub const MAX_ENCODED_LEN: u64 = 64 * 1024;
+
+/// The options `bincode::serialize` uses (fixed-width integers, little-endian, trailing
+/// bytes allowed), plus the `MAX_ENCODED_LEN` size limit.
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_ENCODED_LEN)
+}
+
+/// Decode bytes that must be the canonical `enc` of the value: anything that decodes
+/// but doesn't re-encode to exactly the same bytes (e.g. trailing data) is rejected.
+pub fn dec_canonical<T: serde::Serialize + serde::de::DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    let value: T = bincode_options().deserialize(bytes)?;
+    if enc(&value)? != bytes {
+        return Err(anyhow!("non-canonical encoding"));
+    }
+    Ok(value)
+}
+
+/// `enc(value)`, checked to `dec_canonical` back to exactly `value`. Verifiers use this
+/// so a signature is only ever checked over bytes that name one value: a type whose
+/// encoding loses information (a skipped field, a lossy custom impl) fails here instead
+/// of letting two different values share signed bytes.
+pub fn enc_canonical<T>(value: &T) -> Result<Vec<u8>>
+where
+    T: serde::Serialize + serde::de::DeserializeOwned + PartialEq,
+{
+    let bytes = enc(value)?;
+    if dec_canonical::<T>(&bytes)? != *value {
+        return Err(anyhow!("encoding does not round-trip to the same value"));
+    }
+    Ok(bytes)
+}
+
+/// Domain-separation context for `RegistrationMessage` signatures.
+pub const CTX_PRR: &[u8] = b"mpc/prr/v1";
+/// Domain-separation context for `SnapshotMessage` signatures.
+pub const CTX_SNAPSHOT: &[u8] = b"mpc/snapshot/v1";
+/// Domain-separation context for `RotationMessage` signatures.
+pub const CTX_ROTATION: &[u8] = b"mpc/rotation/v1";
+/// Domain-separation context for `RegistrationReceipt` signatures.
+pub const CTX_RECEIPT: &[u8] = b"mpc/receipt/v1";
+
+/// The digest that gets signed: H(len(context) || context || Enc(msg)).
+/// The context ties a signature to one message type, so it can't be replayed as another.
+pub fn signing_digest<T: serde::Serialize>(context: &[u8], msg: &T) -> Result<[u8; 32]> {
+    digest_bytes(context, &enc(msg)?)
+}
+
+/// `signing_digest` for verification: over `enc_canonical(msg)`.
+pub fn verifying_digest<T>(context: &[u8], msg: &T) -> Result<[u8; 32]>
+where
+    T: serde::Serialize + serde::de::DeserializeOwned + PartialEq,
+{
+    digest_bytes(context, &enc_canonical(msg)?)
+}
+
+/// Like `signing_digest`, over already-encoded canonical bytes.
+pub fn digest_bytes(context: &[u8], canonical_bytes: &[u8]) -> Result<[u8; 32]> {
+    let ctx_len = u8::try_from(context.len()).map_err(|_| anyhow!("signing context too long"))?;
+    let mut bytes = Vec::with_capacity(1 + context.len() + canonical_bytes.len());
+    bytes.push(ctx_len);
+    bytes.extend_from_slice(context);
+    bytes.extend_from_slice(canonical_bytes);
+    Ok(sha256(&bytes))
+}
+
+/// A signature scheme that signs and verifies `signing_digest`s.
+pub trait SignatureScheme {
+    /// Tag recorded in signed messages to select this scheme.
+    const TAG: u8;
+    type SigningKey;
+    type VerifyingKey;
+    type Signature;
+
+    /// Length in bytes of the raw public key encoding.
+    fn pubkey_len() -> usize;
+    fn verifying_key_from_bytes(bytes: &[u8]) -> Result<Self::VerifyingKey>;
+    fn sign(sk: &Self::SigningKey, digest: &[u8; 32]) -> Self::Signature;
+    fn verify(pk: &Self::VerifyingKey, digest: &[u8; 32], sig: &[u8]) -> Result<()>;
+}
+
+/// Ed25519 (strict verification). The default scheme.
+pub struct Ed25519;
+
+impl SignatureScheme for Ed25519 {
+    const TAG: u8 = 0;
+    type SigningKey = SigningKey;
+    type VerifyingKey = VerifyingKey;
+    type Signature = [u8; 64];
+
+    fn pubkey_len() -> usize {
+        32
+    }
+
+    fn verifying_key_from_bytes(bytes: &[u8]) -> Result<VerifyingKey> {
+        let pk: &[u8; 32] = bytes
+            .try_into()
+            .map_err(|_| anyhow!("ed25519 pubkey must be {} bytes", Self::pubkey_len()))?;
+        Ok(VerifyingKey::from_bytes(pk)?)
+    }
+
+    fn sign(sk: &SigningKey, digest: &[u8; 32]) -> [u8; 64] {
+        let sig: Signature = sk.sign(digest);
+        sig.to_bytes()
+    }
+
+    fn verify(pk: &VerifyingKey, digest: &[u8; 32], sig: &[u8]) -> Result<()> {
+        let sig = Signature::from_slice(sig)?;
+        pk.verify_strict(digest, &sig)
+            .map_err(|e| anyhow!("signature verification failed: {e}"))
+    }
+}
+
+/// Sign: sigma = Sign(sk, signing_digest(context, msg)) under scheme `S`.
+pub fn sign_struct_with<S: SignatureScheme, T: serde::Serialize>(
+    sk: &S::SigningKey,
+    context: &[u8],
+    msg: &T,
+) -> Result<S::Signature> {
+    let h = signing_digest(context, msg)?;
+    Ok(S::sign(sk, &h))
+}
+
+/// Verify: Verify(pk, verifying_digest(context, msg), sigma) under scheme `S`.
+pub fn verify_struct_with<S: SignatureScheme, T>(
+    pk: &S::VerifyingKey,
+    context: &[u8],
+    msg: &T,
+    sig: &[u8],
+) -> Result<()>
+where
+    T: serde::Serialize + serde::de::DeserializeOwned + PartialEq,
+{
+    let h = verifying_digest(context, msg)?;
+    S::verify(pk, &h, sig)
+}
+
+/// Verify with the scheme selected by `scheme` tag and a raw public key.
+pub fn verify_struct_tagged<T>(
+    scheme: u8,
+    pk: &[u8],
+    context: &[u8],
+    msg: &T,
+    sig: &[u8],
+) -> Result<()>
+where
+    T: serde::Serialize + serde::de::DeserializeOwned + PartialEq,
+{
+    verify_bytes_tagged(scheme, pk, context, &enc_canonical(msg)?, sig)
+}
+
+/// Like `verify_struct_tagged`, over the exact canonical bytes that were signed.
+pub fn verify_bytes_tagged(
+    scheme: u8,
+    pk: &[u8],
+    context: &[u8],
+    canonical_bytes: &[u8],
+    sig: &[u8],
+) -> Result<()> {
+    match scheme {
+        Ed25519::TAG => {
+            let pk = Ed25519::verifying_key_from_bytes(pk)?;
+            verify_bytes(&pk, context, canonical_bytes, sig)
+        }
+        other => Err(anyhow!("unknown signature scheme tag: {other}")),
+    }
+}
+
+/// Reject public keys no honest party can hold: malformed encodings and, for Ed25519,
+/// small-order points (the identity and all-zero key among them), which verify forged
+/// signatures and never a real one.
+pub fn check_pubkey_tagged(scheme: u8, pk: &[u8]) -> Result<()> {
+    match scheme {
+        Ed25519::TAG => {
+            if Ed25519::verifying_key_from_bytes(pk)?.is_weak() {
+                return Err(anyhow!("ed25519 pubkey is a small-order point"));
+            }
+            Ok(())
+        }
+        other => Err(anyhow!("unknown signature scheme tag: {other}")),
+    }
+}
+
+/// Verify: Verify(pk, H(len(context) || context || canonical_bytes), sigma). Ed25519.
+pub fn verify_bytes(pk: &VerifyingKey, context: &[u8], canonical_bytes: &[u8], sig: &[u8]) -> Result<()> {
+    Ed25519::verify(pk, &digest_bytes(context, canonical_bytes)?, sig)
 }
 
-/// Sign: sigma = Sign(sk, H(Enc(msg))).
-pub fn sign_struct<T: serde::Serialize>(sk: &SigningKey, msg: &T) -> Result<[u8; 64]> {
-    let bytes = enc(msg)?;
-    let h = sha256(&bytes);
-    let sig: Signature = sk.sign(&h);
-    Ok(sig.to_bytes())
+/// Sign: sigma = Sign(sk, signing_digest(context, msg)). Ed25519.
+pub fn sign_struct<T: serde::Serialize>(sk: &SigningKey, context: &[u8], msg: &T) -> Result<[u8; 64]> {
+    sign_struct_with::<Ed25519, T>(sk, context, msg)
 }
 
-/// Verify: Verify(pk, H(Enc(msg)), sigma).
-pub fn verify_struct<T: serde::Serialize>(pk: &VerifyingKey, msg: &T, sig_bytes: &[u8; 64]) -> Result<()> {
-    let bytes = enc(msg)?;
-    let h = sha256(&bytes);
+/// Verify: Verify(pk, verifying_digest(context, msg), sigma). Ed25519.
+pub fn verify_struct<T>(
+    pk: &VerifyingKey,
+    context: &[u8],
+    msg: &T,
+    sig_bytes: &[u8; 64],
+) -> Result<()>
+where
+    T: serde::Serialize + serde::de::DeserializeOwned + PartialEq,
+{
+    verify_struct_with::<Ed25519, T>(pk, context, msg, sig_bytes)
+}
 
-    let sig = Signature::from_bytes(sig_bytes);
-    pk.verify_strict(&h, &sig)
-        .map_err(|e| anyhow!("signature verification failed: {e}"))
+/// Verify signatures over precomputed `signing_digest`s, in parallel.
+/// Each is checked with `verify_strict`, as everywhere else: ed25519-dalek's `verify_batch`
+/// checks the cofactored equation, which accepts signatures `verify_strict` refuses (e.g.
+/// over small-order keys), so the batch and per-signature paths could disagree.
+/// On failure this doesn't say which item is bad; fall back to `verify_struct` for that.
+pub fn verify_digests_batch(digests: &[[u8; 32]], sigs: &[[u8; 64]], pks: &[VerifyingKey]) -> Result<()> {
+    if digests.len() != sigs.len() || sigs.len() != pks.len() {
+        return Err(anyhow!(
+            "batch signature verification failed: {} digests, {} signatures, {} keys",
+            digests.len(),
+            sigs.len(),
+            pks.len()
+        ));
+    }
+    digests
+        .par_iter()
+        .zip(sigs.par_iter())
+        .zip(pks.par_iter())
+        .try_for_each(|((digest, sig), pk)| Ed25519::verify(pk, digest, sig))
+        .map_err(|e| anyhow!("batch signature verification failed: {e}"))
 }
 
 /// Parse verifying key from raw bytes.
 pub fn verifying_key_from_bytes(pk: &[u8; 32]) -> Result<VerifyingKey> {
     Ok(VerifyingKey::from_bytes(pk)?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tagged_verification_dispatches_on_the_scheme() {
+        let sk = SigningKey::from_bytes(&[1; 32]);
+        let pk = sk.verifying_key().to_bytes();
+        let sig = sign_struct_with::<Ed25519, _>(&sk, CTX_PRR, &42u64).unwrap();
+        verify_struct_tagged(Ed25519::TAG, &pk, CTX_PRR, &42u64, &sig).unwrap();
+        assert!(verify_struct_tagged(Ed25519::TAG, &pk, CTX_PRR, &43u64, &sig).is_err());
+        assert!(verify_struct_tagged(Ed25519::TAG, &pk[..31], CTX_PRR, &42u64, &sig).is_err());
+    }
+
+    #[test]
+    fn unknown_scheme_tag_is_rejected() {
+        let sk = SigningKey::from_bytes(&[1; 32]);
+        let pk = sk.verifying_key().to_bytes();
+        let sig = sign_struct(&sk, CTX_PRR, &42u64).unwrap();
+        let err = verify_struct_tagged(7, &pk, CTX_PRR, &42u64, &sig).unwrap_err();
+        assert!(err.to_string().contains("unknown signature scheme tag: 7"), "{err}");
+    }
+
+    /// Loses `b` in encoding, so two different values share the same bytes.
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Lossy {
+        a: u8,
+        #[serde(skip)]
+        b: u8,
+    }
+
+    #[test]
+    fn non_canonical_bytes_are_rejected() {
+        let mut bytes = enc(&42u64).unwrap();
+        assert_eq!(dec_canonical::<u64>(&bytes).unwrap(), 42);
+        bytes.push(0);
+        assert!(dec_canonical::<u64>(&bytes).is_err());
+    }
+
+    #[test]
+    fn signatures_over_lossy_encodings_do_not_verify() {
+        let sk = SigningKey::from_bytes(&[1; 32]);
+        let msg = Lossy { a: 1, b: 2 };
+        // The signature is over bytes that also encode Lossy { a: 1, b: 0 }.
+        let sig = sign_struct(&sk, CTX_PRR, &msg).unwrap();
+        assert!(enc_canonical(&msg).is_err());
+        assert!(verify_struct(&sk.verifying_key(), CTX_PRR, &msg, &sig).is_err());
+        let pk = sk.verifying_key().to_bytes();
+        assert!(verify_struct_tagged(Ed25519::TAG, &pk, CTX_PRR, &msg, &sig).is_err());
+        assert!(verifying_digest(CTX_PRR, &msg).is_err());
+
+        let exact = Lossy { a: 1, b: 0 };
+        verify_struct(&sk.verifying_key(), CTX_PRR, &exact, &sig).unwrap();
+    }
+
+    #[test]
+    fn encodings_are_plain_bincode_within_the_size_limit() {
+        let value = (7u64, vec![1u8, 2, 3], String::from("mpc"));
+        assert_eq!(enc(&value).unwrap(), bincode::serialize(&value).unwrap());
+
+        let limit = MAX_ENCODED_LEN as usize;
+        // A Vec<u8> encodes as an 8-byte length prefix and its bytes.
+        let fits = vec![0u8; limit - 8];
+        assert_eq!(dec_canonical::<Vec<u8>>(&enc(&fits).unwrap()).unwrap(), fits);
+        assert!(enc(&vec![0u8; limit - 7]).is_err());
+
+        // A hostile length prefix fails instead of allocating what it claims.
+        assert!(dec_canonical::<Vec<u8>>(&u64::MAX.to_le_bytes()).is_err());
+        // Bytes that are all there, but over the limit, are refused too.
+        let mut over = MAX_ENCODED_LEN.to_le_bytes().to_vec();
+        over.resize(limit + 8, 0);
+        let err = dec_canonical::<Vec<u8>>(&over).unwrap_err();
+        assert!(err.to_string().contains("limit"), "{err}");
+    }
+
+    #[test]
+    fn batch_verification_agrees_with_per_signature_verification() {
+        let keys: Vec<SigningKey> = (1..=8u8).map(|n| SigningKey::from_bytes(&[n; 32])).collect();
+        let pks: Vec<VerifyingKey> = keys.iter().map(SigningKey::verifying_key).collect();
+        let msgs: Vec<u64> = (0..8).collect();
+        let mut sigs: Vec<[u8; 64]> =
+            keys.iter().zip(&msgs).map(|(sk, m)| sign_struct(sk, CTX_PRR, m).unwrap()).collect();
+        let digests: Vec<[u8; 32]> = msgs.iter().map(|m| verifying_digest(CTX_PRR, m).unwrap()).collect();
+        let failing = |sigs: &[[u8; 64]]| -> Vec<usize> {
+            let ok = |i: usize| verify_struct(&pks[i], CTX_PRR, &msgs[i], &sigs[i]).is_ok();
+            (0..8).filter(|&i| !ok(i)).collect()
+        };
+        verify_digests_batch(&digests, &sigs, &pks).unwrap();
+        assert!(failing(&sigs).is_empty());
+
+        sigs[5] = sigs[4];
+        assert!(verify_digests_batch(&digests, &sigs, &pks).is_err());
+        assert_eq!(failing(&sigs), [5]);
+        assert!(verify_digests_batch(&digests[..7], &sigs[..7], &pks).is_err());
+
+        // A small-order key with an identity R and s = 0 satisfies the cofactored equation
+        // ed25519-dalek's verify_batch checks, but not verify_strict; both paths refuse it.
+        let mut identity = [0u8; 32];
+        identity[0] = 1;
+        let weak = VerifyingKey::from_bytes(&identity).unwrap();
+        let mut forged = [0u8; 64];
+        forged[0] = 1;
+        let mut pks = pks.clone();
+        sigs[5] = sign_struct(&keys[5], CTX_PRR, &msgs[5]).unwrap();
+        sigs[3] = forged;
+        pks[3] = weak;
+        let dalek_sigs: Vec<Signature> = sigs.iter().map(Signature::from_bytes).collect();
+        let msgs_bytes: Vec<&[u8]> = digests.iter().map(|d| d.as_slice()).collect();
+        assert!(ed25519_dalek::verify_batch(&msgs_bytes, &dalek_sigs, &pks).is_ok());
+        assert!(Ed25519::verify(&weak, &digests[3], &forged).is_err());
+        assert!(verify_digests_batch(&digests, &sigs, &pks).is_err());
+    }
+
+    #[test]
+    fn signatures_do_not_verify_under_another_context() {
+        let sk = SigningKey::from_bytes(&[1; 32]);
+        let pk = sk.verifying_key();
+        let contexts = [CTX_PRR, CTX_SNAPSHOT, CTX_ROTATION];
+        for signed_under in contexts {
+            let sig = sign_struct(&sk, signed_under, &42u64).unwrap();
+            for checked_under in contexts {
+                // Same key, same bytes: only the context differs.
+                let ok = verify_struct(&pk, checked_under, &42u64, &sig).is_ok();
+                assert_eq!(ok, signed_under == checked_under, "{checked_under:?}");
+            }
+        }
+        // Nor does a bare signature over the encoding, without any context.
+        let bare = sk.sign(&enc(&42u64).unwrap()).to_bytes();
+        for context in contexts {
+            assert!(verify_struct(&pk, context, &42u64, &bare).is_err());
+        }
+    }
+
+    #[test]
+    fn weak_ed25519_keys_are_rejected() {
+        assert!(check_pubkey_tagged(Ed25519::TAG, &[0; 32]).is_err());
+        let mut identity = [0; 32];
+        identity[0] = 1;
+        assert!(check_pubkey_tagged(Ed25519::TAG, &identity).is_err());
+    }
+}
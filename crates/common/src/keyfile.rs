@@ -0,0 +1,325 @@
+use anyhow::{anyhow, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::Engine as _;
+use chacha20poly1305::{aead::Aead, KeyInit, XChaCha20Poly1305, XNonce};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::env::VarError;
+use std::io::{Read, Write as _};
+
+/// On-disk key file (JSON).
+/// Plaintext files hold the raw seed in `sk_seed_b64`; encrypted files hold it in `encrypted`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyFile {
+    /// raw 32-byte signing key seed (ed25519), plaintext
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sk_seed_b64: Option<String>,
+    /// seed sealed under a passphrase
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted: Option<EncryptedSeed>,
+}
+
+/// Seed encrypted with XChaCha20-Poly1305 under an Argon2id-derived key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedSeed {
+    /// `SEAL_VERSION`.
+    pub version: u32,
+    pub kdf: String,
+    pub params: KdfParams,
+    pub salt_b64: String,
+    pub nonce_b64: String,
+    pub ciphertext_b64: String,
+}
+
+/// Argon2id costs a seed was sealed with. Stored with it, so a file still opens after the
+/// costs used for new files change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    /// Memory, in KiB.
+    pub m_cost: u32,
+    /// Passes over the memory.
+    pub t_cost: u32,
+    /// Lanes.
+    pub p_cost: u32,
+}
+
+impl KdfParams {
+    /// What new files are sealed with.
+    pub const DEFAULT: Self = Self { m_cost: 19 * 1024, t_cost: 2, p_cost: 1 };
+}
+
+const KDF_ARGON2ID: &str = "argon2id";
+
+/// Version of the `EncryptedSeed` layout written by `seal`.
+pub const SEAL_VERSION: u32 = 1;
+
+fn b64() -> &'static base64::engine::GeneralPurpose {
+    &base64::engine::general_purpose::STANDARD
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], params: KdfParams) -> Result<[u8; 32]> {
+    let params = Params::new(params.m_cost, params.t_cost, params.p_cost, None)
+        .map_err(|e| anyhow!("bad key file kdf params: {e}"))?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow!("key derivation failed: {e}"))?;
+    Ok(key)
+}
+
+fn seed32(bytes: &[u8]) -> Result<[u8; 32]> {
+    if bytes.len() != 32 {
+        return Err(anyhow!("key seed must be 32 bytes"));
+    }
+    let mut seed = [0u8; 32];
+    seed.copy_from_slice(bytes);
+    Ok(seed)
+}
+
+/// Decode a base64 key seed (e.g. injected by a secret manager instead of a key file).
+pub fn seed_from_b64(s: &str) -> Result<[u8; 32]> {
+    seed32(&b64().decode(s.trim())?)
+}
+
+/// A base64 key seed from the environment variable `env` (looked up with `lookup`, e.g.
+/// `std::env::var`) or, failing that, read from `input` (e.g. stdin) if `stdin` is set.
+/// `None` if neither was asked for, i.e. use the key file.
+pub fn seed_from_env_or_stdin(
+    env: Option<&str>,
+    stdin: bool,
+    lookup: impl FnOnce(&str) -> Result<String, VarError>,
+    input: impl Read,
+) -> Result<Option<[u8; 32]>> {
+    if let Some(var) = env {
+        let v = lookup(var).map_err(|e| anyhow!("key seed variable {var}: {e}"))?;
+        return seed_from_b64(&v).map(Some).map_err(|e| anyhow!("bad key seed in {var}: {e}"));
+    }
+    if stdin {
+        let v = std::io::read_to_string(input)?;
+        return seed_from_b64(&v).map(Some).map_err(|e| anyhow!("bad key seed on stdin: {e}"));
+    }
+    Ok(None)
+}
+
+impl EncryptedSeed {
+    pub fn seal(seed: &[u8; 32], passphrase: &str) -> Result<Self> {
+        Self::seal_with(seed, passphrase, KdfParams::DEFAULT)
+    }
+
+    /// `seal` with the given Argon2id costs instead of the default ones.
+    pub fn seal_with(seed: &[u8; 32], passphrase: &str, params: KdfParams) -> Result<Self> {
+        let mut salt = [0u8; 16];
+        let mut nonce = [0u8; 24];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+
+        let key = derive_key(passphrase, &salt, params)?;
+        let cipher = XChaCha20Poly1305::new(&key.into());
+        let ciphertext = cipher
+            .encrypt(XNonce::from_slice(&nonce), seed.as_slice())
+            .map_err(|_| anyhow!("key encryption failed"))?;
+
+        Ok(Self {
+            version: SEAL_VERSION,
+            kdf: KDF_ARGON2ID.to_string(),
+            params,
+            salt_b64: b64().encode(salt),
+            nonce_b64: b64().encode(nonce),
+            ciphertext_b64: b64().encode(ciphertext),
+        })
+    }
+
+    pub fn open(&self, passphrase: &str) -> Result<[u8; 32]> {
+        if self.kdf != KDF_ARGON2ID {
+            return Err(anyhow!("unsupported key file kdf: {}", self.kdf));
+        }
+        if self.version != SEAL_VERSION {
+            return Err(anyhow!("unsupported key file version {}", self.version));
+        }
+        let salt = b64().decode(&self.salt_b64)?;
+        let nonce = b64().decode(&self.nonce_b64)?;
+        let ciphertext = b64().decode(&self.ciphertext_b64)?;
+        if nonce.len() != 24 {
+            return Err(anyhow!("key file nonce must be 24 bytes"));
+        }
+
+        let key = derive_key(passphrase, &salt, self.params)?;
+        let cipher = XChaCha20Poly1305::new(&key.into());
+        let seed = cipher
+            .decrypt(XNonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| anyhow!("wrong passphrase or corrupted key file"))?;
+        seed32(&seed)
+    }
+}
+
+impl KeyFile {
+    /// Build a key file for `seed`, encrypted if a passphrase is given.
+    pub fn new(seed: &[u8; 32], passphrase: Option<&str>) -> Result<Self> {
+        Ok(match passphrase {
+            Some(p) => Self {
+                sk_seed_b64: None,
+                encrypted: Some(EncryptedSeed::seal(seed, p)?),
+            },
+            None => Self {
+                sk_seed_b64: Some(b64().encode(seed)),
+                encrypted: None,
+            },
+        })
+    }
+
+    /// Recover the 32-byte seed, decrypting with `passphrase` if the file is encrypted.
+    pub fn seed(&self, passphrase: Option<&str>) -> Result<[u8; 32]> {
+        match (&self.encrypted, &self.sk_seed_b64) {
+            (Some(enc), _) => {
+                let p = passphrase.ok_or_else(|| anyhow!("key file is encrypted but no passphrase was given"))?;
+                enc.open(p)
+            }
+            (None, Some(s)) => seed32(&b64().decode(s)?),
+            (None, None) => Err(anyhow!("key file has neither sk_seed_b64 nor encrypted seed")),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Option<Self>> {
+        match fs::read_to_string(path) {
+            Ok(data) => Ok(Some(serde_json::from_str(&data)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write the file at `path`, readable and writable by its owner only (on unix), also
+    /// when it replaces an existing file.
+    pub fn save(&self, path: &str) -> Result<()> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{OpenOptionsExt as _, PermissionsExt as _};
+            options.mode(0o600);
+            // `mode` only applies to a new file; tighten one being replaced first.
+            if fs::metadata(path).is_ok() {
+                fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+            }
+        }
+        options.open(path)?.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap enough for tests.
+    const CHEAP: KdfParams = KdfParams { m_cost: 64, t_cost: 1, p_cost: 1 };
+
+    #[test]
+    fn seeds_open_with_the_params_they_were_sealed_with() {
+        let seed = [7u8; 32];
+        let sealed = EncryptedSeed::seal_with(&seed, "pw", CHEAP).unwrap();
+        let json = serde_json::to_value(&sealed).unwrap();
+        assert_eq!(json["version"], SEAL_VERSION);
+        assert_eq!(json["params"]["m_cost"], 64);
+        let reread: EncryptedSeed = serde_json::from_value(json).unwrap();
+        assert_eq!(reread.open("pw").unwrap(), seed);
+        assert!(reread.open("wrong").is_err());
+
+        // The stored params are what's used: others derive another key.
+        let mut altered = reread.clone();
+        altered.params = KdfParams { t_cost: 2, ..CHEAP };
+        assert!(altered.open("pw").is_err());
+
+        // Both the version and the params are required.
+        for field in ["version", "params"] {
+            let mut json = serde_json::to_value(&reread).unwrap();
+            json.as_object_mut().unwrap().remove(field);
+            assert!(serde_json::from_value::<EncryptedSeed>(json).is_err(), "{field}");
+        }
+        let mut future = reread;
+        future.version = SEAL_VERSION + 1;
+        let err = future.open("pw").unwrap_err();
+        assert!(err.to_string().contains("unsupported key file version"), "{err}");
+    }
+
+    #[test]
+    fn sealed_key_files_open_only_with_their_passphrase() {
+        let seed = [5u8; 32];
+        let sealed = EncryptedSeed::seal_with(&seed, "right", CHEAP).unwrap();
+        let file = KeyFile { sk_seed_b64: None, encrypted: Some(sealed) };
+        let file: KeyFile = serde_json::from_str(&serde_json::to_string(&file).unwrap()).unwrap();
+        assert!(file.sk_seed_b64.is_none());
+        assert_eq!(file.seed(Some("right")).unwrap(), seed);
+
+        for wrong in ["wrong", "Right", ""] {
+            let err = file.seed(Some(wrong)).unwrap_err();
+            assert!(err.to_string().contains("wrong passphrase"), "{wrong:?}: {err}");
+        }
+        let err = file.seed(None).unwrap_err();
+        assert!(err.to_string().contains("no passphrase was given"), "{err}");
+
+        let mut tampered = file.encrypted.clone().unwrap();
+        let mut ciphertext = b64().decode(&tampered.ciphertext_b64).unwrap();
+        ciphertext[0] ^= 1;
+        tampered.ciphertext_b64 = b64().encode(ciphertext);
+        assert!(tampered.open("right").is_err());
+
+        // A plaintext file needs no passphrase, and ignores one.
+        let plain = KeyFile::new(&seed, None).unwrap();
+        assert_eq!(plain.seed(None).unwrap(), seed);
+        assert_eq!(plain.seed(Some("right")).unwrap(), seed);
+    }
+
+    #[test]
+    fn seeds_come_from_the_named_variable_or_the_input() {
+        let seed = [9u8; 32];
+        let encoded = b64().encode(seed);
+        let from_env = |var: &str, value: &str, stdin: bool| {
+            let lookup = |name: &str| match name {
+                "SEED" => Ok(value.to_string()),
+                _ => Err(VarError::NotPresent),
+            };
+            seed_from_env_or_stdin(Some(var), stdin, lookup, &b"not read"[..])
+        };
+        let unread = |_: &str| -> Result<String, VarError> { panic!("env looked up") };
+
+        assert_eq!(from_env("SEED", &encoded, false).unwrap(), Some(seed));
+        // The variable wins even when stdin is asked for too; the input is left alone.
+        assert_eq!(from_env("SEED", &encoded, true).unwrap(), Some(seed));
+        let err = from_env("OTHER", &encoded, false).unwrap_err();
+        assert!(err.to_string().contains("key seed variable OTHER"), "{err}");
+        let err = from_env("SEED", "short", false).unwrap_err();
+        assert!(err.to_string().contains("bad key seed in SEED"), "{err}");
+
+        let line = format!("{encoded}\n");
+        let got = seed_from_env_or_stdin(None, true, unread, line.as_bytes());
+        assert_eq!(got.unwrap(), Some(seed));
+        let err = seed_from_env_or_stdin(None, true, unread, &b""[..]).unwrap_err();
+        assert!(err.to_string().contains("bad key seed on stdin"), "{err}");
+
+        // Neither asked for: the caller falls back to its key file.
+        assert_eq!(seed_from_env_or_stdin(None, false, unread, &b"x"[..]).unwrap(), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn key_files_are_written_owner_only() {
+        use std::os::unix::fs::PermissionsExt as _;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key.json");
+        let path = path.to_str().unwrap();
+        let mode = |path: &str| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        let sealed = EncryptedSeed::seal_with(&[3; 32], "pw", CHEAP).unwrap();
+        let file = KeyFile { sk_seed_b64: None, encrypted: Some(sealed) };
+        file.save(path).unwrap();
+        assert_eq!(mode(path), 0o600);
+        assert_eq!(KeyFile::load(path).unwrap().unwrap().seed(Some("pw")).unwrap(), [3; 32]);
+
+        // Replacing a looser file tightens it too.
+        fs::set_permissions(path, fs::Permissions::from_mode(0o644)).unwrap();
+        KeyFile::new(&[4; 32], None).unwrap().save(path).unwrap();
+        assert_eq!(mode(path), 0o600);
+        assert_eq!(KeyFile::load(path).unwrap().unwrap().seed(None).unwrap(), [4; 32]);
+    }
+}
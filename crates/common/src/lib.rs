@@ -1,3 +1,8 @@
 pub mod crypto;
+pub mod keyfile;
+pub mod logging;
 pub mod merkle;
+pub mod ratelimit;
+pub mod shutdown;
+pub mod smt;
 pub mod types;
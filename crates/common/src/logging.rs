@@ -0,0 +1,102 @@
+use std::fmt;
+use tracing_subscriber::fmt::format::{Format, Json, JsonFields};
+use tracing_subscriber::fmt::SubscriberBuilder;
+
+/// How the binaries write their logs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines (tracing's default format).
+    #[default]
+    Text,
+    /// One JSON object per line (tracing-subscriber's JSON format): timestamp, level,
+    /// target, message and the event's fields at the top level, and the fields of the span
+    /// it happened in (e.g. request_id, party_id, epoch) under `span`.
+    Json,
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        })
+    }
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("unknown log format {other:?} (expected text or json)")),
+        }
+    }
+}
+
+/// Install the global tracing subscriber, writing to stdout in `format`.
+pub fn init(format: LogFormat) {
+    match format {
+        LogFormat::Text => tracing_subscriber::fmt().init(),
+        LogFormat::Json => json().init(),
+    }
+}
+
+/// The subscriber for `LogFormat::Json`, still to be given a writer and installed.
+fn json() -> SubscriberBuilder<JsonFields, Format<Json>> {
+    tracing_subscriber::fmt().json().flatten_event(true).with_current_span(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    /// Collects what the subscriber writes.
+    #[derive(Clone, Default)]
+    struct Buf(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buf {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(data)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_lines_carry_event_and_span_fields() {
+        let buf = Buf::default();
+        let writer = buf.clone();
+        let subscriber = json().with_writer(move || writer.clone()).finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request", request_id = 7, party_id = 3);
+            let _entered = span.enter();
+            tracing::info!(epoch = 5, "registered");
+        });
+
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> =
+            out.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), 1, "{out}");
+        let line = &lines[0];
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["message"], "registered");
+        assert_eq!(line["epoch"], 5);
+        assert_eq!(line["span"]["request_id"], 7);
+        assert_eq!(line["span"]["party_id"], 3);
+        assert!(line["timestamp"].is_string());
+    }
+
+    #[test]
+    fn formats_parse_back() {
+        for format in [LogFormat::Text, LogFormat::Json] {
+            assert_eq!(format.to_string().parse::<LogFormat>(), Ok(format));
+        }
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+}
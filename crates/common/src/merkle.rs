@@ -1,34 +1,409 @@
-use crate::crypto::sha256;
+use crate::crypto::Hasher;
+use anyhow::{anyhow, Result};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
 /// Merkle leaf hash for a PRR: H(bytes).
-pub fn leaf_hash(leaf_bytes: &[u8]) -> [u8; 32] {
-    sha256(leaf_bytes)
+pub fn leaf_hash(h: Hasher, leaf_bytes: &[u8]) -> [u8; 32] {
+    h.hash(leaf_bytes)
 }
 
 /// Hash two nodes: H(left || right).
-fn hash_node(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+pub(crate) fn hash_node(h: Hasher, a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
     let mut buf = [0u8; 64];
     buf[..32].copy_from_slice(a);
     buf[32..].copy_from_slice(b);
-    sha256(&buf)
+    h.hash(&buf)
+}
+
+/// Root of the empty log: H("EMPTY"). Not H(""), which is also the root of a log holding a
+/// single empty leaf; no record encodes to these five bytes, so no one-leaf root equals it.
+pub fn empty_root(h: Hasher) -> [u8; 32] {
+    h.hash(b"EMPTY")
 }
 
 /// Compute Merkle root from leaves.
-/// - If no leaves: root = H("").
+/// - If no leaves: root = `empty_root`.
 /// - If odd number at a level: duplicate last.
-pub fn merkle_root(mut leaves: Vec<[u8; 32]>) -> [u8; 32] {
+///
+/// Known answers with `Hasher::Sha256`, for checking other implementations:
+/// - no leaves: `cc1d2f838445db7aec431df9ee8a871f40e7aa5e064fc056633ef8c60fab7b06`
+/// - leaves of `"a"`, `"b"`, `"c"`, i.e. H(H(la || lb) || H(lc || lc)):
+///   `d31a37ef6ac14a2db1470c4316beb5592e6afd4465022339adafda76a18ffabe`
+///
+/// Levels are hashed in place: node `i` of the next level overwrites `leaves[i]`, which has
+/// already been read (its children are at `2i` and `2i + 1`), so no level is reallocated.
+pub fn merkle_root(h: Hasher, mut leaves: Vec<[u8; 32]>) -> [u8; 32] {
     if leaves.is_empty() {
-        return sha256(&[]);
+        return empty_root(h);
     }
-    while leaves.len() > 1 {
-        if leaves.len() % 2 == 1 {
-            leaves.push(*leaves.last().unwrap());
+    let mut len = leaves.len();
+    while len > 1 {
+        for i in 0..len / 2 {
+            leaves[i] = hash_node(h, &leaves[2 * i], &leaves[2 * i + 1]);
         }
-        let mut next = Vec::with_capacity(leaves.len() / 2);
-        for pair in leaves.chunks(2) {
-            next.push(hash_node(&pair[0], &pair[1]));
+        if len & 1 == 1 {
+            let last = leaves[len - 1];
+            leaves[len / 2] = hash_node(h, &last, &last);
         }
-        leaves = next;
+        len = len.div_ceil(2);
     }
     leaves[0]
 }
+
+/// Below this many nodes, a level is hashed serially: splitting it across threads costs
+/// more than it saves.
+pub const PARALLEL_MERKLE_THRESHOLD: usize = 4096;
+
+/// Leaf hashes of `leaf_bytes`, computed in parallel for large inputs.
+pub fn leaf_hashes<T: AsRef<[u8]> + Sync>(h: Hasher, leaf_bytes: &[T]) -> Vec<[u8; 32]> {
+    if leaf_bytes.len() < PARALLEL_MERKLE_THRESHOLD {
+        return leaf_bytes.iter().map(|b| leaf_hash(h, b.as_ref())).collect();
+    }
+    leaf_bytes.par_iter().map(|b| leaf_hash(h, b.as_ref())).collect()
+}
+
+/// Same root as `merkle_root`, hashing each level's pairs in parallel while the level has
+/// at least `PARALLEL_MERKLE_THRESHOLD` nodes, then finishing serially.
+pub fn merkle_root_par(h: Hasher, mut leaves: Vec<[u8; 32]>) -> [u8; 32] {
+    while leaves.len() >= PARALLEL_MERKLE_THRESHOLD {
+        // An odd level's last chunk is a single node, paired with itself.
+        leaves = leaves
+            .par_chunks(2)
+            .map(|pair| hash_node(h, &pair[0], pair.get(1).unwrap_or(&pair[0])))
+            .collect();
+    }
+    merkle_root(h, leaves)
+}
+
+/// Proof that leaf `leaf_index` (0-based) is in the tree over `log_len` leaves.
+/// `siblings` runs from the leaf level up and skips levels where the node is the
+/// duplicated last one (it pairs with itself).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct InclusionProof {
+    pub leaf_index: u64,
+    pub log_len: u64,
+    pub siblings: Vec<[u8; 32]>,
+}
+
+/// Build the inclusion proof for `leaves[leaf_index]`.
+pub fn inclusion_proof(h: Hasher, leaves: &[[u8; 32]], leaf_index: u64) -> Result<InclusionProof> {
+    let log_len = leaves.len() as u64;
+    if leaf_index >= log_len {
+        return Err(anyhow!("leaf_index={leaf_index} out of bounds for log_len={log_len}"));
+    }
+    let mut level = leaves.to_vec();
+    let mut i = leaf_index as usize;
+    let mut siblings = Vec::new();
+    while level.len() > 1 {
+        let len = level.len();
+        if len % 2 == 1 {
+            level.push(level[len - 1]);
+        }
+        // The last node of an odd level pairs with its own duplicate: no sibling needed.
+        if !(len % 2 == 1 && i == len - 1) {
+            siblings.push(level[i ^ 1]);
+        }
+        level = level.chunks(2).map(|p| hash_node(h, &p[0], &p[1])).collect();
+        i /= 2;
+    }
+    Ok(InclusionProof { leaf_index, log_len, siblings })
+}
+
+/// Check `proof` shows `leaf` at `proof.leaf_index` in the tree with root `root`.
+pub fn verify_inclusion(
+    h: Hasher,
+    root: &[u8; 32],
+    leaf: &[u8; 32],
+    proof: &InclusionProof,
+) -> Result<()> {
+    if proof.leaf_index >= proof.log_len {
+        return Err(anyhow!(
+            "inclusion proof leaf_index={} out of bounds for log_len={}",
+            proof.leaf_index,
+            proof.log_len
+        ));
+    }
+    let mut acc = *leaf;
+    let mut i = proof.leaf_index;
+    let mut len = proof.log_len;
+    let mut siblings = proof.siblings.iter();
+    while len > 1 {
+        acc = if len % 2 == 1 && i == len - 1 {
+            hash_node(h, &acc, &acc)
+        } else {
+            let sib =
+                siblings.next().ok_or_else(|| anyhow!("inclusion proof is missing siblings"))?;
+            if i & 1 == 0 {
+                hash_node(h, &acc, sib)
+            } else {
+                hash_node(h, sib, &acc)
+            }
+        };
+        i /= 2;
+        len = len.div_ceil(2);
+    }
+    if siblings.next().is_some() {
+        return Err(anyhow!("inclusion proof has unused siblings"));
+    }
+    if acc != *root {
+        return Err(anyhow!("inclusion proof does not match root"));
+    }
+    Ok(())
+}
+
+/// Proof that the log of `new_size` leaves extends the log of `old_size` leaves.
+/// `old_nodes` are the maximal complete subtrees of the first `old_size` leaves (enough to
+/// rebuild the old root); `new_nodes` are the complete subtrees covering the rest. Both
+/// are listed left to right.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ConsistencyProof {
+    pub old_size: u64,
+    pub new_size: u64,
+    pub old_nodes: Vec<[u8; 32]>,
+    pub new_nodes: Vec<[u8; 32]>,
+}
+
+/// Height of the tree over `size` leaves (a single leaf is height 0).
+fn tree_height(size: u64) -> u32 {
+    if size <= 1 {
+        0
+    } else {
+        64 - (size - 1).leading_zeros()
+    }
+}
+
+/// Rebuild the root of the tree over `size` leaves top-down, stopping at complete
+/// subtrees that lie entirely before `split` (old) or entirely within `split..size`
+/// (new). `node(is_old, level, index)` supplies their hashes; everything else is
+/// recomputed, duplicating the last node of odd levels like `merkle_root`.
+fn fold_tree(
+    h: Hasher,
+    size: u64,
+    split: u64,
+    level: u32,
+    index: u64,
+    node: &mut impl FnMut(bool, u32, u64) -> Result<[u8; 32]>,
+) -> Result<[u8; 32]> {
+    let start = u128::from(index) << level;
+    let end = start + (1u128 << level);
+    if end <= u128::from(split) {
+        return node(true, level, index);
+    }
+    if start >= u128::from(split) && end <= u128::from(size) {
+        return node(false, level, index);
+    }
+    let left = fold_tree(h, size, split, level - 1, 2 * index, node)?;
+    let right = if (u128::from(2 * index + 1) << (level - 1)) < u128::from(size) {
+        fold_tree(h, size, split, level - 1, 2 * index + 1, node)?
+    } else {
+        left
+    };
+    Ok(hash_node(h, &left, &right))
+}
+
+/// Prove that `leaves` (the whole log) extends its first `old_size` leaves.
+pub fn consistency_proof(
+    h: Hasher,
+    leaves: &[[u8; 32]],
+    old_size: u64,
+) -> Result<ConsistencyProof> {
+    let new_size = leaves.len() as u64;
+    if old_size > new_size {
+        return Err(anyhow!("old_size={old_size} exceeds log size {new_size}"));
+    }
+    let mut old_nodes = Vec::new();
+    let mut new_nodes = Vec::new();
+    if new_size > 0 {
+        fold_tree(h, new_size, old_size, tree_height(new_size), 0, &mut |is_old, level, index| {
+            let start = (index << level) as usize;
+            let node = merkle_root(h, leaves[start..start + (1usize << level)].to_vec());
+            if is_old {
+                old_nodes.push(node);
+            } else {
+                new_nodes.push(node);
+            }
+            Ok(node)
+        })?;
+    }
+    Ok(ConsistencyProof { old_size, new_size, old_nodes, new_nodes })
+}
+
+/// Check `proof` shows the log with root `new_root` extends the log with root `old_root`.
+pub fn verify_consistency(
+    h: Hasher,
+    proof: &ConsistencyProof,
+    old_root: &[u8; 32],
+    new_root: &[u8; 32],
+) -> Result<()> {
+    let (old_size, new_size) = (proof.old_size, proof.new_size);
+    if old_size > new_size {
+        return Err(anyhow!("consistency proof old_size={old_size} > new_size={new_size}"));
+    }
+
+    let rebuild = |size: u64, with_new: bool| -> Result<[u8; 32]> {
+        if size == 0 {
+            return Ok(empty_root(h));
+        }
+        let mut old_it = proof.old_nodes.iter();
+        let mut new_it = proof.new_nodes.iter();
+        let root = fold_tree(h, size, old_size, tree_height(size), 0, &mut |is_old, _, _| {
+            let next = if is_old { old_it.next() } else { new_it.next() };
+            next.copied().ok_or_else(|| anyhow!("consistency proof is missing nodes"))
+        })?;
+        if old_it.next().is_some() || (with_new && new_it.next().is_some()) {
+            return Err(anyhow!("consistency proof has unused nodes"));
+        }
+        Ok(root)
+    };
+
+    if rebuild(old_size, false)? != *old_root {
+        return Err(anyhow!("consistency proof does not match the old root (log_len={old_size})"));
+    }
+    if rebuild(new_size, true)? != *new_root {
+        return Err(anyhow!("consistency proof does not match the new root (log_len={new_size})"));
+    }
+    Ok(())
+}
+
+/// Check that `appended` are leaves `old_size..new_size` of the log with root `new_root`,
+/// rebuilding that root from `proof`'s old nodes and the appended leaves in place of its
+/// new nodes. Together with `verify_consistency` this authenticates records fetched
+/// since the old snapshot without fetching the rest of the log.
+pub fn verify_appended(
+    h: Hasher,
+    proof: &ConsistencyProof,
+    new_root: &[u8; 32],
+    appended: &[[u8; 32]],
+) -> Result<()> {
+    let (old_size, new_size) = (proof.old_size, proof.new_size);
+    if old_size > new_size || appended.len() as u64 != new_size - old_size {
+        return Err(anyhow!(
+            "got {} appended leaves for a proof of {old_size}..{new_size}",
+            appended.len()
+        ));
+    }
+    if appended.is_empty() {
+        return Ok(());
+    }
+    let mut old_it = proof.old_nodes.iter();
+    let mut node = |is_old: bool, level: u32, index: u64| {
+        if is_old {
+            let next = old_it.next().copied();
+            return next.ok_or_else(|| anyhow!("consistency proof is missing nodes"));
+        }
+        let start = ((index << level) - old_size) as usize;
+        Ok(merkle_root(h, appended[start..start + (1usize << level)].to_vec()))
+    };
+    let root = fold_tree(h, new_size, old_size, tree_height(new_size), 0, &mut node)?;
+    if root != *new_root {
+        return Err(anyhow!("appended leaves do not match the new root (log_len={new_size})"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(n: u8) -> Vec<[u8; 32]> {
+        (0..n).map(|i| leaf_hash(Hasher::Sha256, &[i])).collect()
+    }
+
+    fn many_leaves(h: Hasher, n: usize) -> Vec<[u8; 32]> {
+        (0..n as u32).map(|i| leaf_hash(h, &i.to_be_bytes())).collect()
+    }
+
+    /// `merkle_root` as it was before levels were hashed in place: a new `Vec` per level.
+    fn merkle_root_allocating(h: Hasher, leaves: Vec<[u8; 32]>) -> [u8; 32] {
+        if leaves.is_empty() {
+            return empty_root(h);
+        }
+        let mut level = leaves;
+        while level.len() > 1 {
+            let mut next = Vec::with_capacity(level.len().div_ceil(2));
+            let mut i = 0;
+            while i < level.len() {
+                let left = level[i];
+                let right = if i + 1 < level.len() { level[i + 1] } else { level[i] };
+                next.push(hash_node(h, &left, &right));
+                i += 2;
+            }
+            level = next;
+        }
+        level[0]
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn in_place_root_matches_the_allocating_one(
+            leaves in proptest::collection::vec(proptest::array::uniform32(0u8..), 0..=1000),
+            blake3 in proptest::bool::ANY,
+        ) {
+            let h = if blake3 { Hasher::Blake3 } else { Hasher::Sha256 };
+            proptest::prop_assert_eq!(
+                merkle_root(h, leaves.clone()),
+                merkle_root_allocating(h, leaves)
+            );
+        }
+    }
+
+    #[test]
+    fn empty_root_differs_from_every_single_leaf_root() {
+        for h in [Hasher::Sha256, Hasher::Blake3] {
+            let empty = merkle_root(h, Vec::new());
+            assert_eq!(empty, empty_root(h));
+            assert_ne!(empty, merkle_root(h, vec![leaf_hash(h, &[])]), "{h:?}");
+            for leaf in many_leaves(h, 256) {
+                assert_ne!(empty, merkle_root(h, vec![leaf]), "{h:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_root_matches_the_serial_one() {
+        let t = PARALLEL_MERKLE_THRESHOLD;
+        let mut sizes: Vec<usize> = (0..=70).collect();
+        // Around the threshold, at the level above it, and odd sizes on either path.
+        sizes.extend([t - 1, t, t + 1, 2 * t - 1, 2 * t, 2 * t + 1, 3 * t + 7, 4 * t + 3]);
+        for h in [Hasher::Sha256, Hasher::Blake3] {
+            let all = many_leaves(h, *sizes.iter().max().unwrap());
+            for &n in &sizes {
+                let leaves = all[..n].to_vec();
+                let root = merkle_root(h, leaves.clone());
+                assert_eq!(merkle_root_par(h, leaves), root, "{h:?} with {n} leaves");
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_leaf_hashes_match_the_serial_ones() {
+        let t = PARALLEL_MERKLE_THRESHOLD;
+        for n in [0, 1, t - 1, t, t + 1] {
+            let bytes: Vec<[u8; 4]> = (0..n as u32).map(u32::to_be_bytes).collect();
+            let serial: Vec<_> = bytes.iter().map(|b| leaf_hash(Hasher::Sha256, b)).collect();
+            assert_eq!(leaf_hashes(Hasher::Sha256, &bytes), serial, "{n} leaves");
+        }
+    }
+
+    #[test]
+    fn appended_leaves_rebuild_the_new_root() {
+        let h = Hasher::Sha256;
+        for new_size in 1..=9u8 {
+            let all = leaves(new_size);
+            let root = merkle_root(h, all.clone());
+            for old_size in 0..=new_size as usize {
+                let proof = consistency_proof(h, &all, old_size as u64).unwrap();
+                verify_appended(h, &proof, &root, &all[old_size..]).unwrap();
+
+                if old_size < all.len() {
+                    let mut forged = all[old_size..].to_vec();
+                    forged[0][0] ^= 1;
+                    assert!(verify_appended(h, &proof, &root, &forged).is_err());
+                    let short = &all[old_size + 1..];
+                    assert!(verify_appended(h, &proof, &root, short).is_err());
+                }
+            }
+        }
+    }
+}
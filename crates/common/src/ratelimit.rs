@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Buckets tracked before idle (fully refilled) ones are pruned. Pruning scans every
+/// bucket, so it runs at most once per `refill` however many calls come in at capacity.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Per-key token bucket: up to `burst` requests at once, refilled at `per_sec`.
+#[derive(Debug)]
+pub struct RateLimiter<K> {
+    burst: f64,
+    per_sec: f64,
+    buckets: HashMap<K, Bucket>,
+    /// How long an empty bucket takes to refill completely.
+    refill: Duration,
+    last_prune: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(burst: u32, per_sec: f64) -> Self {
+        let (burst, per_sec) = (f64::from(burst.max(1)), per_sec.max(f64::MIN_POSITIVE));
+        Self {
+            burst,
+            per_sec,
+            buckets: HashMap::new(),
+            refill: Duration::try_from_secs_f64(burst / per_sec).unwrap_or(Duration::MAX),
+            last_prune: None,
+        }
+    }
+
+    /// Take one token for `key`, or return how long until one is available.
+    pub fn check(&mut self, key: K) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&mut self, key: K, now: Instant) -> Result<(), Duration> {
+        // Only a bucket idle for a whole `refill` can be dropped, so scanning more often
+        // than that mostly finds nothing new.
+        if self.buckets.len() >= PRUNE_THRESHOLD
+            && self.last_prune.is_none_or(|at| now.duration_since(at) >= self.refill)
+        {
+            self.prune(now);
+            self.last_prune = Some(now);
+        }
+        let (burst, per_sec) = (self.burst, self.per_sec);
+        let b = self.buckets.entry(key).or_insert(Bucket { tokens: burst, last: now });
+        b.tokens = (b.tokens + now.duration_since(b.last).as_secs_f64() * per_sec).min(burst);
+        b.last = now;
+        if b.tokens >= 1.0 {
+            b.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - b.tokens) / per_sec))
+        }
+    }
+
+    /// Drop buckets that have refilled completely; they're equivalent to a fresh one.
+    fn prune(&mut self, now: Instant) {
+        let (burst, per_sec) = (self.burst, self.per_sec);
+        self.buckets
+            .retain(|_, b| b.tokens + now.duration_since(b.last).as_secs_f64() * per_sec < burst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_full_limiter_prunes_at_most_once_per_refill() {
+        let mut limiter = RateLimiter::new(1, 1.0);
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+        for key in 0..PRUNE_THRESHOLD {
+            limiter.check_at(key, t0).unwrap();
+        }
+        // At capacity: the first call scans, and finds nothing refilled yet.
+        limiter.check_at(PRUNE_THRESHOLD, at(100)).unwrap();
+        assert_eq!(limiter.buckets.len(), PRUNE_THRESHOLD + 1);
+
+        // The first buckets have refilled, but a second scan is not due yet.
+        limiter.check_at(PRUNE_THRESHOLD + 1, at(1050)).unwrap();
+        assert_eq!(limiter.buckets.len(), PRUNE_THRESHOLD + 2);
+
+        // Once a refill has passed since the last scan, the next call drops every full bucket.
+        limiter.check_at(PRUNE_THRESHOLD + 2, at(1200)).unwrap();
+        assert_eq!(limiter.buckets.len(), 2);
+        assert!(limiter.buckets.contains_key(&(PRUNE_THRESHOLD + 1)));
+    }
+}
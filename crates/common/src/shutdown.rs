@@ -0,0 +1,93 @@
+use tracing::warn;
+
+/// Resolves on SIGINT (Ctrl-C) or, on Unix, SIGTERM.
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("failed to listen for Ctrl-C: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(e) => {
+                warn!("failed to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::io::BufRead as _;
+    use std::process::{Command, Stdio};
+    use std::time::{Duration, Instant};
+
+    /// Set in the child process this test re-runs itself as.
+    const CHILD: &str = "SHUTDOWN_TEST_CHILD";
+    /// Printed by the child once its handlers are installed.
+    const READY: &str = "signal handlers installed";
+
+    #[test]
+    fn resolves_on_sigterm_only() {
+        if std::env::var_os(CHILD).is_some() {
+            let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            rt.block_on(async {
+                let sig = signal();
+                tokio::pin!(sig);
+                // The first poll installs the handlers, so the parent's SIGTERM can't kill us.
+                let early = tokio::time::timeout(Duration::from_millis(50), &mut sig).await;
+                assert!(early.is_err(), "resolved without a signal");
+                println!("{READY}");
+                sig.await;
+            });
+            return;
+        }
+
+        // Signal a copy of this test in its own process, not the whole test binary.
+        let module = module_path!().split_once("::").unwrap().1;
+        let name = format!("{module}::resolves_on_sigterm_only");
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", &name, "--nocapture", "--test-threads=1"])
+            .env(CHILD, "1")
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stdout = std::io::BufReader::new(child.stdout.take().unwrap());
+        let mut lines = stdout.lines().map_while(Result::ok);
+        let ready = lines.any(|line| line.ends_with(READY));
+        assert!(ready, "child exited before installing its handlers");
+        // Keep reading, so the child can report its result.
+        std::thread::spawn(move || lines.for_each(drop));
+
+        let pid = child.id().to_string();
+        let kill = Command::new("kill").args(["-TERM", &pid]).status().unwrap();
+        assert!(kill.success());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let status = loop {
+            if let Some(status) = child.try_wait().unwrap() {
+                break status;
+            }
+            if Instant::now() > deadline {
+                child.kill().unwrap();
+                panic!("SIGTERM not seen");
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        // Killed by the signal instead if the handler wasn't in place.
+        assert!(status.success(), "child {status}");
+    }
+}
@@ -0,0 +1,172 @@
+use crate::crypto::Hasher;
+use crate::merkle::hash_node;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Depth of the sparse Merkle tree: one level per bit of the u64 party_id key,
+/// most significant bit at the root.
+pub const SMT_DEPTH: usize = 64;
+
+/// Leaf value at keys with no entry.
+pub const SMT_EMPTY_LEAF: [u8; 32] = [0u8; 32];
+
+/// Sibling hashes along a key's path, from the leaf level (index 0) up to the root.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SmtProof {
+    pub siblings: Vec<[u8; 32]>,
+}
+
+/// (key, leaf hash), kept sorted by key.
+type Entry = (u64, [u8; 32]);
+
+/// Roots of empty subtrees: `defaults[h]` for a subtree of height `h`.
+fn defaults(h: Hasher) -> Vec<[u8; 32]> {
+    let mut d = vec![SMT_EMPTY_LEAF];
+    for height in 0..SMT_DEPTH {
+        d.push(hash_node(h, &d[height], &d[height]));
+    }
+    d
+}
+
+/// Root of the subtree of height `height` holding `entries` (sorted by key, all sharing
+/// the bits above `height`).
+fn subtree_root(h: Hasher, entries: &[Entry], height: usize, defaults: &[[u8; 32]]) -> [u8; 32] {
+    if entries.is_empty() {
+        return defaults[height];
+    }
+    if height == 0 {
+        return entries[0].1;
+    }
+    let (left, right) = split(entries, height - 1);
+    hash_node(
+        h,
+        &subtree_root(h, left, height - 1, defaults),
+        &subtree_root(h, right, height - 1, defaults),
+    )
+}
+
+/// Split sorted entries on key bit `bit`: (bit clear, bit set).
+fn split(entries: &[Entry], bit: usize) -> (&[Entry], &[Entry]) {
+    entries.split_at(entries.partition_point(|(k, _)| (k >> bit) & 1 == 0))
+}
+
+/// Sparse Merkle root over `leaves` (party_id -> leaf hash).
+pub fn smt_root(h: Hasher, leaves: &BTreeMap<u64, [u8; 32]>) -> [u8; 32] {
+    let entries: Vec<Entry> = leaves.iter().map(|(k, v)| (*k, *v)).collect();
+    subtree_root(h, &entries, SMT_DEPTH, &defaults(h))
+}
+
+/// Proof for `key` against `smt_root(leaves)`. Works whether or not `key` is present;
+/// an absent key proves non-membership.
+pub fn smt_proof(h: Hasher, leaves: &BTreeMap<u64, [u8; 32]>, key: u64) -> SmtProof {
+    let defaults = defaults(h);
+    let entries: Vec<Entry> = leaves.iter().map(|(k, v)| (*k, *v)).collect();
+    let mut siblings = vec![SMT_EMPTY_LEAF; SMT_DEPTH];
+    let mut path = &entries[..];
+    for height in (0..SMT_DEPTH).rev() {
+        let (left, right) = split(path, height);
+        if (key >> height) & 1 == 0 {
+            siblings[height] = subtree_root(h, right, height, &defaults);
+            path = left;
+        } else {
+            siblings[height] = subtree_root(h, left, height, &defaults);
+            path = right;
+        }
+    }
+    SmtProof { siblings }
+}
+
+/// Check `proof` shows `key` maps to `leaf` under `root`; `leaf: None` checks that
+/// `key` is absent.
+pub fn verify_smt_proof(
+    h: Hasher,
+    root: &[u8; 32],
+    key: u64,
+    leaf: Option<&[u8; 32]>,
+    proof: &SmtProof,
+) -> Result<()> {
+    if proof.siblings.len() != SMT_DEPTH {
+        return Err(anyhow!(
+            "smt proof has {} siblings, expected {SMT_DEPTH}",
+            proof.siblings.len()
+        ));
+    }
+    let mut acc = leaf.copied().unwrap_or(SMT_EMPTY_LEAF);
+    for (height, sib) in proof.siblings.iter().enumerate() {
+        acc = if (key >> height) & 1 == 0 {
+            hash_node(h, &acc, sib)
+        } else {
+            hash_node(h, sib, &acc)
+        };
+    }
+    if acc != *root {
+        return Err(anyhow!("smt proof for key={key} does not match root"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::leaf_hash;
+
+    fn leaves(h: Hasher, keys: &[u64]) -> BTreeMap<u64, [u8; 32]> {
+        keys.iter().map(|&k| (k, leaf_hash(h, &k.to_le_bytes()))).collect()
+    }
+
+    #[test]
+    fn present_keys_prove_membership_and_absent_keys_absence() {
+        let h = Hasher::Sha256;
+        let tree = leaves(h, &[0, 1, 2, 7, 1 << 40, u64::MAX]);
+        let root = smt_root(h, &tree);
+        for (&key, leaf) in &tree {
+            let proof = smt_proof(h, &tree, key);
+            verify_smt_proof(h, &root, key, Some(leaf), &proof).unwrap();
+            assert!(verify_smt_proof(h, &root, key, None, &proof).is_err());
+        }
+        for key in [3, 6, 8, 1 << 39, u64::MAX - 1] {
+            let proof = smt_proof(h, &tree, key);
+            verify_smt_proof(h, &root, key, None, &proof).unwrap();
+            let claimed = leaf_hash(h, &key.to_le_bytes());
+            assert!(verify_smt_proof(h, &root, key, Some(&claimed), &proof).is_err());
+        }
+    }
+
+    #[test]
+    fn tampered_proofs_are_rejected() {
+        let h = Hasher::Sha256;
+        let tree = leaves(h, &[1, 2, 3, 100]);
+        let root = smt_root(h, &tree);
+        let leaf = tree[&2];
+        let proof = smt_proof(h, &tree, 2);
+
+        for height in [0, 1, 6, 63] {
+            let mut forged = proof.clone();
+            forged.siblings[height][0] ^= 1;
+            assert!(verify_smt_proof(h, &root, 2, Some(&leaf), &forged).is_err(), "{height}");
+        }
+        let mut other_leaf = leaf;
+        other_leaf[31] ^= 1;
+        assert!(verify_smt_proof(h, &root, 2, Some(&other_leaf), &proof).is_err());
+        // The proof is tied to the key's path, not just to the leaf.
+        assert!(verify_smt_proof(h, &root, 3, Some(&leaf), &proof).is_err());
+        let short = SmtProof { siblings: proof.siblings[1..].to_vec() };
+        assert!(verify_smt_proof(h, &root, 2, Some(&leaf), &short).is_err());
+        // Absence of a key can't be proven with another key's proof.
+        let absent = smt_proof(h, &tree, 4);
+        assert!(verify_smt_proof(h, &root, 2, None, &absent).is_err());
+    }
+
+    #[test]
+    fn root_depends_on_every_entry_and_the_hash() {
+        let tree = leaves(Hasher::Sha256, &[5, 9]);
+        let root = smt_root(Hasher::Sha256, &tree);
+        assert_eq!(smt_root(Hasher::Sha256, &BTreeMap::new()), defaults(Hasher::Sha256)[64]);
+        assert_ne!(smt_root(Hasher::Blake3, &tree), root);
+        let mut moved = tree.clone();
+        let leaf = moved.remove(&9).unwrap();
+        moved.insert(10, leaf);
+        assert_ne!(smt_root(Hasher::Sha256, &moved), root);
+    }
+}
@@ -1,50 +1,328 @@
+use crate::crypto::{sha256, Hasher};
+use crate::merkle::InclusionProof;
+use crate::smt::SmtProof;
 use serde::{Deserialize, Serialize};
 use serde_big_array::BigArray;
+use base64::Engine as _;
 
-/// Party endpoint. Keep as a string for simplicity: "ip:port" or "host:port".
+/// Current version of the signed `RegistrationMessage` layout.
+pub const REGISTRATION_MSG_VERSION: u8 = 4;
+
+/// Current version of the signed `SnapshotMessage` layout.
+pub const SNAPSHOT_MSG_VERSION: u8 = 6;
+
+/// Current version of the signed `RegistrationReceipt` layout.
+pub const RECEIPT_MSG_VERSION: u8 = 1;
+
+/// Party endpoint. Keep as a string for simplicity: "ip:port", "[v6]:port" or "host:port".
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Endpoint {
     pub addr: String,
 }
 
+impl Endpoint {
+    /// Check `addr` is "ipv4:port", "[ipv6]:port" or "hostname:port" with a nonzero port.
+    /// Bare IPv6 without brackets is rejected since its port can't be told apart, as are
+    /// IPs no peer could dial (unspecified, multicast, broadcast).
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let addr = &self.addr;
+        if let Ok(sa) = addr.parse::<std::net::SocketAddr>() {
+            if sa.port() == 0 {
+                anyhow::bail!("invalid endpoint {addr:?}: port must be nonzero");
+            }
+            let ip = sa.ip();
+            let broadcast = matches!(ip, std::net::IpAddr::V4(v4) if v4.is_broadcast());
+            if ip.is_unspecified() || ip.is_multicast() || broadcast {
+                anyhow::bail!("invalid endpoint {addr:?}: {ip} is not a dialable address");
+            }
+            return Ok(());
+        }
+        let (host, port) = addr
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow::anyhow!("invalid endpoint {addr:?}: expected host:port"))?;
+        match port.parse::<u16>() {
+            Ok(p) if p != 0 => {}
+            _ => anyhow::bail!("invalid endpoint {addr:?}: bad port {port:?}"),
+        }
+        if host.contains(':') || host.starts_with('[') {
+            anyhow::bail!("invalid endpoint {addr:?}: IPv6 addresses must be written [addr]:port");
+        }
+        if host.parse::<std::net::Ipv4Addr>().is_err() && !is_hostname(host) {
+            anyhow::bail!("invalid endpoint {addr:?}: bad host {host:?}");
+        }
+        Ok(())
+    }
+}
+
+/// RFC 1123 hostname: dot-separated labels of 1..=63 alphanumerics/hyphens, not starting
+/// or ending with a hyphen, at most 253 chars overall, and not all-numeric.
+fn is_hostname(host: &str) -> bool {
+    let host = host.strip_suffix('.').unwrap_or(host);
+    let label_ok = |l: &str| {
+        (1..=63).contains(&l.len())
+            && l.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+            && !l.starts_with('-')
+            && !l.ends_with('-')
+    };
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(label_ok)
+        && !host.bytes().all(|b| b.is_ascii_digit() || b == b'.')
+}
+
 /// Party Registration *message* (what is signed by the party).
 /// This is the canonical structure that is serialized (bincode) and signed.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct RegistrationMessage {
+    /// Layout version (`REGISTRATION_MSG_VERSION`). Part of the signed bytes.
+    pub version: u8,
     pub epoch: u64,
     pub party_id: u64,
     pub endpoint: Endpoint,
-    /// Party public key (ed25519) in raw bytes (32 bytes).
+    /// `SignatureScheme::TAG` of the scheme `pk_party` and the signatures belong to.
+    pub scheme: u8,
+    /// Party public key in raw bytes (32 bytes for ed25519).
     pub pk_party: [u8; 32],
     /// Monotonic per-party sequence within an epoch.
     pub seq: u64,
     /// Random 128-bit nonce for uniqueness/hygiene.
     pub nonce: [u8; 16],
+    /// Unix time (seconds) at which the party signed this message.
+    pub created_at_unix: u64,
+    /// Set when this registration rotates the party's key from `old_pk` to `pk_party`.
+    pub rotation: Option<KeyRotation>,
+}
+
+/// What the *old* key signs to endorse a rotation to a new key.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RotationMessage {
+    pub epoch: u64,
+    pub party_id: u64,
+    pub seq: u64,
+    pub old_pk: [u8; 32],
+    pub new_pk: [u8; 32],
+}
+
+/// Old-key endorsement carried inside a rotating `RegistrationMessage`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KeyRotation {
+    pub old_pk: [u8; 32],
+    /// Old key's signature over signing_digest(CTX_ROTATION, RotationMessage).
+    #[serde(with = "BigArray")]
+    pub sig_old: [u8; 64],
+}
+
+impl RegistrationMessage {
+    /// The message `old_pk` signs to endorse rotating to this message's `pk_party`.
+    pub fn rotation_message(&self, old_pk: [u8; 32]) -> RotationMessage {
+        RotationMessage {
+            epoch: self.epoch,
+            party_id: self.party_id,
+            seq: self.seq,
+            old_pk,
+            new_pk: self.pk_party,
+        }
+    }
+
+    /// Reject messages with a layout version this build doesn't understand.
+    pub fn check_version(&self) -> anyhow::Result<()> {
+        if self.version != REGISTRATION_MSG_VERSION {
+            anyhow::bail!(
+                "unsupported RegistrationMessage version: got={}, supported={}",
+                self.version,
+                REGISTRATION_MSG_VERSION
+            );
+        }
+        Ok(())
+    }
 }
 
 /// Party Registration Record = message + party signature.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct PartyRegistrationRecord {
     pub msg: RegistrationMessage,
-    /// Party signature over H(Enc(msg)).
+    /// Party signature over signing_digest(CTX_PRR, msg).
     #[serde(with = "BigArray")]
     pub sig_party: [u8; 64],
 }
 
+/// What log compaction leaves at a superseded record's position: the position's leaf, so
+/// indices, roots and proofs stay as they were, and whose record it was.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Tombstone {
+    pub party_id: u64,
+    pub seq: u64,
+    /// leaf_hash of the record that was dropped.
+    pub leaf: [u8; 32],
+    /// The dropped record's nonce, so a replayed log still refuses its reuse.
+    pub nonce: [u8; 16],
+}
+
+/// One position of the log as served by /entries: a record, or the tombstone compaction
+/// left in place of a superseded one. Records are boxed so a compacted log's tombstones
+/// take only their own size.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum LogEntry {
+    Record(Box<PartyRegistrationRecord>),
+    Tombstone { tombstone: Tombstone },
+}
+
+impl LogEntry {
+    pub fn party_id(&self) -> u64 {
+        match self {
+            LogEntry::Record(prr) => prr.msg.party_id,
+            LogEntry::Tombstone { tombstone } => tombstone.party_id,
+        }
+    }
+
+    pub fn seq(&self) -> u64 {
+        match self {
+            LogEntry::Record(prr) => prr.msg.seq,
+            LogEntry::Tombstone { tombstone } => tombstone.seq,
+        }
+    }
+
+    /// The record, unless it was compacted away.
+    pub fn record(&self) -> Option<&PartyRegistrationRecord> {
+        match self {
+            LogEntry::Record(prr) => Some(prr),
+            LogEntry::Tombstone { .. } => None,
+        }
+    }
+
+    /// The Merkle leaf at this position: the record's leaf hash, or the tombstone's.
+    pub fn leaf(&self, h: Hasher) -> anyhow::Result<[u8; 32]> {
+        match self {
+            LogEntry::Record(prr) => Ok(crate::merkle::leaf_hash(h, &crate::crypto::enc(prr)?)),
+            LogEntry::Tombstone { tombstone } => Ok(tombstone.leaf),
+        }
+    }
+}
+
+impl From<PartyRegistrationRecord> for LogEntry {
+    fn from(prr: PartyRegistrationRecord) -> Self {
+        LogEntry::Record(Box::new(prr))
+    }
+}
+
 /// Watchtower Snapshot *message* (what is signed by watchtower).
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct SnapshotMessage {
+    /// Layout version (`SNAPSHOT_MSG_VERSION`). Part of the signed bytes.
+    pub version: u8,
     pub epoch: u64,
     pub log_len: u64,
     /// Merkle root committing to PRR log [1..log_len]
     pub merkle_root: [u8; 32],
+    /// Sparse Merkle root keyed by party_id over each party's latest PRR leaf hash
+    /// (`common::smt`); gives O(log N) (non-)membership proofs.
+    pub smt_root: [u8; 32],
+    /// `crypto::Hasher` tag both roots (and their leaf hashes) are computed with.
+    pub hash_alg: u8,
+    /// Public key of the watchtower that signs this message, so the signature also binds
+    /// the key it verifies under.
+    pub watchtower_pk: [u8; 32],
+}
+
+impl SnapshotMessage {
+    /// Reject messages with a layout version this build doesn't understand.
+    pub fn check_version(&self) -> anyhow::Result<()> {
+        if self.version != SNAPSHOT_MSG_VERSION {
+            anyhow::bail!(
+                "unsupported SnapshotMessage version: got={}, supported={}",
+                self.version,
+                SNAPSHOT_MSG_VERSION
+            );
+        }
+        Ok(())
+    }
+
+    /// The hash this snapshot's roots are computed with.
+    pub fn hasher(&self) -> anyhow::Result<Hasher> {
+        Hasher::from_tag(self.hash_alg)
+    }
+
+    /// This view's `snapshot_id`.
+    pub fn snapshot_id(&self) -> [u8; 32] {
+        snapshot_id(self.epoch, self.log_len, &self.merkle_root)
+    }
+
+    /// `snapshot_id`, hex-encoded as logs and `SNAPSHOT_ID_HEADER` carry it.
+    pub fn snapshot_id_hex(&self) -> String {
+        self.snapshot_id().iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    /// Reject a snapshot whose embedded watchtower key isn't `pk_w`, the (pinned) key it
+    /// is about to be verified with.
+    pub fn check_watchtower_pk(&self, pk_w: &[u8; 32]) -> anyhow::Result<()> {
+        if self.watchtower_pk != *pk_w {
+            let b64 = |v: &[u8]| base64::engine::general_purpose::STANDARD.encode(v);
+            anyhow::bail!(
+                "snapshot embeds watchtower pubkey {}, expected {}",
+                b64(&self.watchtower_pk),
+                b64(pk_w)
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Content-addressed handle for one view of an epoch's log: SHA-256 of `epoch` and
+/// `log_len` (little-endian u64s) and `merkle_root`. Unlike `(epoch, log_len)` alone, two
+/// views that disagree on the root get different ids.
+pub fn snapshot_id(epoch: u64, log_len: u64, merkle_root: &[u8; 32]) -> [u8; 32] {
+    let mut buf = [0u8; 48];
+    buf[..8].copy_from_slice(&epoch.to_le_bytes());
+    buf[8..16].copy_from_slice(&log_len.to_le_bytes());
+    buf[16..].copy_from_slice(merkle_root);
+    sha256(&buf)
 }
 
 /// Signed roster snapshot = snapshot message + watchtower signature.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct SignedRosterSnapshot {
     pub msg: SnapshotMessage,
-    /// Watchtower signature over H(Enc(msg)).
+    /// Watchtower signature over signing_digest(CTX_SNAPSHOT, msg).
+    #[serde(with = "BigArray")]
+    pub sig_watchtower: [u8; 64],
+}
+
+/// Watchtower acknowledgment that a specific PRR was accepted at `assigned_index`
+/// (what is signed by the watchtower).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RegistrationReceipt {
+    /// Layout version (`RECEIPT_MSG_VERSION`). Part of the signed bytes.
+    pub version: u8,
+    pub party_id: u64,
+    pub seq: u64,
+    /// 1-indexed position of the PRR in the log.
+    pub assigned_index: u64,
+    /// leaf_hash(enc(prr)) of the accepted record, tying the receipt to its exact bytes.
+    pub prr_leaf: [u8; 32],
+    /// The snapshot right after the append (`log_len == assigned_index`).
+    pub snapshot_after: SnapshotMessage,
+}
+
+impl RegistrationReceipt {
+    /// Reject receipts with a layout version this build doesn't understand.
+    pub fn check_version(&self) -> anyhow::Result<()> {
+        if self.version != RECEIPT_MSG_VERSION {
+            anyhow::bail!(
+                "unsupported RegistrationReceipt version: got={}, supported={}",
+                self.version,
+                RECEIPT_MSG_VERSION
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Registration receipt + watchtower signature.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SignedRegistrationReceipt {
+    pub receipt: RegistrationReceipt,
+    /// Watchtower signature over signing_digest(CTX_RECEIPT, receipt).
     #[serde(with = "BigArray")]
     pub sig_watchtower: [u8; 64],
 }
@@ -55,17 +333,241 @@ pub struct RegisterRequest {
     pub prr: PartyRegistrationRecord,
 }
 
-/// Response payload for /register and /snapshot.
+/// Response payload for /register.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisterResponse {
+    pub srs: SignedRosterSnapshot,
+    pub receipt: SignedRegistrationReceipt,
+}
+
+/// One of the checks /register runs on a record, as reported by /verify.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifyCheck {
+    /// Which check: "version", "epoch", "endpoint", "pubkey", "signature", "created_at",
+    /// "seq", "nonce", "key_binding" or "caps".
+    pub check: String,
+    pub passed: bool,
+    /// The error /register would answer with, if the check failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<WatchtowerError>,
+}
+
+/// Response payload for /verify: the outcome of each check /register would run on the
+/// record, in order. Unlike /register it runs them all and logs nothing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifyResponse {
+    /// True if every check passed, i.e. /register would accept the record.
+    pub accepted: bool,
+    /// True if the record is byte-for-byte the party's latest, which /register answers
+    /// with its original response. The checks after "seq" are then skipped.
+    #[serde(default)]
+    pub resubmission: bool,
+    pub checks: Vec<VerifyCheck>,
+}
+
+/// Response payload for /snapshot and /finalize.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SnapshotResponse {
     pub srs: SignedRosterSnapshot,
+    /// True once the epoch is finalized; `srs` is then the final roster commitment.
+    #[serde(default)]
+    pub finalized: bool,
 }
 
-/// Response payload for /entries.
+/// Response payload for /party/{party_id}: the party's latest record, with proofs
+/// against `srs` that it is in the log and is the latest one.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct EntriesResponse {
-    /// 1-indexed entries, returned in order.
-    pub entries: Vec<PartyRegistrationRecord>,
+pub struct PartyResponse {
+    pub prr: PartyRegistrationRecord,
+    /// 1-indexed log position of `prr`.
+    pub index: u64,
+    /// Inclusion of `prr` under `srs.msg.merkle_root`.
+    pub proof: InclusionProof,
+    /// Membership of the party's latest leaf under `srs.msg.smt_root`.
+    pub smt_proof: SmtProof,
+    pub srs: SignedRosterSnapshot,
+}
+
+/// Response payload for /merkle_proof: the record at `index` with its inclusion proof
+/// against `srs`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MerkleProofResponse {
+    pub prr: PartyRegistrationRecord,
+    /// 1-indexed log position of `prr`.
+    pub index: u64,
+    /// Inclusion of `prr` under `srs.msg.merkle_root`.
+    pub proof: InclusionProof,
+    pub srs: SignedRosterSnapshot,
+}
+
+/// Response payload for /compact.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompactResponse {
+    /// How many superseded records this compaction replaced with tombstones.
+    pub tombstoned: u64,
+    /// Snapshot of the log, the same before and after: compaction keeps every leaf. The
+    /// one before is also kept among the epoch's checkpoints.
+    pub srs: SignedRosterSnapshot,
+}
+
+/// Response payload for /checkpoints.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckpointsResponse {
+    /// Snapshots signed each time the log reached a multiple of the checkpoint interval,
+    /// in increasing log_len order.
+    pub checkpoints: Vec<SignedRosterSnapshot>,
+}
+
+/// One party in a /roster response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RosterParty {
+    pub party_id: u64,
+    pub endpoint: String,
+    pub seq: u64,
+    pub pk_party_b64: String,
+    /// When the watchtower received the party's latest record (unix seconds).
+    pub last_seen_unix: u64,
+}
+
+/// Response payload for /roster: each party's latest record, sorted by party_id. Unsigned
+/// and advisory; membership is proven against a snapshot (see /party).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RosterResponse {
+    pub log_len: u64,
+    pub parties: Vec<RosterParty>,
+}
+
+/// Stable, machine-readable error codes carried in `WatchtowerError` bodies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// The record is for a different epoch than the watchtower's.
+    EpochMismatch,
+    /// The record's epoch is outside the window of epochs the watchtower accepts.
+    EpochOutOfWindow,
+    /// A party or rotation signature failed to verify.
+    BadSignature,
+    /// The party public key is malformed or degenerate (e.g. a small-order point).
+    BadPubkey,
+    /// The record's seq is not above the party's last accepted seq.
+    SeqNotIncreasing,
+    /// The record reuses a nonce from one of the party's recent registrations.
+    NonceReused,
+    /// The epoch has been finalized and takes no more registrations (HTTP 409).
+    EpochFinalized,
+    /// The epoch already has `max_parties` parties and the record is from a new one.
+    PartyCapReached,
+    /// The epoch's log already holds `max_log_len` records.
+    LogFull,
+    /// The requested range or size is beyond the current log (HTTP 404).
+    OutOfRange,
+    /// No such epoch or party (HTTP 404).
+    NotFound,
+    Unauthorized,
+    RateLimited,
+    /// Any other malformed or disallowed request (HTTP 400; 415 or 422 for a body that
+    /// isn't the expected JSON).
+    BadRequest,
+    /// The request body is over the watchtower's size cap (HTTP 413).
+    BodyTooLarge,
+    /// The watchtower failed, e.g. writing its log file; the request may succeed later
+    /// (HTTP 500).
+    Internal,
+    /// The log was compacted while /entries was streaming it; retry the request.
+    LogChanged,
+    /// The watchtower is a read-only replica and takes no registrations (HTTP 405).
+    ReadOnly,
+    /// A code this build doesn't know (sent by a newer watchtower).
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::EpochMismatch => "EPOCH_MISMATCH",
+            ErrorCode::EpochOutOfWindow => "EPOCH_OUT_OF_WINDOW",
+            ErrorCode::BadSignature => "BAD_SIGNATURE",
+            ErrorCode::BadPubkey => "BAD_PUBKEY",
+            ErrorCode::SeqNotIncreasing => "SEQ_NOT_INCREASING",
+            ErrorCode::NonceReused => "NONCE_REUSED",
+            ErrorCode::EpochFinalized => "EPOCH_FINALIZED",
+            ErrorCode::PartyCapReached => "PARTY_CAP_REACHED",
+            ErrorCode::LogFull => "LOG_FULL",
+            ErrorCode::OutOfRange => "OUT_OF_RANGE",
+            ErrorCode::NotFound => "NOT_FOUND",
+            ErrorCode::Unauthorized => "UNAUTHORIZED",
+            ErrorCode::RateLimited => "RATE_LIMITED",
+            ErrorCode::BadRequest => "BAD_REQUEST",
+            ErrorCode::BodyTooLarge => "BODY_TOO_LARGE",
+            ErrorCode::Internal => "INTERNAL",
+            ErrorCode::LogChanged => "LOG_CHANGED",
+            ErrorCode::ReadOnly => "READ_ONLY",
+            ErrorCode::Unknown => "UNKNOWN",
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// JSON body of every watchtower error response: `{ "code": ..., "message": ... }`.
+/// Also usable as an `anyhow` error, so the code survives from where it's raised.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WatchtowerError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl WatchtowerError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+}
+
+impl std::fmt::Display for WatchtowerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for WatchtowerError {}
+
+/// /entries streams its records as NDJSON: one `LogEntry` per line, in order. A stream
+/// cut short ends with an `EntriesError` line instead.
+pub const ENTRIES_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Last line of an /entries stream the watchtower had to abort: `{ "error": { ... } }`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EntriesError {
+    pub error: WatchtowerError,
+}
+
+/// Response header set on /entries when the range was truncated by the page limit:
+/// where to continue from.
+pub const NEXT_FROM_HEADER: &str = "x-next-from";
+
+/// Response header set on /snapshot and /snapshot_at: the served snapshot's
+/// `SnapshotMessage::snapshot_id_hex`.
+pub const SNAPSHOT_ID_HEADER: &str = "x-snapshot-id";
+
+/// Response payload for /healthz.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthResponse {
+    pub epoch: u64,
+    pub log_len: u64,
+    pub uptime_secs: u64,
+}
+
+/// Response payload for a party's /peers: who it has handshaked with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeersResponse {
+    pub party_id: u64,
+    /// Sorted party_ids this party has successfully handshaked with.
+    pub connected: Vec<u64>,
 }
 
 /// Optional gossip payload (party-to-party) to detect watchtower equivocation.
@@ -73,4 +575,183 @@ pub struct EntriesResponse {
 pub struct GossipSnapshot {
     pub from_party_id: u64,
     pub srs: SignedRosterSnapshot,
+    /// A record in the sender's view with its proof against `srs`, so a receiver that
+    /// sees a conflicting root can tell at which index the views differ.
+    #[serde(default)]
+    pub evidence: Option<GossipEvidence>,
+}
+
+/// One gossiped snapshot as recorded by the receiving party, for GET /gossip/history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GossipObservation {
+    /// When the gossip was received (unix seconds).
+    pub received_at_unix: u64,
+    pub from_party_id: u64,
+    pub epoch: u64,
+    pub log_len: u64,
+    pub merkle_root_b64: String,
+}
+
+/// The record at a (contested) log index, with its inclusion proof against a snapshot root.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GossipEvidence {
+    /// 1-based log position of `prr`.
+    pub index: u64,
+    pub prr: PartyRegistrationRecord,
+    pub proof: InclusionProof,
+}
+
+/// `value` as canonical JSON: object keys sorted, no whitespace. Equal values serialize
+/// to the same bytes whatever their types' field order, so evidence and dumps written by
+/// different watchtowers or builds can be compared byte for byte.
+pub fn canonical_json<T: Serialize>(value: &T) -> serde_json::Result<String> {
+    serde_json::to_string(&sort_keys(serde_json::to_value(value)?))
+}
+
+fn sort_keys(value: serde_json::Value) -> serde_json::Value {
+    use serde_json::Value;
+    match value {
+        Value::Object(map) => {
+            let mut fields: Vec<(String, Value)> = map.into_iter().collect();
+            fields.sort_by(|a, b| a.0.cmp(&b.0));
+            Value::Object(fields.into_iter().map(|(k, v)| (k, sort_keys(v))).collect())
+        }
+        Value::Array(items) => Value::Array(items.into_iter().map(sort_keys).collect()),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(watchtower_pk: [u8; 32]) -> SnapshotMessage {
+        SnapshotMessage {
+            version: SNAPSHOT_MSG_VERSION,
+            epoch: 1,
+            log_len: 2,
+            merkle_root: [3; 32],
+            smt_root: [4; 32],
+            hash_alg: Hasher::Sha256.tag(),
+            watchtower_pk,
+        }
+    }
+
+    #[test]
+    fn registration_without_scheme_is_refused() {
+        let msg = RegistrationMessage {
+            version: REGISTRATION_MSG_VERSION,
+            epoch: 1,
+            party_id: 2,
+            endpoint: Endpoint { addr: "10.0.0.2:9000".into() },
+            scheme: 0,
+            pk_party: [5; 32],
+            seq: 1,
+            nonce: [6; 16],
+            created_at_unix: 1_700_000_000,
+            rotation: None,
+        };
+        let mut json = serde_json::to_value(&msg).unwrap();
+        json.as_object_mut().unwrap().remove("scheme");
+        let err = serde_json::from_value::<RegistrationMessage>(json).unwrap_err();
+        assert!(err.to_string().contains("missing field `scheme`"), "{err}");
+    }
+
+    #[test]
+    fn embedded_watchtower_pk_must_match() {
+        let msg = snapshot([9; 32]);
+        msg.check_watchtower_pk(&[9; 32]).unwrap();
+        let err = msg.check_watchtower_pk(&[8; 32]).unwrap_err();
+        assert!(err.to_string().contains("snapshot embeds watchtower pubkey"), "{err}");
+    }
+
+    #[test]
+    fn canonical_json_is_sorted_compact_and_stable() {
+        let obs = GossipObservation {
+            received_at_unix: 7,
+            from_party_id: 2,
+            epoch: 1,
+            log_len: 3,
+            merkle_root_b64: "cm9vdA==".into(),
+        };
+        let json = canonical_json(&obs).unwrap();
+        let expected = concat!(
+            r#"{"epoch":1,"from_party_id":2,"log_len":3,"#,
+            r#""merkle_root_b64":"cm9vdA==","received_at_unix":7}"#
+        );
+        assert_eq!(json, expected);
+
+        // Nested objects are sorted too, and reparsing gives back the same bytes.
+        let srs = SignedRosterSnapshot { msg: snapshot([9; 32]), sig_watchtower: [5; 64] };
+        let json = canonical_json(&srs).unwrap();
+        assert!(json.starts_with(r#"{"msg":{"epoch":1,"hash_alg":"#), "{json}");
+        assert!(!json.contains([' ', '\n']));
+        let reparsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(canonical_json(&reparsed).unwrap(), json);
+        assert_eq!(canonical_json(&srs.clone()).unwrap(), json);
+    }
+
+    #[test]
+    fn snapshot_id_changes_iff_epoch_log_len_or_root_does() {
+        let msg = snapshot([9; 32]);
+        let id = msg.snapshot_id();
+        assert_eq!(id, snapshot_id(1, 2, &[3; 32]));
+        assert_eq!(msg.snapshot_id_hex().len(), 64);
+
+        // Fields outside the three don't name a different view.
+        let mut same = snapshot([8; 32]);
+        same.smt_root = [0; 32];
+        same.hash_alg = Hasher::Blake3.tag();
+        assert_eq!(same.snapshot_id(), id);
+
+        let changed: [fn(&mut SnapshotMessage); 3] = [
+            |m| m.epoch += 1,
+            |m| m.log_len += 1,
+            |m| m.merkle_root[31] ^= 1,
+        ];
+        for change in changed {
+            let mut other = msg.clone();
+            change(&mut other);
+            assert_ne!(other.snapshot_id(), id, "{other:?}");
+        }
+        // Fields don't run into each other: moving a value between them changes the id.
+        assert_ne!(snapshot_id(2, 1, &[3; 32]), id);
+    }
+
+    #[test]
+    fn snapshot_without_hash_alg_or_watchtower_pk_is_refused() {
+        for field in ["hash_alg", "watchtower_pk"] {
+            let mut json = serde_json::to_value(snapshot([9; 32])).unwrap();
+            json.as_object_mut().unwrap().remove(field);
+            let err = serde_json::from_value::<SnapshotMessage>(json).unwrap_err();
+            assert!(err.to_string().contains(&format!("missing field `{field}`")), "{err}");
+        }
+    }
+
+    #[test]
+    fn endpoints_are_ipv4_bracketed_ipv6_or_hostnames_with_a_port() {
+        let valid = |addr: &str| Endpoint { addr: addr.into() }.validate();
+        for ok in ["10.0.0.1:9000", "[::1]:9000", "[2001:db8::7]:1", "party-1.example.com:9000"] {
+            valid(ok).unwrap_or_else(|e| panic!("{ok}: {e}"));
+        }
+        valid("localhost.:9000").unwrap();
+        let malformed = [
+            "10.0.0.1",
+            "10.0.0.1:0",
+            "10.0.0.1:70000",
+            "::1:9000",
+            "[::1]",
+            "0.0.0.0:9000",
+            "224.0.0.1:9000",
+            "255.255.255.255:9000",
+            "-party.example.com:9000",
+            "bad_host:9000",
+            "1.2.3:9000",
+            ":9000",
+        ];
+        for bad in malformed {
+            let err = valid(bad).unwrap_err();
+            assert!(err.to_string().contains("invalid endpoint"), "{bad}: {err}");
+        }
+    }
 }
@@ -0,0 +1,148 @@
+//! Fixed test vectors for the bytes other implementations must reproduce: the record
+//! encoding, its signing digest and signature, leaf hashes and Merkle roots. A failure
+//! here means the wire or signed format changed; don't update a vector without meaning to.
+
+use common::crypto::{
+    enc, sign_struct, signing_digest, verify_struct, Ed25519, Hasher, SignatureScheme, CTX_PRR,
+};
+use common::merkle::{leaf_hash, merkle_root};
+use common::types::{
+    Endpoint, PartyRegistrationRecord, RegistrationMessage, REGISTRATION_MSG_VERSION,
+};
+use ed25519_dalek::SigningKey;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Party `n`'s key: the seed is `n` repeated.
+fn key(party_id: u64) -> SigningKey {
+    SigningKey::from_bytes(&[party_id as u8; 32])
+}
+
+fn msg(party_id: u64, seq: u64) -> RegistrationMessage {
+    RegistrationMessage {
+        version: REGISTRATION_MSG_VERSION,
+        epoch: 7,
+        party_id,
+        endpoint: Endpoint { addr: format!("10.0.0.{party_id}:9000") },
+        scheme: Ed25519::TAG,
+        pk_party: key(party_id).verifying_key().to_bytes(),
+        seq,
+        nonce: [seq as u8; 16],
+        created_at_unix: 1_700_000_000,
+        rotation: None,
+    }
+}
+
+fn prr(party_id: u64, seq: u64) -> PartyRegistrationRecord {
+    let msg = msg(party_id, seq);
+    let sig_party = sign_struct(&key(party_id), CTX_PRR, &msg).unwrap();
+    PartyRegistrationRecord { msg, sig_party }
+}
+
+/// Five records, three parties, two of which re-registered.
+fn log() -> Vec<PartyRegistrationRecord> {
+    [(1, 1), (2, 1), (1, 2), (3, 1), (2, 2)].map(|(party_id, seq)| prr(party_id, seq)).to_vec()
+}
+
+fn leaves(h: Hasher) -> Vec<[u8; 32]> {
+    log().iter().map(|prr| leaf_hash(h, &enc(prr).unwrap())).collect()
+}
+
+const MSG_ENC: &str = "\
+    04070000000000000001000000000000000d0000000000000031302e302e302e313a39303030008a88e3\
+    dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c01000000000000000101010101\
+    010101010101010101010100f153650000000000";
+
+#[test]
+fn registration_message_encoding() {
+    assert_eq!(hex(&enc(&msg(1, 1)).unwrap()), MSG_ENC);
+    assert_eq!(
+        hex(&key(1).verifying_key().to_bytes()),
+        "8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c"
+    );
+    // A record is its message followed by the 64 signature bytes.
+    let prr = prr(1, 1);
+    assert_eq!(hex(&enc(&prr).unwrap()), format!("{MSG_ENC}{}", hex(&prr.sig_party)));
+}
+
+#[test]
+fn registration_signature() {
+    let msg = msg(1, 1);
+    assert_eq!(
+        hex(&signing_digest(CTX_PRR, &msg).unwrap()),
+        "dcd67ff5bba8c3a12b01b1ff5fbb05df29fd6c3d03ff068625fab332267cf12e"
+    );
+    let sig = sign_struct(&key(1), CTX_PRR, &msg).unwrap();
+    assert_eq!(
+        hex(&sig),
+        "71ca99c5fd9974ce3cfb32b5eeead9b3cd1453f4300799e0b9d1e83261cc8015\
+         9748b5b620588fe68f315c49060555d8b85a7509902b5945ba81bbc58bc1cd05"
+    );
+    verify_struct(&key(1).verifying_key(), CTX_PRR, &msg, &sig).unwrap();
+}
+
+#[test]
+fn leaf_hashes() {
+    let leaves: Vec<String> = leaves(Hasher::Sha256).iter().map(|l| hex(l)).collect();
+    assert_eq!(
+        leaves,
+        [
+            "e2d42b4993a967aa031264beef93e634f00e77b973b5cea1190d6ea1216e60f9",
+            "63fee1c7a61a605aa2caa6129d6dca4d835cd7e39db962bbfc731c3d84dc606a",
+            "4b7e6ee16f2b6d5f497a751e3b935c23c4cfa4ad053d9a7290317ad9a74314e0",
+            "57c5e4ae73f766022b57c91dfe9d7d9a9247a11f0f1d58aca4f87721367469d8",
+            "1567a9b09b101d1566b13e469fb96520856f658b3091e3191f3fe4aaaf2d9c2a",
+        ]
+    );
+}
+
+/// Roots of the first 0..=5 records of `log()`; 1, 3 and 5 have odd levels, whose last
+/// node is paired with itself.
+#[test]
+fn merkle_roots_of_each_prefix() {
+    let cases = [
+        (
+            Hasher::Sha256,
+            [
+                "cc1d2f838445db7aec431df9ee8a871f40e7aa5e064fc056633ef8c60fab7b06",
+                "e2d42b4993a967aa031264beef93e634f00e77b973b5cea1190d6ea1216e60f9",
+                "3c78416fd8ef946a9f874a8b3327c1de81d90328d679feccfe9b73228904eb5e",
+                "2dd653f0d062f423db86685553e423ebd43c717940b75b24028447d854b7af3c",
+                "862ddf95ebfc0f5c130a54f110fcfd5ee25bfa08b25a7acb7ee0eed006b19bc1",
+                "3abb5fa94b43987305748d4671f60cc4546ebd452b82a0b56ce3e12163db580e",
+            ],
+        ),
+        (
+            Hasher::Blake3,
+            [
+                "d10f29f1f455b3127d7f47b273fce0b00dfc8467399563947ac684f885eb91f1",
+                "0248560513a018dbccddb51183d6adb6155ca268fffe7473a18369e2b0f8edfc",
+                "a1fc38c5d383df9688012038f80a423cdda600bfdf57742e3bde0061afbab6f6",
+                "0bc9ffdd0d1d37c6e60ad97231918e7e3de58b72e5a26c6093de2ae1ecf3683d",
+                "9a928a311b5d0bcaa726d2dec90a3ca30ede615de36343e10b8ef47435f677e0",
+                "58fa02241a34634c91b8d4dde5b197a5824c4a3ab00b293c5c7b90b87cbf59d7",
+            ],
+        ),
+    ];
+    for (h, roots) in cases {
+        let leaves = leaves(h);
+        for (n, root) in roots.iter().enumerate() {
+            assert_eq!(hex(&merkle_root(h, leaves[..n].to_vec())), *root, "{h}, {n} leaves");
+        }
+    }
+}
+
+#[test]
+fn empty_log_roots() {
+    // The hash of "EMPTY", not of nothing: H("") is also the root of one empty leaf.
+    for h in [Hasher::Sha256, Hasher::Blake3] {
+        assert_eq!(merkle_root(h, Vec::new()), h.hash(b"EMPTY"), "{h}");
+        assert_ne!(merkle_root(h, Vec::new()), merkle_root(h, vec![leaf_hash(h, b"")]), "{h}");
+    }
+    assert_eq!(
+        hex(&merkle_root(Hasher::Sha256, Vec::new())),
+        "cc1d2f838445db7aec431df9ee8a871f40e7aa5e064fc056633ef8c60fab7b06"
+    );
+}
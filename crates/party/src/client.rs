@@ -1,81 +1,853 @@
+use crate::state::{LightBase, PartyStateFile, RosterChange, RosterEntry};
 use anyhow::{anyhow, Result};
 use common::{
-    crypto::{enc, verify_struct, verifying_key_from_bytes},
-    merkle::{leaf_hash, merkle_root},
+    crypto::{
+        enc, verify_digests_batch, verify_struct, verify_struct_tagged, verifying_digest,
+        verifying_key_from_bytes, Ed25519, Hasher, SignatureScheme, CTX_PRR, CTX_RECEIPT,
+        CTX_ROTATION, CTX_SNAPSHOT,
+    },
+    merkle::{
+        leaf_hash, leaf_hashes, merkle_root_par, verify_appended, verify_consistency,
+        verify_inclusion, ConsistencyProof, InclusionProof,
+    },
+    smt::{smt_root, verify_smt_proof},
     types::{
-        EntriesResponse, PartyRegistrationRecord, RegisterRequest, SnapshotResponse,
-        SignedRosterSnapshot,
+        CheckpointsResponse, EntriesError, ErrorCode, LogEntry, MerkleProofResponse,
+        PartyRegistrationRecord, PartyResponse, RegisterRequest, RegisterResponse,
+        RosterResponse, SignedRegistrationReceipt, SignedRosterSnapshot, SnapshotResponse,
+        WatchtowerError, NEXT_FROM_HEADER,
     },
 };
 use ed25519_dalek::VerifyingKey;
+use futures::stream::BoxStream;
+use futures::{stream, StreamExt, TryStreamExt};
+use rand::Rng;
+use reqwest::header::{ETAG, IF_NONE_MATCH};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use base64::Engine as _;
+
+/// Default number of entries requested per chunk by `entries_chunked`.
+pub const DEFAULT_ENTRIES_CHUNK: u64 = 500;
+
+/// Largest log a snapshot may claim for `entries_chunked` to fetch it.
+pub const MAX_LOG_LEN: u64 = 10_000_000;
+
+/// Maximum number of chunk requests in flight in `entries_chunked`.
+const ENTRIES_CONCURRENCY: usize = 4;
+
+/// Largest JSON response body read from the watchtower (bytes).
+pub const MAX_RESPONSE_BYTES: usize = 16 * 1024 * 1024;
+
+/// Largest single /entries NDJSON line accepted (bytes). A record is well under 1 KiB.
+pub const MAX_ENTRY_LINE_BYTES: usize = 64 * 1024;
+
+/// How long a `subscribe_snapshots` stream stays open; it then ends with a timeout error
+/// and must be renewed. This replaces the client's usual request timeout for it.
+pub const SUBSCRIPTION_LIFETIME: Duration = Duration::from_secs(600);
+
+/// Snapshots announced by /snapshot/subscribe, unverified.
+pub type SnapshotUpdates = BoxStream<'static, Result<SignedRosterSnapshot, ClientError>>;
+
+/// Retry policy for watchtower requests.
+/// Only connection errors, timeouts and 5xx responses are retried; 4xx never are.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the first retry; doubled on each subsequent retry.
+    pub base_delay: Duration,
+    /// Upper bound of the random jitter added to each delay.
+    pub jitter: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(200),
+            jitter: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn delay_for(&self, attempt: u32) -> Duration {
+        let backoff = self.base_delay.saturating_mul(1u32 << (attempt - 1).min(16));
+        let jitter_ms = self.jitter.as_millis() as u64;
+        let jitter = if jitter_ms == 0 {
+            0
+        } else {
+            rand::thread_rng().gen_range(0..=jitter_ms)
+        };
+        backoff + Duration::from_millis(jitter)
+    }
+}
+
+/// Why a watchtower request failed.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The request couldn't be sent or the connection failed (incl. timeouts).
+    Transport(reqwest::Error),
+    /// The watchtower answered with a non-2xx status.
+    Status { code: reqwest::StatusCode, body: String },
+    /// The response body wasn't what the endpoint promises.
+    Decode(String),
+    /// The response was well-formed but failed verification.
+    Verification(String),
+    /// The caller asked for something that can't be requested.
+    Request(String),
+}
+
+impl ClientError {
+    /// Whether the same request might succeed later: connection failures, timeouts and 5xx.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ClientError::Transport(e) => e.is_connect() || e.is_timeout(),
+            ClientError::Status { code, .. } => code.is_server_error(),
+            _ => false,
+        }
+    }
+
+    /// The watchtower's structured error, if this is a `Status` whose body carries one.
+    pub fn watchtower_error(&self) -> Option<WatchtowerError> {
+        match self {
+            ClientError::Status { body, .. } => serde_json::from_str(body).ok(),
+            _ => None,
+        }
+    }
+
+    fn decode(e: impl std::fmt::Display) -> Self {
+        ClientError::Decode(e.to_string())
+    }
+
+    /// Wrap a failed check on fetched data.
+    pub fn verification(e: anyhow::Error) -> Self {
+        ClientError::Verification(format!("{e:#}"))
+    }
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Transport(e) => write!(f, "watchtower request failed: {e}"),
+            ClientError::Status { code, body } => match self.watchtower_error() {
+                Some(err) => write!(f, "watchtower returned {code}: {err}"),
+                None => write!(f, "watchtower returned {code}: {body}"),
+            },
+            ClientError::Decode(msg) => write!(f, "malformed watchtower response: {msg}"),
+            ClientError::Verification(msg) => write!(f, "verification failed: {msg}"),
+            ClientError::Request(msg) => write!(f, "invalid request: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Transport(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_decode() {
+            ClientError::decode(e)
+        } else {
+            ClientError::Transport(e)
+        }
+    }
+}
+
+/// `base` as `scheme://host[:port]`, the prefix every request path is appended to. The
+/// scheme must be http or https and the URL must name a host and nothing after it: a
+/// path other than `/`, a query, a fragment or credentials are refused. The host is
+/// lowercased and a default port (80 / 443) dropped.
+pub fn normalize_base_url(base: &str) -> Result<String, ClientError> {
+    let bad = |why: &str| ClientError::Request(format!("bad watchtower url {base:?}: {why}"));
+    let url = reqwest::Url::parse(base.trim()).map_err(|e| bad(&e.to_string()))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(bad("scheme must be http or https"));
+    }
+    let host = url.host_str().ok_or_else(|| bad("no host"))?;
+    if url.path() != "/" {
+        return Err(bad("must not have a path"));
+    }
+    if url.query().is_some() || url.fragment().is_some() {
+        return Err(bad("must not have a query or fragment"));
+    }
+    if !url.username().is_empty() || url.password().is_some() {
+        return Err(bad("must not carry credentials"));
+    }
+    Ok(match url.port() {
+        Some(port) => format!("{}://{host}:{port}", url.scheme()),
+        None => format!("{}://{host}", url.scheme()),
+    })
+}
 
 #[derive(Clone)]
 pub struct WatchtowerClient {
     base: String,
     http: reqwest::Client,
+    retry: RetryPolicy,
+    /// Bearer token sent on mutating requests (/register).
+    token: Option<String>,
+    /// Epoch named on read requests (`?epoch=`); the watchtower's default epoch if unset.
+    epoch: Option<u64>,
+    /// Last /snapshot ETag and the snapshot it named, for `If-None-Match` polling.
+    snapshot_cache: Arc<Mutex<Option<(String, SignedRosterSnapshot)>>>,
 }
 
-impl WatchtowerClient {
-    pub fn new(base: String) -> Self {
+/// HTTP settings for `WatchtowerClient`. Timeouts are always finite, so a hung
+/// watchtower fails the request instead of stalling the caller.
+#[derive(Debug, Clone)]
+pub struct WatchtowerClientConfig {
+    /// Time allowed to establish a TCP (and TLS) connection.
+    pub connect_timeout: Duration,
+    /// Time allowed for a whole request, from sending it to reading the last body byte.
+    /// Applies per attempt; retries each get a fresh budget.
+    pub request_timeout: Duration,
+    /// Idle keep-alive connections kept per host.
+    pub pool_max_idle_per_host: usize,
+    /// How long an idle pooled connection is kept before being closed.
+    pub pool_idle_timeout: Duration,
+    /// Speak HTTP/2 from the first byte instead of HTTP/1.1 (or ALPN-negotiated HTTP/2 over
+    /// TLS). All requests then share one multiplexed connection; the watchtower, or the TLS
+    /// proxy in front of it, must accept HTTP/2 without negotiation.
+    pub http2_prior_knowledge: bool,
+    pub retry: RetryPolicy,
+    /// HTTP(S) proxy URL for all watchtower requests. If unset, the `HTTPS_PROXY` /
+    /// `HTTP_PROXY` environment variables apply (honoring `NO_PROXY`).
+    pub proxy: Option<String>,
+    /// PEM file with extra root certificates to trust (e.g. a private CA), on top of
+    /// the built-in roots.
+    pub ca_cert_file: Option<String>,
+}
+
+impl Default for WatchtowerClientConfig {
+    fn default() -> Self {
         Self {
-            base: base.trim_end_matches('/').to_string(),
-            http: reqwest::Client::new(),
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(30),
+            pool_max_idle_per_host: 8,
+            pool_idle_timeout: Duration::from_secs(90),
+            http2_prior_knowledge: false,
+            retry: RetryPolicy::default(),
+            proxy: None,
+            ca_cert_file: None,
+        }
+    }
+}
+
+impl WatchtowerClient {
+    pub fn new(base: String) -> Result<Self, ClientError> {
+        Self::new_with_retry(base, RetryPolicy::default())
+    }
+
+    pub fn new_with_retry(base: String, retry: RetryPolicy) -> Result<Self, ClientError> {
+        Self::new_with_config(base, WatchtowerClientConfig { retry, ..Default::default() })
+    }
+
+    /// Fails on a `base` that isn't an `http(s)://host[:port]` URL (see `normalize_base_url`)
+    /// or on an unusable `config`.
+    pub fn new_with_config(
+        base: String,
+        config: WatchtowerClientConfig,
+    ) -> Result<Self, ClientError> {
+        let base = normalize_base_url(&base)?;
+        if config.connect_timeout.is_zero() || config.request_timeout.is_zero() {
+            return Err(ClientError::Request("watchtower client timeouts must be nonzero".into()));
+        }
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(config.connect_timeout)
+            .timeout(config.request_timeout)
+            .pool_max_idle_per_host(config.pool_max_idle_per_host)
+            .pool_idle_timeout(config.pool_idle_timeout);
+        if config.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        if let Some(proxy) = &config.proxy {
+            let proxy = reqwest::Proxy::all(proxy)
+                .map_err(|e| ClientError::Request(format!("bad proxy url {proxy}: {e}")))?;
+            builder = builder.proxy(proxy);
+        }
+        if let Some(path) = &config.ca_cert_file {
+            let pem = std::fs::read(path)
+                .map_err(|e| ClientError::Request(format!("failed to read CA file {path}: {e}")))?;
+            let certs = reqwest::Certificate::from_pem_bundle(&pem)
+                .map_err(|e| ClientError::Request(format!("bad CA file {path}: {e}")))?;
+            if certs.is_empty() {
+                return Err(ClientError::Request(format!("no certificates in CA file {path}")));
+            }
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        let http = builder.build()?;
+        Ok(Self {
+            base,
+            http,
+            retry: config.retry,
+            token: None,
+            epoch: None,
+            snapshot_cache: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// Send `Authorization: Bearer <token>` on mutating requests.
+    pub fn with_bearer_token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    /// Read the log, snapshots and proofs of `epoch`, for watchtowers serving several.
+    /// The snapshot cache is not shared with clients of other epochs.
+    pub fn with_epoch(mut self, epoch: u64) -> Self {
+        self.epoch = Some(epoch);
+        self.snapshot_cache = Arc::new(Mutex::new(None));
+        self
+    }
+
+    /// `{base}{path_and_query}`, plus this client's `epoch` parameter if it has one.
+    fn url(&self, path_and_query: &str) -> String {
+        let url = format!("{}{path_and_query}", self.base);
+        match self.epoch {
+            Some(e) if url.contains('?') => format!("{url}&epoch={e}"),
+            Some(e) => format!("{url}?epoch={e}"),
+            None => url,
+        }
+    }
+
+    /// Send the request built by `build`, retrying transient failures per the retry policy.
+    async fn send_with_retry<F>(&self, build: F) -> Result<reqwest::Response, ClientError>
+    where
+        F: Fn() -> reqwest::RequestBuilder,
+    {
+        let mut attempt = 1;
+        loop {
+            let res = build().send().await.map_err(ClientError::from);
+            let retryable = match &res {
+                Ok(resp) => resp.status().is_server_error(),
+                Err(e) => e.is_transient(),
+            };
+            if !retryable || attempt >= self.retry.max_attempts {
+                return res;
+            }
+            tokio::time::sleep(self.retry.delay_for(attempt)).await;
+            attempt += 1;
         }
     }
 
-    pub async fn get_watchtower_pubkey_b64(&self) -> Result<String> {
+    pub async fn get_watchtower_pubkey_b64(&self) -> Result<String, ClientError> {
         let url = format!("{}/watchtower_pubkey", self.base);
-        let resp = self.http.get(url).send().await?;
+        let resp = self.send_with_retry(|| self.http.get(&url)).await?;
         if !resp.status().is_success() {
-            return Err(anyhow!("watchtower_pubkey failed: {}", resp.status()));
+            return Err(status_error(resp).await);
         }
         Ok(resp.text().await?)
     }
 
-    pub async fn register(&self, prr: PartyRegistrationRecord) -> Result<SignedRosterSnapshot> {
+    pub async fn register(
+        &self,
+        prr: PartyRegistrationRecord,
+    ) -> Result<RegisterResponse, ClientError> {
         let url = format!("{}/register", self.base);
+        let req = RegisterRequest { prr };
         let resp = self
-            .http
-            .post(url)
-            .json(&RegisterRequest { prr })
-            .send()
+            .send_with_retry(|| {
+                let rb = self.http.post(&url).json(&req);
+                match &self.token {
+                    Some(t) => rb.bearer_auth(t),
+                    None => rb,
+                }
+            })
             .await?;
         if !resp.status().is_success() {
-            return Err(anyhow!("register failed: {} {}", resp.status(), resp.text().await?));
+            return Err(status_error(resp).await);
         }
-        let sr: SnapshotResponse = resp.json().await?;
-        Ok(sr.srs)
+        json_body(resp).await
     }
 
-    pub async fn snapshot(&self) -> Result<SignedRosterSnapshot> {
-        let url = format!("{}/snapshot", self.base);
-        let resp = self.http.get(url).send().await?;
+    /// Current snapshot. Sends the last ETag seen, and returns the cached snapshot
+    /// unchanged when the watchtower answers 304.
+    pub async fn snapshot(&self) -> Result<SignedRosterSnapshot, ClientError> {
+        let url = self.url("/snapshot");
+        let cached = self.snapshot_cache.lock().unwrap().clone();
+        let resp = self
+            .send_with_retry(|| {
+                let rb = self.http.get(&url);
+                match &cached {
+                    Some((etag, _)) => rb.header(IF_NONE_MATCH, etag),
+                    None => rb,
+                }
+            })
+            .await?;
+        if resp.status() == reqwest::StatusCode::NOT_MODIFIED {
+            return cached.map(|(_, srs)| srs).ok_or_else(|| {
+                ClientError::Decode("304 Not Modified without a cached snapshot".into())
+            });
+        }
         if !resp.status().is_success() {
-            return Err(anyhow!("snapshot failed: {}", resp.status()));
+            return Err(status_error(resp).await);
         }
-        let sr: SnapshotResponse = resp.json().await?;
+        let etag = resp.headers().get(ETAG).and_then(|v| v.to_str().ok()).map(str::to_string);
+        let sr: SnapshotResponse = json_body(resp).await?;
+        *self.snapshot_cache.lock().unwrap() = etag.map(|etag| (etag, sr.srs.clone()));
         Ok(sr.srs)
     }
 
-    pub async fn entries(&self, from: u64, to: u64) -> Result<Vec<PartyRegistrationRecord>> {
-        let url = format!("{}/entries?from={}&to={}", self.base, from, to);
-        let resp = self.http.get(url).send().await?;
+    /// Subscribe to /snapshot/subscribe: the current snapshot, then each new one as the
+    /// watchtower signs it. Snapshots are not verified; bursts may be coalesced. The stream
+    /// ends after an error, when the watchtower closes it, or after `SUBSCRIPTION_LIFETIME`.
+    pub async fn subscribe_snapshots(&self) -> Result<SnapshotUpdates, ClientError> {
+        let url = self.url("/snapshot/subscribe");
+        let resp = self
+            .send_with_retry(|| self.http.get(&url).timeout(SUBSCRIPTION_LIFETIME))
+            .await?;
+        if !resp.status().is_success() {
+            return Err(status_error(resp).await);
+        }
+        let body = resp.bytes_stream().boxed();
+        let events = stream::unfold(Some((body, Vec::new())), |state| async move {
+            let (mut body, mut buf) = state?;
+            loop {
+                // Events end with a blank line.
+                if let Some(pos) = buf.windows(2).position(|w| w == b"\n\n") {
+                    let event: Vec<u8> = buf.drain(..pos + 2).collect();
+                    match parse_snapshot_event(&event) {
+                        Ok(Some(srs)) => return Some((Ok(srs), Some((body, buf)))),
+                        Ok(None) => continue,
+                        Err(e) => return Some((Err(e), None)),
+                    }
+                }
+                if buf.len() > MAX_RESPONSE_BYTES {
+                    let e = format!("snapshot event longer than {MAX_RESPONSE_BYTES} bytes");
+                    return Some((Err(ClientError::Decode(e)), None));
+                }
+                match body.next().await? {
+                    Ok(chunk) => buf.extend_from_slice(&chunk),
+                    Err(e) => return Some((Err(e.into()), None)),
+                }
+            }
+        });
+        Ok(events.boxed())
+    }
+
+    /// `party_id`'s latest record with proofs; `None` if the watchtower doesn't know it.
+    pub async fn party(&self, party_id: u64) -> Result<Option<PartyResponse>, ClientError> {
+        let url = self.url(&format!("/party/{party_id}"));
+        let resp = self.send_with_retry(|| self.http.get(&url)).await?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !resp.status().is_success() {
+            return Err(status_error(resp).await);
+        }
+        Ok(Some(json_body(resp).await?))
+    }
+
+    /// The watchtower's signed snapshot over the first `log_len` entries of its log. The
+    /// signature is not checked here.
+    pub async fn snapshot_at(&self, log_len: u64) -> Result<SignedRosterSnapshot, ClientError> {
+        let url = self.url(&format!("/snapshot_at?log_len={log_len}"));
+        let resp = self.send_with_retry(|| self.http.get(&url)).await?;
+        if !resp.status().is_success() {
+            return Err(status_error(resp).await);
+        }
+        json_body(resp).await
+    }
+
+    /// The record at `index` (1-based) with its inclusion proof against the current snapshot.
+    pub async fn merkle_proof(&self, index: u64) -> Result<MerkleProofResponse, ClientError> {
+        let url = self.url(&format!("/merkle_proof?index={index}"));
+        let resp = self.send_with_retry(|| self.http.get(&url)).await?;
+        if !resp.status().is_success() {
+            return Err(status_error(resp).await);
+        }
+        json_body(resp).await
+    }
+
+    /// Checkpoint snapshots the watchtower has signed, in log_len order.
+    pub async fn checkpoints(&self) -> Result<Vec<SignedRosterSnapshot>, ClientError> {
+        let url = self.url("/checkpoints");
+        let resp = self.send_with_retry(|| self.http.get(&url)).await?;
+        if !resp.status().is_success() {
+            return Err(status_error(resp).await);
+        }
+        let cr: CheckpointsResponse = json_body(resp).await?;
+        Ok(cr.checkpoints)
+    }
+
+    /// The watchtower's /roster: each party's latest record and when it was received.
+    /// Unsigned, so only advisory; membership is verified from the log.
+    pub async fn roster(&self) -> Result<RosterResponse, ClientError> {
+        let url = self.url("/roster");
+        let resp = self.send_with_retry(|| self.http.get(&url)).await?;
+        if !resp.status().is_success() {
+            return Err(status_error(resp).await);
+        }
+        json_body(resp).await
+    }
+
+    /// Proof that the log of length `to` extends the log of length `from`.
+    pub async fn consistency(&self, from: u64, to: u64) -> Result<ConsistencyProof, ClientError> {
+        let url = self.url(&format!("/consistency?from={from}&to={to}"));
+        let resp = self.send_with_retry(|| self.http.get(&url)).await?;
         if !resp.status().is_success() {
-            return Err(anyhow!("entries failed: {} {}", resp.status(), resp.text().await?));
+            return Err(status_error(resp).await);
+        }
+        json_body(resp).await
+    }
+
+    /// Fetch entries `from..=to`, following the server's `next_from` cursor if it
+    /// truncates the range. Each response must hold exactly the entries of its page
+    /// (`cur..next_from`, or `cur..=to` for the last), or this fails before any of them
+    /// reach a root check.
+    pub async fn entries(&self, from: u64, to: u64) -> Result<Vec<LogEntry>, ClientError> {
+        if from > to {
+            let msg = format!("invalid entries range: from={from} > to={to}");
+            return Err(ClientError::Request(msg));
+        }
+        let mut out = Vec::new();
+        let mut cur = from;
+        loop {
+            let url = self.url(&format!("/entries?from={cur}&to={to}"));
+            let resp = self.send_with_retry(|| self.http.get(&url)).await?;
+            if !resp.status().is_success() {
+                return Err(status_error(resp).await);
+            }
+            let next_from = match resp.headers().get(NEXT_FROM_HEADER) {
+                Some(v) => Some(
+                    v.to_str()
+                        .ok()
+                        .and_then(|v| v.parse::<u64>().ok())
+                        .ok_or_else(|| {
+                            ClientError::Decode(format!("malformed {NEXT_FROM_HEADER} header"))
+                        })?,
+                ),
+                None => None,
+            };
+            let page_end = match next_from {
+                None => to,
+                Some(next) if next > cur && next <= to => next - 1,
+                Some(next) => {
+                    return Err(ClientError::Decode(format!(
+                        "entries cursor did not advance: from={cur} next_from={next}"
+                    )))
+                }
+            };
+            let expected = page_end - cur + 1;
+            let page_start = out.len();
+
+            // Parse the NDJSON body line by line as it arrives.
+            let mut body = resp.bytes_stream();
+            let mut buf = Vec::new();
+            while let Some(chunk) = body.next().await {
+                buf.extend_from_slice(&chunk?);
+                while let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+                    if (out.len() - page_start) as u64 == expected {
+                        return Err(ClientError::Verification(format!(
+                            "entries {cur}..={page_end} returned more than {expected} entries"
+                        )));
+                    }
+                    out.push(parse_entry_line(&buf[..pos]).map_err(ClientError::decode)?);
+                    buf.drain(..=pos);
+                }
+                if buf.len() > MAX_ENTRY_LINE_BYTES {
+                    return Err(ClientError::Decode(format!(
+                        "entries line longer than {MAX_ENTRY_LINE_BYTES} bytes"
+                    )));
+                }
+            }
+            if !buf.is_empty() {
+                return Err(ClientError::Decode("entries stream ended mid-record".into()));
+            }
+            let got = (out.len() - page_start) as u64;
+            if got != expected {
+                return Err(ClientError::Verification(format!(
+                    "entries {cur}..={page_end} returned {got} entries, expected {expected}"
+                )));
+            }
+
+            match next_from {
+                None => break,
+                Some(next) => cur = next,
+            }
         }
-        let er: EntriesResponse = resp.json().await?;
-        Ok(er.entries)
+        Ok(out)
+    }
+
+    /// Fetch the current snapshot and the full log it commits to, verified against each
+    /// other under `pk_w`. With `expected_epoch`, a snapshot for another epoch is rejected.
+    pub async fn fetch_verified_log(
+        &self,
+        pk_w: &VerifyingKey,
+        expected_epoch: Option<u64>,
+    ) -> Result<(SignedRosterSnapshot, Vec<LogEntry>), ClientError> {
+        let srs = self.snapshot().await?;
+        // Checked once, before its log_len sizes anything.
+        verify_snapshot_for(pk_w, expected_epoch, &srs).map_err(ClientError::verification)?;
+        // Full fetch 1..log_len so we can recompute Merkle root and verify end-to-end.
+        let entries = self.entries_chunked(1, srs.msg.log_len, DEFAULT_ENTRIES_CHUNK).await?;
+        verify_full_log(&srs, &entries).map_err(ClientError::verification)?;
+        Ok((srs, entries))
     }
+
+    /// The current roster, verified end to end: each party's latest accepted record,
+    /// folded with the same rules a party applies to its own state, sorted by party_id.
+    pub async fn fetch_verified_roster(
+        &self,
+        pk_w: &VerifyingKey,
+    ) -> Result<Vec<RosterEntry>, ClientError> {
+        let (srs, entries) = self.fetch_verified_log(pk_w, self.epoch).await?;
+        // The roster doesn't depend on whose state it is, so any party_id will do.
+        let mut st = PartyStateFile::new(srs.msg.epoch, 0);
+        st.apply_verified(srs, &entries);
+        let mut roster: Vec<RosterEntry> = st.roster.into_values().collect();
+        roster.sort_unstable_by_key(|e| e.party_id);
+        Ok(roster)
+    }
+
+    /// Fetch and verify the full log and apply it to `st`, returning the roster changes.
+    /// The snapshot must be for `st.epoch` and, unless `allow_rollback`, must not roll back
+    /// the log `st` already accepted (see `PartyStateFile::check_rollback`).
+    pub async fn sync_state(
+        &self,
+        pk_w: &VerifyingKey,
+        st: &mut PartyStateFile,
+        allow_rollback: bool,
+    ) -> Result<Vec<RosterChange>, ClientError> {
+        let (srs, entries) = self.fetch_verified_log(pk_w, Some(st.epoch)).await?;
+        st.check_rollback(&srs, allow_rollback).map_err(ClientError::verification)?;
+        Ok(st.apply_verified(srs, &entries))
+    }
+
+    /// Light alternative to `fetch_verified_log`: the watchtower's current snapshot, checked
+    /// to extend `base.srs` (consistency proof) and to still include the party's own latest
+    /// record at `base.index` (inclusion proof), and the records appended since `base.srs`,
+    /// checked against both. The rest of the log isn't downloaded.
+    pub async fn fetch_verified_extension(
+        &self,
+        pk_w: &VerifyingKey,
+        base: &LightBase,
+    ) -> Result<(SignedRosterSnapshot, Vec<LogEntry>), ClientError> {
+        let (older, index, own) = (&base.srs, base.index, &base.own);
+        // The proof response carries the snapshot it was made against; that is the new one.
+        let mp = self.merkle_proof(index).await?;
+        verify_snapshot_signature(pk_w, &mp.srs)
+            .and_then(|()| verify_entry_with_proof(&mp.srs, &mp.prr, mp.index, &mp.proof))
+            .map_err(ClientError::verification)?;
+        let pk_b64 = base64::engine::general_purpose::STANDARD.encode(mp.prr.msg.pk_party);
+        if mp.prr.msg.party_id != base.party_id
+            || mp.prr.msg.seq != own.seq
+            || pk_b64 != own.pk_party_b64
+        {
+            return Err(ClientError::Verification(format!(
+                "record at index={index} is not party_id={}'s latest (seq={})",
+                base.party_id, own.seq
+            )));
+        }
+
+        let srs = mp.srs;
+        if srs.msg == older.msg {
+            return Ok((srs, Vec::new()));
+        }
+        if srs.msg.log_len < older.msg.log_len {
+            return Err(ClientError::Verification(format!(
+                "snapshot log_len={} is behind the accepted log_len={}",
+                srs.msg.log_len, older.msg.log_len
+            )));
+        }
+        if srs.msg.log_len > MAX_LOG_LEN {
+            return Err(ClientError::Verification(format!(
+                "snapshot log_len={} exceeds the {MAX_LOG_LEN}-entry limit",
+                srs.msg.log_len
+            )));
+        }
+        let proof = self.consistency(older.msg.log_len, srs.msg.log_len).await?;
+        // The new snapshot's signature was checked with the inclusion proof.
+        verify_snapshot_signature(pk_w, older)
+            .and_then(|()| verify_extension(older, &srs, &proof))
+            .map_err(ClientError::verification)?;
+        let appended = if srs.msg.log_len > older.msg.log_len {
+            self.entries(older.msg.log_len + 1, srs.msg.log_len).await?
+        } else {
+            Vec::new()
+        };
+        verify_appended_log(&srs, &proof, &appended).map_err(ClientError::verification)?;
+        Ok((srs, appended))
+    }
+
+    /// Fetch entries `from..=to` in chunks of `chunk_size`, with a bounded number of
+    /// requests in flight. Chunks are concatenated in order and checked to be gap-free.
+    /// `to` is capped at `MAX_LOG_LEN` before anything is requested or sized by it, so a
+    /// snapshot's log_len can be passed as is once its signature is checked. The empty
+    /// range `from = to + 1` (e.g. `1..=0` for an empty log) needs no request.
+    pub async fn entries_chunked(
+        &self,
+        from: u64,
+        to: u64,
+        chunk_size: u64,
+    ) -> Result<Vec<LogEntry>, ClientError> {
+        if chunk_size == 0 {
+            return Err(ClientError::Request("chunk_size must be > 0".into()));
+        }
+        if from == 0 || from > to.saturating_add(1) {
+            let msg = format!("invalid entries range: from={from}, to={to}");
+            return Err(ClientError::Request(msg));
+        }
+        if to > MAX_LOG_LEN {
+            return Err(ClientError::Verification(format!(
+                "entries up to {to} exceed the {MAX_LOG_LEN}-entry limit"
+            )));
+        }
+
+        let ranges = (from..=to)
+            .step_by(chunk_size as usize)
+            .map(move |start| (start, start.saturating_add(chunk_size - 1).min(to)));
+        stream::iter(ranges)
+            .map(|(a, b)| async move {
+                let chunk = self.entries(a, b).await?;
+                let expected = b - a + 1;
+                if chunk.len() as u64 != expected {
+                    return Err(ClientError::Verification(format!(
+                        "entries chunk {a}..={b} returned {} entries, expected {expected}",
+                        chunk.len()
+                    )));
+                }
+                Ok(chunk)
+            })
+            .buffered(ENTRIES_CONCURRENCY)
+            .try_fold(Vec::new(), |mut out, chunk| async move {
+                out.extend(chunk);
+                Ok(out)
+            })
+            .await
+    }
+}
+
+fn parse_entry_line(line: &[u8]) -> Result<LogEntry> {
+    serde_json::from_slice(line).map_err(|e| match serde_json::from_slice::<EntriesError>(line) {
+        // The watchtower cut the stream short and said why.
+        Ok(aborted) => aborted.error.into(),
+        Err(_) => anyhow!("malformed entries line: {e}"),
+    })
+}
+
+/// The snapshot in one Server-Sent Event, or `None` for a keep-alive or another event type.
+fn parse_snapshot_event(event: &[u8]) -> Result<Option<SignedRosterSnapshot>, ClientError> {
+    let event = std::str::from_utf8(event).map_err(ClientError::decode)?;
+    let mut data = Vec::new();
+    for line in event.lines() {
+        if let Some(name) = line.strip_prefix("event:") {
+            if name.trim_start() != "snapshot" {
+                return Ok(None);
+            }
+        } else if let Some(value) = line.strip_prefix("data:") {
+            data.push(value.strip_prefix(' ').unwrap_or(value));
+        }
+    }
+    if data.is_empty() {
+        return Ok(None);
+    }
+    let sr: SnapshotResponse = serde_json::from_str(&data.join("\n")).map_err(ClientError::decode)?;
+    Ok(Some(sr.srs))
+}
+
+/// `ClientError::Status` for a non-2xx watchtower response.
+async fn status_error(resp: reqwest::Response) -> ClientError {
+    let code = resp.status();
+    match read_body(resp, MAX_RESPONSE_BYTES).await {
+        Ok(body) => ClientError::Status { code, body: String::from_utf8_lossy(&body).into_owned() },
+        Err(e) => e,
+    }
+}
+
+/// Decode a JSON response body of at most `MAX_RESPONSE_BYTES`.
+async fn json_body<T: serde::de::DeserializeOwned>(
+    resp: reqwest::Response,
+) -> Result<T, ClientError> {
+    let body = read_body(resp, MAX_RESPONSE_BYTES).await?;
+    serde_json::from_slice(&body).map_err(ClientError::decode)
+}
+
+/// Read a response body, failing once it exceeds `limit` bytes rather than buffering it.
+async fn read_body(resp: reqwest::Response, limit: usize) -> Result<Vec<u8>, ClientError> {
+    if resp.content_length().is_some_and(|len| len > limit as u64) {
+        return Err(ClientError::Decode(format!("response body exceeds {limit} bytes")));
+    }
+    let mut body = resp.bytes_stream();
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        if buf.len() + chunk.len() > limit {
+            return Err(ClientError::Decode(format!("response body exceeds {limit} bytes")));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf)
+}
+
+/// Parse a captured /entries body (NDJSON, one entry per line).
+pub fn parse_entries_ndjson(data: &[u8]) -> Result<Vec<LogEntry>> {
+    data.split(|b| *b == b'\n')
+        .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+        .map(parse_entry_line)
+        .collect()
+}
+
+/// Check a snapshot's version, that it embeds `pk_w` as the watchtower key, and its
+/// signature under `pk_w`. Says nothing about the log it commits to.
+pub fn verify_snapshot_signature(pk_w: &VerifyingKey, srs: &SignedRosterSnapshot) -> Result<()> {
+    srs.msg.check_version()?;
+    srs.msg.check_watchtower_pk(pk_w.as_bytes())?;
+    verify_struct(pk_w, CTX_SNAPSHOT, &srs.msg, &srs.sig_watchtower)
 }
 
 /// Verify a watchtower snapshot signature and consistency with fetched PRRs (Merkle root).
+/// With `expected_epoch`, a snapshot for any other epoch fails with `EPOCH_MISMATCH`.
+///
+/// This is `verify_snapshot_signature` followed by `verify_full_log`.
 pub fn verify_snapshot_and_log(
     pk_w: &VerifyingKey,
+    expected_epoch: Option<u64>,
+    srs: &SignedRosterSnapshot,
+    full_log: &[LogEntry],
+) -> Result<()> {
+    verify_snapshot_for(pk_w, expected_epoch, srs)?;
+    verify_full_log(srs, full_log)
+}
+
+/// `verify_snapshot_signature`, and with `expected_epoch`, that the snapshot is for it.
+fn verify_snapshot_for(
+    pk_w: &VerifyingKey,
+    expected_epoch: Option<u64>,
+    srs: &SignedRosterSnapshot,
+) -> Result<()> {
+    verify_snapshot_signature(pk_w, srs)?;
+
+    // A validly signed snapshot of another epoch says nothing about this one.
+    if let Some(epoch) = expected_epoch.filter(|&e| e != srs.msg.epoch) {
+        return Err(WatchtowerError::new(
+            ErrorCode::EpochMismatch,
+            format!("snapshot is for epoch={}, expected epoch={epoch}", srs.msg.epoch),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Check that `full_log` is exactly the log `srs` commits to: its length, every record's
+/// signatures, and both roots. The snapshot's own signature is not checked here (see
+/// `verify_snapshot_signature`).
+pub fn verify_full_log(
     srs: &SignedRosterSnapshot,
-    full_log: &[PartyRegistrationRecord],
+    full_log: &[LogEntry],
 ) -> Result<()> {
-    // Verify watchtower signature on snapshot message
-    verify_struct(pk_w, &srs.msg, &srs.sig_watchtower)?;
+    let h = srs.msg.hasher()?;
 
     // Verify log length
     let k = srs.msg.log_len as usize;
@@ -87,21 +859,1178 @@ pub fn verify_snapshot_and_log(
         ));
     }
 
-    // Verify each PRR signature and build leaves
-    let mut leaves = Vec::with_capacity(full_log.len());
-    for prr in full_log {
+    // Batch-verify all PRR (and rotation) signatures; if the batch fails, re-check
+    // each entry individually to pinpoint the bad one.
+    let mut digests = Vec::with_capacity(full_log.len());
+    let mut sigs = Vec::with_capacity(full_log.len());
+    let mut pks = Vec::with_capacity(full_log.len());
+    for prr in full_log.iter().filter_map(LogEntry::record) {
+        prr.msg.check_version()?;
+        if prr.msg.scheme != Ed25519::TAG {
+            // Only ed25519 is batched; anything else goes through the tagged per-entry path.
+            verify_prr_signatures(prr)?;
+            continue;
+        }
         let pk_party = verifying_key_from_bytes(&prr.msg.pk_party)?;
-        verify_struct(&pk_party, &prr.msg, &prr.sig_party)?;
+        if pk_party.is_weak() {
+            return Err(anyhow!("weak pk_party for party_id={}", prr.msg.party_id));
+        }
+        digests.push(verifying_digest(CTX_PRR, &prr.msg)?);
+        sigs.push(prr.sig_party);
+        pks.push(pk_party);
+        if let Some(rot) = &prr.msg.rotation {
+            digests.push(verifying_digest(CTX_ROTATION, &prr.msg.rotation_message(rot.old_pk))?);
+            sigs.push(rot.sig_old);
+            pks.push(verifying_key_from_bytes(&rot.old_pk)?);
+        }
+    }
+    if verify_digests_batch(&digests, &sigs, &pks).is_err() {
+        let records = full_log.iter().enumerate().filter_map(|(i, e)| Some((i, e.record()?)));
+        for (i, prr) in records {
+            verify_prr_signatures(prr).map_err(|e| {
+                anyhow!("entry {} (party_id={}) failed verification: {e}", i + 1, prr.msg.party_id)
+            })?;
+        }
+    }
 
-        let bytes = enc(prr)?;
-        leaves.push(leaf_hash(&bytes));
+    // Compaction only drops superseded records; each party's latest must be served whole.
+    let mut latest: BTreeMap<u64, &LogEntry> = BTreeMap::new();
+    for entry in full_log {
+        latest.insert(entry.party_id(), entry);
+    }
+    if let Some(entry) = latest.values().find(|entry| entry.record().is_none()) {
+        return Err(anyhow!(
+            "the latest record of party_id={} (seq={}) is a tombstone",
+            entry.party_id(),
+            entry.seq()
+        ));
     }
 
-    let root = merkle_root(leaves);
+    let root = log_root(h, full_log)?;
     if root != srs.msg.merkle_root {
         return Err(anyhow!(
             "merkle root mismatch: snapshot root != computed root"
         ));
     }
+    if log_smt_root(h, full_log)? != srs.msg.smt_root {
+        return Err(anyhow!(
+            "smt root mismatch: snapshot smt_root != computed root over latest records"
+        ));
+    }
+    Ok(())
+}
+
+/// Check that `appended` are the records `srs` added to the log `proof` extends: their
+/// signatures, and their leaves against `srs`'s root (see `merkle::verify_appended`).
+pub fn verify_appended_log(
+    srs: &SignedRosterSnapshot,
+    proof: &ConsistencyProof,
+    appended: &[LogEntry],
+) -> Result<()> {
+    let h = srs.msg.hasher()?;
+    for prr in appended.iter().filter_map(LogEntry::record) {
+        prr.msg.check_version()?;
+        verify_prr_signatures(prr).map_err(|e| {
+            anyhow!("appended party_id={} failed verification: {e}", prr.msg.party_id)
+        })?;
+    }
+    verify_appended(h, proof, &srs.msg.merkle_root, &log_leaves(h, appended)?)
+}
+
+/// Verify a registration receipt: signed by `pk_w`, for exactly `prr`, and matching the
+/// snapshot `srs` returned alongside it.
+pub fn verify_receipt(
+    pk_w: &VerifyingKey,
+    prr: &PartyRegistrationRecord,
+    srs: &SignedRosterSnapshot,
+    receipt: &SignedRegistrationReceipt,
+) -> Result<()> {
+    let r = &receipt.receipt;
+    r.check_version()?;
+    verify_struct(pk_w, CTX_RECEIPT, r, &receipt.sig_watchtower)
+        .map_err(|e| anyhow!("invalid receipt signature: {e}"))?;
+    if r.party_id != prr.msg.party_id || r.seq != prr.msg.seq {
+        return Err(anyhow!(
+            "receipt is for party_id={} seq={}, submitted party_id={} seq={}",
+            r.party_id,
+            r.seq,
+            prr.msg.party_id,
+            prr.msg.seq
+        ));
+    }
+    if r.prr_leaf != leaf_hash(srs.msg.hasher()?, &enc(prr)?) {
+        return Err(anyhow!("receipt does not commit to the submitted record"));
+    }
+    if r.snapshot_after != srs.msg || r.assigned_index != srs.msg.log_len {
+        return Err(anyhow!(
+            "receipt assigned_index={} does not match snapshot log_len={}",
+            r.assigned_index,
+            srs.msg.log_len
+        ));
+    }
+    Ok(())
+}
+
+/// Verify a /party response without the full log: the snapshot is signed by `pk_w`, the
+/// record is signed by its party, sits at `index` under the Merkle root, and is the
+/// party's latest record under the SMT root.
+pub fn verify_party_record(pk_w: &VerifyingKey, resp: &PartyResponse) -> Result<()> {
+    let srs = &resp.srs;
+    let leaf = verify_record_inclusion(pk_w, srs, &resp.prr, resp.index, &resp.proof)?;
+    let h = srs.msg.hasher()?;
+    verify_smt_proof(h, &srs.msg.smt_root, resp.prr.msg.party_id, Some(&leaf), &resp.smt_proof)
+        .map_err(|e| anyhow!("record is not the party's latest: {e}"))
+}
+
+/// Verify a /merkle_proof response: the snapshot is signed by `pk_w`, and the validly
+/// signed record is at the claimed index of the log it commits to.
+pub fn verify_merkle_proof(pk_w: &VerifyingKey, resp: &MerkleProofResponse) -> Result<()> {
+    verify_record_inclusion(pk_w, &resp.srs, &resp.prr, resp.index, &resp.proof).map(|_| ())
+}
+
+/// Check `srs`'s signature, `prr`'s signatures and that `proof` places `prr` at `index`
+/// (1-based) under `srs`'s root. Returns the record's leaf hash.
+fn verify_record_inclusion(
+    pk_w: &VerifyingKey,
+    srs: &SignedRosterSnapshot,
+    prr: &PartyRegistrationRecord,
+    index: u64,
+    proof: &InclusionProof,
+) -> Result<[u8; 32]> {
+    verify_snapshot_signature(pk_w, srs)?;
+    verify_entry_with_proof(srs, prr, index, proof)
+}
+
+/// Check `prr`'s signatures and that `proof` places it at `index` (1-based) under `srs`'s
+/// Merkle root, without the rest of the log. Returns the record's leaf hash. The snapshot's
+/// own signature is not checked here (see `verify_snapshot_signature`).
+pub fn verify_entry_with_proof(
+    srs: &SignedRosterSnapshot,
+    prr: &PartyRegistrationRecord,
+    index: u64,
+    proof: &InclusionProof,
+) -> Result<[u8; 32]> {
+    prr.msg.check_version()?;
+    verify_prr_signatures(prr)?;
+
+    if index == 0 || proof.leaf_index != index - 1 {
+        return Err(anyhow!(
+            "inclusion proof is for leaf_index={}, record claimed at index={index}",
+            proof.leaf_index
+        ));
+    }
+    if proof.log_len != srs.msg.log_len {
+        return Err(anyhow!(
+            "inclusion proof log_len={} but snapshot log_len={}",
+            proof.log_len,
+            srs.msg.log_len
+        ));
+    }
+    let h = srs.msg.hasher()?;
+    let leaf = leaf_hash(h, &enc(prr)?);
+    verify_inclusion(h, &srs.msg.merkle_root, &leaf, proof)?;
+    Ok(leaf)
+}
+
+/// Verify that `newer` extends `older`: both signed by `pk_w` for the same epoch, and
+/// `proof` links `older`'s root to `newer`'s.
+pub fn verify_snapshot_extends(
+    pk_w: &VerifyingKey,
+    older: &SignedRosterSnapshot,
+    newer: &SignedRosterSnapshot,
+    proof: &ConsistencyProof,
+) -> Result<()> {
+    for srs in [older, newer] {
+        verify_snapshot_signature(pk_w, srs)?;
+    }
+    verify_extension(older, newer, proof)
+}
+
+/// `verify_snapshot_extends` without the snapshots' signatures.
+fn verify_extension(
+    older: &SignedRosterSnapshot,
+    newer: &SignedRosterSnapshot,
+    proof: &ConsistencyProof,
+) -> Result<()> {
+    if older.msg.epoch != newer.msg.epoch {
+        return Err(anyhow!(
+            "epoch mismatch: older snapshot epoch={}, newer epoch={}",
+            older.msg.epoch,
+            newer.msg.epoch
+        ));
+    }
+    if proof.old_size != older.msg.log_len || proof.new_size != newer.msg.log_len {
+        return Err(anyhow!(
+            "consistency proof is for {}..{}, snapshots are {}..{}",
+            proof.old_size,
+            proof.new_size,
+            older.msg.log_len,
+            newer.msg.log_len
+        ));
+    }
+    if older.msg.hash_alg != newer.msg.hash_alg {
+        return Err(anyhow!(
+            "hash_alg mismatch: older snapshot hash_alg={}, newer hash_alg={}",
+            older.msg.hash_alg,
+            newer.msg.hash_alg
+        ));
+    }
+    let h = newer.msg.hasher()?;
+    verify_consistency(h, proof, &older.msg.merkle_root, &newer.msg.merkle_root)
+}
+
+/// Verify a single PRR's party signature (and rotation endorsement, if any).
+pub fn verify_prr_signatures(prr: &PartyRegistrationRecord) -> Result<()> {
+    let scheme = prr.msg.scheme;
+    verify_struct_tagged(scheme, &prr.msg.pk_party, CTX_PRR, &prr.msg, &prr.sig_party)?;
+    if let Some(rot) = &prr.msg.rotation {
+        let rot_msg = prr.msg.rotation_message(rot.old_pk);
+        verify_struct_tagged(scheme, &rot.old_pk, CTX_ROTATION, &rot_msg, &rot.sig_old)?;
+    }
     Ok(())
 }
+
+/// Recompute the Merkle root over leaf hashes of serialized PRRs.
+pub fn log_root(h: Hasher, full_log: &[LogEntry]) -> Result<[u8; 32]> {
+    Ok(merkle_root_par(h, log_leaves(h, full_log)?))
+}
+
+/// The Merkle leaf of each log entry: records are hashed (in parallel for large logs),
+/// tombstones carry theirs.
+pub fn log_leaves(h: Hasher, log: &[LogEntry]) -> Result<Vec<[u8; 32]>> {
+    let encoded = log.iter().filter_map(LogEntry::record).map(enc).collect::<Result<Vec<_>>>()?;
+    let mut hashed = leaf_hashes(h, &encoded).into_iter();
+    Ok(log
+        .iter()
+        .map(|entry| match entry {
+            LogEntry::Record(_) => hashed.next().expect("one leaf per record"),
+            LogEntry::Tombstone { tombstone } => tombstone.leaf,
+        })
+        .collect())
+}
+
+/// Recompute the sparse Merkle root (party_id -> latest record's leaf) from the full log.
+pub fn log_smt_root(h: Hasher, full_log: &[LogEntry]) -> Result<[u8; 32]> {
+    let mut latest = BTreeMap::new();
+    for (entry, leaf) in full_log.iter().zip(log_leaves(h, full_log)?) {
+        latest.insert(entry.party_id(), leaf);
+    }
+    Ok(smt_root(h, &latest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{
+        entry, party_key, prr, sign_snapshot, snapshot_of, watchtower_key, EPOCH,
+    };
+    use axum::extract::Query;
+    use common::crypto::sign_struct;
+    use common::types::{ErrorCode, KeyRotation, Tombstone};
+    use axum::http::StatusCode;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Serve `app` on a local port, returning its base URL.
+    async fn serve(app: axum::Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    /// A client for `url` making up to `max_attempts` attempts, 1ms apart.
+    fn quick_client(url: String, max_attempts: u32) -> WatchtowerClient {
+        let retry =
+            RetryPolicy { max_attempts, base_delay: Duration::from_millis(1), jitter: Duration::ZERO };
+        WatchtowerClient::new_with_retry(url, retry).unwrap()
+    }
+
+    /// Count each request, answering the first `failures` with `status`, the rest with "pk".
+    fn flaky(
+        hits: &Arc<AtomicUsize>,
+        failures: usize,
+        status: StatusCode,
+    ) -> axum::routing::MethodRouter {
+        let hits = hits.clone();
+        let handler = move || {
+            let failed = hits.fetch_add(1, Ordering::SeqCst) < failures;
+            async move { if failed { (status, "no") } else { (StatusCode::OK, "pk") } }
+        };
+        axum::routing::any(handler)
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried_up_to_the_attempt_limit() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let app = axum::Router::new()
+            .route("/watchtower_pubkey", flaky(&hits, 2, StatusCode::SERVICE_UNAVAILABLE));
+        let url = serve(app).await;
+        assert_eq!(quick_client(url.clone(), 3).get_watchtower_pubkey_b64().await.unwrap(), "pk");
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+        hits.store(0, Ordering::SeqCst);
+        let err = quick_client(url, 2).get_watchtower_pubkey_b64().await.unwrap_err();
+        assert!(err.to_string().contains("503"), "{err}");
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let app = axum::Router::new()
+            .route("/entries", flaky(&hits, 2, StatusCode::BAD_REQUEST))
+            .route("/watchtower_pubkey", flaky(&hits, 2, StatusCode::NOT_FOUND));
+        let wt = quick_client(serve(app).await, 3);
+        let err = wt.entries(0, 1).await.unwrap_err();
+        assert!(err.to_string().contains("400"), "{err}");
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        assert!(wt.get_watchtower_pubkey_b64().await.is_err());
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn each_status_maps_to_a_transient_or_permanent_error() {
+        let answer = Arc::new(Mutex::new((StatusCode::OK, String::new())));
+        let handler = {
+            let answer = answer.clone();
+            move || {
+                let answer = answer.lock().unwrap().clone();
+                async move { answer }
+            }
+        };
+        let app = axum::Router::new()
+            .route("/watchtower_pubkey", axum::routing::get(handler.clone()))
+            .route("/snapshot", axum::routing::get(handler));
+        let wt = quick_client(serve(app).await, 1);
+
+        let structured = WatchtowerError::new(ErrorCode::SeqNotIncreasing, "seq must increase");
+        let structured = serde_json::to_string(&structured).unwrap();
+        for (code, body, transient) in [
+            (StatusCode::BAD_REQUEST, structured.as_str(), false),
+            (StatusCode::UNAUTHORIZED, "", false),
+            (StatusCode::NOT_FOUND, "no such thing", false),
+            (StatusCode::CONFLICT, "", false),
+            (StatusCode::PAYLOAD_TOO_LARGE, "", false),
+            (StatusCode::TOO_MANY_REQUESTS, "", false),
+            (StatusCode::INTERNAL_SERVER_ERROR, "", true),
+            (StatusCode::BAD_GATEWAY, "", true),
+            (StatusCode::SERVICE_UNAVAILABLE, "", true),
+        ] {
+            *answer.lock().unwrap() = (code, body.to_string());
+            let err = wt.get_watchtower_pubkey_b64().await.unwrap_err();
+            let ClientError::Status { code: got, body: got_body } = &err else {
+                panic!("{code}: {err}");
+            };
+            assert_eq!((*got, got_body.as_str()), (code, body));
+            assert_eq!(err.is_transient(), transient, "{code}");
+            assert!(err.to_string().contains(code.as_str()), "{err}");
+        }
+        // A structured body is decoded for the caller; a plain one is not.
+        *answer.lock().unwrap() = (StatusCode::BAD_REQUEST, structured);
+        let err = wt.get_watchtower_pubkey_b64().await.unwrap_err();
+        assert_eq!(err.watchtower_error().unwrap().code, ErrorCode::SeqNotIncreasing);
+        assert!(err.to_string().contains("seq must increase"), "{err}");
+        *answer.lock().unwrap() = (StatusCode::SERVICE_UNAVAILABLE, "down".into());
+        assert!(wt.get_watchtower_pubkey_b64().await.unwrap_err().watchtower_error().is_none());
+
+        // A 200 with a body that isn't a snapshot is permanent.
+        *answer.lock().unwrap() = (StatusCode::OK, "not json".into());
+        let err = wt.snapshot().await.unwrap_err();
+        assert!(matches!(err, ClientError::Decode(_)), "{err}");
+        assert!(!err.is_transient());
+        // Nobody listening is transient.
+        let err = unreachable_client().get_watchtower_pubkey_b64().await.unwrap_err();
+        assert!(matches!(&err, ClientError::Transport(e) if e.is_connect()), "{err}");
+        assert!(err.is_transient());
+        for err in [
+            ClientError::verification(anyhow!("bad root")),
+            ClientError::Request("from > to".into()),
+        ] {
+            assert!(!err.is_transient(), "{err}");
+        }
+    }
+
+    #[test]
+    fn an_aborted_entries_stream_reports_why() {
+        let mut ndjson = serde_json::to_vec(&entry(&party_key(1), 1, 1)).unwrap();
+        ndjson.extend_from_slice(b"\n{\"error\":{\"code\":\"LOG_CHANGED\",\"message\":\"x\"}}\n");
+        let err = parse_entries_ndjson(&ndjson).unwrap_err();
+        let err = err.downcast::<WatchtowerError>().unwrap();
+        assert_eq!(err.code, ErrorCode::LogChanged);
+
+        let err = parse_entries_ndjson(b"{\"msg\":1}\n").unwrap_err();
+        assert!(err.to_string().contains("malformed entries line"), "{err}");
+    }
+
+    /// A client for a watchtower nobody listens on: any request it makes fails.
+    fn unreachable_client() -> WatchtowerClient {
+        let retry = RetryPolicy { max_attempts: 1, ..Default::default() };
+        WatchtowerClient::new_with_retry("http://127.0.0.1:1".into(), retry).unwrap()
+    }
+
+    #[tokio::test]
+    async fn entries_chunked_checks_the_range_before_fetching() {
+        let wt = unreachable_client();
+        // More entries than a client will fetch, however a snapshot claims them.
+        let err = wt.entries_chunked(1, u64::MAX, 500).await.unwrap_err();
+        assert!(err.to_string().contains("exceed the"), "{err}");
+        for (from, to) in [(0, 5), (3, 1)] {
+            let err = wt.entries_chunked(from, to, 500).await.unwrap_err();
+            assert!(matches!(err, ClientError::Request(_)), "{from}..={to}: {err}");
+        }
+
+        // An empty log needs no request at all.
+        assert!(wt.entries_chunked(1, 0, 500).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_forged_snapshot_is_refused_before_its_entries_are_fetched() {
+        let sk_w = watchtower_key();
+        let log = vec![entry(&party_key(1), 1, 1)];
+        let forged = sign_snapshot(&party_key(1), snapshot_of(&sk_w, &log).msg);
+        let entries_fetched = Arc::new(AtomicUsize::new(0));
+        let fetched = entries_fetched.clone();
+        let snapshot = move || {
+            let srs = forged.clone();
+            async move { axum::Json(SnapshotResponse { srs, finalized: false }) }
+        };
+        let entries = move || {
+            fetched.fetch_add(1, Ordering::SeqCst);
+            async { "" }
+        };
+        let app = axum::Router::new()
+            .route("/snapshot", axum::routing::get(snapshot))
+            .route("/entries", axum::routing::get(entries));
+        let wt = quick_client(serve(app).await, 1);
+
+        let err = wt.fetch_verified_log(&sk_w.verifying_key(), None).await.unwrap_err();
+        assert!(matches!(err, ClientError::Verification(_)), "{err}");
+        assert_eq!(entries_fetched.load(Ordering::SeqCst), 0);
+    }
+
+    /// Serve `body` in 8 KiB chunks, with no content-length, at /snapshot and /entries.
+    async fn serve_streamed(body: Vec<u8>) -> WatchtowerClient {
+        let body = Arc::new(body);
+        let handler = move || {
+            let body = body.clone();
+            async move {
+                let chunks: Vec<Result<Vec<u8>, std::io::Error>> =
+                    body.chunks(8 * 1024).map(|c| Ok(c.to_vec())).collect();
+                axum::body::Body::from_stream(futures::stream::iter(chunks))
+            }
+        };
+        let app = axum::Router::new()
+            .route("/snapshot", axum::routing::get(handler.clone()))
+            .route("/entries", axum::routing::get(handler));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let retry = RetryPolicy { max_attempts: 1, ..Default::default() };
+        WatchtowerClient::new_with_retry(url, retry).unwrap()
+    }
+
+    #[tokio::test]
+    async fn oversized_responses_are_refused_while_streaming() {
+        let wt = serve_streamed(vec![b' '; MAX_RESPONSE_BYTES + 1]).await;
+        let err = wt.snapshot().await.unwrap_err();
+        assert!(err.to_string().contains("response body exceeds"), "{err}");
+
+        // One /entries line past the cap, never terminated.
+        let wt = serve_streamed(vec![b'{'; MAX_ENTRY_LINE_BYTES + 8 * 1024]).await;
+        let err = wt.entries(1, 1).await.unwrap_err();
+        assert!(err.to_string().contains("entries line longer than"), "{err}");
+    }
+
+    /// Serve `log` at /entries as the watchtower does: NDJSON, at most `page` entries per
+    /// response, with a `next_from` cursor for the rest.
+    async fn serve_log(log: Vec<LogEntry>, page: u64) -> String {
+        let log = Arc::new(log);
+        let handler = move |Query(q): Query<std::collections::HashMap<String, u64>>| {
+            let log = log.clone();
+            async move {
+                let (from, to) = (q["from"], q["to"]);
+                let end = to.min(from + page - 1);
+                let mut body = Vec::new();
+                for entry in &log[from as usize - 1..end as usize] {
+                    serde_json::to_writer(&mut body, entry).unwrap();
+                    body.push(b'\n');
+                }
+                let mut resp = axum::response::Response::new(axum::body::Body::from(body));
+                if end < to {
+                    resp.headers_mut().insert(NEXT_FROM_HEADER, (end + 1).into());
+                }
+                resp
+            }
+        };
+        serve(axum::Router::new().route("/entries", axum::routing::get(handler))).await
+    }
+
+    /// A watchtower serving `log` cut to the current value of `len`.
+    async fn serve_rewindable(log: Vec<LogEntry>, len: Arc<AtomicUsize>) -> WatchtowerClient {
+        let log = Arc::new(log);
+        let served = log.clone();
+        let snapshot = move || {
+            let srs = snapshot_of(&watchtower_key(), &served[..len.load(Ordering::SeqCst)]);
+            async move { axum::Json(serde_json::json!({ "srs": srs })) }
+        };
+        let entries = move |Query(q): Query<std::collections::HashMap<String, u64>>| {
+            let mut body = Vec::new();
+            for entry in &log[q["from"] as usize - 1..q["to"] as usize] {
+                serde_json::to_writer(&mut body, entry).unwrap();
+                body.push(b'\n');
+            }
+            async move { body }
+        };
+        let app = axum::Router::new()
+            .route("/snapshot", axum::routing::get(snapshot))
+            .route("/entries", axum::routing::get(entries));
+        quick_client(serve(app).await, 1)
+    }
+
+    #[tokio::test]
+    async fn syncing_refuses_a_rolled_back_log_but_follows_an_advancing_one() {
+        let pk_w = watchtower_key().verifying_key();
+        let log: Vec<_> = (1..=4).map(|n| entry(&party_key(n), n.into(), 1)).collect();
+        let len = Arc::new(AtomicUsize::new(3));
+        let wt = serve_rewindable(log, len.clone()).await;
+        let mut st = PartyStateFile::new(EPOCH, 1);
+        wt.sync_state(&pk_w, &mut st, false).await.unwrap();
+        assert_eq!((st.last_log_len, st.roster.len()), (3, 3));
+
+        len.store(2, Ordering::SeqCst);
+        let err = wt.sync_state(&pk_w, &mut st, false).await.unwrap_err();
+        assert!(err.to_string().contains("ROLLBACK DETECTED"), "{err}");
+        assert_eq!((st.last_log_len, st.roster.len()), (3, 3));
+        let evidence = st.rollback_evidence.clone().expect("no evidence kept");
+        assert_eq!((evidence.accepted.msg.log_len, evidence.served.msg.log_len), (3, 2));
+
+        len.store(4, Ordering::SeqCst);
+        wt.sync_state(&pk_w, &mut st, false).await.unwrap();
+        assert_eq!((st.last_log_len, st.roster.len()), (4, 4));
+
+        // Only an explicit --allow-rollback takes the shorter log.
+        len.store(2, Ordering::SeqCst);
+        wt.sync_state(&pk_w, &mut st, true).await.unwrap();
+        assert_eq!((st.last_log_len, st.roster.len()), (2, 2));
+    }
+
+    /// A watchtower serving `log` as it stands, proofs included; returns a client and the
+    /// /entries ranges requested.
+    async fn serve_growing(
+        log: Arc<Mutex<Vec<LogEntry>>>,
+    ) -> (WatchtowerClient, Arc<Mutex<Vec<(u64, u64)>>>) {
+        use common::merkle::{consistency_proof, inclusion_proof};
+        type Params = Query<std::collections::HashMap<String, u64>>;
+        let h = Hasher::Sha256;
+        let requested: Arc<Mutex<Vec<(u64, u64)>>> = Arc::default();
+        let (snapshot_log, entries_log, proof_log, consistency_log) =
+            (log.clone(), log.clone(), log.clone(), log);
+        let snapshot = move || {
+            let srs = snapshot_of(&watchtower_key(), &snapshot_log.lock().unwrap());
+            async move { axum::Json(serde_json::json!({ "srs": srs })) }
+        };
+        let seen = requested.clone();
+        let entries = move |Query(q): Params| {
+            seen.lock().unwrap().push((q["from"], q["to"]));
+            let mut body = Vec::new();
+            for entry in &entries_log.lock().unwrap()[q["from"] as usize - 1..q["to"] as usize] {
+                serde_json::to_writer(&mut body, entry).unwrap();
+                body.push(b'\n');
+            }
+            async move { body }
+        };
+        let merkle_proof = move |Query(q): Params| {
+            let log = proof_log.lock().unwrap();
+            let index = q["index"];
+            let leaves = log_leaves(h, &log).unwrap();
+            let mp = MerkleProofResponse {
+                prr: log[index as usize - 1].record().unwrap().clone(),
+                index,
+                proof: inclusion_proof(h, &leaves, index - 1).unwrap(),
+                srs: snapshot_of(&watchtower_key(), &log),
+            };
+            async move { axum::Json(mp) }
+        };
+        let consistency = move |Query(q): Params| {
+            let leaves = log_leaves(h, &consistency_log.lock().unwrap()).unwrap();
+            let proof = consistency_proof(h, &leaves[..q["to"] as usize], q["from"]).unwrap();
+            async move { axum::Json(proof) }
+        };
+        let app = axum::Router::new()
+            .route("/snapshot", axum::routing::get(snapshot))
+            .route("/entries", axum::routing::get(entries))
+            .route("/merkle_proof", axum::routing::get(merkle_proof))
+            .route("/consistency", axum::routing::get(consistency));
+        (quick_client(serve(app).await, 1), requested)
+    }
+
+    #[tokio::test]
+    async fn light_syncs_after_the_first_fetch_only_the_appended_entries() {
+        let pk_w = watchtower_key().verifying_key();
+        let log: Vec<_> = (1..=3).map(|n| entry(&party_key(n), n.into(), 1)).collect();
+        let log = Arc::new(Mutex::new(log));
+        let (wt, requested) = serve_growing(log.clone()).await;
+        let mut st = PartyStateFile::new(EPOCH, 1);
+        wt.sync_state(&pk_w, &mut st, false).await.unwrap();
+        assert_eq!(std::mem::take(&mut *requested.lock().unwrap()), [(1, 3)]);
+
+        log.lock().unwrap().extend([entry(&party_key(4), 4, 1), entry(&party_key(2), 2, 2)]);
+        for _ in 0..2 {
+            let base = st.light_base().unwrap();
+            let (srs, appended) = wt.fetch_verified_extension(&pk_w, &base).await.unwrap();
+            st.apply_appended(srs, &appended);
+        }
+        // Only the appended range, once; the unchanged second tick fetches no entries.
+        assert_eq!(*requested.lock().unwrap(), [(4, 5)]);
+
+        let mut full = PartyStateFile::new(EPOCH, 1);
+        wt.sync_state(&pk_w, &mut full, false).await.unwrap();
+        let roster = |st: &PartyStateFile| serde_json::to_value(&st.roster).unwrap();
+        assert_eq!(roster(&st), roster(&full));
+        assert_eq!(st.last_log_len, 5);
+    }
+
+    #[tokio::test]
+    async fn entries_pages_must_hold_exactly_their_entries() {
+        let log: Vec<_> = (1..=4).map(|n| entry(&party_key(n as u8), n, 1)).collect();
+        // Answers every request with the first `n` entries of `log`.
+        let serve_n = |n: usize| {
+            let body: Vec<u8> = log[..n]
+                .iter()
+                .flat_map(|e| [serde_json::to_vec(e).unwrap(), b"\n".to_vec()].concat())
+                .collect();
+            let app = axum::Router::new().route("/entries", axum::routing::get(|| async { body }));
+            async move { quick_client(serve(app).await, 1) }
+        };
+        assert_eq!(serve_n(3).await.entries(1, 3).await.unwrap(), log[..3]);
+        let err = serve_n(2).await.entries(1, 3).await.unwrap_err();
+        assert!(err.to_string().contains("returned 2 entries, expected 3"), "{err}");
+        let err = serve_n(4).await.entries(1, 3).await.unwrap_err();
+        assert!(err.to_string().contains("returned more than 3 entries"), "{err}");
+
+        // A reversed range is refused before anything is fetched.
+        let err = serve_n(0).await.entries(3, 2).await.unwrap_err();
+        assert!(matches!(err, ClientError::Request(_)), "{err}");
+    }
+
+    #[tokio::test]
+    async fn chunked_entries_equal_a_single_fetch() {
+        let log: Vec<_> = (1..=1000).map(|n| entry(&party_key((n % 200) as u8), n, 1)).collect();
+        let wt = quick_client(serve_log(log.clone(), 256).await, 1);
+
+        let whole = wt.entries(1, 1000).await.unwrap();
+        assert_eq!(whole, log);
+        for chunk_size in [1000, 100, 7, 333, 2000] {
+            let chunked = wt.entries_chunked(1, 1000, chunk_size).await;
+            assert_eq!(chunked.unwrap(), whole, "chunk_size={chunk_size}");
+        }
+        let tail = wt.entries_chunked(901, 1000, 7).await.unwrap();
+        assert_eq!(tail, whole[900..]);
+        assert!(wt.entries_chunked(1, 1000, 0).await.is_err());
+    }
+
+    #[test]
+    fn snapshot_must_embed_the_key_it_verifies_under() {
+        let sk_w = watchtower_key();
+        let log = vec![entry(&party_key(1), 1, 1)];
+        let srs = snapshot_of(&sk_w, &log);
+        verify_snapshot_and_log(&sk_w.verifying_key(), None, &srs, &log).unwrap();
+
+        // Validly signed, but claiming to come from another watchtower.
+        let mut msg = srs.msg.clone();
+        msg.watchtower_pk = party_key(9).verifying_key().to_bytes();
+        let srs = sign_snapshot(&sk_w, msg);
+        let err = verify_snapshot_and_log(&sk_w.verifying_key(), None, &srs, &log).unwrap_err();
+        assert!(err.to_string().contains("snapshot embeds watchtower pubkey"), "{err}");
+    }
+
+    #[test]
+    fn snapshots_of_another_epoch_are_refused_with_epoch_mismatch() {
+        let sk_w = watchtower_key();
+        let pk_w = sk_w.verifying_key();
+        let log = vec![entry(&party_key(1), 1, 1)];
+        let srs = snapshot_of(&sk_w, &log);
+        verify_snapshot_and_log(&pk_w, Some(EPOCH), &srs, &log).unwrap();
+
+        // Validly signed by the same watchtower, for the next epoch.
+        let mut msg = srs.msg.clone();
+        msg.epoch = EPOCH + 1;
+        let other = sign_snapshot(&sk_w, msg);
+        verify_snapshot_signature(&pk_w, &other).unwrap();
+        let err = verify_snapshot_and_log(&pk_w, Some(EPOCH), &other, &log).unwrap_err();
+        let code = err.downcast_ref::<WatchtowerError>().map(|e| e.code);
+        assert_eq!(code, Some(ErrorCode::EpochMismatch), "{err}");
+    }
+
+    #[test]
+    fn messages_of_an_unknown_version_are_refused() {
+        let sk_w = watchtower_key();
+        let log = vec![entry(&party_key(1), 1, 1)];
+        let mut msg = snapshot_of(&sk_w, &log).msg;
+        msg.version += 1;
+        let srs = sign_snapshot(&sk_w, msg);
+        let err = verify_snapshot_and_log(&sk_w.verifying_key(), None, &srs, &log).unwrap_err();
+        assert!(err.to_string().contains("unsupported SnapshotMessage version"), "{err}");
+
+        // A validly signed record of a newer layout, under a current snapshot.
+        let mut msg = prr(&party_key(1), 1, 1).msg;
+        msg.version += 1;
+        let sig_party = sign_struct(&party_key(1), CTX_PRR, &msg).unwrap();
+        let log = vec![LogEntry::from(PartyRegistrationRecord { msg, sig_party })];
+        let srs = snapshot_of(&sk_w, &log);
+        let err = verify_snapshot_and_log(&sk_w.verifying_key(), None, &srs, &log).unwrap_err();
+        assert!(err.to_string().contains("unsupported RegistrationMessage version"), "{err}");
+    }
+
+    #[test]
+    fn merkle_proofs_verify_only_for_their_record_and_index() {
+        use common::merkle::inclusion_proof;
+        let (sk_w, h) = (watchtower_key(), Hasher::Sha256);
+        let pk_w = sk_w.verifying_key();
+        let log: Vec<_> = (1..=3).map(|n| entry(&party_key(n), n.into(), 1)).collect();
+        let leaves = log_leaves(h, &log).unwrap();
+        let mp = MerkleProofResponse {
+            prr: log[1].record().unwrap().clone(),
+            index: 2,
+            proof: inclusion_proof(h, &leaves, 1).unwrap(),
+            srs: snapshot_of(&sk_w, &log),
+        };
+        verify_merkle_proof(&pk_w, &mp).unwrap();
+
+        let refused = |what: &str, tamper: &dyn Fn(&mut MerkleProofResponse)| {
+            let mut mp = mp.clone();
+            tamper(&mut mp);
+            assert!(verify_merkle_proof(&pk_w, &mp).is_err(), "tampered {what} accepted");
+        };
+        refused("sibling", &|mp| mp.proof.siblings[0][0] ^= 1);
+        refused("index", &|mp| mp.index = 3);
+        refused("record", &|mp| mp.prr = prr(&party_key(2), 2, 2));
+        refused("snapshot signature", &|mp| mp.srs.sig_watchtower[0] ^= 1);
+        assert!(verify_merkle_proof(&party_key(9).verifying_key(), &mp).is_err());
+    }
+
+    #[test]
+    fn snapshot_signatures_verify_on_their_own() {
+        let sk_w = watchtower_key();
+        let pk_w = sk_w.verifying_key();
+        // No log needed, even for a root no log here hashes to.
+        let mut msg = snapshot_of(&sk_w, &[entry(&party_key(1), 1, 1)]).msg;
+        msg.merkle_root = [7; 32];
+        let srs = sign_snapshot(&sk_w, msg);
+        verify_snapshot_signature(&pk_w, &srs).unwrap();
+
+        let mut forged = srs.clone();
+        forged.sig_watchtower[0] ^= 1;
+        assert!(verify_snapshot_signature(&pk_w, &forged).is_err());
+        let mut altered = srs.clone();
+        altered.msg.log_len += 1;
+        assert!(verify_snapshot_signature(&pk_w, &altered).is_err());
+        assert!(verify_snapshot_signature(&party_key(9).verifying_key(), &srs).is_err());
+    }
+
+    #[test]
+    fn full_logs_verify_against_the_snapshot_without_its_signature() {
+        let sk_w = watchtower_key();
+        let log: Vec<_> = (1..=3).map(|n| entry(&party_key(n), n.into(), 1)).collect();
+        let mut srs = snapshot_of(&sk_w, &log);
+        srs.sig_watchtower = [0; 64];
+        verify_full_log(&srs, &log).unwrap();
+
+        let refused = |what: &str, log: &[LogEntry]| {
+            assert!(verify_full_log(&srs, log).is_err(), "{what} accepted");
+        };
+        refused("a truncated log", &log[..2]);
+        refused("an extended log", &[&log[..], &[entry(&party_key(4), 4, 1)]].concat());
+        refused("a reordered log", &[log[1].clone(), log[0].clone(), log[2].clone()]);
+        let substituted = [log[0].clone(), entry(&party_key(2), 2, 2), log[2].clone()];
+        refused("a substituted record", &substituted);
+        let mut forged = log.clone();
+        let LogEntry::Record(prr) = &mut forged[1] else { unreachable!() };
+        prr.sig_party[0] ^= 1;
+        refused("a forged record", &forged);
+    }
+
+    #[test]
+    fn entries_verify_by_proof_without_the_rest_of_the_log() {
+        use common::merkle::inclusion_proof;
+        let (sk_w, h) = (watchtower_key(), Hasher::Sha256);
+        let log: Vec<_> = (1..=5).map(|n| entry(&party_key(n), n.into(), 1)).collect();
+        let leaves = log_leaves(h, &log).unwrap();
+        let mut srs = snapshot_of(&sk_w, &log);
+        srs.sig_watchtower = [0; 64];
+        let record = log[3].record().unwrap();
+        let proof = inclusion_proof(h, &leaves, 3).unwrap();
+        let leaf = verify_entry_with_proof(&srs, record, 4, &proof).unwrap();
+        assert_eq!(leaf, leaves[3]);
+
+        assert!(verify_entry_with_proof(&srs, record, 3, &proof).is_err());
+        assert!(verify_entry_with_proof(&srs, record, 0, &proof).is_err());
+        assert!(verify_entry_with_proof(&srs, log[2].record().unwrap(), 4, &proof).is_err());
+        let mut bad = proof.clone();
+        bad.siblings[0][0] ^= 1;
+        assert!(verify_entry_with_proof(&srs, record, 4, &bad).is_err());
+        let mut bad = proof.clone();
+        bad.log_len -= 1;
+        assert!(verify_entry_with_proof(&srs, record, 4, &bad).is_err());
+        let mut forged = record.clone();
+        forged.sig_party[0] ^= 1;
+        assert!(verify_entry_with_proof(&srs, &forged, 4, &proof).is_err());
+    }
+
+    #[test]
+    fn batch_and_per_entry_verification_agree() {
+        let sk_w = watchtower_key();
+        let mut log: Vec<_> = (1..=20).map(|n| entry(&party_key(n), n.into(), 1)).collect();
+        let (old, new) = (party_key(5), party_key(21));
+        let mut msg = prr(&new, 5, 2).msg;
+        let old_pk = old.verifying_key().to_bytes();
+        let sig_old = sign_struct(&old, CTX_ROTATION, &msg.rotation_message(old_pk)).unwrap();
+        msg.rotation = Some(KeyRotation { old_pk, sig_old });
+        let sig_party = sign_struct(&new, CTX_PRR, &msg).unwrap();
+        log.push(PartyRegistrationRecord { msg, sig_party }.into());
+        let failing = |log: &[LogEntry]| -> Vec<usize> {
+            let bad = |entry: &LogEntry| verify_prr_signatures(entry.record().unwrap()).is_err();
+            (0..log.len()).filter(|&i| bad(&log[i])).map(|i| i + 1).collect()
+        };
+        let pk_w = sk_w.verifying_key();
+        assert!(failing(&log).is_empty());
+        verify_snapshot_and_log(&pk_w, None, &snapshot_of(&sk_w, &log), &log).unwrap();
+
+        // One bad party signature, then one bad rotation endorsement: the batch fails, and
+        // the entry is named.
+        let mut bad_sig = log.clone();
+        let mut record = bad_sig[12].record().unwrap().clone();
+        record.sig_party = log[11].record().unwrap().sig_party;
+        bad_sig[12] = record.into();
+        let mut bad_rotation = log.clone();
+        let mut record = bad_rotation[20].record().unwrap().clone();
+        record.msg.rotation.as_mut().unwrap().sig_old = record.sig_party;
+        record.sig_party = sign_struct(&new, CTX_PRR, &record.msg).unwrap();
+        bad_rotation[20] = record.into();
+        for (log, index, party_id) in [(bad_sig, 13, 13), (bad_rotation, 21, 5)] {
+            assert_eq!(failing(&log), [index]);
+            let err = verify_snapshot_and_log(&pk_w, None, &snapshot_of(&sk_w, &log), &log).unwrap_err();
+            let want = format!("entry {index} (party_id={party_id}) failed verification");
+            assert!(err.to_string().contains(&want), "{err}");
+        }
+    }
+
+    #[test]
+    fn rotation_signatures_are_checked_over_canonical_bytes() {
+        let (old, new) = (party_key(1), party_key(2));
+        let mut msg = prr(&new, 1, 2).msg;
+        let old_pk = old.verifying_key().to_bytes();
+        let sig_old = sign_struct(&old, CTX_ROTATION, &msg.rotation_message(old_pk)).unwrap();
+        msg.rotation = Some(KeyRotation { old_pk, sig_old });
+        let sig_party = sign_struct(&new, CTX_PRR, &msg).unwrap();
+        let rotated = PartyRegistrationRecord { msg, sig_party };
+        verify_prr_signatures(&rotated).unwrap();
+        let log = vec![LogEntry::from(rotated.clone())];
+        verify_full_log(&snapshot_of(&watchtower_key(), &log), &log).unwrap();
+
+        // Endorsed by a key other than the one it claims to rotate from.
+        let mut forged = rotated;
+        let rot_msg = forged.msg.rotation_message(old_pk);
+        let sig_old = sign_struct(&party_key(3), CTX_ROTATION, &rot_msg).unwrap();
+        forged.msg.rotation.as_mut().unwrap().sig_old = sig_old;
+        forged.sig_party = sign_struct(&new, CTX_PRR, &forged.msg).unwrap();
+        assert!(verify_prr_signatures(&forged).is_err());
+    }
+
+    /// `entry` as compaction would leave it.
+    fn tombstone(entry: &LogEntry) -> LogEntry {
+        let leaf = entry.leaf(Hasher::Sha256).unwrap();
+        let nonce = entry.record().unwrap().msg.nonce;
+        let tombstone = Tombstone { party_id: entry.party_id(), seq: entry.seq(), leaf, nonce };
+        LogEntry::Tombstone { tombstone }
+    }
+
+    #[test]
+    fn compacted_logs_verify_unless_a_latest_record_is_dropped() {
+        let sk_w = watchtower_key();
+        let pk_w = sk_w.verifying_key();
+        let (a, b) = (party_key(1), party_key(2));
+        let log = vec![entry(&a, 1, 1), entry(&b, 2, 1), entry(&a, 1, 2)];
+        let srs = snapshot_of(&sk_w, &log);
+
+        let mut compacted = log.clone();
+        compacted[0] = tombstone(&log[0]);
+        verify_snapshot_and_log(&pk_w, None, &srs, &compacted).unwrap();
+        assert_eq!(snapshot_of(&sk_w, &compacted).msg, srs.msg);
+
+        let mut hidden = log.clone();
+        hidden[1] = tombstone(&log[1]);
+        let err = verify_snapshot_and_log(&pk_w, None, &srs, &hidden).unwrap_err();
+        assert!(err.to_string().contains("party_id=2 (seq=1) is a tombstone"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn subscribed_snapshots_are_parsed_across_chunks_and_still_need_verifying() {
+        let sk_w = watchtower_key();
+        let pk_w = sk_w.verifying_key();
+        let good = snapshot_of(&sk_w, &[entry(&party_key(1), 1, 1)]);
+        let mut forged = snapshot_of(&sk_w, &[entry(&party_key(2), 2, 1)]);
+        forged.sig_watchtower[0] ^= 1;
+        let event = |srs: &SignedRosterSnapshot| {
+            let sr = SnapshotResponse { srs: srs.clone(), finalized: false };
+            format!("event: snapshot\ndata: {}\n\n", serde_json::to_string(&sr).unwrap())
+        };
+        let (good_event, forged_event) = (event(&good), event(&forged));
+        // A keep-alive, another event type, then snapshots split mid-line across chunks.
+        let chunks = vec![
+            ":keep-alive\n\n".to_string(),
+            "event: hello\ndata: {}\n\n".to_string(),
+            good_event[..20].to_string(),
+            good_event[20..].to_string(),
+            forged_event,
+            "event: snapshot\ndata: not json\n\n".to_string(),
+            event(&good),
+        ];
+        let body = move || {
+            let chunks = chunks.clone().into_iter().map(Ok::<_, std::io::Error>);
+            async move { axum::body::Body::from_stream(stream::iter(chunks)) }
+        };
+        let app = axum::Router::new().route("/snapshot/subscribe", axum::routing::get(body));
+        let mut updates = quick_client(serve(app).await, 1).subscribe_snapshots().await.unwrap();
+
+        let first = updates.next().await.unwrap().unwrap();
+        assert_eq!(first.msg, good.msg);
+        verify_snapshot_signature(&pk_w, &first).unwrap();
+        // Passed along as sent; verifying it is what refuses it.
+        let second = updates.next().await.unwrap().unwrap();
+        assert_eq!(second.sig_watchtower, forged.sig_watchtower);
+        assert!(verify_snapshot_signature(&pk_w, &second).is_err());
+        // A malformed event ends the stream.
+        let err = updates.next().await.unwrap().unwrap_err();
+        assert!(matches!(err, ClientError::Decode(_)), "{err}");
+        assert!(updates.next().await.is_none());
+    }
+
+    #[test]
+    fn snapshot_events_join_their_data_lines_and_skip_everything_else() {
+        let sr = SnapshotResponse { srs: snapshot_of(&watchtower_key(), &[]), finalized: true };
+        let json = serde_json::to_string_pretty(&sr).unwrap();
+        let data: Vec<_> = json.lines().map(|l| format!("data: {l}\n")).collect();
+        let event = format!("event: snapshot\n{}\n", data.concat());
+        let parsed = parse_snapshot_event(event.as_bytes()).unwrap().unwrap();
+        assert_eq!(parsed.msg, sr.srs.msg);
+
+        assert!(parse_snapshot_event(b":keep-alive\n\n").unwrap().is_none());
+        assert!(parse_snapshot_event(b"event: other\ndata: {}\n\n").unwrap().is_none());
+        assert!(parse_snapshot_event(b"event: snapshot\ndata: {}\n\n").is_err());
+        assert!(parse_snapshot_event(b"event: snapshot\ndata: \xff\n\n").is_err());
+    }
+
+    /// Serve /snapshot as `snapshot_of` the empty log, always under the ETag `"v1"`,
+    /// answering a matching `If-None-Match` with 304. Returns the URL and each request's
+    /// `If-None-Match`.
+    async fn serve_etagged_snapshot() -> (String, Arc<Mutex<Vec<Option<String>>>>) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_by_handler = seen.clone();
+        let handler = move |Query(q): Query<std::collections::HashMap<String, u64>>,
+                            headers: axum::http::HeaderMap| {
+            let inm = headers.get(IF_NONE_MATCH).map(|v| v.to_str().unwrap().to_string());
+            seen_by_handler.lock().unwrap().push(inm.clone());
+            async move {
+                use axum::response::IntoResponse as _;
+                let etag = [(ETAG, "\"v1\"")];
+                if inm.as_deref() == Some("\"v1\"") {
+                    return (StatusCode::NOT_MODIFIED, etag).into_response();
+                }
+                let mut msg = snapshot_of(&watchtower_key(), &[]).msg;
+                msg.epoch = q.get("epoch").copied().unwrap_or(msg.epoch);
+                let srs = sign_snapshot(&watchtower_key(), msg);
+                (etag, axum::Json(SnapshotResponse { srs, finalized: false })).into_response()
+            }
+        };
+        let app = axum::Router::new().route("/snapshot", axum::routing::get(handler));
+        (serve(app).await, seen)
+    }
+
+    #[tokio::test]
+    async fn unchanged_snapshots_come_from_the_cache() {
+        let (url, seen) = serve_etagged_snapshot().await;
+        let wt = quick_client(url, 1);
+        let first = wt.snapshot().await.unwrap();
+        let again = wt.snapshot().await.unwrap();
+        assert_eq!(again.msg, first.msg);
+        assert_eq!(again.sig_watchtower, first.sig_watchtower);
+        assert_eq!(*seen.lock().unwrap(), [None, Some("\"v1\"".to_string())]);
+
+        // Clones share the cache, but not across epochs.
+        assert_eq!(wt.clone().snapshot().await.unwrap().msg, first.msg);
+        let other = wt.clone().with_epoch(EPOCH + 1).snapshot().await.unwrap();
+        assert_eq!(other.msg.epoch, EPOCH + 1);
+        assert_eq!(seen.lock().unwrap()[3], None);
+    }
+
+    #[tokio::test]
+    async fn sequential_calls_reuse_one_connection() {
+        use axum::extract::{ConnectInfo, Request};
+        use axum::http::Version;
+        use std::net::SocketAddr;
+
+        let srs = snapshot_of(&watchtower_key(), &[entry(&party_key(1), 1, 1)]);
+        for http2_prior_knowledge in [false, true] {
+            let seen: Arc<Mutex<Vec<(SocketAddr, Version)>>> = Arc::default();
+            let (log, srs) = (seen.clone(), srs.clone());
+            let handler = move |ConnectInfo(peer): ConnectInfo<SocketAddr>, req: Request| {
+                log.lock().unwrap().push((peer, req.version()));
+                let resp = SnapshotResponse { srs: srs.clone(), finalized: false };
+                async move { axum::Json(resp) }
+            };
+            let app = axum::Router::new().route("/snapshot", axum::routing::get(handler));
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            tokio::spawn(async move { axum::serve(listener, app).await });
+
+            let config = WatchtowerClientConfig { http2_prior_knowledge, ..Default::default() };
+            let wt = WatchtowerClient::new_with_config(url, config).unwrap();
+            for _ in 0..5 {
+                wt.snapshot().await.unwrap();
+            }
+            let seen = seen.lock().unwrap();
+            assert_eq!(seen.len(), 5);
+            assert!(seen.iter().all(|(peer, _)| *peer == seen[0].0), "{seen:?}");
+            let version = if http2_prior_knowledge { Version::HTTP_2 } else { Version::HTTP_11 };
+            assert!(seen.iter().all(|(_, v)| *v == version), "{seen:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn a_hung_watchtower_times_out() {
+        let handler = || async {
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            "pk"
+        };
+        let app = axum::Router::new().route("/watchtower_pubkey", axum::routing::get(handler));
+        let config = WatchtowerClientConfig {
+            request_timeout: Duration::from_millis(100),
+            retry: RetryPolicy { max_attempts: 1, ..Default::default() },
+            ..Default::default()
+        };
+        let wt = WatchtowerClient::new_with_config(serve(app).await, config).unwrap();
+        let started = std::time::Instant::now();
+        let err = wt.get_watchtower_pubkey_b64().await.unwrap_err();
+        assert!(matches!(&err, ClientError::Transport(e) if e.is_timeout()), "{err}");
+        assert!(err.is_transient());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn zero_timeouts_are_refused() {
+        let zero = Duration::ZERO;
+        for config in [
+            WatchtowerClientConfig { connect_timeout: zero, ..Default::default() },
+            WatchtowerClientConfig { request_timeout: zero, ..Default::default() },
+        ] {
+            let err = WatchtowerClient::new_with_config("http://127.0.0.1:1".into(), config);
+            assert!(err.err().unwrap().to_string().contains("timeouts must be nonzero"));
+        }
+    }
+
+    #[test]
+    fn base_urls_are_normalized() {
+        for (base, want) in [
+            ("http://127.0.0.1:7000", "http://127.0.0.1:7000"),
+            ("http://127.0.0.1:7000/", "http://127.0.0.1:7000"),
+            (" HTTP://Watchtower:7000/ ", "http://watchtower:7000"),
+            ("https://watchtower.example:443", "https://watchtower.example"),
+            ("http://watchtower.example:80/", "http://watchtower.example"),
+            ("http://[::1]:7000", "http://[::1]:7000"),
+        ] {
+            assert_eq!(normalize_base_url(base).unwrap(), want, "{base}");
+            let wt = WatchtowerClient::new(base.into()).unwrap();
+            assert_eq!(wt.base, want, "{base}");
+        }
+    }
+
+    #[test]
+    fn malformed_base_urls_are_refused() {
+        for (base, why) in [
+            ("watchtower:7000", "scheme must be http or https"),
+            ("127.0.0.1:7000", "relative URL without a base"),
+            ("ftp://watchtower:7000", "scheme must be http or https"),
+            ("http://watchtower:7000/api", "must not have a path"),
+            ("http://watchtower:7000/?epoch=1", "must not have a query"),
+            ("http://watchtower:7000#x", "must not have a query or fragment"),
+            ("http://user:pw@watchtower:7000", "must not carry credentials"),
+            ("http://watchtower:99999", "invalid port"),
+            ("", "relative URL without a base"),
+        ] {
+            let err = WatchtowerClient::new(base.into()).err().unwrap();
+            assert!(matches!(err, ClientError::Request(_)), "{base}: {err}");
+            assert!(err.to_string().contains(why), "{base}: {err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn fetched_roster_holds_each_partys_latest_record() {
+        let sk_w = watchtower_key();
+        let log = vec![
+            entry(&party_key(1), 1, 1),
+            entry(&party_key(2), 2, 1),
+            entry(&party_key(1), 1, 2),
+        ];
+        let srs = snapshot_of(&sk_w, &log);
+        let body: String = log.iter().map(|e| serde_json::to_string(e).unwrap() + "\n").collect();
+        let snapshot = move || {
+            let srs = srs.clone();
+            async move { axum::Json(SnapshotResponse { srs, finalized: false }) }
+        };
+        let app = axum::Router::new()
+            .route("/snapshot", axum::routing::get(snapshot))
+            .route("/entries", axum::routing::get(move || async move { body }));
+        let wt = quick_client(serve(app).await, 1);
+
+        let roster = wt.fetch_verified_roster(&sk_w.verifying_key()).await.unwrap();
+        let seqs: Vec<_> = roster.iter().map(|e| (e.party_id, e.seq)).collect();
+        assert_eq!(seqs, [(1, 2), (2, 1)]);
+
+        let err = wt.fetch_verified_roster(&party_key(9).verifying_key()).await.unwrap_err();
+        assert!(matches!(err, ClientError::Verification(_)), "{err}");
+    }
+
+    #[test]
+    fn roots_verify_only_under_the_snapshot_hash() {
+        let sk_w = watchtower_key();
+        let log = vec![entry(&party_key(1), 1, 1), entry(&party_key(2), 2, 1)];
+        let mut msg = snapshot_of(&sk_w, &log).msg;
+        msg.merkle_root = log_root(Hasher::Blake3, &log).unwrap();
+        msg.smt_root = log_smt_root(Hasher::Blake3, &log).unwrap();
+        msg.hash_alg = Hasher::Blake3.tag();
+        verify_full_log(&sign_snapshot(&sk_w, msg.clone()), &log).unwrap();
+
+        // The same BLAKE3 roots, labelled SHA-256.
+        msg.hash_alg = Hasher::Sha256.tag();
+        assert!(verify_full_log(&sign_snapshot(&sk_w, msg), &log).is_err());
+    }
+
+    #[test]
+    fn appended_records_are_checked_against_the_new_root() {
+        let sk_w = watchtower_key();
+        let log: Vec<_> = (1..=5).map(|n| entry(&party_key(n), n.into(), 1)).collect();
+        let h = Hasher::Sha256;
+        let leaves = log_leaves(h, &log).unwrap();
+        let proof = common::merkle::consistency_proof(h, &leaves, 2).unwrap();
+        let srs = snapshot_of(&sk_w, &log);
+        verify_appended_log(&srs, &proof, &log[2..]).unwrap();
+
+        // A record the log doesn't hold, validly signed, is still caught.
+        let mut swapped = log[2..].to_vec();
+        swapped[1] = entry(&party_key(4), 4, 2);
+        let err = verify_appended_log(&srs, &proof, &swapped).unwrap_err();
+        assert!(err.to_string().contains("do not match the new root"), "{err}");
+
+        let mut forged = log[2..].to_vec();
+        let mut first = forged[0].record().unwrap().clone();
+        first.sig_party = forged[1].record().unwrap().sig_party;
+        forged[0] = first.into();
+        let err = verify_appended_log(&srs, &proof, &forged).unwrap_err();
+        assert!(err.to_string().contains("failed verification"), "{err}");
+
+        assert!(verify_appended_log(&srs, &proof, &log[3..]).is_err());
+    }
+}
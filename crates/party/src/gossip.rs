@@ -1,61 +1,794 @@
+use crate::client::{log_leaves, verify_prr_signatures};
 use anyhow::{anyhow, Result};
-use axum::{extract::State, http::StatusCode, response::IntoResponse, routing::post, Json, Router};
-use common::crypto::verify_struct;
-use common::types::GossipSnapshot;
+use axum::{
+    extract::{DefaultBodyLimit, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use common::crypto::{enc, verify_struct, CTX_SNAPSHOT};
+use common::merkle::{inclusion_proof, leaf_hash, merkle_root, verify_inclusion};
+use common::types::{
+    canonical_json, GossipEvidence, GossipObservation, GossipSnapshot, LogEntry,
+    SignedRosterSnapshot,
+};
 use ed25519_dalek::VerifyingKey;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::Write;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, Notify};
+use tracing::{error, info, warn};
+use base64::Engine as _;
 
 #[derive(Clone)]
 pub struct GossipState {
     pub pk_w: VerifyingKey,
-    /// Store the last seen SRS (epoch, log_len, root). If conflicts arrive, we report.
-    pub last: Arc<Mutex<Option<common::types::SignedRosterSnapshot>>>,
+    /// The epoch this party is configured for; gossip for any other epoch is rejected.
+    pub epoch: u64,
+    /// The last seen SRS per epoch, so a snapshot from another epoch can't displace the
+    /// one a same-epoch conflict would be caught against. Only the latest
+    /// `MAX_GOSSIP_EPOCHS` epochs are kept.
+    pub last_by_epoch: Arc<Mutex<BTreeMap<u64, SignedRosterSnapshot>>>,
+    /// What each peer gossiped, per (epoch, log_len) and then from_party_id. Only the
+    /// latest `MAX_GOSSIP_ROUNDS` (epoch, log_len) pairs are kept, each with at most
+    /// `MAX_GOSSIP_PEERS_PER_ROUND` peers.
+    pub by_peer: Arc<Mutex<PeerSnapshots>>,
+    /// Every verified gossip received, oldest first, capped at `MAX_GOSSIP_HISTORY`.
+    pub history: Arc<Mutex<VecDeque<GossipObservation>>>,
+    /// Where detected conflicts are appended as evidence, if anywhere.
+    pub conflict_log: Option<Arc<ConflictLog>>,
+}
+
+/// One detected conflict as written to the conflict log: the report and the watchtower
+/// signed snapshots that contradict each other, which together prove the equivocation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GossipConflict {
+    /// When the conflict was detected (unix seconds).
+    pub detected_at_unix: u64,
+    pub report: String,
+    pub snapshots: Vec<SignedRosterSnapshot>,
+}
+
+/// Append-only JSONL file of `GossipConflict`s. Lines are written and synced to disk by a
+/// writer task, off the async workers and outside any gossip lock, and `detected` fires once
+/// a line is on disk, so a halt on conflict never loses its evidence. A conflict between the
+/// same snapshots is written once per process, however often it is seen.
+pub struct ConflictLog {
+    path: String,
+    written: Mutex<BTreeSet<ConflictKey>>,
+    tx: mpsc::UnboundedSender<GossipConflict>,
+    detected: Arc<Notify>,
+}
+
+/// (epoch, log_len, root) of each of a conflict's snapshots, sorted.
+type ConflictKey = Vec<(u64, u64, [u8; 32])>;
+
+impl ConflictLog {
+    /// Log to `path`, with the writer task spawned on the current runtime.
+    pub fn spawn(path: String) -> Arc<Self> {
+        let (tx, mut rx) = mpsc::unbounded_channel::<GossipConflict>();
+        let detected = Arc::new(Notify::new());
+        let (task_path, task_detected) = (path.clone(), detected.clone());
+        tokio::spawn(async move {
+            while let Some(conflict) = rx.recv().await {
+                let path = task_path.clone();
+                let written = tokio::task::spawn_blocking(move || write_conflict(&path, &conflict))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|written| written);
+                // A failed write is logged, not returned: the conflict was still reported
+                // to whoever found it.
+                if let Err(e) = written {
+                    error!("can't write conflict evidence to {task_path}: {e}");
+                }
+                task_detected.notify_one();
+            }
+        });
+        Arc::new(Self { path, written: Mutex::default(), tx, detected })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Queue one conflict for the writer task; `detected` fires once it is written.
+    pub fn append(&self, report: &str, snapshots: Vec<SignedRosterSnapshot>) {
+        let mut key: Vec<_> = snapshots
+            .iter()
+            .map(|srs| (srs.msg.epoch, srs.msg.log_len, srs.msg.merkle_root))
+            .collect();
+        key.sort();
+        if !self.written.lock().unwrap().insert(key) {
+            return;
+        }
+        let detected_at_unix =
+            SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let conflict = GossipConflict { detected_at_unix, report: report.to_string(), snapshots };
+        // Only fails once the runtime, and with it the writer, is shutting down.
+        let _ = self.tx.send(conflict);
+    }
+
+    /// Resolves once a conflict has been written (immediately if one was written since the
+    /// last call).
+    pub async fn detected(&self) {
+        self.detected.notified().await
+    }
+}
+
+/// Append `conflict` to the JSONL file at `path`, as canonical JSON so reports of the same
+/// conflict compare equal byte for byte, and sync it.
+fn write_conflict(path: &str, conflict: &GossipConflict) -> Result<()> {
+    let line = canonical_json(conflict)?;
+    let mut f = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(f, "{line}")?;
+    f.sync_all()?;
+    Ok(())
+}
+
+pub type PeerSnapshots = BTreeMap<(u64, u64), BTreeMap<u64, PeerView>>;
+
+/// What aggregation keeps of one peer's gossip: its snapshot, and of its evidence only the
+/// index and the record's identity, not the proof.
+#[derive(Debug, Clone)]
+pub struct PeerView {
+    pub srs: SignedRosterSnapshot,
+    /// The evidence index and "party_id=.. seq=.. leaf=..", records told apart by leaf
+    /// hash, as two registrations can share party_id and seq.
+    pub evidence: Option<(u64, String)>,
+}
+
+impl PeerView {
+    fn of(gossip: &GossipSnapshot) -> Self {
+        let evidence = gossip.evidence.as_ref().and_then(|ev| {
+            let (h, bytes) = (gossip.srs.msg.hasher().ok()?, enc(&ev.prr).ok()?);
+            let record = format!(
+                "party_id={} seq={} leaf={}",
+                ev.prr.msg.party_id,
+                ev.prr.msg.seq,
+                base64::engine::general_purpose::STANDARD.encode(leaf_hash(h, &bytes))
+            );
+            Some((ev.index, record))
+        });
+        Self { srs: gossip.srs.clone(), evidence }
+    }
+}
+
+/// How many epochs' last seen snapshots `GossipState` keeps.
+pub const MAX_GOSSIP_EPOCHS: usize = 8;
+
+/// How many (epoch, log_len) rounds of per-peer snapshots `GossipState` keeps.
+pub const MAX_GOSSIP_ROUNDS: usize = 64;
+
+/// How many peers' views one round keeps. from_party_id is not authenticated, so once a
+/// round is full, gossip under a new id is still compared against it but not stored.
+pub const MAX_GOSSIP_PEERS_PER_ROUND: usize = 256;
+
+/// Largest /gossip request body accepted (bytes); bigger ones get 413. A gossip with
+/// evidence is well under this even for very long logs.
+pub const MAX_GOSSIP_BODY_BYTES: usize = 256 * 1024;
+
+/// How many received gossips `GossipState` keeps in its history.
+pub const MAX_GOSSIP_HISTORY: usize = 1024;
+
+impl GossipState {
+    pub fn new(pk_w: VerifyingKey, epoch: u64, last: Option<SignedRosterSnapshot>) -> Self {
+        let last_by_epoch = last.into_iter().map(|srs| (srs.msg.epoch, srs)).collect();
+        Self {
+            pk_w,
+            epoch,
+            last_by_epoch: Arc::new(Mutex::new(last_by_epoch)),
+            by_peer: Arc::default(),
+            history: Arc::default(),
+            conflict_log: None,
+        }
+    }
+
+    /// Also append every conflict `aggregate` or `observe` finds to `log`.
+    pub fn with_conflict_log(mut self, log: Arc<ConflictLog>) -> Self {
+        self.conflict_log = Some(log);
+        self
+    }
+
+    /// Check that a peer's snapshot is for this party's epoch and signed by the watchtower.
+    pub fn verify_peer_snapshot(&self, srs: &SignedRosterSnapshot) -> Result<()> {
+        srs.msg.check_version()?;
+        let epoch = srs.msg.epoch;
+        if epoch != self.epoch {
+            return Err(anyhow!(
+                "gossip is for epoch={epoch}, this party is in epoch={}",
+                self.epoch
+            ));
+        }
+        srs.msg
+            .check_watchtower_pk(self.pk_w.as_bytes())
+            .and_then(|()| verify_struct(&self.pk_w, CTX_SNAPSHOT, &srs.msg, &srs.sig_watchtower))
+            .map_err(|e| anyhow!("invalid watchtower signature: {e}"))
+    }
+
+    /// Append a received gossip to the history, dropping the oldest entry when full.
+    pub fn record(&self, from_party_id: u64, srs: &SignedRosterSnapshot) {
+        let received_at_unix =
+            SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let mut history = self.history.lock().unwrap();
+        if history.len() >= MAX_GOSSIP_HISTORY {
+            history.pop_front();
+        }
+        history.push_back(GossipObservation {
+            received_at_unix,
+            from_party_id,
+            epoch: srs.msg.epoch,
+            log_len: srs.msg.log_len,
+            merkle_root_b64: base64::engine::general_purpose::STANDARD.encode(srs.msg.merkle_root),
+        });
+    }
+
+    /// Record a peer's (already verified) gossip alongside the other peers' for the same
+    /// (epoch, log_len). If their roots disagree, return a report grouping the peers by root
+    /// and naming any index where their evidence shows different records.
+    pub fn aggregate(&self, gossip: &GossipSnapshot) -> Option<String> {
+        let key = (gossip.srs.msg.epoch, gossip.srs.msg.log_len);
+        let (pid, view) = (gossip.from_party_id, PeerView::of(gossip));
+        let mut by_peer = self.by_peer.lock().unwrap();
+        let stored = by_peer.entry(key).or_default();
+        let unstored = if stored.len() < MAX_GOSSIP_PEERS_PER_ROUND || stored.contains_key(&pid) {
+            stored.insert(pid, view);
+            None
+        } else {
+            Some(view)
+        };
+        while by_peer.len() > MAX_GOSSIP_ROUNDS {
+            by_peer.pop_first();
+        }
+        let mut round: BTreeMap<u64, &PeerView> =
+            by_peer.get(&key).into_iter().flatten().map(|(pid, v)| (*pid, v)).collect();
+        if let Some(view) = &unstored {
+            round.insert(pid, view);
+        }
+
+        let mut by_root: BTreeMap<[u8; 32], Vec<u64>> = BTreeMap::new();
+        for (pid, v) in &round {
+            by_root.entry(v.srs.msg.merkle_root).or_default().push(*pid);
+        }
+        if by_root.len() < 2 {
+            return None;
+        }
+        let groups: Vec<String> = by_root
+            .iter()
+            .map(|(root, pids)| {
+                let root_b64 = base64::engine::general_purpose::STANDARD.encode(root);
+                let id = round[&pids[0]].srs.msg.snapshot_id_hex();
+                format!("root {root_b64} (snapshot_id {id}) from party_ids {pids:?}")
+            })
+            .collect();
+        let mut report = format!(
+            "QUORUM CONFLICT: epoch={}, log_len={}: {}. Keep all signed snapshots as evidence.",
+            key.0,
+            key.1,
+            groups.join("; ")
+        );
+
+        // Evidence is proven against each sender's root, so two different records at one
+        // index pin down where the signed logs diverge.
+        let mut records: BTreeMap<(u64, String), Vec<u64>> = BTreeMap::new();
+        for (pid, v) in &round {
+            let Some((index, record)) = &v.evidence else { continue };
+            records.entry((*index, record.clone())).or_default().push(*pid);
+        }
+        let mut by_index: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+        for ((index, record), pids) in records {
+            by_index.entry(index).or_default().push(format!("party_ids {pids:?} hold {record}"));
+        }
+        for (index, views) in by_index.iter().filter(|(_, v)| v.len() > 1) {
+            report.push_str(&format!(" Views diverge at index {index}: {}.", views.join("; ")));
+        }
+        if let Some(log) = &self.conflict_log {
+            // One snapshot per root is enough to prove the watchtower signed them all.
+            let snapshots = by_root.values().map(|pids| round[&pids[0]].srs.clone()).collect();
+            log.append(&report, snapshots);
+        }
+        Some(report)
+    }
+
+    /// Record a verified snapshot as the last seen one for its epoch. If it conflicts with
+    /// that epoch's last seen snapshot (same log_len, different root), keep the old one and
+    /// return a report. Snapshots of different epochs are never compared.
+    pub fn observe(&self, srs: &SignedRosterSnapshot) -> Option<String> {
+        let mut last_by_epoch = self.last_by_epoch.lock().unwrap();
+        if let Some(prev) = last_by_epoch.get(&srs.msg.epoch) {
+            // Equivocation detection: same epoch & log_len but different root
+            if prev.msg.log_len == srs.msg.log_len && prev.msg.merkle_root != srs.msg.merkle_root {
+                let report = format!(
+                    "EQUIVOCATION DETECTED: epoch={}, log_len={}, prev_root!=new_root \
+                     (snapshot_ids {} and {}). Keep both signed snapshots as evidence.",
+                    prev.msg.epoch,
+                    prev.msg.log_len,
+                    prev.msg.snapshot_id_hex(),
+                    srs.msg.snapshot_id_hex()
+                );
+                if let Some(log) = &self.conflict_log {
+                    log.append(&report, vec![prev.clone(), srs.clone()]);
+                }
+                return Some(report);
+            }
+        }
+
+        // Update last seen
+        last_by_epoch.insert(srs.msg.epoch, srs.clone());
+        while last_by_epoch.len() > MAX_GOSSIP_EPOCHS {
+            last_by_epoch.pop_first();
+        }
+        None
+    }
+
+    /// One watchdog round with the gossip endpoint at `peer`: pull the snapshot it last saw
+    /// and check it against ours, then push `ours` (if any) for it to check. Returns the
+    /// conflicts either side found; a peer that can't be reached is only logged.
+    pub async fn exchange(
+        &self,
+        peer: &str,
+        from_party_id: u64,
+        ours: Option<SignedRosterSnapshot>,
+    ) -> Vec<String> {
+        let mut reports = Vec::new();
+        match fetch_gossip_snapshot(peer).await {
+            Ok(Some(srs)) => match self.verify_peer_snapshot(&srs) {
+                Ok(()) => {
+                    if let Some(report) = self.observe(&srs) {
+                        reports.push(format!("snapshot from {peer} conflicts: {report}"));
+                    }
+                }
+                Err(e) => warn!("snapshot from {peer} rejected: {e}"),
+            },
+            Ok(None) => {}
+            Err(e) => warn!("can't pull from gossip peer {peer}: {e}"),
+        }
+        let Some(srs) = ours else { return reports };
+        match send_gossip(peer, from_party_id, srs, None).await {
+            Ok(Some(report)) => {
+                reports.push(format!("gossip peer {peer} reports a conflict: {report}"))
+            }
+            Ok(None) => {}
+            Err(e) => warn!("can't push to gossip peer {peer}: {e}"),
+        }
+        reports
+    }
 }
 
 pub fn router(state: GossipState) -> Router {
-    Router::new().route("/gossip", post(gossip)).with_state(state)
+    Router::new()
+        .route("/gossip", post(gossip))
+        .route("/gossip/history", get(history))
+        .route("/gossip/snapshot", get(snapshot))
+        .layer(DefaultBodyLimit::max(MAX_GOSSIP_BODY_BYTES))
+        .with_state(state)
+}
+
+async fn history(State(st): State<GossipState>) -> Json<Vec<GossipObservation>> {
+    Json(st.history.lock().unwrap().iter().cloned().collect())
+}
+
+/// The last seen snapshot for this party's epoch, for peers pulling instead of waiting to
+/// be pushed to.
+async fn snapshot(State(st): State<GossipState>) -> impl IntoResponse {
+    match st.last_by_epoch.lock().unwrap().get(&st.epoch) {
+        Some(srs) => Json(srs.clone()).into_response(),
+        None => (StatusCode::NOT_FOUND, "no snapshot seen yet").into_response(),
+    }
 }
 
 async fn gossip(State(st): State<GossipState>, Json(req): Json<GossipSnapshot>) -> impl IntoResponse {
-    // Verify watchtower signature on received snapshot
-    if let Err(e) = verify_struct(&st.pk_w, &req.srs.msg, &req.srs.sig_watchtower) {
-        return (StatusCode::BAD_REQUEST, format!("invalid watchtower signature: {e}")).into_response();
-    }
-
-    let mut guard = st.last.lock().unwrap();
-    if let Some(prev) = guard.as_ref() {
-        // Equivocation detection: same epoch & log_len but different root
-        if prev.msg.epoch == req.srs.msg.epoch
-            && prev.msg.log_len == req.srs.msg.log_len
-            && prev.msg.merkle_root != req.srs.msg.merkle_root
-        {
-            let msg = format!(
-                "EQUIVOCATION DETECTED: epoch={}, log_len={}, prev_root!=new_root. \
-                 Keep both signed snapshots as evidence.",
-                prev.msg.epoch, prev.msg.log_len
-            );
-            return (StatusCode::CONFLICT, msg).into_response();
+    if let Err(e) = st.verify_peer_snapshot(&req.srs) {
+        return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+    }
+
+    if let Some(ev) = &req.evidence {
+        if let Err(e) = verify_gossip_evidence(&req.srs, ev) {
+            return (StatusCode::BAD_REQUEST, format!("invalid gossip evidence: {e}"))
+                .into_response();
         }
     }
 
-    // Update last seen
-    *guard = Some(req.srs);
+    // Record the peer's view before comparing with ours, so it is kept as evidence either way.
+    st.record(req.from_party_id, &req.srs);
+    let reports: Vec<String> = [st.aggregate(&req), st.observe(&req.srs)]
+        .into_iter()
+        .flatten()
+        .collect();
+    if !reports.is_empty() {
+        for report in &reports {
+            warn!("gossip from party_id={}: {report}", req.from_party_id);
+        }
+        return (StatusCode::CONFLICT, reports.join("\n")).into_response();
+    }
 
     (StatusCode::OK, "ok").into_response()
 }
 
-/// Client helper: send your SRS to a peer's gossip endpoint.
-pub async fn send_gossip(peer_base: &str, from_party_id: u64, srs: common::types::SignedRosterSnapshot) -> Result<()> {
+/// Serve /gossip on `bind_addr`.
+pub async fn serve_gossip(bind_addr: &str, state: GossipState) -> Result<()> {
+    let addr: SocketAddr = bind_addr.parse()?;
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("gossip server listening on {}", addr);
+    axum::serve(listener, router(state)).await?;
+    Ok(())
+}
+
+/// Check that `ev` is a validly signed record of `srs`'s epoch, proven to be at
+/// `ev.index` of the log `srs` commits to.
+pub fn verify_gossip_evidence(srs: &SignedRosterSnapshot, ev: &GossipEvidence) -> Result<()> {
+    if ev.index == 0 || ev.proof.leaf_index != ev.index - 1 {
+        return Err(anyhow!(
+            "evidence index={} does not match proof leaf_index={}",
+            ev.index,
+            ev.proof.leaf_index
+        ));
+    }
+    if ev.proof.log_len != srs.msg.log_len {
+        return Err(anyhow!(
+            "evidence proof is for log_len={}, snapshot has log_len={}",
+            ev.proof.log_len,
+            srs.msg.log_len
+        ));
+    }
+    if ev.prr.msg.epoch != srs.msg.epoch {
+        return Err(anyhow!(
+            "evidence record is for epoch={}, snapshot is for epoch={}",
+            ev.prr.msg.epoch,
+            srs.msg.epoch
+        ));
+    }
+    verify_prr_signatures(&ev.prr)?;
+    let h = srs.msg.hasher()?;
+    verify_inclusion(h, &srs.msg.merkle_root, &leaf_hash(h, &enc(&ev.prr)?), &ev.proof)
+}
+
+/// Evidence for the record at `index` (1-based) of `entries`, the log `srs` commits to.
+/// Fails if that record was compacted into a tombstone.
+pub fn gossip_evidence(
+    srs: &SignedRosterSnapshot,
+    entries: &[LogEntry],
+    index: u64,
+) -> Result<GossipEvidence> {
+    if index == 0 || index > entries.len() as u64 {
+        return Err(anyhow!("evidence index={index} out of range for log_len={}", entries.len()));
+    }
+    let Some(prr) = entries[(index - 1) as usize].record() else {
+        return Err(anyhow!("the record at evidence index={index} was compacted away"));
+    };
+    let h = srs.msg.hasher()?;
+    let leaves = log_leaves(h, entries)?;
+    if merkle_root(h, leaves.clone()) != srs.msg.merkle_root {
+        return Err(anyhow!("entries do not match the snapshot's merkle_root"));
+    }
+    Ok(GossipEvidence { index, prr: prr.clone(), proof: inclusion_proof(h, &leaves, index - 1)? })
+}
+
+/// Client helper: send your SRS (and optional evidence) to a peer's gossip endpoint.
+/// Returns the peer's report if it found the SRS conflicting with what it has seen.
+pub async fn send_gossip(
+    peer_base: &str,
+    from_party_id: u64,
+    srs: SignedRosterSnapshot,
+    evidence: Option<GossipEvidence>,
+) -> Result<Option<String>> {
     let url = format!("{}/gossip", peer_base.trim_end_matches('/'));
     let http = reqwest::Client::new();
     let resp = http
         .post(url)
-        .json(&common::types::GossipSnapshot { from_party_id, srs })
+        .json(&GossipSnapshot { from_party_id, srs, evidence })
         .send()
         .await?;
 
-    if !resp.status().is_success() && resp.status() != StatusCode::CONFLICT {
+    if resp.status() == StatusCode::CONFLICT {
+        return Ok(Some(resp.text().await?));
+    }
+    if !resp.status().is_success() {
         return Err(anyhow!("gossip send failed: {} {}", resp.status(), resp.text().await?));
     }
-    Ok(())
+    Ok(None)
+}
+
+/// Client helper: fetch the snapshot a peer's gossip endpoint last saw for its epoch, or
+/// `None` if it hasn't seen one yet. The caller must verify it.
+pub async fn fetch_gossip_snapshot(peer_base: &str) -> Result<Option<SignedRosterSnapshot>> {
+    let url = format!("{}/gossip/snapshot", peer_base.trim_end_matches('/'));
+    let resp = reqwest::Client::new().get(url).send().await?;
+    if resp.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !resp.status().is_success() {
+        return Err(anyhow!("gossip fetch failed: {} {}", resp.status(), resp.text().await?));
+    }
+    Ok(Some(resp.json().await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{entry, party_key, prr, sign_snapshot, snapshot_of, watchtower_key, EPOCH};
+    use std::time::Duration;
+
+    /// Serve `gs` on an ephemeral local port; returns its base URL.
+    async fn serve(gs: GossipState) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router(gs)).await });
+        format!("http://{addr}")
+    }
+
+    /// `srs` as gossiped by `from_party_id`, without evidence.
+    fn gossip(from_party_id: u64, srs: &SignedRosterSnapshot) -> GossipSnapshot {
+        GossipSnapshot { from_party_id, srs: srs.clone(), evidence: None }
+    }
+
+    #[test]
+    fn observe_flags_a_second_root_for_the_same_log_len() {
+        let sk_w = watchtower_key();
+        let gs = GossipState::new(sk_w.verifying_key(), EPOCH, None);
+        let log: Vec<_> = (1..=2).map(|n| entry(&party_key(n), n.into(), 1)).collect();
+        assert_eq!(gs.observe(&snapshot_of(&sk_w, &log[..1])), None);
+        let longer = snapshot_of(&sk_w, &log);
+        assert_eq!(gs.observe(&longer), None);
+
+        let mut forked = longer.msg.clone();
+        forked.merkle_root = [9; 32];
+        let report = gs.observe(&sign_snapshot(&sk_w, forked.clone())).unwrap();
+        assert!(report.contains("EQUIVOCATION DETECTED"), "{report}");
+        for msg in [&longer.msg, &forked] {
+            assert!(report.contains(&msg.snapshot_id_hex()), "{report}");
+        }
+        // The first snapshot seen at a log_len stays the one compared against.
+        assert_eq!(gs.last_by_epoch.lock().unwrap()[&EPOCH].msg, longer.msg);
+    }
+
+    #[tokio::test]
+    async fn other_epochs_neither_mask_a_conflict_nor_get_past_the_handler() {
+        let sk_w = watchtower_key();
+        let gs = GossipState::new(sk_w.verifying_key(), EPOCH, None);
+        let in_epoch = |epoch: u64, root: u8| {
+            let mut msg = snapshot_of(&sk_w, &[entry(&party_key(1), 1, 1)]).msg;
+            (msg.epoch, msg.merkle_root) = (epoch, [root; 32]);
+            sign_snapshot(&sk_w, msg)
+        };
+
+        // Two epochs interleaved, each at log_len=1 with its own root: no conflict.
+        assert_eq!(gs.observe(&in_epoch(EPOCH, 1)), None);
+        assert_eq!(gs.observe(&in_epoch(EPOCH + 1, 2)), None);
+        assert_eq!(gs.observe(&in_epoch(EPOCH - 1, 3)), None);
+        assert_eq!(gs.observe(&in_epoch(EPOCH + 1, 2)), None);
+        // The planted same-epoch fork is still caught against the first root.
+        let report = gs.observe(&in_epoch(EPOCH, 4)).unwrap();
+        assert!(report.contains(&format!("epoch={EPOCH}, log_len=1")), "{report}");
+        assert!(gs.observe(&in_epoch(EPOCH + 1, 5)).is_some());
+        let roots: Vec<_> =
+            gs.last_by_epoch.lock().unwrap().values().map(|s| s.msg.merkle_root[0]).collect();
+        assert_eq!(roots, [3, 1, 2]);
+
+        // Over HTTP, gossip for another epoch is refused before it is looked at.
+        let url = serve(gs.clone()).await;
+        let err = send_gossip(&url, 2, in_epoch(EPOCH + 1, 6), None).await.unwrap_err();
+        let err = err.to_string();
+        assert!(err.contains("400") && err.contains(&format!("epoch={}", EPOCH + 1)), "{err}");
+        assert!(gs.history.lock().unwrap().is_empty());
+        let report = send_gossip(&url, 2, in_epoch(EPOCH, 4), None).await.unwrap().unwrap();
+        assert!(report.contains("EQUIVOCATION DETECTED"), "{report}");
+    }
+
+    #[tokio::test]
+    async fn history_lists_received_gossip_in_arrival_order() {
+        let sk_w = watchtower_key();
+        let gs = GossipState::new(sk_w.verifying_key(), EPOCH, None);
+        let url = serve(gs.clone()).await;
+        let log: Vec<_> = (1..=3).map(|n| entry(&party_key(n), n.into(), 1)).collect();
+        let grown: Vec<_> = (1..=3).map(|k| snapshot_of(&sk_w, &log[..k])).collect();
+        let mut forked = grown[1].msg.clone();
+        forked.merkle_root = [9; 32];
+        let forked = sign_snapshot(&sk_w, forked);
+        // (from_party_id, snapshot): a fork and a stale snapshot among growing ones.
+        let sent = [(2, &grown[0]), (3, &grown[1]), (2, &forked), (4, &grown[2]), (3, &grown[0])];
+        for (from, srs) in sent {
+            send_gossip(&url, from, srs.clone(), None).await.unwrap();
+        }
+        // Refused gossip is not recorded.
+        let mut forged = grown[2].clone();
+        forged.sig_watchtower[0] ^= 1;
+        assert!(send_gossip(&url, 5, forged, None).await.is_err());
+
+        let history: Vec<GossipObservation> =
+            reqwest::get(format!("{url}/gossip/history")).await.unwrap().json().await.unwrap();
+        let b64 = |srs: &SignedRosterSnapshot| {
+            base64::engine::general_purpose::STANDARD.encode(srs.msg.merkle_root)
+        };
+        let got: Vec<_> = history
+            .iter()
+            .map(|o| (o.from_party_id, o.epoch, o.log_len, o.merkle_root_b64.clone()))
+            .collect();
+        let want: Vec<_> =
+            sent.iter().map(|(from, srs)| (*from, EPOCH, srs.msg.log_len, b64(srs))).collect();
+        assert_eq!(got, want);
+        assert!(history.windows(2).all(|w| w[0].received_at_unix <= w[1].received_at_unix));
+
+        // Full, the oldest observations go first.
+        for i in 0..MAX_GOSSIP_HISTORY as u64 {
+            gs.record(100 + i, &grown[2]);
+        }
+        let history = gs.history.lock().unwrap();
+        assert_eq!(history.len(), MAX_GOSSIP_HISTORY);
+        assert_eq!(history.front().unwrap().from_party_id, 100);
+        assert_eq!(history.back().unwrap().from_party_id, 99 + MAX_GOSSIP_HISTORY as u64);
+    }
+
+    #[test]
+    fn aggregate_groups_disagreeing_peers_by_root() {
+        let sk_w = watchtower_key();
+        let gs = GossipState::new(sk_w.verifying_key(), EPOCH, None);
+        let log: Vec<_> = (1..=2).map(|n| entry(&party_key(n), n.into(), 1)).collect();
+        let srs = snapshot_of(&sk_w, &log);
+        assert_eq!(gs.aggregate(&gossip(1, &srs)), None);
+        assert_eq!(gs.aggregate(&gossip(2, &srs)), None);
+
+        let mut forked = srs.msg.clone();
+        forked.merkle_root = [9; 32];
+        let report = gs.aggregate(&gossip(3, &sign_snapshot(&sk_w, forked))).unwrap();
+        assert!(report.starts_with(&format!("QUORUM CONFLICT: epoch={EPOCH}, log_len=2")));
+        assert!(report.contains("from party_ids [1, 2]"), "{report}");
+        assert!(report.contains("from party_ids [3]"), "{report}");
+
+        // Old rounds are dropped once newer ones fill the window.
+        for log_len in 3..3 + MAX_GOSSIP_ROUNDS as u64 {
+            let mut msg = srs.msg.clone();
+            msg.log_len = log_len;
+            gs.aggregate(&gossip(1, &sign_snapshot(&sk_w, msg)));
+        }
+        let by_peer = gs.by_peer.lock().unwrap();
+        assert_eq!(by_peer.len(), MAX_GOSSIP_ROUNDS);
+        assert!(!by_peer.contains_key(&(EPOCH, 2)));
+    }
+
+    #[test]
+    fn a_flood_of_made_up_peers_fills_one_round_but_no_further() {
+        let sk_w = watchtower_key();
+        let gs = GossipState::new(sk_w.verifying_key(), EPOCH, None);
+        let srs = snapshot_of(&sk_w, &[entry(&party_key(1), 1, 1)]);
+        for pid in 0..4 * MAX_GOSSIP_PEERS_PER_ROUND as u64 {
+            assert_eq!(gs.aggregate(&gossip(pid, &srs)), None);
+        }
+        let peers = |gs: &GossipState| gs.by_peer.lock().unwrap()[&(EPOCH, 1)].len();
+        assert_eq!(peers(&gs), MAX_GOSSIP_PEERS_PER_ROUND);
+
+        // A full round still compares newcomers against what it holds.
+        let mut forked = srs.msg.clone();
+        forked.merkle_root = [9; 32];
+        let report = gs.aggregate(&gossip(u64::MAX, &sign_snapshot(&sk_w, forked))).unwrap();
+        assert!(report.contains(&format!("from party_ids [{}]", u64::MAX)), "{report}");
+        assert_eq!(peers(&gs), MAX_GOSSIP_PEERS_PER_ROUND);
+    }
+
+    fn conflicts(path: &std::path::Path) -> Vec<GossipConflict> {
+        let data = std::fs::read_to_string(path).unwrap_or_default();
+        data.lines().map(|l| serde_json::from_str(l).unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn watchdog_flags_a_watchtower_serving_two_roots() {
+        // One watchtower key, two different logs of the same length: each party is shown
+        // its own snapshot, as a split-view watchtower would.
+        let sk_w = watchtower_key();
+        let srs_a = snapshot_of(&sk_w, &[entry(&party_key(1), 1, 1)]);
+        let srs_b = snapshot_of(&sk_w, &[entry(&party_key(2), 2, 1)]);
+        assert_ne!(srs_a.msg.merkle_root, srs_b.msg.merkle_root);
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut parties = Vec::new();
+        for (name, srs) in [("a", &srs_a), ("b", &srs_b)] {
+            let path = dir.path().join(format!("{name}.jsonl"));
+            let log = ConflictLog::spawn(path.to_str().unwrap().into());
+            let gs = GossipState::new(sk_w.verifying_key(), EPOCH, None)
+                .with_conflict_log(log.clone());
+            assert!(gs.observe(srs).is_none());
+            let url = serve(gs.clone()).await;
+            parties.push((gs, log, path, url));
+        }
+        let (a, b) = (&parties[0], &parties[1]);
+
+        // a pulls b's view (conflict on a's side), then pushes its own (409 from b).
+        let reports = a.0.exchange(&b.3, 1, Some(srs_a.clone())).await;
+        assert_eq!(reports.len(), 2, "{reports:?}");
+        assert!(reports[0].contains("EQUIVOCATION DETECTED"), "{}", reports[0]);
+        assert!(reports[1].contains("reports a conflict"), "{}", reports[1]);
+
+        for (_, log, path, _) in &parties {
+            tokio::time::timeout(Duration::from_secs(5), log.detected()).await.unwrap();
+            let written = conflicts(path);
+            assert_eq!(written.len(), 1);
+            let mut roots: Vec<_> =
+                written[0].snapshots.iter().map(|srs| srs.msg.merkle_root).collect();
+            roots.sort();
+            let mut expected = vec![srs_a.msg.merkle_root, srs_b.msg.merkle_root];
+            expected.sort();
+            assert_eq!(roots, expected);
+        }
+
+        // The same conflict seen again is not written twice.
+        a.0.exchange(&b.3, 1, Some(srs_a.clone())).await;
+        let again = tokio::time::timeout(Duration::from_millis(200), a.1.detected()).await;
+        assert!(again.is_err());
+        assert_eq!(conflicts(&a.2).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn agreeing_peers_are_not_flagged() {
+        let sk_w = watchtower_key();
+        let srs = snapshot_of(&sk_w, &[entry(&party_key(1), 1, 1)]);
+        let peer = GossipState::new(sk_w.verifying_key(), EPOCH, Some(srs.clone()));
+        let url = serve(peer).await;
+        let gs = GossipState::new(sk_w.verifying_key(), EPOCH, Some(srs.clone()));
+        assert!(gs.exchange(&url, 1, Some(srs)).await.is_empty());
+    }
+
+    #[test]
+    fn evidence_proves_its_record_against_the_snapshot_only() {
+        let sk_w = watchtower_key();
+        let log: Vec<_> = (1..=3).map(|n| entry(&party_key(n), n.into(), 1)).collect();
+        let srs = snapshot_of(&sk_w, &log);
+        for index in 1..=3 {
+            let ev = gossip_evidence(&srs, &log, index).unwrap();
+            assert_eq!(ev.prr.msg.party_id, index);
+            verify_gossip_evidence(&srs, &ev).unwrap();
+        }
+        assert!(gossip_evidence(&srs, &log, 0).is_err());
+        assert!(gossip_evidence(&srs, &log, 4).is_err());
+        let other_log = [&log[..2], &[entry(&party_key(9), 9, 1)]].concat();
+        assert!(gossip_evidence(&srs, &other_log, 1).is_err());
+
+        let ev = gossip_evidence(&srs, &log, 2).unwrap();
+        let err = |ev: &GossipEvidence| verify_gossip_evidence(&srs, ev).unwrap_err().to_string();
+        let moved = GossipEvidence { index: 3, ..ev.clone() };
+        assert!(err(&moved).contains("does not match proof leaf_index"), "{}", err(&moved));
+        let mut short = ev.clone();
+        short.proof.log_len = 2;
+        assert!(err(&short).contains("log_len=2"), "{}", err(&short));
+        let mut other_epoch = ev.clone();
+        other_epoch.prr.msg.epoch = EPOCH + 1;
+        assert!(err(&other_epoch).contains("epoch="), "{}", err(&other_epoch));
+        // A validly signed record the snapshot's log doesn't hold at that index.
+        let swapped = GossipEvidence { prr: prr(&party_key(9), 9, 1), ..ev };
+        assert!(verify_gossip_evidence(&srs, &swapped).is_err());
+    }
+
+    #[tokio::test]
+    async fn conflicting_evidence_names_the_diverging_index() {
+        let sk_w = watchtower_key();
+        let shared = entry(&party_key(1), 1, 1);
+        let log_a = vec![shared.clone(), entry(&party_key(2), 2, 1)];
+        let log_b = vec![shared, entry(&party_key(3), 3, 1)];
+        let (srs_a, srs_b) = (snapshot_of(&sk_w, &log_a), snapshot_of(&sk_w, &log_b));
+
+        let gs = GossipState::new(sk_w.verifying_key(), EPOCH, None);
+        let gossip = |from, srs: &SignedRosterSnapshot, log: &[LogEntry], index| {
+            let evidence = Some(gossip_evidence(srs, log, index).unwrap());
+            GossipSnapshot { from_party_id: from, srs: srs.clone(), evidence }
+        };
+        assert!(gs.aggregate(&gossip(1, &srs_a, &log_a, 1)).is_none());
+        let report = gs.aggregate(&gossip(2, &srs_b, &log_b, 1)).unwrap();
+        assert!(report.contains("QUORUM CONFLICT"), "{report}");
+        assert!(!report.contains("diverge"), "{report}");
+
+        gs.aggregate(&gossip(1, &srs_a, &log_a, 2));
+        let report = gs.aggregate(&gossip(2, &srs_b, &log_b, 2)).unwrap();
+        assert!(report.contains("Views diverge at index 2"), "{report}");
+        assert!(report.contains("party_ids [1] hold party_id=2 seq=1"), "{report}");
+        assert!(report.contains("party_ids [2] hold party_id=3 seq=1"), "{report}");
+
+        // Evidence that doesn't check out against the sender's snapshot is refused.
+        let url = serve(gs).await;
+        let mut bad = gossip_evidence(&srs_a, &log_a, 2).unwrap();
+        bad.proof.siblings[0][0] ^= 1;
+        let err = send_gossip(&url, 3, srs_a, Some(bad)).await.unwrap_err();
+        assert!(err.to_string().contains("invalid gossip evidence"), "{err}");
+    }
 }
@@ -1,15 +1,7 @@
 use anyhow::{anyhow, Result};
+use common::keyfile::KeyFile;
 use ed25519_dalek::{SigningKey, VerifyingKey};
 use rand::rngs::OsRng;
-use serde::{Deserialize, Serialize};
-use std::fs;
-use base64::Engine as _;
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct PartyKeysFile {
-    /// 32-byte seed (ed25519 signing key)
-    pub sk_seed_b64: String,
-}
 
 pub struct PartyKeys {
     pub sk: SigningKey,
@@ -17,28 +9,47 @@ pub struct PartyKeys {
 }
 
 impl PartyKeys {
-    pub fn load_or_create(path: &str) -> Result<Self> {
-        let (sk, pk) = if let Ok(data) = fs::read_to_string(path) {
-            let kf: PartyKeysFile = serde_json::from_str(&data)?;
-            let seed = base64::Engine::decode(&base64::engine::general_purpose::STANDARD, kf.sk_seed_b64)?;
-            if seed.len() != 32 {
-                return Err(anyhow!("party key seed must be 32 bytes"));
+    /// Load the key file at `path`, or generate and write a new one.
+    /// With a passphrase, new files are encrypted; plaintext files are still readable.
+    pub fn load_or_create(path: &str, passphrase: Option<&str>) -> Result<Self> {
+        let sk = match KeyFile::load(path)? {
+            Some(kf) => SigningKey::from_bytes(&kf.seed(passphrase)?),
+            None => {
+                let sk = SigningKey::generate(&mut OsRng);
+                KeyFile::new(&sk.to_bytes(), passphrase)?.save(path)?;
+                sk
             }
-            let mut seed32 = [0u8; 32];
-            seed32.copy_from_slice(&seed);
-            let sk = SigningKey::from_bytes(&seed32);
-            let pk = sk.verifying_key();
-            (sk, pk)
-        } else {
-            let sk = SigningKey::generate(&mut OsRng);
-            let pk = sk.verifying_key();
-            let seed32 = sk.to_bytes();
-            let kf = PartyKeysFile {
-                sk_seed_b64: base64::engine::general_purpose::STANDARD.encode(seed32),
-            };
-            fs::write(path, serde_json::to_string_pretty(&kf)?)?;
-            (sk, pk)
         };
+        let pk = sk.verifying_key();
+        Ok(Self { sk, pk })
+    }
+
+    /// Load the key file at `path`, which must exist.
+    pub fn load(path: &str, passphrase: Option<&str>) -> Result<Self> {
+        let kf = KeyFile::load(path)?.ok_or_else(|| anyhow!("no key file at {path}"))?;
+        Ok(Self::from_seed(&kf.seed(passphrase)?))
+    }
+
+    /// Keys from a raw seed, bypassing the key file.
+    pub fn from_seed(seed: &[u8; 32]) -> Self {
+        let sk = SigningKey::from_bytes(seed);
+        let pk = sk.verifying_key();
+        Self { sk, pk }
+    }
+
+    /// Generate a fresh keypair and write it to `path`, which must not exist yet.
+    pub fn create_new(path: &str, passphrase: Option<&str>) -> Result<Self> {
+        if std::path::Path::new(path).exists() {
+            return Err(anyhow!("refusing to overwrite existing key file {path}"));
+        }
+        Self::create(path, passphrase)
+    }
+
+    /// Generate a fresh keypair and write it to `path`, replacing any existing file.
+    pub fn create(path: &str, passphrase: Option<&str>) -> Result<Self> {
+        let sk = SigningKey::generate(&mut OsRng);
+        KeyFile::new(&sk.to_bytes(), passphrase)?.save(path)?;
+        let pk = sk.verifying_key();
         Ok(Self { sk, pk })
     }
 }
@@ -0,0 +1,13 @@
+//! Party-side library: talking to the watchtower, verifying what it serves, and keeping
+//! the local view of the roster. The `party` binary is a CLI over these modules.
+
+pub mod client;
+pub mod gossip;
+pub mod keys;
+pub mod p2p;
+pub mod registration;
+pub mod state;
+
+#[cfg(any(test, feature = "testutil"))]
+#[doc(hidden)]
+pub mod testutil;
@@ -1,25 +1,33 @@
-mod client;
-mod gossip;
-mod keys;
-mod p2p;
-mod state;
+mod mesh;
 
 use anyhow::{anyhow, Result};
-use clap::{Parser, Subcommand};
-use common::crypto::sign_struct;
-use common::types::{Endpoint, PartyRegistrationRecord, RegistrationMessage};
+use base64::Engine as _;
+use clap::{Args, Parser, Subcommand};
+use common::crypto::enc;
+use common::keyfile::seed_from_env_or_stdin;
+use common::logging::{self, LogFormat};
+use common::merkle::leaf_hash;
+use common::shutdown;
+use common::types::{
+    canonical_json, Endpoint, LogEntry, PartyResponse, SignedRosterSnapshot, SnapshotResponse,
+};
 use ed25519_dalek::VerifyingKey;
-use rand::rngs::OsRng;
-use rand::RngCore;
-use std::collections::HashSet;
-use std::time::Duration;
-use tracing::{info, warn};
+use futures::{stream, StreamExt};
+use party::{client, gossip, keys, p2p, registration, state};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn, Instrument};
 
 #[derive(Debug, Parser)]
 #[command(name = "party")]
 pub struct Cli {
     #[command(subcommand)]
     pub cmd: Command,
+    /// Log output: text, or json (one object per line, with epoch and party_id fields).
+    #[arg(long, global = true, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
 }
 
 #[derive(Debug, Subcommand)]
@@ -38,12 +46,105 @@ pub enum Command {
         /// Path to store/load party key seed.
         #[arg(long, default_value = "party_key.json")]
         key_file: String,
+        /// Passphrase for encrypting/decrypting the key file. Plaintext key file if unset.
+        #[arg(long, env = "PARTY_KEY_PASSPHRASE", hide_env_values = true)]
+        key_passphrase: Option<String>,
+        /// Read the key seed (base64) from this environment variable instead of --key-file.
+        #[arg(long, conflicts_with = "key_stdin")]
+        key_env: Option<String>,
+        /// Read the key seed (base64) from stdin instead of --key-file.
+        #[arg(long)]
+        key_stdin: bool,
         /// Path to store/load party state.
         #[arg(long, default_value = "party_state.json")]
         state_file: String,
         /// Watchtower pubkey (base64). If omitted, fetched from /watchtower_pubkey (TOFU).
         #[arg(long)]
         watchtower_pubkey_b64: Option<String>,
+        /// Start a fresh state if the state file is for a different epoch/party_id.
+        #[arg(long)]
+        reset: bool,
+        /// Accept a watchtower pubkey different from the one pinned in the state file.
+        #[arg(long)]
+        allow_key_change: bool,
+        /// Refuse to fetch the watchtower pubkey (TOFU) when neither --watchtower-pubkey-b64
+        /// nor a pinned key is available.
+        #[arg(long)]
+        require_pinned_pubkey: bool,
+        #[command(flatten)]
+        rollback: RollbackArgs,
+        /// Bearer token for the watchtower's mutating endpoints, if it requires one.
+        #[arg(long, env = "WATCHTOWER_TOKEN", hide_env_values = true)]
+        watchtower_token: Option<String>,
+        #[command(flatten)]
+        http: WatchtowerHttpArgs,
+        /// Build and sign the record and check its signature, then print it with its leaf
+        /// hash instead of submitting it. Nothing is sent and no file is written; the key
+        /// file must already exist.
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Generate a party key file without registering, and print its base64 public key
+    /// (e.g. to hand out for pinning).
+    Keygen {
+        /// Where to write the key file.
+        #[arg(long)]
+        out: String,
+        /// Overwrite `--out` if it already exists.
+        #[arg(long)]
+        force: bool,
+        /// Passphrase for encrypting the key file. Plaintext key file if unset.
+        #[arg(long, env = "PARTY_KEY_PASSPHRASE", hide_env_values = true)]
+        key_passphrase: Option<String>,
+    },
+
+    /// Rotate this party's key: generate a new key file and register it, endorsed by the old key.
+    RotateKey {
+        #[arg(long)]
+        watchtower: String,
+        #[arg(long)]
+        epoch: u64,
+        #[arg(long)]
+        party_id: u64,
+        /// This party's externally reachable endpoint "ip:port"
+        #[arg(long)]
+        endpoint: String,
+        /// Current key file (endorses the rotation).
+        #[arg(long, default_value = "party_key.json")]
+        key_file: String,
+        /// Where to write the new key file. Must not exist.
+        #[arg(long)]
+        new_key_file: String,
+        /// Passphrase for the key files. New key file is plaintext if unset.
+        #[arg(long, env = "PARTY_KEY_PASSPHRASE", hide_env_values = true)]
+        key_passphrase: Option<String>,
+        /// Read the current key's seed (base64) from this environment variable instead of
+        /// --key-file.
+        #[arg(long, conflicts_with = "key_stdin")]
+        key_env: Option<String>,
+        /// Read the current key's seed (base64) from stdin instead of --key-file.
+        #[arg(long)]
+        key_stdin: bool,
+        #[arg(long, default_value = "party_state.json")]
+        state_file: String,
+        /// Watchtower pubkey (base64). If omitted, fetched from /watchtower_pubkey (TOFU).
+        #[arg(long)]
+        watchtower_pubkey_b64: Option<String>,
+        /// Accept a watchtower pubkey different from the one pinned in the state file.
+        #[arg(long)]
+        allow_key_change: bool,
+        /// Refuse to fetch the watchtower pubkey (TOFU) when neither --watchtower-pubkey-b64
+        /// nor a pinned key is available.
+        #[arg(long)]
+        require_pinned_pubkey: bool,
+        #[command(flatten)]
+        rollback: RollbackArgs,
+        /// Bearer token for the watchtower's mutating endpoints, if it requires one.
+        #[arg(long, env = "WATCHTOWER_TOKEN", hide_env_values = true)]
+        watchtower_token: Option<String>,
+        #[command(flatten)]
+        http: WatchtowerHttpArgs,
     },
 
     /// Fetch latest roster from watchtower, verify signatures and merkle root.
@@ -59,35 +160,175 @@ pub enum Command {
         /// Watchtower pubkey (base64). If omitted, fetched from /watchtower_pubkey (TOFU).
         #[arg(long)]
         watchtower_pubkey_b64: Option<String>,
+        /// Start a fresh state if the state file is for a different epoch/party_id.
+        #[arg(long)]
+        reset: bool,
+        /// Accept a watchtower pubkey different from the one pinned in the state file.
+        #[arg(long)]
+        allow_key_change: bool,
+        /// Refuse to fetch the watchtower pubkey (TOFU) when neither --watchtower-pubkey-b64
+        /// nor a pinned key is available.
+        #[arg(long)]
+        require_pinned_pubkey: bool,
+        #[command(flatten)]
+        rollback: RollbackArgs,
+        #[command(flatten)]
+        http: WatchtowerHttpArgs,
+    },
+
+    /// Set next_seq in the state file past the seq of the watchtower's latest record for this
+    /// party (e.g. after losing the state file or registering from another machine).
+    ResyncSeq {
+        #[arg(long)]
+        watchtower: String,
+        #[arg(long)]
+        epoch: u64,
+        #[arg(long)]
+        party_id: u64,
+        #[arg(long, default_value = "party_state.json")]
+        state_file: String,
+        /// Watchtower pubkey (base64). If omitted, fetched from /watchtower_pubkey (TOFU).
+        #[arg(long)]
+        watchtower_pubkey_b64: Option<String>,
+        /// Start a fresh state if the state file is for a different epoch/party_id.
+        #[arg(long)]
+        reset: bool,
+        /// Accept a watchtower pubkey different from the one pinned in the state file.
+        #[arg(long)]
+        allow_key_change: bool,
+        /// Refuse to fetch the watchtower pubkey (TOFU) when neither --watchtower-pubkey-b64
+        /// nor a pinned key is available.
+        #[arg(long)]
+        require_pinned_pubkey: bool,
+        #[command(flatten)]
+        http: WatchtowerHttpArgs,
     },
 
     /// A single command that:
-    /// 1) starts a P2P listener on --endpoint,
+    /// 1) starts a P2P listener on --p2p-bind or --endpoint (advertising --advertise if
+    ///    set, else --endpoint),
     /// 2) registers/updates itself (seq persisted),
     /// 3) periodically syncs roster + connects to all peers and logs success.
+    ///
+    /// With --roster-file it skips 2) and takes the roster from the file instead of syncing.
     Run {
-        #[arg(long)]
-        watchtower: String,
+        #[arg(long, required_unless_present = "roster_file")]
+        watchtower: Option<String>,
         #[arg(long)]
         epoch: u64,
         #[arg(long)]
         party_id: u64,
-        /// This party's bind + advertised endpoint "ip:port"
+        /// P2P endpoint "ip:port" registered for peers to dial (unless --advertise is set),
+        /// and bound unless --p2p-bind is set. Port 0 means the bound, OS-picked port.
         #[arg(long)]
         endpoint: String,
+        /// Bind the P2P listener here instead of --endpoint, e.g. a local address when
+        /// --endpoint is a public one the host can't bind (NAT, load balancer).
+        #[arg(long)]
+        p2p_bind: Option<String>,
+        /// Endpoint registered for peers to dial, if not the bind endpoint (e.g. behind NAT
+        /// or when binding 0.0.0.0). Port 0 means the bound port.
+        #[arg(long)]
+        advertise: Option<String>,
+        #[command(flatten)]
+        p2p_limits: P2pLimitArgs,
         /// How often to sync and attempt connections
         #[arg(long, default_value_t = 5)]
         interval_secs: u64,
-        /// TCP connect timeout per peer (ms)
+        /// Add a random 0..=this many ms to each interval, so parties started together
+        /// don't all poll the watchtower at the same moment.
+        #[arg(long, default_value_t = 0)]
+        interval_jitter_ms: u64,
+        /// TCP connect timeout per peer, and again for its handshake (ms)
         #[arg(long, default_value_t = 500)]
         connect_timeout_ms: u64,
         #[arg(long, default_value = "party_key.json")]
         key_file: String,
+        /// Passphrase for encrypting/decrypting the key file. Plaintext key file if unset.
+        #[arg(long, env = "PARTY_KEY_PASSPHRASE", hide_env_values = true)]
+        key_passphrase: Option<String>,
+        /// Read the key seed (base64) from this environment variable instead of --key-file.
+        #[arg(long, conflicts_with = "key_stdin")]
+        key_env: Option<String>,
+        /// Read the key seed (base64) from stdin instead of --key-file.
+        #[arg(long)]
+        key_stdin: bool,
         #[arg(long, default_value = "party_state.json")]
         state_file: String,
         /// Watchtower pubkey (base64). If omitted, fetched from /watchtower_pubkey (TOFU).
         #[arg(long)]
         watchtower_pubkey_b64: Option<String>,
+        /// Start a fresh state if the state file is for a different epoch/party_id.
+        #[arg(long)]
+        reset: bool,
+        /// Accept a watchtower pubkey different from the one pinned in the state file.
+        #[arg(long)]
+        allow_key_change: bool,
+        /// Refuse to fetch the watchtower pubkey (TOFU) when neither --watchtower-pubkey-b64
+        /// nor a pinned key is available.
+        #[arg(long)]
+        require_pinned_pubkey: bool,
+        #[command(flatten)]
+        rollback: RollbackArgs,
+        /// Bearer token for the watchtower's mutating endpoints, if it requires one.
+        #[arg(long, env = "WATCHTOWER_TOKEN", hide_env_values = true)]
+        watchtower_token: Option<String>,
+        #[command(flatten)]
+        http: WatchtowerHttpArgs,
+        /// Serve GET /peers (the peers this party has handshaked with, for mesh-status) at
+        /// this address. Not served without it.
+        #[arg(long, value_name = "ADDR")]
+        peers_bind: Option<String>,
+        /// Consecutive failed probes before a connected peer is marked disconnected.
+        #[arg(long, default_value_t = 3)]
+        max_probe_failures: u32,
+        /// Maximum peer connection attempts in flight at once.
+        #[arg(long, default_value_t = 16)]
+        connect_concurrency: usize,
+        /// Also serve the gossip endpoint at this address, fed with each synced snapshot.
+        #[arg(long)]
+        gossip_bind: Option<String>,
+        /// Subscribe to the watchtower's snapshot stream and sync as soon as it announces a
+        /// new snapshot, instead of only every --interval-secs.
+        #[arg(long, conflicts_with = "roster_file")]
+        subscribe: bool,
+        /// After the first full verify, check each new snapshot with a consistency proof and
+        /// this party's own inclusion proof, and download only the records appended since
+        /// (checked against both) instead of the whole log. Falls back to a full verify if
+        /// any check fails, e.g. after a compaction.
+        #[arg(long)]
+        light: bool,
+        /// Run without a watchtower: verify this signed snapshot and log (see
+        /// `fetch-roster --save`) under the pinned watchtower pubkey and use its roster. The
+        /// party must already be in it. To pick up a newer roster, restart with a newer file.
+        #[arg(long, conflicts_with_all = ["watchtower", "light"])]
+        roster_file: Option<String>,
+        /// Keep a JSON connectivity summary (epoch, roster_size, connected_peers,
+        /// all_connected) here, rewritten whenever it changes, plus an empty `<path>.ready`
+        /// that exists only while every roster peer is connected.
+        #[arg(long)]
+        ready_file: Option<String>,
+    },
+
+    /// Serve only the P2P listener at --bind, without registering or syncing; for checking
+    /// that peers can reach this host (firewalls, NAT).
+    P2pServe {
+        /// P2P bind address "ip:port".
+        #[arg(long, visible_alias = "p2p-bind")]
+        bind: String,
+        /// State file whose roster incoming party_ids are checked against (read once at
+        /// startup). Without it or --roster-file every peer is logged as not in the roster.
+        #[arg(long)]
+        state_file: Option<String>,
+        /// Check incoming party_ids against the roster in this signed snapshot and log
+        /// (see `fetch-roster --save`), verified under --watchtower-pubkey-b64.
+        #[arg(long, conflicts_with = "state_file", requires = "watchtower_pubkey_b64")]
+        roster_file: Option<String>,
+        /// Watchtower pubkey (base64) the --roster-file must be signed by.
+        #[arg(long)]
+        watchtower_pubkey_b64: Option<String>,
+        #[command(flatten)]
+        p2p_limits: P2pLimitArgs,
     },
 
     /// Serve a gossip endpoint at --bind (separate from P2P), for equivocation detection.
@@ -106,6 +347,65 @@ pub enum Command {
         /// Watchtower pubkey (base64). If omitted, fetched from /watchtower_pubkey (TOFU).
         #[arg(long)]
         watchtower_pubkey_b64: Option<String>,
+        /// Start a fresh state if the state file is for a different epoch/party_id.
+        #[arg(long)]
+        reset: bool,
+        /// Accept a watchtower pubkey different from the one pinned in the state file.
+        #[arg(long)]
+        allow_key_change: bool,
+        /// Refuse to fetch the watchtower pubkey (TOFU) when neither --watchtower-pubkey-b64
+        /// nor a pinned key is available.
+        #[arg(long)]
+        require_pinned_pubkey: bool,
+        #[command(flatten)]
+        http: WatchtowerHttpArgs,
+    },
+
+    /// Equivocation watchdog: serve a gossip endpoint at --bind and, every interval, fetch
+    /// the watchtower's snapshot, then pull from and push to every --peer gossip endpoint.
+    /// Conflicting signed snapshots are appended to --conflict-log as evidence.
+    GossipRun {
+        /// Bind address for this party's gossip server (e.g. 0.0.0.0:9001).
+        #[arg(long)]
+        bind: String,
+        #[arg(long)]
+        watchtower: String,
+        #[arg(long)]
+        epoch: u64,
+        #[arg(long)]
+        party_id: u64,
+        /// Peer gossip endpoint (e.g. http://ip:port); repeat for each peer.
+        #[arg(long = "peer")]
+        peers: Vec<String>,
+        #[arg(long, default_value_t = 10)]
+        interval_secs: u64,
+        /// Time each peer gets for one pull and push (ms).
+        #[arg(long, default_value_t = 5000)]
+        peer_timeout_ms: u64,
+        /// JSONL file each detected conflict is appended to, with the signed snapshots.
+        #[arg(long, default_value = "gossip_conflicts.jsonl")]
+        conflict_log: String,
+        /// Exit nonzero as soon as a conflict is detected, to halt the MPC setup, instead
+        /// of logging it and carrying on.
+        #[arg(long)]
+        exit_on_conflict: bool,
+        #[arg(long, default_value = "party_state.json")]
+        state_file: String,
+        /// Watchtower pubkey (base64). If omitted, fetched from /watchtower_pubkey (TOFU).
+        #[arg(long)]
+        watchtower_pubkey_b64: Option<String>,
+        /// Start a fresh state if the state file is for a different epoch/party_id.
+        #[arg(long)]
+        reset: bool,
+        /// Accept a watchtower pubkey different from the one pinned in the state file.
+        #[arg(long)]
+        allow_key_change: bool,
+        /// Refuse to fetch the watchtower pubkey (TOFU) when neither --watchtower-pubkey-b64
+        /// nor a pinned key is available.
+        #[arg(long)]
+        require_pinned_pubkey: bool,
+        #[command(flatten)]
+        http: WatchtowerHttpArgs,
     },
 
     /// Send your current snapshot to a peer's gossip endpoint (e.g. http://ip:port).
@@ -116,126 +416,788 @@ pub enum Command {
         party_id: u64,
         #[arg(long, default_value = "party_state.json")]
         state_file: String,
+        /// Attach the record at this log index (1-based) with its inclusion proof, so a
+        /// peer with a conflicting root can see where the views diverge.
+        #[arg(long, requires = "watchtower")]
+        evidence_index: Option<u64>,
+        /// Watchtower to fetch the log from when building evidence. The snapshot is checked
+        /// against the watchtower pubkey pinned in the state file first.
+        #[arg(long)]
+        watchtower: Option<String>,
+        #[command(flatten)]
+        http: WatchtowerHttpArgs,
+    },
+
+    /// Offline audit: verify a captured /snapshot response against a captured /entries response.
+    Verify {
+        /// JSON file containing a /snapshot response.
+        #[arg(long)]
+        snapshot_file: String,
+        /// NDJSON file containing an /entries response covering 1..log_len.
+        #[arg(long)]
+        entries_file: String,
+        /// Watchtower pubkey (base64).
+        #[arg(long)]
+        watchtower_pubkey_b64: String,
+        /// Epoch the snapshot must be for (any epoch if unset).
+        #[arg(long)]
+        epoch: Option<u64>,
+    },
+
+    /// Fetch a party's latest record from the watchtower and verify its proofs against the
+    /// signed snapshot, without downloading the log.
+    GetParty {
+        #[arg(long)]
+        watchtower: String,
+        #[arg(long)]
+        party_id: u64,
+        /// Watchtower pubkey (base64).
+        #[arg(long)]
+        watchtower_pubkey_b64: String,
+        #[command(flatten)]
+        http: WatchtowerHttpArgs,
+    },
+
+    /// Fetch a party's latest record with its proofs, verify them, and write them to a
+    /// self-contained membership bundle that `verify-membership` can check offline.
+    ProveMembership {
+        #[arg(long)]
+        watchtower: String,
+        #[arg(long)]
+        party_id: u64,
+        /// Watchtower pubkey (base64).
+        #[arg(long)]
+        watchtower_pubkey_b64: String,
+        /// Epoch to prove membership in (the watchtower's default if unset).
+        #[arg(long)]
+        epoch: Option<u64>,
+        /// Where to write the bundle (JSON).
+        #[arg(long)]
+        out: String,
+        #[command(flatten)]
+        http: WatchtowerHttpArgs,
+    },
+
+    /// Offline audit: verify a membership bundle's snapshot signature, record signature,
+    /// inclusion proof and latest-record proof against a pinned watchtower pubkey.
+    VerifyMembership {
+        /// JSON file written by `prove-membership`.
+        #[arg(long)]
+        bundle: String,
+        /// Watchtower pubkey (base64).
+        #[arg(long)]
+        watchtower_pubkey_b64: String,
+        /// Epoch the bundle must be for (any epoch if unset).
+        #[arg(long)]
+        epoch: Option<u64>,
+        /// Party the bundle must be for (any party if unset).
+        #[arg(long)]
+        party_id: Option<u64>,
+    },
+
+    /// Fetch the watchtower's checkpoints and verify each is signed and extended by the
+    /// current snapshot.
+    Checkpoints {
+        #[arg(long)]
+        watchtower: String,
+        /// Watchtower pubkey (base64).
+        #[arg(long)]
+        watchtower_pubkey_b64: String,
+        #[command(flatten)]
+        http: WatchtowerHttpArgs,
+    },
+
+    /// Query every roster peer's /peers and print who can reach whom.
+    MeshStatus {
+        #[arg(long, default_value = "party_state.json")]
+        state_file: String,
+        /// Port the peers serve /peers on (their Run --peers-bind), at the host of their
+        /// roster endpoint.
+        #[arg(long)]
+        peers_port: u16,
+        /// Per-peer request timeout (ms)
+        #[arg(long, default_value_t = 1000)]
+        timeout_ms: u64,
+    },
+
+    /// Compare two state files (e.g. two parties' views) and exit nonzero if they differ.
+    DiffState {
+        #[arg(long)]
+        a: String,
+        #[arg(long)]
+        b: String,
     },
 
     /// Print current roster from local state.
     ShowRoster {
         #[arg(long, default_value = "party_state.json")]
         state_file: String,
+        /// Output format; json/csv emit one row per party, sorted by party_id.
+        #[arg(long, value_enum, default_value_t = RosterFormat::Text)]
+        format: RosterFormat,
+        /// Leave out parties whose latest registration the watchtower received more than
+        /// this many seconds ago (as of the last sync; when it signed it, for a state synced
+        /// before receive times were kept). Advisory only: they remain in the log and the
+        /// saved roster.
+        #[arg(long, value_name = "SECS")]
+        hide_stale: Option<u64>,
+        /// Print --format json as canonical JSON (sorted keys, no whitespace), so two
+        /// parties' rosters can be diffed byte for byte.
+        #[arg(long)]
+        canonical: bool,
+    },
+
+    /// Fetch and verify the watchtower's full log and print the resulting roster, without
+    /// touching any local state.
+    FetchRoster {
+        #[arg(long)]
+        watchtower: String,
+        /// Watchtower pubkey (base64).
+        #[arg(long)]
+        watchtower_pubkey_b64: String,
+        /// Output format; json/csv emit one row per party, sorted by party_id.
+        #[arg(long, value_enum, default_value_t = RosterFormat::Text)]
+        format: RosterFormat,
+        /// Also write the verified snapshot and log here, for use as a --roster-file.
+        #[arg(long)]
+        save: Option<String>,
+        /// Print --format json and write --save as canonical JSON (sorted keys, no
+        /// whitespace), so dumps from different watchtowers can be diffed byte for byte.
+        #[arg(long)]
+        canonical: bool,
+        #[command(flatten)]
+        http: WatchtowerHttpArgs,
     },
 }
 
+/// How watchtower calls are made, for every command that talks to a watchtower.
+#[derive(Debug, Clone, Args)]
+pub struct WatchtowerHttpArgs {
+    /// Per-request timeout for watchtower calls (ms).
+    #[arg(long, default_value_t = 30_000)]
+    request_timeout_ms: u64,
+    /// HTTP(S) proxy for watchtower calls. Defaults to HTTPS_PROXY / HTTP_PROXY.
+    #[arg(long)]
+    proxy: Option<String>,
+    /// PEM file of extra root certificates to trust for the watchtower (e.g. a private CA).
+    #[arg(long)]
+    ca_cert: Option<String>,
+    /// Talk HTTP/2 to the watchtower without negotiating it, so every call reuses one
+    /// multiplexed connection. The watchtower (or its TLS proxy) must accept HTTP/2.
+    #[arg(long)]
+    http2: bool,
+}
+
+impl WatchtowerHttpArgs {
+    /// Watchtower client with the default config, except for these.
+    fn client(self, base: String) -> Result<client::WatchtowerClient> {
+        let config = client::WatchtowerClientConfig {
+            request_timeout: Duration::from_millis(self.request_timeout_ms),
+            proxy: self.proxy,
+            ca_cert_file: self.ca_cert,
+            http2_prior_knowledge: self.http2,
+            ..Default::default()
+        };
+        Ok(client::WatchtowerClient::new_with_config(base, config)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum RosterFormat {
+    Text,
+    Json,
+    Csv,
+}
+
+/// Whether a sync may accept a rolled-back log (Register, RotateKey, Sync, Run).
+#[derive(Debug, Clone, Args)]
+pub struct RollbackArgs {
+    /// Accept a snapshot with a shorter log than the one already accepted (e.g. after
+    /// the operator restored the watchtower from an older backup). The rollback is still
+    /// recorded as evidence.
+    #[arg(long)]
+    allow_rollback: bool,
+}
+
+/// Limits on the incoming P2P connections a listener serves (Run, P2pServe).
+#[derive(Debug, Clone, Args)]
+pub struct P2pLimitArgs {
+    /// Incoming P2P connections served at once; more are closed unanswered.
+    #[arg(long, default_value_t = 256)]
+    p2p_max_connections: usize,
+    /// Incoming P2P connections accepted from one IP in a burst.
+    #[arg(long, default_value_t = 64)]
+    p2p_ip_rate_burst: u32,
+    /// Sustained incoming P2P connections accepted per IP, per second.
+    #[arg(long, default_value_t = 16.0)]
+    p2p_ip_rate_per_sec: f64,
+    /// Time an incoming peer gets to complete the P2P handshake (ms).
+    #[arg(long, default_value_t = 5000)]
+    p2p_handshake_timeout_ms: u64,
+}
+
+impl P2pLimitArgs {
+    fn limits(&self) -> p2p::P2pLimits {
+        p2p::P2pLimits {
+            max_connections: self.p2p_max_connections,
+            ip_rate_burst: self.p2p_ip_rate_burst,
+            ip_rate_per_sec: self.p2p_ip_rate_per_sec,
+            handshake_timeout: Duration::from_millis(self.p2p_handshake_timeout_ms),
+        }
+    }
+}
+
+impl Command {
+    /// The epoch and party_id this command acts for, where it names them; attached to
+    /// everything it logs.
+    fn log_scope(&self) -> (Option<u64>, Option<u64>) {
+        match self {
+            Command::Register { epoch, party_id, .. }
+            | Command::RotateKey { epoch, party_id, .. }
+            | Command::Sync { epoch, party_id, .. }
+            | Command::ResyncSeq { epoch, party_id, .. }
+            | Command::Run { epoch, party_id, .. }
+            | Command::GossipServe { epoch, party_id, .. }
+            | Command::GossipRun { epoch, party_id, .. } => (Some(*epoch), Some(*party_id)),
+            Command::GossipSend { party_id, .. } | Command::GetParty { party_id, .. } => {
+                (None, Some(*party_id))
+            }
+            Command::Verify { epoch, .. } => (*epoch, None),
+            Command::ProveMembership { epoch, party_id, .. } => (*epoch, Some(*party_id)),
+            Command::VerifyMembership { epoch, party_id, .. } => (*epoch, *party_id),
+            _ => (None, None),
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt().init();
     let cli = Cli::parse();
+    logging::init(cli.log_format);
+    let (epoch, party_id) = cli.cmd.log_scope();
+    let span = tracing::info_span!("party", epoch, party_id);
+    run(cli.cmd, shutdown::signal()).instrument(span).await
+}
 
-    match cli.cmd {
+/// Run `cmd`; the long-running commands stop cleanly once `stop` resolves.
+async fn run(cmd: Command, stop: impl std::future::Future<Output = ()> + Send + 'static) -> Result<()> {
+    match cmd {
         Command::Register {
             watchtower,
             epoch,
             party_id,
             endpoint,
             key_file,
+            key_passphrase,
+            key_env,
+            key_stdin,
             state_file,
             watchtower_pubkey_b64,
+            reset,
+            allow_key_change,
+            require_pinned_pubkey,
+            rollback: RollbackArgs { allow_rollback },
+            watchtower_token,
+            http,
+            dry_run,
         } => {
-            let wt = client::WatchtowerClient::new(watchtower);
-            let pk_w = load_or_fetch_watchtower_pk(&wt, watchtower_pubkey_b64).await?;
-            let keys = keys::PartyKeys::load_or_create(&key_file)?;
-            let mut st = state::PartyStateFile::load_or_init(&state_file, epoch, party_id)?;
+            if dry_run {
+                let keys = existing_party_keys(
+                    &key_file,
+                    key_passphrase.as_deref(),
+                    cli_seed(key_env.as_deref(), key_stdin)?,
+                )?;
+                let st = state::PartyStateFile::load_or_init(&state_file, epoch, party_id, reset)?;
+                let msg = registration::registration_message(&keys, &st, endpoint)?;
+                let prr = registration::sign_registration(&keys, msg)?;
+                // Hash as the last accepted snapshot does; a fresh state has none, so assume
+                // the default.
+                let h = st.current_srs.as_ref().map(|srs| srs.msg.hasher()).transpose()?;
+                let h = h.unwrap_or_default();
+                let leaf = leaf_hash(h, &enc(&prr)?);
+                let out = serde_json::json!({
+                    "prr": prr,
+                    "hash_alg": h.to_string(),
+                    "leaf_hash_b64": base64::engine::general_purpose::STANDARD.encode(leaf),
+                });
+                println!("{}", serde_json::to_string_pretty(&out)?);
+                info!("dry run: not submitted, next_seq stays {}", st.next_seq);
+                return Ok(());
+            }
+            let wt = http.client(watchtower)?
+                .with_epoch(epoch)
+                .with_bearer_token(watchtower_token);
+            let seed = cli_seed(key_env.as_deref(), key_stdin)?;
+            let keys = party_keys(&key_file, key_passphrase.as_deref(), seed)?;
+            let mut st = state::PartyStateFile::load_or_init(&state_file, epoch, party_id, reset)?;
+            let pk_w = load_or_fetch_watchtower_pk(
+                &wt,
+                watchtower_pubkey_b64,
+                &mut st,
+                allow_key_change,
+                require_pinned_pubkey,
+            )
+            .await?;
 
-            register_self(&wt, &keys, &mut st, endpoint).await?;
-            full_sync_and_verify(&wt, &pk_w, &mut st).await?;
-            st.save(&state_file)?;
+            registration::register_self(&wt, &pk_w, &keys, &mut st, endpoint).await?;
+            sync_and_save(&wt, &pk_w, &mut st, &state_file, allow_rollback).await?;
 
             info!("registered and synced. roster_size={}", st.roster.len());
         }
 
+        Command::Keygen { out, force, key_passphrase } => {
+            let keys = if force {
+                keys::PartyKeys::create(&out, key_passphrase.as_deref())?
+            } else {
+                keys::PartyKeys::create_new(&out, key_passphrase.as_deref())?
+            };
+            println!("{}", base64::engine::general_purpose::STANDARD.encode(keys.pk.to_bytes()));
+        }
+
+        Command::RotateKey {
+            watchtower,
+            epoch,
+            party_id,
+            endpoint,
+            key_file,
+            new_key_file,
+            key_passphrase,
+            key_env,
+            key_stdin,
+            state_file,
+            watchtower_pubkey_b64,
+            allow_key_change,
+            require_pinned_pubkey,
+            rollback: RollbackArgs { allow_rollback },
+            watchtower_token,
+            http,
+        } => {
+            let wt = http.client(watchtower)?
+                .with_epoch(epoch)
+                .with_bearer_token(watchtower_token);
+            // The old key must exist: rotating away from a freshly made one would bind a
+            // key nobody endorsed.
+            let old_keys = existing_party_keys(
+                &key_file,
+                key_passphrase.as_deref(),
+                cli_seed(key_env.as_deref(), key_stdin)?,
+            )?;
+            let mut st = state::PartyStateFile::load_or_init(&state_file, epoch, party_id, false)?;
+            let pk_w = load_or_fetch_watchtower_pk(
+                &wt,
+                watchtower_pubkey_b64,
+                &mut st,
+                allow_key_change,
+                require_pinned_pubkey,
+            )
+            .await?;
+            let new_keys = keys::PartyKeys::create_new(&new_key_file, key_passphrase.as_deref())?;
+
+            let rotated =
+                registration::rotate_self(&wt, &pk_w, &old_keys, &new_keys, &mut st, endpoint)
+                    .await;
+            if let Err(e) = rotated {
+                return Err(discard_unbound_key(e, &new_key_file));
+            }
+            sync_and_save(&wt, &pk_w, &mut st, &state_file, allow_rollback).await?;
+
+            info!(
+                "rotated key. new pk_party_b64={} (use --key-file {} from now on)",
+                base64::engine::general_purpose::STANDARD.encode(new_keys.pk.to_bytes()),
+                new_key_file
+            );
+        }
+
         Command::Sync {
             watchtower,
             epoch,
             party_id,
             state_file,
             watchtower_pubkey_b64,
+            reset,
+            allow_key_change,
+            require_pinned_pubkey,
+            rollback: RollbackArgs { allow_rollback },
+            http,
         } => {
-            let wt = client::WatchtowerClient::new(watchtower);
-            let pk_w = load_or_fetch_watchtower_pk(&wt, watchtower_pubkey_b64).await?;
-            let mut st = state::PartyStateFile::load_or_init(&state_file, epoch, party_id)?;
-            full_sync_and_verify(&wt, &pk_w, &mut st).await?;
-            st.save(&state_file)?;
+            let wt = http.client(watchtower)?.with_epoch(epoch);
+            let mut st = state::PartyStateFile::load_or_init(&state_file, epoch, party_id, reset)?;
+            let pk_w = load_or_fetch_watchtower_pk(
+                &wt,
+                watchtower_pubkey_b64,
+                &mut st,
+                allow_key_change,
+                require_pinned_pubkey,
+            )
+            .await?;
+            sync_and_save(&wt, &pk_w, &mut st, &state_file, allow_rollback).await?;
             info!("synced. roster_size={}", st.roster.len());
         }
 
+        Command::ResyncSeq {
+            watchtower,
+            epoch,
+            party_id,
+            state_file,
+            watchtower_pubkey_b64,
+            reset,
+            allow_key_change,
+            require_pinned_pubkey,
+            http,
+        } => {
+            let wt = http.client(watchtower)?.with_epoch(epoch);
+            let mut st = state::PartyStateFile::load_or_init(&state_file, epoch, party_id, reset)?;
+            let pk_w = load_or_fetch_watchtower_pk(
+                &wt,
+                watchtower_pubkey_b64,
+                &mut st,
+                allow_key_change,
+                require_pinned_pubkey,
+            )
+            .await?;
+            let before = st.next_seq;
+            match registration::resync_next_seq(&wt, &pk_w, &mut st).await? {
+                Some(server_seq) => info!(
+                    "watchtower has seq={server_seq}; next_seq {before} -> {}",
+                    st.next_seq
+                ),
+                None => {
+                    warn!("the watchtower has no record for this party; next_seq stays {before}")
+                }
+            }
+            st.save(&state_file)?;
+        }
+
         Command::Run {
             watchtower,
             epoch,
             party_id,
             endpoint,
+            p2p_bind,
+            advertise,
+            p2p_limits,
             interval_secs,
+            interval_jitter_ms,
             connect_timeout_ms,
             key_file,
+            key_passphrase,
+            key_env,
+            key_stdin,
             state_file,
             watchtower_pubkey_b64,
+            reset,
+            allow_key_change,
+            require_pinned_pubkey,
+            rollback: RollbackArgs { allow_rollback },
+            watchtower_token,
+            http,
+            peers_bind,
+            max_probe_failures,
+            connect_concurrency,
+            gossip_bind,
+            subscribe,
+            light,
+            roster_file,
+            ready_file,
         } => {
-            let wt = client::WatchtowerClient::new(watchtower);
-            let pk_w = load_or_fetch_watchtower_pk(&wt, watchtower_pubkey_b64).await?;
-            let keys = keys::PartyKeys::load_or_create(&key_file)?;
-            let mut st = state::PartyStateFile::load_or_init(&state_file, epoch, party_id)?;
+            let mut st = state::PartyStateFile::load_or_init(&state_file, epoch, party_id, reset)?;
 
-            // Start P2P listener in background.
-            let p2p_bind = endpoint.clone();
-            tokio::spawn(async move {
-                if let Err(e) = p2p::serve_p2p(&p2p_bind).await {
-                    eprintln!("p2p server error: {e}");
+            // Bind now so the advertised port is known; peers dialing before the server
+            // task starts just wait in the accept backlog.
+            let (listener, advertise) =
+                bind_p2p_listener(&endpoint, p2p_bind.as_deref(), advertise.as_deref()).await?;
+            info!("advertising p2p endpoint {advertise}");
+
+            let (source, pk_w) = match (roster_file, watchtower) {
+                (Some(path), _) => {
+                    // No watchtower to fetch the key from: it must be given or pinned.
+                    let b64 = watchtower_pubkey_b64
+                        .or_else(|| st.pinned_watchtower_pk_b64.clone())
+                        .ok_or_else(|| {
+                            anyhow!("--roster-file needs --watchtower-pubkey-b64 or a pinned key")
+                        })?;
+                    let pk_w = pin_watchtower_pk(&b64, &mut st, allow_key_change)?;
+                    let rf = load_roster_file(&path, &pk_w, Some(epoch))?;
+                    let checked = st.check_rollback(&rf.srs, allow_rollback);
+                    if checked.is_err() {
+                        st.save(&state_file)?;
+                    }
+                    checked?;
+                    for change in st.apply_verified(rf.srs.clone(), &rf.entries) {
+                        info!("roster: {}", change);
+                    }
+                    match st.roster.get(&party_id) {
+                        None => warn!("party_id={party_id} is not in the roster file"),
+                        Some(e) if e.endpoint != advertise => warn!(
+                            "roster file has endpoint {} for this party, not {advertise}",
+                            e.endpoint
+                        ),
+                        Some(_) => {}
+                    }
+                    st.save(&state_file)?;
+                    (RosterSource::File(rf.srs), pk_w)
+                }
+                (None, Some(watchtower)) => {
+                    let wt = http.client(watchtower)?
+                        .with_epoch(epoch)
+                        .with_bearer_token(watchtower_token);
+                    let keys = party_keys(
+                        &key_file,
+                        key_passphrase.as_deref(),
+                        cli_seed(key_env.as_deref(), key_stdin)?,
+                    )?;
+                    let pk_w = load_or_fetch_watchtower_pk(
+                        &wt,
+                        watchtower_pubkey_b64,
+                        &mut st,
+                        allow_key_change,
+                        require_pinned_pubkey,
+                    )
+                    .await?;
+
+                    // Register/update self so others can find us.
+                    registration::register_self(&wt, &pk_w, &keys, &mut st, advertise).await?;
+                    sync_and_save(&wt, &pk_w, &mut st, &state_file, allow_rollback).await?;
+                    (RosterSource::Watchtower(wt), pk_w)
+                }
+                (None, None) => return Err(anyhow!("--watchtower or --roster-file is required")),
+            };
+
+            // From here the state is shared with the P2P server. Only this loop writes and
+            // saves it, and never holds the lock across an await.
+            let shared: state::SharedState = Arc::new(Mutex::new(st));
+            let p2p_state = shared.clone();
+            let p2p_task = tokio::spawn(async move {
+                if let Err(e) = p2p::serve_p2p(listener, p2p_state, p2p_limits.limits()).await {
+                    error!("p2p server error: {e}");
                 }
             });
 
-            // Register/update self so others can find us.
-            register_self(&wt, &keys, &mut st, endpoint).await?;
-            full_sync_and_verify(&wt, &pk_w, &mut st).await?;
-            st.save(&state_file)?;
+            // Per-peer liveness, re-probed every tick; shared with the /peers status server.
+            let peers: mesh::PeerTable = Arc::default();
+            let status_task = peers_bind.map(|bind| {
+                let peers_state = mesh::PeersState { party_id, peers: peers.clone() };
+                tokio::spawn(async move {
+                    if let Err(e) = mesh::serve_status(&bind, peers_state).await {
+                        error!("status server error: {e}");
+                    }
+                })
+            });
 
-            // Connectivity tracking: only log "connected to X" once per peer.
-            let mut connected: HashSet<u64> = HashSet::new();
+            // Co-hosted gossip server, seeded with the registration snapshot and fed each
+            // tick's verified snapshot, so a conflicting peer gossip is caught in-process.
+            let gossip = gossip_bind.map(|bind| {
+                let last = shared.lock().unwrap().current_srs.clone();
+                let gs = gossip::GossipState::new(pk_w, epoch, last);
+                let task_gs = gs.clone();
+                let task = tokio::spawn(async move {
+                    if let Err(e) = gossip::serve_gossip(&bind, task_gs).await {
+                        error!("gossip server error: {e}");
+                    }
+                });
+                (gs, task)
+            });
 
+            // Listen from now on, but only act between ticks, so a tick's own save always
+            // completes before the final one below.
+            let (shutdown_tx, mut shutdown) = tokio::sync::oneshot::channel();
+            tokio::spawn(async move {
+                stop.await;
+                let _ = shutdown_tx.send(());
+            });
+            let mut last_ready = None;
+            let mut updates: Option<client::SnapshotUpdates> = None;
             loop {
-                if let Err(e) = full_sync_and_verify(&wt, &pk_w, &mut st).await {
-                    warn!("sync error: {}", e);
-                } else {
-                    // Attempt to connect to all peers (excluding self).
-                    let my_id = st.party_id;
-                    let peers: Vec<(u64, String)> = st
-                        .roster
-                        .iter()
-                        .filter(|(pid, _)| **pid != my_id)
-                        .map(|(pid, entry)| (*pid, entry.endpoint.clone()))
-                        .collect();
-
-                    for (pid, addr) in peers {
-                        if connected.contains(&pid) {
-                            continue;
+                let fetched = match &source {
+                    // The file's roster was applied at startup; only the peers are re-probed.
+                    RosterSource::File(srs) => Ok((srs.clone(), Fetched::Appended(Vec::new()))),
+                    RosterSource::Watchtower(wt) => {
+                        let extended = if light {
+                            let base = shared.lock().unwrap().light_base();
+                            let extended = match &base {
+                                Some(base) => wt.fetch_verified_extension(&pk_w, base).await,
+                                None => Err(client::ClientError::Request(
+                                    "light sync needs a full sync that included this party first"
+                                        .into(),
+                                )),
+                            };
+                            match extended {
+                                Ok(extended) => Some(extended),
+                                Err(e) => {
+                                    warn!("light verify failed, doing a full verify: {}", e);
+                                    None
+                                }
+                            }
+                        } else {
+                            None
+                        };
+                        match extended {
+                            Some((srs, appended)) => Ok((srs, Fetched::Appended(appended))),
+                            None => {
+                                let fetched = wt.fetch_verified_log(&pk_w, Some(epoch)).await;
+                                fetched.and_then(|(srs, log)| {
+                                    let mut st = shared.lock().unwrap();
+                                    let checked = st.check_rollback(&srs, allow_rollback);
+                                    checked.map_err(client::ClientError::verification)?;
+                                    Ok((srs, Fetched::Full(log)))
+                                })
+                            }
                         }
-                        match p2p::connect_and_handshake(&addr, my_id, connect_timeout_ms).await {
-                            Ok(_) => {
-                                connected.insert(pid);
-                                info!("connected to party_id={} at {}", pid, addr);
+                    }
+                };
+                match fetched {
+                    Err(e) if e.is_transient() => {
+                        warn!("watchtower unavailable, will retry: {}", e)
+                    }
+                    Err(e) => {
+                        error!("sync failed: {}", e);
+                        // Keep any rollback evidence the check recorded.
+                        shared.lock().unwrap().save(&state_file)?;
+                    }
+                    Ok((srs, entries)) => {
+                        if let Some((gs, _)) = &gossip {
+                            if let Some(report) = gs.observe(&srs) {
+                                error!("watchtower snapshot conflicts with gossip: {report}");
                             }
-                            Err(_) => {
+                        }
+                        let (changes, targets) = {
+                            let mut st = shared.lock().unwrap();
+                            let changes = match entries {
+                                Fetched::Full(log) => st.apply_verified(srs, &log),
+                                Fetched::Appended(appended) => st.apply_appended(srs, &appended),
+                            };
+                            // Probe all peers (excluding self).
+                            let targets: Vec<(u64, String)> = st
+                                .roster
+                                .iter()
+                                .filter(|(pid, _)| **pid != party_id)
+                                .map(|(pid, entry)| (*pid, entry.endpoint.clone()))
+                                .collect();
+                            (changes, targets)
+                        };
+                        for change in &changes {
+                            info!("roster: {}", change);
+                        }
+                        mesh::forget_moved(&peers, &changes);
+
+                        let results =
+                            mesh::probe(targets, party_id, connect_timeout_ms, connect_concurrency)
+                                .await;
+
+                        for (pid, addr, ok) in results {
+                            let transition = peers
+                                .lock()
+                                .unwrap()
+                                .entry(pid)
+                                .or_default()
+                                .record(ok, max_probe_failures);
+                            match transition {
+                                Some(mesh::Transition::Connected) => {
+                                    info!("connected to party_id={} at {}", pid, addr)
+                                }
+                                Some(mesh::Transition::Disconnected) => warn!(
+                                    "party_id={} at {} disconnected after {} failed probes",
+                                    pid, addr, max_probe_failures
+                                ),
+                                Some(mesh::Transition::Reconnected) => {
+                                    info!("reconnected to party_id={} at {}", pid, addr)
+                                }
                                 // Not fatal; peer may not be up yet.
+                                None => {}
+                            }
+                        }
+
+                        if let RosterSource::Watchtower(wt) = &source {
+                            match wt.roster().await {
+                                Ok(roster) => shared.lock().unwrap().apply_last_seen(&roster),
+                                Err(e) => warn!("could not fetch last-seen times: {e}"),
+                            }
+                        }
+
+                        let st = shared.lock().unwrap();
+                        st.save(&state_file)?;
+                        // Count against the roster; the peer table may still hold departed peers.
+                        let (mut peer_count, mut connected_peers) = (0, 0);
+                        {
+                            let peers = peers.lock().unwrap();
+                            for pid in st.roster.keys().filter(|pid| **pid != party_id) {
+                                peer_count += 1;
+                                if peers.get(pid).is_some_and(|l| l.connected) {
+                                    connected_peers += 1;
+                                }
+                            }
+                        }
+                        info!(
+                            "ready-check: roster_size={}, connected_peers={}",
+                            st.roster.len(),
+                            connected_peers
+                        );
+                        let ready = mesh::ReadyStatus {
+                            epoch,
+                            roster_size: st.roster.len(),
+                            connected_peers,
+                            all_connected: connected_peers == peer_count,
+                        };
+                        if let Some(path) = &ready_file {
+                            if last_ready.as_ref() != Some(&ready) {
+                                mesh::write_ready_file(path, &ready)?;
+                                last_ready = Some(ready);
                             }
                         }
                     }
+                }
 
-                    st.save(&state_file)?;
-                    info!(
-                        "ready-check: roster_size={}, connected_peers={}",
-                        st.roster.len(),
-                        connected.len()
-                    );
+                match &source {
+                    RosterSource::Watchtower(wt) if subscribe && updates.is_none() => {
+                        match wt.subscribe_snapshots().await {
+                            Ok(stream) => updates = Some(stream),
+                            Err(e) => warn!("can't subscribe to snapshots, polling meanwhile: {e}"),
+                        }
+                    }
+                    _ => {}
+                }
+
+                // Wait out the interval, cut short if the subscription announces a snapshot
+                // other than the one in the state. The tick verifies it as usual.
+                let interval = Duration::from_secs(interval_secs);
+                let pause = mesh::jittered(interval, interval_jitter_ms, &mut rand::thread_rng());
+                let deadline = tokio::time::Instant::now() + pause;
+                let mut stop = false;
+                loop {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(deadline) => break,
+                        srs = next_update(&mut updates) => {
+                            let st = shared.lock().unwrap();
+                            let current = st.current_srs.as_ref().map(|c| &c.msg);
+                            if current.map(|m| (m.log_len, m.merkle_root))
+                                != Some((srs.msg.log_len, srs.msg.merkle_root))
+                            {
+                                break;
+                            }
+                        }
+                        _ = &mut shutdown => {
+                            stop = true;
+                            break;
+                        }
+                    }
+                }
+                if stop {
+                    break;
                 }
+            }
 
-                tokio::time::sleep(Duration::from_secs(interval_secs)).await;
+            info!("shutdown requested; stopping p2p, status and gossip servers");
+            p2p_task.abort();
+            if let Some(task) = status_task {
+                task.abort();
+            }
+            if let Some((_, task)) = gossip {
+                task.abort();
+            }
+            shared.lock().unwrap().save(&state_file)?;
+            info!("state saved to {}", state_file);
+            if let Some(path) = &ready_file {
+                mesh::remove_ready_sentinel(path)?;
             }
         }
 
@@ -246,97 +1208,477 @@ async fn main() -> Result<()> {
             party_id,
             state_file,
             watchtower_pubkey_b64,
+            reset,
+            allow_key_change,
+            require_pinned_pubkey,
+            http,
         } => {
-            let wt = client::WatchtowerClient::new(watchtower);
-            let pk_w = load_or_fetch_watchtower_pk(&wt, watchtower_pubkey_b64).await?;
-
+            let wt = http.client(watchtower)?;
             // Initialize gossip state with current snapshot if exists.
-            let st = state::PartyStateFile::load_or_init(&state_file, epoch, party_id)?;
-            let shared_last = std::sync::Arc::new(std::sync::Mutex::new(st.current_srs.clone()));
-
-            let gs = gossip::GossipState {
-                pk_w,
-                last: shared_last,
-            };
-
-            let app = gossip::router(gs);
-            let addr: std::net::SocketAddr = bind.parse()?;
-            let listener = tokio::net::TcpListener::bind(addr).await?;
-            info!("gossip server listening on {}", addr);
-            axum::serve(listener, app).await?;
-        }
-
-        Command::GossipSend { peer, party_id, state_file } => {
-            let st: state::PartyStateFile =
-                serde_json::from_str(&std::fs::read_to_string(&state_file)?)?;
-            let srs = st.current_srs.ok_or_else(|| anyhow!("no current_srs in state file"))?;
-            gossip::send_gossip(&peer, party_id, srs).await?;
-            info!("gossip sent to {}", peer);
+            // Run/Sync own the state file; it is only saved here to persist a new pin.
+            let mut st = state::PartyStateFile::load_or_init(&state_file, epoch, party_id, reset)?;
+            let pinned_before = st.pinned_watchtower_pk_b64.clone();
+            let pk_w = load_or_fetch_watchtower_pk(
+                &wt,
+                watchtower_pubkey_b64,
+                &mut st,
+                allow_key_change,
+                require_pinned_pubkey,
+            )
+            .await?;
+            save_new_pin(&st, pinned_before, &state_file)?;
+            let gs = gossip::GossipState::new(pk_w, epoch, st.current_srs.clone());
+            gossip::serve_gossip(&bind, gs).await?;
         }
 
-        Command::ShowRoster { state_file } => {
-            let st: state::PartyStateFile =
-                serde_json::from_str(&std::fs::read_to_string(&state_file)?)?;
-            println!("epoch: {}", st.epoch);
-            println!("party_id: {}", st.party_id);
-            println!("next_seq: {}", st.next_seq);
-            println!("last_log_len: {}", st.last_log_len);
-            println!("roster (party_id -> endpoint, seq):");
-            let mut keys: Vec<_> = st.roster.keys().cloned().collect();
-            keys.sort();
-            for pid in keys {
-                let e = &st.roster[&pid];
-                println!("  {} -> {}, seq={}", pid, e.endpoint, e.seq);
-            }
+        Command::GossipRun {
+            bind,
+            watchtower,
+            epoch,
+            party_id,
+            peers,
+            interval_secs,
+            peer_timeout_ms,
+            conflict_log,
+            exit_on_conflict,
+            state_file,
+            watchtower_pubkey_b64,
+            reset,
+            allow_key_change,
+            require_pinned_pubkey,
+            http,
+        } => {
+            let wt = http.client(watchtower)?.with_epoch(epoch);
+            // Run/Sync own the state file; it is only saved here to persist a new pin.
+            let mut st = state::PartyStateFile::load_or_init(&state_file, epoch, party_id, reset)?;
+            let pinned_before = st.pinned_watchtower_pk_b64.clone();
+            let pk_w = load_or_fetch_watchtower_pk(
+                &wt,
+                watchtower_pubkey_b64,
+                &mut st,
+                allow_key_change,
+                require_pinned_pubkey,
+            )
+            .await?;
+            save_new_pin(&st, pinned_before, &state_file)?;
+            let log = gossip::ConflictLog::spawn(conflict_log);
+            let gs = gossip::GossipState::new(pk_w, epoch, st.current_srs.clone())
+                .with_conflict_log(log.clone());
+            let server_gs = gs.clone();
+            let server = tokio::spawn(async move {
+                if let Err(e) = gossip::serve_gossip(&bind, server_gs).await {
+                    error!("gossip server error: {e}");
+                }
+            });
+
+            let (shutdown_tx, mut shutdown) = tokio::sync::oneshot::channel();
+            tokio::spawn(async move {
+                stop.await;
+                let _ = shutdown_tx.send(());
+            });
+            loop {
+                // Our own view first, so it is what gets pushed to the peers.
+                match wt.snapshot().await {
+                    Ok(srs) => match gs.verify_peer_snapshot(&srs) {
+                        Ok(()) => {
+                            if let Some(report) = gs.observe(&srs) {
+                                error!("watchtower snapshot conflicts with gossip: {report}");
+                            }
+                        }
+                        Err(e) => error!("watchtower snapshot rejected: {e}"),
+                    },
+                    Err(e) => warn!("watchtower unavailable, will retry: {e}"),
+                }
+                let ours = gs.last_by_epoch.lock().unwrap().get(&epoch).cloned();
+
+                stream::iter(&peers)
+                    .for_each_concurrent(16, |peer| {
+                        let (gs, ours) = (&gs, ours.clone());
+                        let exchange = async move {
+                            for report in gs.exchange(peer, party_id, ours).await {
+                                error!("{report}");
+                            }
+                        };
+                        async move {
+                            let limit = Duration::from_millis(peer_timeout_ms);
+                            if tokio::time::timeout(limit, exchange).await.is_err() {
+                                warn!("gossip peer {peer} timed out");
+                            }
+                        }
+                    })
+                    .await;
+
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(interval_secs)) => {}
+                    _ = log.detected(), if exit_on_conflict => {
+                        server.abort();
+                        return Err(anyhow!(
+                            "equivocation detected; evidence written to {}",
+                            log.path()
+                        ));
+                    }
+                    _ = &mut shutdown => break,
+                }
+            }
+            info!("shutdown requested; stopping gossip server");
+            server.abort();
+        }
+
+        Command::P2pServe {
+            bind,
+            state_file,
+            roster_file,
+            watchtower_pubkey_b64,
+            p2p_limits,
+        } => {
+            let st = match (state_file, roster_file, watchtower_pubkey_b64) {
+                (Some(path), _, _) => read_json_file(&path, "state")?,
+                (None, Some(path), Some(b64)) => {
+                    let rf = load_roster_file(&path, &parse_watchtower_pk(&b64)?, None)?;
+                    // The roster doesn't depend on whose state it is, so any party_id will do.
+                    let mut st = state::PartyStateFile::new(rf.srs.msg.epoch, 0);
+                    st.apply_verified(rf.srs, &rf.entries);
+                    st
+                }
+                _ => state::PartyStateFile::new(0, 0),
+            };
+            let listener = p2p::bind_p2p(&bind).await?;
+            p2p::serve_p2p(listener, Arc::new(Mutex::new(st)), p2p_limits.limits()).await?;
+        }
+
+        Command::GossipSend { peer, party_id, state_file, evidence_index, watchtower, http } => {
+            let st: state::PartyStateFile = read_json_file(&state_file, "state")?;
+            let srs = st.current_srs.ok_or_else(|| anyhow!("no current_srs in state file"))?;
+            let evidence = match (evidence_index, watchtower) {
+                (Some(index), Some(watchtower)) => {
+                    // The log prefix our snapshot commits to; checked against its root below.
+                    let pinned = st.pinned_watchtower_pk_b64.as_deref().ok_or_else(|| {
+                        anyhow!("no pinned watchtower pubkey in state file {state_file}")
+                    })?;
+                    let pk_w = parse_watchtower_pk(pinned)?;
+                    client::verify_snapshot_signature(&pk_w, &srs)?;
+                    let wt = http.client(watchtower)?.with_epoch(srs.msg.epoch);
+                    let chunk = client::DEFAULT_ENTRIES_CHUNK;
+                    let entries = wt.entries_chunked(1, srs.msg.log_len, chunk).await?;
+                    Some(gossip::gossip_evidence(&srs, &entries, index)?)
+                }
+                _ => None,
+            };
+            if let Some(report) = gossip::send_gossip(&peer, party_id, srs, evidence).await? {
+                warn!("peer reports a conflict: {report}");
+            }
+            info!("gossip sent to {}", peer);
+        }
+
+        Command::Verify {
+            snapshot_file,
+            entries_file,
+            watchtower_pubkey_b64,
+            epoch,
+        } => {
+            let pk_w = parse_watchtower_pk(&watchtower_pubkey_b64)?;
+            print!("{}", verify_capture(&pk_w, epoch, &snapshot_file, &entries_file)?);
+        }
+
+        Command::GetParty { watchtower, party_id, watchtower_pubkey_b64, http } => {
+            let wt = http.client(watchtower)?;
+            let pk_w = parse_watchtower_pk(&watchtower_pubkey_b64)?;
+            let Some(resp) = wt.party(party_id).await? else {
+                return Err(anyhow!("watchtower has no record for party_id={party_id}"));
+            };
+            client::verify_party_record(&pk_w, &resp)?;
+            println!(
+                "party_id={} seq={} endpoint={} index={} log_len={}",
+                party_id,
+                resp.prr.msg.seq,
+                resp.prr.msg.endpoint.addr,
+                resp.index,
+                resp.srs.msg.log_len
+            );
+            println!("PASS");
+        }
+
+        Command::ProveMembership { watchtower, party_id, watchtower_pubkey_b64, epoch, out, http } => {
+            let mut wt = http.client(watchtower)?;
+            if let Some(epoch) = epoch {
+                wt = wt.with_epoch(epoch);
+            }
+            let pk_w = parse_watchtower_pk(&watchtower_pubkey_b64)?;
+            let Some(bundle) = wt.party(party_id).await? else {
+                return Err(anyhow!("watchtower has no record for party_id={party_id}"));
+            };
+            check_membership_bundle(&pk_w, &bundle, epoch, Some(party_id))?;
+            std::fs::write(&out, serde_json::to_string_pretty(&bundle)?)
+                .map_err(|e| anyhow!("failed to write bundle file {out}: {e}"))?;
+            println!(
+                "wrote {out}: party_id={party_id} epoch={} index={} log_len={}",
+                bundle.srs.msg.epoch, bundle.index, bundle.srs.msg.log_len
+            );
+        }
+
+        Command::VerifyMembership { bundle, watchtower_pubkey_b64, epoch, party_id } => {
+            let pk_w = parse_watchtower_pk(&watchtower_pubkey_b64)?;
+            let bundle: PartyResponse = read_json_file(&bundle, "bundle")?;
+            println!(
+                "party_id={} seq={} epoch={} index={} log_len={} merkle_root_b64={}",
+                bundle.prr.msg.party_id,
+                bundle.prr.msg.seq,
+                bundle.srs.msg.epoch,
+                bundle.index,
+                bundle.srs.msg.log_len,
+                base64::engine::general_purpose::STANDARD.encode(bundle.srs.msg.merkle_root)
+            );
+            match check_membership_bundle(&pk_w, &bundle, epoch, party_id) {
+                Ok(()) => println!("PASS"),
+                Err(e) => {
+                    println!("FAIL: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        Command::Checkpoints { watchtower, watchtower_pubkey_b64, http } => {
+            let wt = http.client(watchtower)?;
+            let pk_w = parse_watchtower_pk(&watchtower_pubkey_b64)?;
+            let srs = wt.snapshot().await?;
+            let checkpoints = wt.checkpoints().await?;
+            println!("current log_len: {}", srs.msg.log_len);
+            let mut failed = false;
+            for cp in &checkpoints {
+                let res = match wt.consistency(cp.msg.log_len, srs.msg.log_len).await {
+                    Ok(proof) => client::verify_snapshot_extends(&pk_w, cp, &srs, &proof),
+                    Err(e) => Err(e.into()),
+                };
+                match res {
+                    Ok(()) => println!("checkpoint log_len={}: PASS", cp.msg.log_len),
+                    Err(e) => {
+                        failed = true;
+                        println!("checkpoint log_len={}: FAIL: {e}", cp.msg.log_len);
+                    }
+                }
+            }
+            if failed {
+                std::process::exit(1);
+            }
+        }
+
+        Command::MeshStatus { state_file, peers_port, timeout_ms } => {
+            let st: state::PartyStateFile =
+                serde_json::from_str(&std::fs::read_to_string(&state_file)?)?;
+            let targets = st
+                .roster
+                .iter()
+                .map(|(pid, e)| Ok((*pid, mesh::peers_addr(&e.endpoint, peers_port)?)))
+                .collect::<Result<BTreeMap<u64, String>>>()?;
+            let reach = mesh::collect(&targets, Duration::from_millis(timeout_ms)).await;
+            print!("{}", mesh::render_matrix(&reach));
+        }
+
+        Command::DiffState { a, b } => {
+            let (report, code) = diff_state(&a, &b)?;
+            print!("{report}");
+            if code != 0 {
+                std::process::exit(code);
+            }
+        }
+
+        Command::ShowRoster { state_file, format, hide_stale, canonical } => {
+            let st: state::PartyStateFile =
+                serde_json::from_str(&std::fs::read_to_string(&state_file)?)?;
+            if let RosterFormat::Text = format {
+                println!("epoch: {}", st.epoch);
+                println!("party_id: {}", st.party_id);
+                println!("next_seq: {}", st.next_seq);
+                println!("last_log_len: {}", st.last_log_len);
+            }
+            let mut roster: BTreeMap<u64, state::RosterEntry> = st.roster.into_iter().collect();
+            if let Some(secs) = hide_stale {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                drop_stale(&mut roster, secs, now);
+            }
+            print!("{}", render_roster(&roster, format, canonical)?);
+        }
+
+        Command::FetchRoster {
+            watchtower,
+            watchtower_pubkey_b64,
+            format,
+            save,
+            canonical,
+            http,
+        } => {
+            let wt = http.client(watchtower)?;
+            let pk_w = parse_watchtower_pk(&watchtower_pubkey_b64)?;
+            let (srs, entries) = wt.fetch_verified_log(&pk_w, None).await?;
+            // The roster doesn't depend on whose state it is, so any party_id will do.
+            let mut st = state::PartyStateFile::new(srs.msg.epoch, 0);
+            st.apply_verified(srs.clone(), &entries);
+            print!("{}", render_roster(&st.roster.into_iter().collect(), format, canonical)?);
+            if let Some(path) = save {
+                let rf = state::RosterFile { srs, entries };
+                let json = if canonical {
+                    canonical_json(&rf)?
+                } else {
+                    serde_json::to_string_pretty(&rf)?
+                };
+                std::fs::write(&path, json)
+                    .map_err(|e| anyhow!("failed to write roster file {path}: {e}"))?;
+            }
         }
     }
 
     Ok(())
 }
 
-async fn register_self(
-    wt: &client::WatchtowerClient,
-    keys: &keys::PartyKeys,
-    st: &mut state::PartyStateFile,
-    endpoint: String,
-) -> Result<()> {
-    let seq = st.next_seq;
-
-    let mut nonce = [0u8; 16];
-    OsRng.fill_bytes(&mut nonce);
-
-    let msg = RegistrationMessage {
-        epoch: st.epoch,
-        party_id: st.party_id,
-        endpoint: Endpoint { addr: endpoint },
-        pk_party: keys.pk.to_bytes(),
-        seq,
-        nonce,
-    };
+/// The seed given with --key-env or --key-stdin, if either was: read from this process's
+/// environment or stdin.
+fn cli_seed(key_env: Option<&str>, key_stdin: bool) -> Result<Option<[u8; 32]>> {
+    seed_from_env_or_stdin(key_env, key_stdin, |var| std::env::var(var), std::io::stdin())
+}
 
-    let sig_party = sign_struct(&keys.sk, &msg)?;
-    let prr = PartyRegistrationRecord { msg, sig_party };
+/// This party's keys: from `seed` if one was given, otherwise loaded from (or created at)
+/// `key_file`.
+fn party_keys(
+    key_file: &str,
+    passphrase: Option<&str>,
+    seed: Option<[u8; 32]>,
+) -> Result<keys::PartyKeys> {
+    match seed {
+        Some(seed) => Ok(keys::PartyKeys::from_seed(&seed)),
+        None => keys::PartyKeys::load_or_create(key_file, passphrase),
+    }
+}
 
-    let srs = wt.register(prr).await?;
-    st.current_srs = Some(srs);
+/// Like `party_keys`, but the key file must already exist.
+fn existing_party_keys(
+    key_file: &str,
+    passphrase: Option<&str>,
+    seed: Option<[u8; 32]>,
+) -> Result<keys::PartyKeys> {
+    match seed {
+        Some(seed) => Ok(keys::PartyKeys::from_seed(&seed)),
+        None => keys::PartyKeys::load(key_file, passphrase),
+    }
+}
 
-    // Advance sequence for next re-register/update.
-    st.next_seq = st.next_seq.saturating_add(1);
+/// After a failed rotation, remove the new key file if the watchtower refused the
+/// rotation, so a retry can write a fresh one. On any other failure (a timeout, a bad
+/// receipt) the rotation may have been accepted, so the key is kept.
+fn discard_unbound_key(e: anyhow::Error, new_key_file: &str) -> anyhow::Error {
+    let refused = matches!(
+        e.downcast_ref::<client::ClientError>(),
+        Some(ce @ client::ClientError::Status { .. }) if !ce.is_transient()
+    );
+    if !refused {
+        return e.context(format!(
+            "the rotation may have been accepted; keeping {new_key_file}. Check with \
+             get-party before retrying with another --new-key-file"
+        ));
+    }
+    match std::fs::remove_file(new_key_file) {
+        Ok(()) => e.context(format!("rotation refused; removed the unused {new_key_file}")),
+        Err(rm) => e.context(format!("rotation refused; failed to remove {new_key_file}: {rm}")),
+    }
+}
+
+/// `sync_state` and the roster's last-seen times, then save `st` to `path`. The state is
+/// saved even if the sync fails, so a refused rollback's evidence is kept.
+async fn sync_and_save(
+    wt: &client::WatchtowerClient,
+    pk_w: &VerifyingKey,
+    st: &mut state::PartyStateFile,
+    path: &str,
+    allow_rollback: bool,
+) -> Result<()> {
+    let synced = wt.sync_state(pk_w, st, allow_rollback).await;
+    if synced.is_ok() {
+        match wt.roster().await {
+            Ok(roster) => st.apply_last_seen(&roster),
+            Err(e) => warn!("could not fetch last-seen times: {e}"),
+        }
+    }
+    st.save(path)?;
+    synced?;
     Ok(())
 }
 
+/// The records a `Run` tick fetched along with its snapshot.
+enum Fetched {
+    /// The whole log, for a full sync.
+    Full(Vec<LogEntry>),
+    /// Only the records appended since the accepted snapshot, for a light sync.
+    Appended(Vec<LogEntry>),
+}
+
+/// Resolve the watchtower pubkey (provided, or fetched via TOFU) and check it against the
+/// key pinned in the party state. The first key seen is pinned. With `require_pinned`
+/// nothing is fetched: the key must be provided or already pinned.
 async fn load_or_fetch_watchtower_pk(
     wt: &client::WatchtowerClient,
     provided_b64: Option<String>,
+    st: &mut state::PartyStateFile,
+    allow_key_change: bool,
+    require_pinned: bool,
 ) -> Result<VerifyingKey> {
-    let b64 = if let Some(v) = provided_b64 {
-        v
-    } else {
-        // TOFU: fetch from watchtower. For production you'd pin it.
-        wt.get_watchtower_pubkey_b64().await?
+    let b64 = match (provided_b64, &st.pinned_watchtower_pk_b64) {
+        (Some(v), _) => v,
+        (None, Some(pinned)) if require_pinned => pinned.clone(),
+        (None, None) if require_pinned => {
+            return Err(anyhow!(
+                "no watchtower pubkey given or pinned; pass --watchtower-pubkey-b64 \
+                 (--require-pinned-pubkey refuses to fetch it unauthenticated)"
+            ));
+        }
+        // TOFU: fetch from watchtower and check it against the pin, if any.
+        (None, _) => wt.get_watchtower_pubkey_b64().await?,
     };
+    pin_watchtower_pk(&b64, st, allow_key_change)
+}
+
+/// Parse the watchtower pubkey `b64` and check it against the key pinned in the party
+/// state, pinning it if none is.
+fn pin_watchtower_pk(
+    b64: &str,
+    st: &mut state::PartyStateFile,
+    allow_key_change: bool,
+) -> Result<VerifyingKey> {
+    let pk_w = parse_watchtower_pk(b64)?;
+    let pk_b64 = base64::engine::general_purpose::STANDARD.encode(pk_w.to_bytes());
+
+    match &st.pinned_watchtower_pk_b64 {
+        None => {
+            info!("pinning watchtower pubkey {}", pk_b64);
+        }
+        Some(pinned) if *pinned == pk_b64 => {}
+        Some(pinned) if allow_key_change => {
+            warn!("watchtower pubkey changed: pinned={} new={} (allowed)", pinned, pk_b64);
+        }
+        Some(pinned) => {
+            return Err(anyhow!(
+                "watchtower pubkey {pk_b64} does not match pinned key {pinned}. \
+                 Refusing to continue; pass --allow-key-change if the rotation is expected."
+            ));
+        }
+    }
+    st.pinned_watchtower_pk_b64 = Some(pk_b64);
+    Ok(pk_w)
+}
 
-    let bytes = base64::Engine::decode(&base64::engine::general_purpose::STANDARD, b64)?;
+/// Save `st` to `path` if its pinned watchtower pubkey is no longer `pinned_before`, so a
+/// key trusted on first use stays pinned for later runs.
+fn save_new_pin(
+    st: &state::PartyStateFile,
+    pinned_before: Option<String>,
+    path: &str,
+) -> Result<()> {
+    if st.pinned_watchtower_pk_b64 != pinned_before {
+        st.save(path)?;
+    }
+    Ok(())
+}
+
+fn parse_watchtower_pk(b64: &str) -> Result<VerifyingKey> {
+    let bytes = base64::Engine::decode(&base64::engine::general_purpose::STANDARD, b64.trim())?;
     if bytes.len() != 32 {
         return Err(anyhow!("watchtower pubkey must be 32 bytes"));
     }
@@ -345,20 +1687,927 @@ async fn load_or_fetch_watchtower_pk(
     Ok(VerifyingKey::from_bytes(&pk32)?)
 }
 
-async fn full_sync_and_verify(
-    wt: &client::WatchtowerClient,
+/// `roster` in `format`; with `canonical`, JSON is canonical instead of pretty-printed.
+fn render_roster(
+    roster: &BTreeMap<u64, state::RosterEntry>,
+    format: RosterFormat,
+    canonical: bool,
+) -> Result<String> {
+    let mut out = String::new();
+    match format {
+        RosterFormat::Text => {
+            out.push_str("roster (party_id -> endpoint, seq):\n");
+            for (pid, e) in roster {
+                out.push_str(&format!(
+                    "  {} -> {}, seq={}, created_at_unix={}, history_len={}\n",
+                    pid, e.endpoint, e.seq, e.created_at_unix, e.history_len
+                ));
+            }
+        }
+        RosterFormat::Json => {
+            let rows: Vec<RosterRow> = roster
+                .iter()
+                .map(|(pid, e)| RosterRow {
+                    party_id: *pid,
+                    endpoint: &e.endpoint,
+                    seq: e.seq,
+                    pk_party_b64: &e.pk_party_b64,
+                    history_len: e.history_len,
+                })
+                .collect();
+            if canonical {
+                out.push_str(&canonical_json(&rows)?);
+            } else {
+                out.push_str(&serde_json::to_string_pretty(&rows)?);
+            }
+            out.push('\n');
+        }
+        RosterFormat::Csv => {
+            out.push_str("party_id,endpoint,seq,pk_party_b64,history_len\n");
+            for (pid, e) in roster {
+                let endpoint = csv_field(&e.endpoint);
+                out.push_str(&format!(
+                    "{},{},{},{},{}\n",
+                    pid, endpoint, e.seq, e.pk_party_b64, e.history_len
+                ));
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Drop the parties last seen more than `secs` before `now`.
+fn drop_stale(roster: &mut BTreeMap<u64, state::RosterEntry>, secs: u64, now: u64) {
+    roster.retain(|_, e| e.last_seen_or_created() >= now.saturating_sub(secs));
+}
+
+/// `diff-state` output for the state files at `a` and `b`, and the exit code: 0 if they
+/// agree, 1 if they differ.
+fn diff_state(a: &str, b: &str) -> Result<(String, i32)> {
+    let st_a: state::PartyStateFile = read_json_file(a, "state")?;
+    let st_b: state::PartyStateFile = read_json_file(b, "state")?;
+    let diffs = st_a.diff(&st_b);
+    if diffs.is_empty() {
+        return Ok(("SAME\n".to_string(), 0));
+    }
+    let mut out = format!("DIFFER ({} differences):\n", diffs.len());
+    for d in diffs {
+        out.push_str(&format!("  {d}\n"));
+    }
+    Ok((out, 1))
+}
+
+/// One `ShowRoster --format json` row.
+#[derive(serde::Serialize)]
+struct RosterRow<'a> {
+    party_id: u64,
+    endpoint: &'a str,
+    seq: u64,
+    pk_party_b64: &'a str,
+    history_len: u64,
+}
+
+/// Quote a CSV field if it contains a separator, quote or newline.
+fn csv_field(v: &str) -> String {
+    if v.contains([',', '"', '\n']) {
+        format!("\"{}\"", v.replace('"', "\"\""))
+    } else {
+        v.to_string()
+    }
+}
+
+/// The next snapshot announced on `updates`; never resolves without a subscription. A
+/// failed or closed subscription is logged and dropped, to be renewed next tick.
+async fn next_update(updates: &mut Option<client::SnapshotUpdates>) -> SignedRosterSnapshot {
+    loop {
+        let Some(stream) = updates else {
+            return std::future::pending().await;
+        };
+        match stream.next().await {
+            Some(Ok(srs)) => return srs,
+            Some(Err(e)) => warn!("snapshot subscription failed: {e}"),
+            None => info!("snapshot subscription closed"),
+        }
+        *updates = None;
+    }
+}
+
+/// Bind `Run`'s P2P listener at `p2p_bind`, or `endpoint` if unset, and pick the endpoint
+/// to register: `advertise`, else `endpoint` if something else was bound (see
+/// `advertised_endpoint`).
+async fn bind_p2p_listener(
+    endpoint: &str,
+    p2p_bind: Option<&str>,
+    advertise: Option<&str>,
+) -> Result<(tokio::net::TcpListener, String)> {
+    let listener = p2p::bind_p2p(p2p_bind.unwrap_or(endpoint)).await?;
+    let registered = advertise.or(p2p_bind.map(|_| endpoint));
+    let advertise = advertised_endpoint(listener.local_addr()?, registered)?;
+    Ok((listener, advertise))
+}
+
+/// The endpoint to register for a P2P listener bound at `bound`: `advertise` if given
+/// (a 0 port taking the bound one), otherwise the bound address itself.
+fn advertised_endpoint(bound: SocketAddr, advertise: Option<&str>) -> Result<String> {
+    let endpoint = match advertise {
+        None if bound.ip().is_unspecified() => {
+            return Err(anyhow!(
+                "p2p listener bound to {bound}, which peers can't dial; set --advertise"
+            ))
+        }
+        None => bound.to_string(),
+        Some(adv) => match adv.strip_suffix(":0") {
+            Some(host) => format!("{host}:{}", bound.port()),
+            None => adv.to_string(),
+        },
+    };
+    Endpoint { addr: endpoint.clone() }
+        .validate()
+        .map_err(|e| anyhow!("bad advertised endpoint: {e}"))?;
+    Ok(endpoint)
+}
+
+/// Where `Run` gets its roster from each tick.
+enum RosterSource {
+    Watchtower(client::WatchtowerClient),
+    /// The snapshot of a verified `--roster-file`, whose roster was applied at startup.
+    File(SignedRosterSnapshot),
+}
+
+/// Read a `--roster-file` and verify it under `pk_w`, for `epoch` if given.
+fn load_roster_file(
+    path: &str,
     pk_w: &VerifyingKey,
-    st: &mut state::PartyStateFile,
+    epoch: Option<u64>,
+) -> Result<state::RosterFile> {
+    let rf: state::RosterFile = read_json_file(path, "roster")?;
+    client::verify_snapshot_and_log(pk_w, epoch, &rf.srs, &rf.entries)
+        .map_err(|e| anyhow!("roster file {path} failed verification: {e}"))?;
+    Ok(rf)
+}
+
+/// Verify a membership bundle (a /party response) under `pk_w`, and that it is for `epoch`
+/// and `party_id` where those are given.
+fn check_membership_bundle(
+    pk_w: &VerifyingKey,
+    bundle: &PartyResponse,
+    epoch: Option<u64>,
+    party_id: Option<u64>,
 ) -> Result<()> {
-    let srs = wt.snapshot().await?;
-    // Full fetch 1..log_len so we can recompute Merkle root and verify end-to-end.
-    let k = srs.msg.log_len;
-    let entries = if k == 0 { vec![] } else { wt.entries(1, k).await? };
+    client::verify_party_record(pk_w, bundle)?;
+    let (got_epoch, got_id) = (bundle.srs.msg.epoch, bundle.prr.msg.party_id);
+    if let Some(epoch) = epoch.filter(|&e| e != got_epoch) {
+        return Err(anyhow!("bundle is for epoch={got_epoch}, expected epoch={epoch}"));
+    }
+    if let Some(id) = party_id.filter(|&id| id != got_id) {
+        return Err(anyhow!("bundle is for party_id={got_id}, expected party_id={id}"));
+    }
+    Ok(())
+}
 
-    client::verify_snapshot_and_log(pk_w, &srs, &entries)?;
+/// Check a captured /snapshot response and /entries NDJSON against each other under `pk_w`
+/// (and `epoch`, if given). Returns the report to print: the recomputed root, then PASS;
+/// a failed check is an error naming the root recomputed from the entries.
+fn verify_capture(
+    pk_w: &VerifyingKey,
+    epoch: Option<u64>,
+    snapshot_file: &str,
+    entries_file: &str,
+) -> Result<String> {
+    let sr: SnapshotResponse = read_json_file(snapshot_file, "snapshot")?;
+    let entries = std::fs::read(entries_file)
+        .map_err(|e| anyhow!("failed to read entries file {entries_file}: {e}"))
+        .and_then(|data| client::parse_entries_ndjson(&data))
+        .map_err(|e| anyhow!("malformed entries file {entries_file}: {e}"))?;
 
-    st.current_srs = Some(srs);
-    st.last_log_len = k;
-    st.apply_prrs(&entries);
-    Ok(())
+    let root = client::log_root(sr.srs.msg.hasher()?, &entries)?;
+    let root_b64 = base64::engine::general_purpose::STANDARD.encode(root);
+    client::verify_snapshot_and_log(pk_w, epoch, &sr.srs, &entries)
+        .map_err(|e| anyhow!("FAIL: {e} (recomputed_root_b64: {root_b64})"))?;
+    Ok(format!("recomputed_root_b64: {root_b64}\nPASS\n"))
+}
+
+fn read_json_file<T: serde::de::DeserializeOwned>(path: &str, what: &str) -> Result<T> {
+    let data = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("failed to read {what} file {path}: {e}"))?;
+    serde_json::from_str(&data).map_err(|e| anyhow!("malformed {what} file {path}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::types::{
+        ErrorCode, LogEntry, RegisterRequest, SignedRosterSnapshot, SnapshotMessage,
+        WatchtowerError, SNAPSHOT_MSG_VERSION,
+    };
+    use common::types::PartyRegistrationRecord;
+    use party::testutil::{
+        entry, party_key, party_response, prr, snapshot_of, watchtower_key, FakeWatchtower, EPOCH,
+    };
+
+    #[tokio::test]
+    async fn listener_binds_locally_and_advertises_another_address() {
+        let listener = p2p::bind_p2p("127.0.0.1:0").await.unwrap();
+        let bound = listener.local_addr().unwrap();
+        assert_eq!(advertised_endpoint(bound, None).unwrap(), bound.to_string());
+        let fixed = advertised_endpoint(bound, Some("203.0.113.7:9000")).unwrap();
+        assert_eq!(fixed, "203.0.113.7:9000");
+        let same_port = advertised_endpoint(bound, Some("203.0.113.7:0")).unwrap();
+        assert_eq!(same_port, format!("203.0.113.7:{}", bound.port()));
+
+        let wildcard: SocketAddr = "0.0.0.0:9000".parse().unwrap();
+        let err = advertised_endpoint(wildcard, None).unwrap_err();
+        assert!(err.to_string().contains("set --advertise"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn run_binds_p2p_bind_and_registers_endpoint() {
+        let (listener, registered) =
+            bind_p2p_listener("203.0.113.7:9000", Some("127.0.0.1:0"), None).await.unwrap();
+        assert!(listener.local_addr().unwrap().ip().is_loopback());
+        assert_eq!(registered, "203.0.113.7:9000");
+        let err = bind_p2p_listener("127.0.0.1:0", Some("256.0.0.1:0"), None).await.unwrap_err();
+        assert!(err.to_string().contains("256.0.0.1:0"), "{err}");
+
+        // End to end: what Run registers is --endpoint, not the address it bound.
+        let sent = Arc::new(Mutex::new(None));
+        let capture = sent.clone();
+        let register = move |axum::Json(req): axum::Json<RegisterRequest>| async move {
+            *capture.lock().unwrap() = Some(req);
+            let err = WatchtowerError::new(ErrorCode::BadRequest, "not today");
+            (axum::http::StatusCode::BAD_REQUEST, axum::Json(err))
+        };
+        let app = axum::Router::new().route("/register", axum::routing::post(register));
+        let wt = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", wt.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(wt, app).await });
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let pk_w = ed25519_dalek::SigningKey::from_bytes(&[0xee; 32]).verifying_key();
+        let pk_w = base64::engine::general_purpose::STANDARD.encode(pk_w);
+        let base = ["party", "run", "--watchtower", &url, "--epoch", "1", "--party-id", "1"];
+        let p2p = ["--endpoint", "203.0.113.7:9000", "--p2p-bind", "127.0.0.1:0"];
+        let files = ["--key-file", &path("key.json"), "--state-file", &path("state.json")];
+        let args = [&base[..], &p2p, &files, &["--watchtower-pubkey-b64", &pk_w]].concat();
+        let cmd = Cli::try_parse_from(args).unwrap().cmd;
+        assert!(run(cmd, std::future::pending()).await.is_err());
+        let req = sent.lock().unwrap().take().expect("nothing registered");
+        assert_eq!(req.prr.msg.endpoint.addr, "203.0.113.7:9000");
+    }
+
+    #[test]
+    fn run_and_p2p_serve_take_the_same_p2p_limits() {
+        let limits = |args: &[&str]| match Cli::try_parse_from(args).unwrap().cmd {
+            Command::Run { p2p_limits, .. } | Command::P2pServe { p2p_limits, .. } => {
+                p2p_limits.limits()
+            }
+            _ => panic!("no p2p limits"),
+        };
+        let run = ["party", "run", "--watchtower", "http://wt", "--epoch", "1", "--party-id"];
+        let run = [&run[..], &["1", "--endpoint", "127.0.0.1:0"]].concat();
+        let serve = ["party", "p2p-serve", "--bind", "127.0.0.1:0"];
+        let defaults = p2p::P2pLimits::default();
+        for base in [&run[..], &serve[..]] {
+            let got = limits(base);
+            assert_eq!(got.max_connections, defaults.max_connections);
+            assert_eq!(got.ip_rate_burst, defaults.ip_rate_burst);
+            assert_eq!(got.ip_rate_per_sec, defaults.ip_rate_per_sec);
+            assert_eq!(got.handshake_timeout, defaults.handshake_timeout);
+
+            let set = ["--p2p-max-connections", "8", "--p2p-ip-rate-burst", "2"];
+            let set = [&set[..], &["--p2p-ip-rate-per-sec", "0.5"]].concat();
+            let set = [&set[..], &["--p2p-handshake-timeout-ms", "250"]].concat();
+            let got = limits(&[base, &set[..]].concat());
+            assert_eq!(got.max_connections, 8);
+            assert_eq!(got.ip_rate_burst, 2);
+            assert_eq!(got.ip_rate_per_sec, 0.5);
+            assert_eq!(got.handshake_timeout, Duration::from_millis(250));
+        }
+    }
+
+    /// A local address nothing listens on (just now, at least).
+    fn free_addr() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    /// Handshake as `party_id` with `addr`, retrying while the listener comes up.
+    async fn handshake_when_up(addr: &str, party_id: u64) -> Result<()> {
+        for _ in 0..50 {
+            match p2p::connect_and_handshake(addr, party_id, 1000).await {
+                Err(e) if e.to_string().contains("refused") => {
+                    tokio::time::sleep(Duration::from_millis(20)).await
+                }
+                done => return done,
+            }
+        }
+        Err(anyhow!("nothing listening on {addr}"))
+    }
+
+    #[tokio::test]
+    async fn p2p_serve_answers_handshakes_on_its_bind_address() {
+        let bind = free_addr();
+        let cli = Cli::try_parse_from(["party", "p2p-serve", "--bind", &bind]).unwrap();
+        let server = tokio::spawn(run(cli.cmd, std::future::pending()));
+        handshake_when_up(&bind, 2).await.unwrap();
+        // One connection per handshake; the listener keeps serving.
+        p2p::connect_and_handshake(&bind, 3, 1000).await.unwrap();
+        assert!(!server.is_finished());
+        server.abort();
+    }
+
+    /// Answer handshakes at `addr` as a peer would; returns the handshakes answered so far.
+    async fn handshake_counter(addr: &str) -> Arc<Mutex<u32>> {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let count: Arc<Mutex<u32>> = Arc::default();
+        let answered = count.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                if socket.read_exact(&mut [0u8; 8]).await.is_ok() {
+                    let _ = socket.write_all(b"OK").await;
+                    *answered.lock().unwrap() += 1;
+                }
+            }
+        });
+        count
+    }
+
+    /// Party `n`'s first registration, at `addr`.
+    fn entry_at(n: u8, addr: &str) -> LogEntry {
+        let sk = party_key(n);
+        let mut msg = prr(&sk, n.into(), 1).msg;
+        msg.endpoint.addr = addr.into();
+        let sig_party = common::crypto::sign_struct(&sk, common::crypto::CTX_PRR, &msg).unwrap();
+        PartyRegistrationRecord { msg, sig_party }.into()
+    }
+
+    /// Write a roster file of `entries` under the test watchtower's snapshot.
+    fn write_roster_file(path: &str, entries: Vec<LogEntry>) -> state::RosterFile {
+        let rf = state::RosterFile { srs: snapshot_of(&watchtower_key(), &entries), entries };
+        std::fs::write(path, serde_json::to_string(&rf).unwrap()).unwrap();
+        rf
+    }
+
+    /// `party run` as party 1 at `me`, with its roster from `roster` and state in `dir`.
+    fn roster_run(dir: &std::path::Path, me: &str, roster: &str, extra: &[&str]) -> Command {
+        let pk_w = watchtower_key().verifying_key();
+        let b64 = base64::engine::general_purpose::STANDARD.encode(pk_w);
+        let state_file = dir.join("state.json");
+        let (epoch, state_file) = (EPOCH.to_string(), state_file.to_str().unwrap());
+        let args = ["party", "run", "--epoch", &epoch, "--party-id", "1", "--endpoint", me];
+        let own = ["--roster-file", roster, "--watchtower-pubkey-b64", &b64];
+        let own = own.into_iter().chain(["--state-file", state_file]);
+        let args = args.into_iter().chain(own).chain(extra.iter().copied());
+        Cli::try_parse_from(args).unwrap().cmd
+    }
+
+    #[tokio::test]
+    async fn run_takes_its_roster_from_a_signed_file_and_connects_to_its_peers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let (me, peer) = (free_addr(), free_addr());
+        let handshakes = handshake_counter(&peer).await;
+        let entries = vec![entry_at(1, &me), entry_at(2, &peer)];
+        let rf = write_roster_file(&path("roster.json"), entries);
+        let mut tampered = rf.clone();
+        tampered.entries[1] = entry_at(2, "127.0.0.1:1");
+        std::fs::write(path("tampered.json"), serde_json::to_string(&tampered).unwrap()).unwrap();
+
+        // Refused before anything is bound or dialed.
+        let cmd = roster_run(dir.path(), &me, &path("tampered.json"), &[]);
+        let err = tokio::time::timeout(Duration::from_secs(5), run(cmd, std::future::pending()))
+            .await
+            .expect("a tampered roster file was not refused")
+            .unwrap_err();
+        assert!(err.to_string().contains("failed verification"), "{err}");
+        assert_eq!(*handshakes.lock().unwrap(), 0);
+
+        let cmd = roster_run(dir.path(), &me, &path("roster.json"), &[]);
+        let running = tokio::spawn(run(cmd, std::future::pending()));
+        for _ in 0..250 {
+            if *handshakes.lock().unwrap() > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(*handshakes.lock().unwrap() > 0, "never dialed the peer in the roster file");
+        // And it answers handshakes at its own roster endpoint.
+        p2p::connect_and_handshake(&me, 2, 1000).await.unwrap();
+        running.abort();
+        let st = state::PartyStateFile::load_or_init(&path("state.json"), EPOCH, 1, false).unwrap();
+        assert_eq!(st.roster.len(), 2);
+        assert_eq!(st.current_srs.unwrap().msg, rf.srs.msg);
+    }
+
+    /// Poll the ready file at `path` until its status satisfies `want`.
+    async fn ready_status_when(
+        path: &str,
+        want: impl Fn(&serde_json::Value) -> bool,
+    ) -> serde_json::Value {
+        for _ in 0..250 {
+            let status = std::fs::read_to_string(path).ok();
+            if let Some(status) = status.and_then(|s| serde_json::from_str(&s).ok()) {
+                if want(&status) {
+                    return status;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("ready file never reached the expected status");
+    }
+
+    #[tokio::test]
+    async fn ready_file_tracks_the_mesh_and_its_sentinel_appears_once_all_peers_are_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let (me, up, late) = (free_addr(), free_addr(), free_addr());
+        let entries = vec![entry_at(1, &me), entry_at(2, &up), entry_at(3, &late)];
+        write_roster_file(&path("roster.json"), entries);
+        handshake_counter(&up).await;
+
+        let (ready, sentinel) = (path("ready.json"), path("ready.json.ready"));
+        let extra = ["--ready-file", ready.as_str(), "--interval-secs", "1"];
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let cmd = roster_run(dir.path(), &me, &path("roster.json"), &extra);
+        let running = tokio::spawn(run(cmd, async move {
+            let _ = stopped.await;
+        }));
+        let status = ready_status_when(&ready, |s| s["connected_peers"] == 1).await;
+        assert_eq!(status["epoch"], EPOCH);
+        assert_eq!(status["roster_size"], 3);
+        assert_eq!(status["all_connected"], false);
+        assert!(!std::path::Path::new(&sentinel).exists());
+
+        handshake_counter(&late).await;
+        let status = ready_status_when(&ready, |s| s["all_connected"] == true).await;
+        assert_eq!(status["connected_peers"], 2);
+        assert!(std::path::Path::new(&sentinel).exists());
+
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+        assert!(!std::path::Path::new(&sentinel).exists(), "sentinel outlived the run");
+    }
+
+    #[tokio::test]
+    async fn run_saves_a_well_formed_state_file_on_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let (key_file, state_file) = (path("key.json"), path("state.json"));
+        let watchtower = FakeWatchtower::default().serve(false).await;
+        let pk_w = watchtower_key().verifying_key();
+        let b64 = base64::engine::general_purpose::STANDARD.encode(pk_w);
+        let epoch = EPOCH.to_string();
+        let args = ["party", "run", "--watchtower", &watchtower, "--epoch", &epoch];
+        let args = args.into_iter().chain(["--party-id", "1", "--endpoint", "127.0.0.1:0"]);
+        let extra = ["--key-file", &key_file, "--state-file", &state_file, "--interval-secs", "60"];
+        let args = args.chain(extra).chain(["--watchtower-pubkey-b64", &b64]);
+        let cli = Cli::try_parse_from(args).unwrap();
+
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let stop = async {
+            let _ = stop_rx.await;
+        };
+        let trigger = async {
+            // Let the first tick run and settle into its wait before asking it to stop.
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            std::fs::remove_file(&state_file).unwrap();
+            stop_tx.send(()).unwrap();
+        };
+        let (ran, ()) = tokio::join!(
+            tokio::time::timeout(Duration::from_secs(5), run(cli.cmd, stop)),
+            trigger
+        );
+        ran.expect("run did not stop").unwrap();
+
+        // Written again by the final save, and loads as this party's state.
+        let st = state::PartyStateFile::load_or_init(&state_file, EPOCH, 1, false).unwrap();
+        assert_eq!(st.roster.len(), 1);
+        assert_eq!(st.current_srs.expect("no snapshot saved").msg.log_len, 1);
+        assert_eq!(st.pinned_watchtower_pk_b64, Some(b64));
+    }
+
+    #[tokio::test]
+    async fn co_hosted_gossip_catches_a_snapshot_conflicting_with_the_synced_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let (key_file, state_file) = (path("key.json"), path("state.json"));
+        let watchtower = FakeWatchtower::default().serve(false).await;
+        let pk_w = watchtower_key().verifying_key();
+        let b64 = base64::engine::general_purpose::STANDARD.encode(pk_w);
+        let (epoch, gossip_bind) = (EPOCH.to_string(), free_addr());
+        let args = ["party", "run", "--watchtower", &watchtower, "--epoch", &epoch];
+        let args = args.into_iter().chain(["--party-id", "1", "--endpoint", "127.0.0.1:0"]);
+        let extra = ["--key-file", &key_file, "--state-file", &state_file, "--interval-secs", "60"];
+        let args = args.chain(extra).chain(["--watchtower-pubkey-b64", &b64]);
+        let cli = Cli::try_parse_from(args.chain(["--gossip-bind", &gossip_bind])).unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(run(cli.cmd, async move {
+            let _ = stopped.await;
+        }));
+
+        // Registered and synced: the gossip server holds the snapshot with this party in it.
+        let peer = format!("http://{gossip_bind}");
+        let mut synced = None;
+        for _ in 0..250 {
+            if let Ok(Some(srs)) = gossip::fetch_gossip_snapshot(&peer).await {
+                synced = Some(srs);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let synced = synced.expect("co-hosted gossip server never saw the synced snapshot");
+        assert_eq!(synced.msg.log_len, 1);
+        assert_eq!(gossip::send_gossip(&peer, 2, synced.clone(), None).await.unwrap(), None);
+
+        // The same watchtower, signing another root for the same log.
+        let mut forked = synced.msg.clone();
+        forked.merkle_root = [9; 32];
+        let forked = party::testutil::sign_snapshot(&watchtower_key(), forked);
+        let report = gossip::send_gossip(&peer, 2, forked, None).await.unwrap();
+        let report = report.expect("conflicting gossip was accepted");
+        assert!(report.contains("EQUIVOCATION DETECTED"), "{report}");
+
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn commands_that_sync_take_allow_rollback() {
+        let allowed = |args: &[&str]| match Cli::try_parse_from(args).unwrap().cmd {
+            Command::Register { rollback, .. }
+            | Command::RotateKey { rollback, .. }
+            | Command::Sync { rollback, .. }
+            | Command::Run { rollback, .. } => rollback.allow_rollback,
+            _ => panic!("no --allow-rollback"),
+        };
+        let scope = ["--watchtower", "http://wt", "--epoch", "1", "--party-id", "1"];
+        let endpoint = ["--endpoint", "127.0.0.1:0"];
+        for cmd in [
+            &[&["party", "register"][..], &scope, &endpoint].concat(),
+            &[&["party", "rotate-key", "--new-key-file", "k2"][..], &scope, &endpoint].concat(),
+            &[&["party", "sync"][..], &scope].concat(),
+            &[&["party", "run"][..], &scope, &endpoint].concat(),
+        ] {
+            assert!(!allowed(cmd));
+            assert!(allowed(&[cmd, &["--allow-rollback"][..]].concat()));
+        }
+    }
+
+    #[test]
+    fn roster_exports_as_csv_and_json_sorted_by_party_id() {
+        let entry = |endpoint: &str, seq| state::RosterEntry {
+            party_id: 0,
+            endpoint: endpoint.into(),
+            pk_party_b64: "cGs=".into(),
+            seq,
+            created_at_unix: 0,
+            last_seen_unix: None,
+            history_len: seq,
+        };
+        let roster = BTreeMap::from([
+            (3, entry("10.0.0.3:9000", 1)),
+            (1, entry("[::1]:9000", 2)),
+            (2, entry("a,b:9000", 1)),
+        ]);
+
+        let csv = render_roster(&roster, RosterFormat::Csv, false).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 1 + roster.len());
+        assert_eq!(lines[0], "party_id,endpoint,seq,pk_party_b64,history_len");
+        assert_eq!(lines[1], "1,[::1]:9000,2,cGs=,2");
+        assert_eq!(lines[2], "2,\"a,b:9000\",1,cGs=,1");
+
+        let json = render_roster(&roster, RosterFormat::Json, false).unwrap();
+        let rows: Vec<serde_json::Value> = serde_json::from_str(&json).unwrap();
+        let ids: Vec<_> = rows.iter().map(|r| r["party_id"].as_u64().unwrap()).collect();
+        assert_eq!(ids, [1, 2, 3]);
+        let first = serde_json::json!({
+            "party_id": 1, "endpoint": "[::1]:9000", "seq": 2, "pk_party_b64": "cGs=",
+            "history_len": 2,
+        });
+        assert_eq!(rows[0], first);
+
+        // Canonical JSON holds the same rows with sorted keys, and renders the same
+        // however the roster was built.
+        let canonical = render_roster(&roster, RosterFormat::Json, true).unwrap();
+        assert!(canonical.starts_with(r#"[{"endpoint":"[::1]:9000","history_len":2,"#));
+        assert_eq!(serde_json::from_str::<Vec<serde_json::Value>>(&canonical).unwrap(), rows);
+        let rebuilt: BTreeMap<_, _> = roster.clone().into_iter().rev().collect();
+        assert_eq!(render_roster(&rebuilt, RosterFormat::Json, true).unwrap(), canonical);
+
+        // Text stays the default.
+        let cli = Cli::try_parse_from(["party", "show-roster"]).unwrap();
+        let Command::ShowRoster { format, .. } = cli.cmd else { panic!("not show-roster") };
+        let text = render_roster(&roster, format, false).unwrap();
+        assert!(text.starts_with("roster (party_id -> endpoint, seq):\n  1 -> [::1]:9000"));
+    }
+
+    #[test]
+    fn drop_stale_keeps_only_parties_seen_within_the_window() {
+        let now = 1_700_010_000;
+        let entry = |created_at_unix, last_seen_unix| state::RosterEntry {
+            party_id: 0,
+            endpoint: "10.0.0.1:9000".into(),
+            pk_party_b64: "cGs=".into(),
+            seq: 1,
+            created_at_unix,
+            last_seen_unix,
+            history_len: 1,
+        };
+        let mut roster = BTreeMap::from([
+            (1, entry(now - 7200, Some(now - 3600))),
+            (2, entry(now - 7200, Some(now - 10))),
+            // Not synced since receive times were kept: judged by when it signed.
+            (3, entry(now - 30, None)),
+            (4, entry(now - 7200, None)),
+        ]);
+        drop_stale(&mut roster, 60, now);
+        assert_eq!(roster.keys().copied().collect::<Vec<_>>(), [2, 3]);
+    }
+
+    #[test]
+    fn diff_state_exits_nonzero_only_when_the_files_differ() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let msg = SnapshotMessage {
+            version: SNAPSHOT_MSG_VERSION,
+            epoch: 1,
+            log_len: 2,
+            merkle_root: [3; 32],
+            smt_root: [4; 32],
+            hash_alg: 0,
+            watchtower_pk: [5; 32],
+        };
+        let mut st = state::PartyStateFile::new(1, 1);
+        st.current_srs = Some(SignedRosterSnapshot { msg, sig_watchtower: [0; 64] });
+        st.last_log_len = 2;
+        st.save(&path("a.json")).unwrap();
+        st.save(&path("same.json")).unwrap();
+        st.current_srs.as_mut().unwrap().msg.merkle_root = [6; 32];
+        st.save(&path("forked.json")).unwrap();
+
+        assert_eq!(diff_state(&path("a.json"), &path("same.json")).unwrap(), ("SAME\n".into(), 0));
+        let (report, code) = diff_state(&path("a.json"), &path("forked.json")).unwrap();
+        assert_eq!(code, 1);
+        let root = |b: u8| base64::engine::general_purpose::STANDARD.encode([b; 32]);
+        let expected = format!("  snapshot root: a={} b={}\n", root(3), root(6));
+        assert_eq!(report, format!("DIFFER (1 differences):\n{expected}"));
+        assert!(diff_state(&path("a.json"), &path("missing.json")).is_err());
+    }
+
+    #[test]
+    fn verify_passes_a_matching_capture_and_fails_a_tampered_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let sk_w = watchtower_key();
+        let log = vec![entry(&party_key(1), 1, 1), entry(&party_key(2), 2, 1)];
+        let sr = SnapshotResponse { srs: snapshot_of(&sk_w, &log), finalized: false };
+        std::fs::write(path("snapshot.json"), serde_json::to_string(&sr).unwrap()).unwrap();
+        let write_entries = |name: &str, log: &[LogEntry]| {
+            let lines = log.iter().map(|e| serde_json::to_string(e).unwrap() + "\n");
+            std::fs::write(path(name), lines.collect::<String>()).unwrap();
+        };
+        write_entries("entries.ndjson", &log);
+        let mut tampered = log.clone();
+        tampered[1] = entry(&party_key(3), 2, 1);
+        write_entries("tampered.ndjson", &tampered);
+        let pk_w = sk_w.verifying_key();
+        let verify = |epoch, entries: &str| {
+            verify_capture(&pk_w, epoch, &path("snapshot.json"), &path(entries))
+        };
+
+        let root = base64::engine::general_purpose::STANDARD.encode(sr.srs.msg.merkle_root);
+        let report = verify(Some(EPOCH), "entries.ndjson").unwrap();
+        assert_eq!(report, format!("recomputed_root_b64: {root}\nPASS\n"));
+
+        let err = verify(None, "tampered.ndjson").unwrap_err().to_string();
+        assert!(err.starts_with("FAIL: "), "{err}");
+        assert!(!err.contains(&root), "the tampered log has another root: {err}");
+        let err = verify(Some(EPOCH + 1), "entries.ndjson").unwrap_err().to_string();
+        assert!(err.starts_with("FAIL: ") && err.contains(&root), "{err}");
+        assert!(verify(None, "missing.ndjson").is_err());
+    }
+
+    #[test]
+    fn membership_bundles_are_checked_down_to_proof_leaf_and_signature() {
+        let pk_w = watchtower_key().verifying_key();
+        let log: Vec<LogEntry> = (1..=3).map(|id| entry(&party_key(id as u8), id, 1)).collect();
+        let bundle = party_response(&log, 2).unwrap();
+        check_membership_bundle(&pk_w, &bundle, Some(EPOCH), Some(2)).unwrap();
+        check_membership_bundle(&pk_w, &bundle, None, None).unwrap();
+
+        let refused = |what: &str, tamper: &dyn Fn(&mut PartyResponse)| {
+            let mut bundle = bundle.clone();
+            tamper(&mut bundle);
+            let res = check_membership_bundle(&pk_w, &bundle, Some(EPOCH), Some(2));
+            assert!(res.is_err(), "a bundle with a tampered {what} was accepted");
+        };
+        refused("proof", &|b| b.proof.siblings[0][0] ^= 1);
+        refused("index", &|b| b.index = 1);
+        // A validly signed record that is not the one the proof is for.
+        refused("leaf", &|b| b.prr = prr(&party_key(2), 2, 2));
+        refused("record signature", &|b| b.prr.sig_party[0] ^= 1);
+        refused("snapshot signature", &|b| b.srs.sig_watchtower[0] ^= 1);
+        refused("root", &|b| b.srs.msg.merkle_root[0] ^= 1);
+
+        let other_key = party_key(9).verifying_key();
+        assert!(check_membership_bundle(&other_key, &bundle, None, None).is_err());
+        let err = check_membership_bundle(&pk_w, &bundle, Some(EPOCH + 1), None).unwrap_err();
+        assert!(err.to_string().contains("expected epoch="), "{err}");
+        let err = check_membership_bundle(&pk_w, &bundle, None, Some(3)).unwrap_err();
+        assert!(err.to_string().contains("expected party_id=3"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn keygen_writes_a_loadable_key_and_overwrites_only_with_force() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("key.json").to_str().unwrap().to_string();
+        let keygen = |extra: &[&str]| {
+            let args = ["party", "keygen", "--out", &out];
+            let cli = Cli::try_parse_from(args.iter().chain(extra)).unwrap();
+            run(cli.cmd, std::future::pending())
+        };
+
+        keygen(&[]).await.unwrap();
+        let first = keys::PartyKeys::load(&out, None).unwrap();
+        let saved = std::fs::read(&out).unwrap();
+
+        let err = keygen(&[]).await.unwrap_err();
+        assert!(err.to_string().contains("refusing to overwrite"), "{err}");
+        assert_eq!(std::fs::read(&out).unwrap(), saved);
+
+        keygen(&["--force"]).await.unwrap();
+        let second = keys::PartyKeys::load(&out, None).unwrap();
+        assert_ne!(second.pk, first.pk);
+    }
+
+    #[tokio::test]
+    async fn dry_run_registration_touches_neither_the_watchtower_nor_next_seq() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let (key_file, state_file) = (path("key.json"), path("state.json"));
+        keys::PartyKeys::load_or_create(&key_file, None).unwrap();
+        let mut st = state::PartyStateFile::new(1, 1);
+        st.next_seq = 3;
+        st.save(&state_file).unwrap();
+        let saved = std::fs::read_to_string(&state_file).unwrap();
+
+        let hits: Arc<Mutex<u32>> = Arc::default();
+        let counter = hits.clone();
+        let app = axum::Router::new().fallback(move || async move {
+            *counter.lock().unwrap() += 1;
+            axum::http::StatusCode::SERVICE_UNAVAILABLE
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let args = ["party", "register", "--watchtower", &url, "--epoch", "1", "--party-id", "1"];
+        let extra = ["--endpoint", "10.0.0.1:9000", "--key-file", &key_file];
+        let extra = extra.into_iter().chain(["--state-file", &state_file, "--dry-run"]);
+        let cli = Cli::try_parse_from(args.into_iter().chain(extra)).unwrap();
+        run(cli.cmd, std::future::pending()).await.unwrap();
+
+        assert_eq!(*hits.lock().unwrap(), 0);
+        assert_eq!(std::fs::read_to_string(&state_file).unwrap(), saved);
+    }
+
+    #[test]
+    fn rotate_key_takes_the_current_key_from_env_or_stdin_over_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_file = dir.path().join("party_key.json");
+        let key_file = key_file.to_str().unwrap();
+        let from_file = keys::PartyKeys::load_or_create(key_file, None).unwrap();
+        let other = keys::PartyKeys::from_seed(&[8; 32]);
+        let other_b64 = base64::engine::general_purpose::STANDARD.encode([8; 32]);
+        let var = "PARTY_TEST_ROTATE_KEY_SEED";
+        let env = |name: &str| {
+            if name == var { Ok(other_b64.clone()) } else { Err(std::env::VarError::NotPresent) }
+        };
+
+        let base = ["party", "rotate-key", "--watchtower", "http://wt", "--epoch", "1"];
+        let rest = ["--party-id", "1", "--endpoint", "127.0.0.1:0", "--new-key-file", "k2"];
+        let keys_with = |flags: &[&str], stdin: &str| {
+            let args = [&base[..], &rest, &["--key-file", key_file], flags];
+            let cli = Cli::try_parse_from(args.concat()).unwrap();
+            let Command::RotateKey { key_file, key_passphrase, key_env, key_stdin, .. } = cli.cmd
+            else {
+                panic!("not rotate-key")
+            };
+            let seed = seed_from_env_or_stdin(key_env.as_deref(), key_stdin, env, stdin.as_bytes());
+            existing_party_keys(&key_file, key_passphrase.as_deref(), seed.unwrap()).unwrap()
+        };
+        assert_eq!(keys_with(&["--key-env", var], "").pk, other.pk);
+        assert_eq!(keys_with(&["--key-stdin"], &format!("{other_b64}\n")).pk, other.pk);
+        assert_eq!(keys_with(&[], &other_b64).pk, from_file.pk);
+
+        let both = [&base[..], &rest, &["--key-env", var, "--key-stdin"]];
+        assert!(Cli::try_parse_from(both.concat()).is_err());
+        let missing = dir.path().join("missing.json");
+        assert!(existing_party_keys(missing.to_str().unwrap(), None, None).is_err());
+        assert!(!missing.exists());
+    }
+
+    #[tokio::test]
+    async fn rotate_key_needs_the_old_key_and_drops_the_new_one_when_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let (key_file, new_key_file) = (path("key.json"), path("new_key.json"));
+        keys::PartyKeys::create(&key_file, None).unwrap();
+        let app = axum::Router::new().route(
+            "/register",
+            axum::routing::post(|| async {
+                let err = WatchtowerError::new(ErrorCode::BadSignature, "refused");
+                (axum::http::StatusCode::BAD_REQUEST, axum::Json(err))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let pk_w = ed25519_dalek::SigningKey::from_bytes(&[0xee; 32]).verifying_key();
+        let pk_w = base64::engine::general_purpose::STANDARD.encode(pk_w);
+        let rotate = |key_file: &str| {
+            let base = ["party", "rotate-key", "--watchtower", &url, "--epoch", "1"];
+            let rest = ["--party-id", "1", "--endpoint", "10.0.0.1:9000", "--key-file", key_file];
+            let files = ["--new-key-file", &new_key_file, "--state-file", &path("state.json")];
+            let args = [&base[..], &rest, &files, &["--watchtower-pubkey-b64", &pk_w]].concat();
+            Cli::try_parse_from(args).unwrap().cmd
+        };
+
+        // A mistyped --key-file is an error, not a fresh key to rotate from.
+        let typo = path("kye.json");
+        let err = run(rotate(&typo), std::future::pending()).await.unwrap_err();
+        assert!(err.to_string().contains("no key file"), "{err}");
+        assert!(!std::path::Path::new(&typo).exists());
+        assert!(!std::path::Path::new(&new_key_file).exists());
+
+        // A refused rotation leaves no new key file behind, so it can be retried.
+        for _ in 0..2 {
+            let err = run(rotate(&key_file), std::future::pending()).await.unwrap_err();
+            assert!(format!("{err:#}").contains("BAD_SIGNATURE"), "{err:#}");
+            assert!(!std::path::Path::new(&new_key_file).exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn the_first_key_is_pinned_and_a_conflicting_one_refused_on_later_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json").to_str().unwrap().to_string();
+        let wt = client::WatchtowerClient::new("http://127.0.0.1:1".into()).unwrap();
+        let b64 = |n: u8| {
+            let sk = ed25519_dalek::SigningKey::from_bytes(&[n; 32]);
+            base64::engine::general_purpose::STANDARD.encode(sk.verifying_key())
+        };
+
+        // First run: nothing pinned, so the key is pinned and saved.
+        let mut st = state::PartyStateFile::load_or_init(&path, 1, 1, false).unwrap();
+        load_or_fetch_watchtower_pk(&wt, Some(b64(0xee)), &mut st, false, false).await.unwrap();
+        st.save(&path).unwrap();
+
+        // Later runs: the same key passes, another is refused and leaves the pin alone.
+        let mut st = state::PartyStateFile::load_or_init(&path, 1, 1, false).unwrap();
+        assert_eq!(st.pinned_watchtower_pk_b64, Some(b64(0xee)));
+        load_or_fetch_watchtower_pk(&wt, Some(b64(0xee)), &mut st, false, false).await.unwrap();
+        let err = load_or_fetch_watchtower_pk(&wt, Some(b64(0xdd)), &mut st, false, false)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("does not match pinned key"), "{err}");
+        assert_eq!(st.pinned_watchtower_pk_b64, Some(b64(0xee)));
+
+        // Unless the change is allowed, which re-pins.
+        load_or_fetch_watchtower_pk(&wt, Some(b64(0xdd)), &mut st, true, false).await.unwrap();
+        assert_eq!(st.pinned_watchtower_pk_b64, Some(b64(0xdd)));
+    }
+
+    #[tokio::test]
+    async fn require_pinned_pubkey_refuses_tofu_but_accepts_a_given_or_pinned_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json").to_str().unwrap().to_string();
+        let sk = ed25519_dalek::SigningKey::from_bytes(&[0xee; 32]);
+        let served = base64::engine::general_purpose::STANDARD.encode(sk.verifying_key());
+        let body = served.clone();
+        let app = axum::Router::new()
+            .route("/watchtower_pubkey", axum::routing::get(move || async move { body }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let wt = client::WatchtowerClient::new(url).unwrap();
+
+        // Nothing given or pinned: refused without fetching, and nothing is pinned.
+        let mut st = state::PartyStateFile::load_or_init(&path, 1, 1, false).unwrap();
+        let err = load_or_fetch_watchtower_pk(&wt, None, &mut st, false, true).await.unwrap_err();
+        assert!(err.to_string().contains("--watchtower-pubkey-b64"), "{err}");
+        assert_eq!(st.pinned_watchtower_pk_b64, None);
+
+        // Without the flag the key is fetched (TOFU) and pinned...
+        let pk = load_or_fetch_watchtower_pk(&wt, None, &mut st, false, false).await.unwrap();
+        assert_eq!(pk, sk.verifying_key());
+        assert_eq!(st.pinned_watchtower_pk_b64, Some(served.clone()));
+
+        // ...after which the pin satisfies the flag, even with the watchtower gone.
+        let offline = client::WatchtowerClient::new("http://127.0.0.1:1".into()).unwrap();
+        let pk = load_or_fetch_watchtower_pk(&offline, None, &mut st, false, true).await.unwrap();
+        assert_eq!(pk, sk.verifying_key());
+    }
 }
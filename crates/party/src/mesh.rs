@@ -0,0 +1,437 @@
+use anyhow::{anyhow, Result};
+use axum::{extract::State, routing::get, Json, Router};
+use common::types::PeersResponse;
+use crate::p2p;
+use crate::state::RosterChange;
+use futures::{stream, StreamExt};
+use rand::Rng;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Liveness of one peer, as probed by the Run loop.
+#[derive(Debug, Clone, Default)]
+pub struct PeerLiveness {
+    /// Time of the last successful handshake.
+    pub last_ok: Option<Instant>,
+    pub consecutive_failures: u32,
+    pub connected: bool,
+}
+
+/// A change in a peer's liveness worth logging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    Connected,
+    Disconnected,
+    Reconnected,
+}
+
+impl PeerLiveness {
+    /// Record one probe; a connected peer is demoted after `max_failures` in a row.
+    pub fn record(&mut self, ok: bool, max_failures: u32) -> Option<Transition> {
+        if ok {
+            let transition = match (self.connected, self.last_ok) {
+                (true, _) => None,
+                (false, None) => Some(Transition::Connected),
+                (false, Some(_)) => Some(Transition::Reconnected),
+            };
+            self.last_ok = Some(Instant::now());
+            self.consecutive_failures = 0;
+            self.connected = true;
+            transition
+        } else {
+            self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+            if self.connected && self.consecutive_failures >= max_failures {
+                self.connected = false;
+                return Some(Transition::Disconnected);
+            }
+            None
+        }
+    }
+}
+
+/// party_id -> liveness, shared between the Run loop and /peers.
+pub type PeerTable = Arc<Mutex<HashMap<u64, PeerLiveness>>>;
+
+/// Forget the liveness of peers that moved or left, so a moved peer is re-handshaked at
+/// its current roster endpoint on the next probe.
+pub fn forget_moved(peers: &PeerTable, changes: &[RosterChange]) {
+    let mut peers = peers.lock().unwrap();
+    for change in changes {
+        match change {
+            RosterChange::EndpointChanged { party_id, .. } | RosterChange::Removed { party_id } => {
+                peers.remove(party_id);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct PeersState {
+    pub party_id: u64,
+    pub peers: PeerTable,
+}
+
+pub fn router(state: PeersState) -> Router {
+    Router::new().route("/peers", get(peers)).with_state(state)
+}
+
+async fn peers(State(st): State<PeersState>) -> Json<PeersResponse> {
+    let mut connected: Vec<u64> = st
+        .peers
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, l)| l.connected)
+        .map(|(pid, _)| *pid)
+        .collect();
+    connected.sort_unstable();
+    Json(PeersResponse { party_id: st.party_id, connected })
+}
+
+/// Handshake as `my_party_id` with each `(party_id, endpoint)` in `targets`, up to
+/// `concurrency` at once and each with its own `timeout_ms`. Returns whether each succeeded,
+/// in completion order.
+pub async fn probe(
+    targets: Vec<(u64, String)>,
+    my_party_id: u64,
+    timeout_ms: u64,
+    concurrency: usize,
+) -> Vec<(u64, String, bool)> {
+    stream::iter(targets)
+        .map(|(pid, addr)| async move {
+            let ok = p2p::connect_and_handshake(&addr, my_party_id, timeout_ms).await.is_ok();
+            (pid, addr, ok)
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await
+}
+
+/// Serve /peers on `bind_addr`.
+pub async fn serve_status(bind_addr: &str, state: PeersState) -> Result<()> {
+    let addr: SocketAddr = bind_addr.parse()?;
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("status server listening on {}", addr);
+    axum::serve(listener, router(state)).await?;
+    Ok(())
+}
+
+/// Where a party serves /peers for mesh-status: the host of its roster endpoint, at `port`.
+pub fn peers_addr(endpoint: &str, port: u16) -> Result<String> {
+    let (host, _) = endpoint
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("endpoint {endpoint} is not host:port"))?;
+    Ok(format!("{host}:{port}"))
+}
+
+/// Query /peers of every roster member (party_id -> its /peers address). Peers that
+/// can't be reached, or that answer for a different party_id, map to `None`.
+pub async fn collect(
+    targets: &BTreeMap<u64, String>,
+    timeout: Duration,
+) -> BTreeMap<u64, Option<BTreeSet<u64>>> {
+    let http = reqwest::Client::builder().timeout(timeout).build().unwrap_or_default();
+    let queries = targets.iter().map(|(&pid, addr)| {
+        let http = http.clone();
+        async move {
+            let fetch = async {
+                let url = format!("http://{addr}/peers");
+                let pr: PeersResponse = http.get(url).send().await?.error_for_status()?.json().await?;
+                if pr.party_id != pid {
+                    return Err(anyhow!("peer at {addr} reports party_id={}", pr.party_id));
+                }
+                Ok(pr.connected.into_iter().collect::<BTreeSet<u64>>())
+            };
+            (pid, fetch.await.ok())
+        }
+    });
+    futures::future::join_all(queries).await.into_iter().collect()
+}
+
+/// Render an N×N reachability matrix: row = reporting party, column = peer.
+/// "X" = handshaked, "." = not, "-" = self; unreachable rows are left blank.
+pub fn render_matrix(reach: &BTreeMap<u64, Option<BTreeSet<u64>>>) -> String {
+    let ids: Vec<u64> = reach.keys().copied().collect();
+    let w = ids.iter().map(|id| id.to_string().len()).max().unwrap_or(1).max(4) + 2;
+
+    let label = "from\\to";
+    let lw = label.len().max(w);
+    let mut out = format!("{label:<lw$}");
+    for id in &ids {
+        out.push_str(&format!("{id:>w$}"));
+    }
+    out.push('\n');
+    for (from, row) in reach {
+        out.push_str(&format!("{from:<lw$}"));
+        if let Some(connected) = row {
+            for to in &ids {
+                let cell = if to == from {
+                    "-"
+                } else if connected.contains(to) {
+                    "X"
+                } else {
+                    "."
+                };
+                out.push_str(&format!("{cell:>w$}"));
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Connectivity summary written to `Run --ready-file`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ReadyStatus {
+    pub epoch: u64,
+    pub roster_size: usize,
+    /// Roster peers (other than this party) currently handshaked.
+    pub connected_peers: usize,
+    /// Every roster peer is connected.
+    pub all_connected: bool,
+}
+
+/// Atomically write `status` to `path`, and keep the sentinel `{path}.ready` present
+/// exactly while `status.all_connected`, so scripts can wait for the full mesh.
+pub fn write_ready_file(path: &str, status: &ReadyStatus) -> Result<()> {
+    let tmp = format!("{path}.tmp");
+    std::fs::write(&tmp, serde_json::to_string(status)?)?;
+    std::fs::rename(&tmp, path)?;
+    let sentinel = format!("{path}.ready");
+    if status.all_connected {
+        std::fs::write(&sentinel, b"")?;
+    } else {
+        remove_ready_sentinel(path)?;
+    }
+    Ok(())
+}
+
+/// `interval` plus a uniformly random 0..=`jitter_ms` ms, so parties started together
+/// drift apart instead of polling the watchtower in lockstep.
+pub fn jittered(interval: Duration, jitter_ms: u64, rng: &mut impl Rng) -> Duration {
+    if jitter_ms == 0 {
+        return interval;
+    }
+    interval + Duration::from_millis(rng.gen_range(0..=jitter_ms))
+}
+
+/// Remove the `{path}.ready` sentinel if it exists.
+pub fn remove_ready_sentinel(path: &str) -> Result<()> {
+    match std::fs::remove_file(format!("{path}.ready")) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::PartyStateFile;
+    use common::types::{
+        Endpoint, LogEntry, PartyRegistrationRecord, RegistrationMessage, REGISTRATION_MSG_VERSION,
+    };
+
+    /// Serve /peers for `party_id`, handshaked with `connected`; returns its address.
+    async fn serve_peers(party_id: u64, connected: &[u64]) -> String {
+        let peers: PeerTable = Arc::default();
+        for &pid in connected {
+            peers.lock().unwrap().entry(pid).or_default().record(true, 1);
+        }
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let app = router(PeersState { party_id, peers });
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    #[tokio::test]
+    async fn matrix_shows_a_partial_mesh_with_unreachable_rows_blank() {
+        // 1 and 2 reach each other; 2 also reaches 3, which serves no /peers.
+        let closed = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let targets = BTreeMap::from([
+            (1, serve_peers(1, &[2]).await),
+            (2, serve_peers(2, &[1, 3]).await),
+            (3, closed),
+        ]);
+        let reach = collect(&targets, Duration::from_secs(2)).await;
+        assert_eq!(reach[&1], Some(BTreeSet::from([2])));
+        assert_eq!(reach[&2], Some(BTreeSet::from([1, 3])));
+        assert_eq!(reach[&3], None);
+        let rows: Vec<String> = render_matrix(&reach)
+            .lines()
+            .map(|l| l.split_whitespace().collect::<Vec<_>>().join(" "))
+            .collect();
+        assert_eq!(rows, ["from\\to 1 2 3", "1 - X .", "2 X - X", "3"]);
+
+        // Answering for another party counts as unreachable.
+        let impostor = BTreeMap::from([(4, serve_peers(5, &[]).await)]);
+        assert_eq!(collect(&impostor, Duration::from_secs(2)).await[&4], None);
+    }
+
+    #[tokio::test]
+    async fn probes_run_concurrently_up_to_the_cap() {
+        use futures::FutureExt as _;
+        // A peer that leaves each probe unanswered until it holds `cap` of them, then hangs
+        // up on them all; probes only finish when a full round is in flight, so no clock is
+        // involved. A probe already waiting beyond the cap is an overrun.
+        let cap = 10;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let rounds: Arc<Mutex<Vec<usize>>> = Arc::default();
+        let seen = rounds.clone();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                held.push(socket);
+                if held.len() == cap {
+                    tokio::task::yield_now().await;
+                    while let Some(Ok((extra, _))) = listener.accept().now_or_never() {
+                        held.push(extra);
+                    }
+                    seen.lock().unwrap().push(held.len());
+                    held.clear();
+                }
+            }
+        });
+        let targets: Vec<(u64, String)> = (2..52).map(|pid| (pid, addr.clone())).collect();
+
+        let results = probe(targets, 1, 30_000, cap).await;
+        assert_eq!(results.len(), 50);
+        assert!(results.iter().all(|(_, _, ok)| !ok));
+        // ceil(50 / 10) full rounds, none over the cap.
+        assert_eq!(*rounds.lock().unwrap(), [cap; 5]);
+    }
+
+    /// Answer handshakes as a peer would; returns the address and the handshakes answered.
+    async fn serve_handshakes() -> (String, Arc<Mutex<u32>>) {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let count: Arc<Mutex<u32>> = Arc::default();
+        let answered = count.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                if socket.read_exact(&mut [0u8; 8]).await.is_ok() {
+                    let _ = socket.write_all(b"OK").await;
+                    *answered.lock().unwrap() += 1;
+                }
+            }
+        });
+        (addr, count)
+    }
+
+    /// A registration of `party_id` at `addr`, as taken from an already verified log.
+    fn record(party_id: u64, seq: u64, addr: &str) -> LogEntry {
+        let msg = RegistrationMessage {
+            version: REGISTRATION_MSG_VERSION,
+            epoch: 1,
+            party_id,
+            endpoint: Endpoint { addr: addr.into() },
+            scheme: 0,
+            pk_party: [party_id as u8; 32],
+            seq,
+            nonce: [seq as u8; 16],
+            created_at_unix: 1_700_000_000 + seq,
+            rotation: None,
+        };
+        PartyRegistrationRecord { msg, sig_party: [0; 64] }.into()
+    }
+
+    #[tokio::test]
+    async fn a_moved_peer_is_reprobed_at_its_new_endpoint() {
+        let (old, at_old) = serve_handshakes().await;
+        let (new, at_new) = serve_handshakes().await;
+        let peers: PeerTable = Arc::default();
+        let mut st = PartyStateFile::new(1, 1);
+        // One Run tick: probe every roster peer at its roster endpoint.
+        let tick = |st: &PartyStateFile| {
+            let targets: Vec<(u64, String)> =
+                st.roster.iter().map(|(pid, e)| (*pid, e.endpoint.clone())).collect();
+            let peers = peers.clone();
+            async move {
+                let results = probe(targets, 1, 1000, 4).await;
+                let mut peers = peers.lock().unwrap();
+                results
+                    .into_iter()
+                    .map(|(pid, _, ok)| peers.entry(pid).or_default().record(ok, 3))
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        st.apply_prrs(&[record(2, 1, &old)]);
+        assert_eq!(tick(&st).await, [Some(Transition::Connected)]);
+        let changes = st.apply_prrs(&[record(2, 1, &old), record(2, 2, &new)]);
+        forget_moved(&peers, &changes);
+        assert!(!peers.lock().unwrap().contains_key(&2));
+
+        // Handshaked afresh at the new address, not reported as still connected.
+        assert_eq!(tick(&st).await, [Some(Transition::Connected)]);
+        assert_eq!((*at_old.lock().unwrap(), *at_new.lock().unwrap()), (1, 1));
+    }
+
+    #[test]
+    fn a_peer_is_demoted_after_max_failures_and_reported_back_on_reconnect() {
+        let mut peer = PeerLiveness::default();
+        // Failures before the first handshake are counted but change nothing.
+        assert_eq!(peer.record(false, 3), None);
+        assert_eq!(peer.record(true, 3), Some(Transition::Connected));
+        let first_ok = peer.last_ok.unwrap();
+        assert_eq!((peer.connected, peer.consecutive_failures), (true, 0));
+        assert_eq!(peer.record(true, 3), None);
+
+        // A blip short of the threshold is forgiven by the next success.
+        assert_eq!(peer.record(false, 3), None);
+        assert_eq!(peer.record(false, 3), None);
+        assert_eq!(peer.record(true, 3), None);
+        assert_eq!(peer.consecutive_failures, 0);
+
+        // Down after three in a row, and said so only once.
+        for _ in 0..2 {
+            assert_eq!(peer.record(false, 3), None);
+        }
+        assert_eq!(peer.record(false, 3), Some(Transition::Disconnected));
+        assert!(!peer.connected);
+        assert_eq!(peer.record(false, 3), None);
+        assert_eq!(peer.consecutive_failures, 4);
+        assert!(peer.last_ok.unwrap() >= first_ok);
+
+        // Back up: a reconnect, not a first connect.
+        assert_eq!(peer.record(true, 3), Some(Transition::Reconnected));
+        assert_eq!((peer.connected, peer.consecutive_failures), (true, 0));
+        assert!(peer.last_ok.unwrap() >= first_ok);
+    }
+
+    #[test]
+    fn jittered_sleeps_vary_within_the_band() {
+        use rand::SeedableRng;
+        let interval = Duration::from_secs(5);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let pauses: Vec<Duration> = (0..1000).map(|_| jittered(interval, 250, &mut rng)).collect();
+        let band = interval..=interval + Duration::from_millis(250);
+        assert!(pauses.iter().all(|p| band.contains(p)), "{pauses:?}");
+        // Spread over the band, not stuck at either end.
+        let (min, max) = (pauses.iter().min().unwrap(), pauses.iter().max().unwrap());
+        assert!(*min < interval + Duration::from_millis(25), "{min:?}");
+        assert!(*max > interval + Duration::from_millis(225), "{max:?}");
+        // The same seed gives the same pauses.
+        let mut again = rand::rngs::StdRng::seed_from_u64(42);
+        assert_eq!(jittered(interval, 250, &mut again), pauses[0]);
+        // No jitter by default: the interval as is.
+        assert_eq!(jittered(interval, 0, &mut rng), interval);
+    }
+
+    #[test]
+    fn peers_are_queried_on_their_endpoint_host() {
+        assert_eq!(peers_addr("10.0.0.1:9000", 7000).unwrap(), "10.0.0.1:7000");
+        assert_eq!(peers_addr("[::1]:9000", 7000).unwrap(), "[::1]:7000");
+        assert!(peers_addr("no-port", 7000).is_err());
+    }
+}
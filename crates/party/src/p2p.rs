@@ -1,45 +1,175 @@
+use crate::state::SharedState;
 use anyhow::{anyhow, Result};
+use common::ratelimit::RateLimiter;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tracing::{info, warn};
+use tokio::sync::Semaphore;
+use tracing::{debug, info, warn};
 
-/// Minimal handshake: client sends its party_id as 8 bytes LE.
-/// Server logs incoming connections and replies "OK".
-pub async fn serve_p2p(bind_addr: &str) -> Result<()> {
-    let addr: SocketAddr = bind_addr.parse()?;
-    let listener = TcpListener::bind(addr).await?;
-    info!("p2p listener bound on {}", addr);
+/// Limits on incoming P2P connections. Connections over a limit are closed at once,
+/// without reading from them.
+#[derive(Debug, Clone, Copy)]
+pub struct P2pLimits {
+    /// Connections being served at once.
+    pub max_connections: usize,
+    /// Connections accepted from one IP in a burst.
+    pub ip_rate_burst: u32,
+    /// Sustained connections accepted per IP, per second.
+    pub ip_rate_per_sec: f64,
+    /// Time a peer gets to complete the handshake before its connection is dropped.
+    pub handshake_timeout: Duration,
+}
+
+impl Default for P2pLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: 256,
+            ip_rate_burst: 64,
+            ip_rate_per_sec: 16.0,
+            handshake_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// How often refused connections are reported, so a flood doesn't flood the log too.
+const REFUSED_LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Backoff after a failed accept (e.g. out of file descriptors), instead of spinning.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
 
+/// Bind the P2P listener. Port 0 picks a free port; see `local_addr` for the result.
+pub async fn bind_p2p(bind_addr: &str) -> Result<TcpListener> {
+    let addr: SocketAddr =
+        bind_addr.parse().map_err(|e| anyhow!("bad p2p bind address {bind_addr:?}: {e}"))?;
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| anyhow!("can't bind p2p listener on {addr}: {e}"))?;
+    info!("p2p listener bound on {}", listener.local_addr()?);
+    Ok(listener)
+}
+
+/// Minimal handshake: client sends its party_id as 8 bytes LE.
+/// Server logs incoming connections, checking them against the roster in `state`, and
+/// replies "OK". Connections beyond `limits` are closed unanswered.
+pub async fn serve_p2p(listener: TcpListener, state: SharedState, limits: P2pLimits) -> Result<()> {
+    let slots = Arc::new(Semaphore::new(limits.max_connections));
+    let mut ip_limiter = RateLimiter::new(limits.ip_rate_burst, limits.ip_rate_per_sec);
+    let mut refused = Refused::default();
     loop {
-        let (mut socket, peer_addr) = listener.accept().await?;
+        let (mut socket, peer_addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("p2p accept failed: {e}");
+                tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+        if ip_limiter.check(peer_addr.ip()).is_err() {
+            refused.record(peer_addr, "per-IP rate limit");
+            continue;
+        }
+        let Ok(permit) = slots.clone().try_acquire_owned() else {
+            refused.record(peer_addr, "max connections");
+            continue;
+        };
+        let state = state.clone();
+        let deadline = limits.handshake_timeout;
         tokio::spawn(async move {
-            match handle_incoming(&mut socket, peer_addr).await {
-                Ok(_) => {}
-                Err(e) => warn!("p2p incoming error from {}: {}", peer_addr, e),
+            let _permit = permit;
+            match tokio::time::timeout(deadline, handle_incoming(&mut socket, peer_addr, &state))
+                .await
+            {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => warn!("p2p incoming error from {}: {}", peer_addr, e),
+                Err(_) => warn!("p2p incoming from {peer_addr}: handshake timed out, dropping"),
             }
         });
     }
 }
 
-async fn handle_incoming(socket: &mut TcpStream, peer_addr: SocketAddr) -> Result<()> {
+/// Connections refused since the last report.
+#[derive(Default)]
+struct Refused {
+    count: u64,
+    last_report: Option<Instant>,
+}
+
+impl Refused {
+    fn record(&mut self, peer_addr: SocketAddr, limit: &str) {
+        self.count += 1;
+        let now = Instant::now();
+        if self.last_report.is_some_and(|t| now.duration_since(t) < REFUSED_LOG_INTERVAL) {
+            return;
+        }
+        warn!(
+            "p2p: refused {} connection(s) over limits, latest from {peer_addr} ({limit})",
+            self.count
+        );
+        self.count = 0;
+        self.last_report = Some(now);
+    }
+}
+
+async fn handle_incoming(
+    socket: &mut TcpStream,
+    peer_addr: SocketAddr,
+    state: &SharedState,
+) -> Result<()> {
     let mut buf = [0u8; 8];
     socket.read_exact(&mut buf).await?;
     let remote_party_id = u64::from_le_bytes(buf);
-    info!("p2p incoming: connected from party_id={} ({})", remote_party_id, peer_addr);
+    match roster_endpoint(state, remote_party_id) {
+        // Peers re-probe every tick, so this is per-probe noise at info level.
+        Some(endpoint) => debug!(
+            "p2p incoming: connected from party_id={} ({}), roster endpoint {}",
+            remote_party_id, peer_addr, endpoint
+        ),
+        // Likely a peer that registered after our last sync.
+        None => warn!(
+            "p2p incoming: party_id={} ({}) is not in our roster",
+            remote_party_id, peer_addr
+        ),
+    }
 
     socket.write_all(b"OK").await?;
     Ok(())
 }
 
+/// `party_id`'s endpoint in the roster as the `Run` loop last synced it.
+fn roster_endpoint(state: &SharedState, party_id: u64) -> Option<String> {
+    state.lock().unwrap().roster.get(&party_id).map(|e| e.endpoint.clone())
+}
+
 /// Attempt a TCP connection to `addr` and send `my_party_id` as handshake.
+/// Hostnames are resolved here and each resolved address is tried in turn, each with
+/// its own `timeout_ms`; the handshake on a connected address gets `timeout_ms` too.
 /// Returns Ok(()) on success.
 pub async fn connect_and_handshake(addr: &str, my_party_id: u64, timeout_ms: u64) -> Result<()> {
-    let fut = TcpStream::connect(addr);
-    let mut stream = tokio::time::timeout(std::time::Duration::from_millis(timeout_ms), fut)
+    let timeout = std::time::Duration::from_millis(timeout_ms);
+    let resolved: Vec<SocketAddr> = tokio::time::timeout(timeout, tokio::net::lookup_host(addr))
         .await
-        .map_err(|_| anyhow!("connect timeout"))??;
+        .map_err(|_| anyhow!("resolve timeout for {addr}"))??
+        .collect();
 
+    let mut last_err = anyhow!("{addr} resolved to no addresses");
+    for sa in resolved {
+        match tokio::time::timeout(timeout, TcpStream::connect(sa)).await {
+            Ok(Ok(stream)) => {
+                return tokio::time::timeout(timeout, handshake(stream, my_party_id))
+                    .await
+                    .map_err(|_| anyhow!("handshake timeout with {sa}"))?
+            }
+            Ok(Err(e)) => last_err = anyhow!("connect to {sa} failed: {e}"),
+            Err(_) => last_err = anyhow!("connect timeout to {sa}"),
+        }
+    }
+    Err(last_err)
+}
+
+async fn handshake(mut stream: TcpStream, my_party_id: u64) -> Result<()> {
     // Send my party_id
     stream.write_all(&my_party_id.to_le_bytes()).await?;
 
@@ -51,3 +181,139 @@ pub async fn connect_and_handshake(addr: &str, my_party_id: u64, timeout_ms: u64
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::PartyStateFile;
+    use crate::testutil::{entry, party_key, snapshot_of, watchtower_key, EPOCH};
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn hostnames_resolve_at_connect_time() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket.read_exact(&mut [0u8; 8]).await.unwrap();
+            socket.write_all(b"OK").await.unwrap();
+        });
+        connect_and_handshake(&format!("localhost:{}", addr.port()), 2, 1000).await.unwrap();
+        let err = connect_and_handshake("no-such-host.invalid:9000", 2, 1000).await.unwrap_err();
+        assert!(!err.to_string().contains("handshake"), "{err}");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn server_sees_the_roster_as_the_loop_updates_it() {
+        let shared: SharedState = Arc::new(Mutex::new(PartyStateFile::new(EPOCH, 1)));
+        let listener = bind_p2p("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server_state = shared.clone();
+        tokio::spawn(serve_p2p(listener, server_state.clone(), P2pLimits::default()));
+
+        // Party 2 registered after our last sync: it's let in, but not in the roster yet.
+        connect_and_handshake(&addr, 2, 2000).await.unwrap();
+        assert_eq!(roster_endpoint(&server_state, 2), None);
+
+        let log: Vec<_> = (1..=2).map(|n| entry(&party_key(n), n.into(), 1)).collect();
+        shared.lock().unwrap().apply_verified(snapshot_of(&watchtower_key(), &log), &log);
+        assert_eq!(roster_endpoint(&server_state, 2).as_deref(), Some("10.0.0.2:9000"));
+
+        // The loop rewriting the state doesn't stall handshakes, nor they the loop.
+        let writer = tokio::spawn(async move {
+            for len in (1..=2).cycle().take(200) {
+                let log = &log[..len];
+                shared.lock().unwrap().apply_verified(snapshot_of(&watchtower_key(), log), log);
+                tokio::task::yield_now().await;
+            }
+        });
+        let handshakes = (0..32).map(|pid| connect_and_handshake(&addr, pid, 2000));
+        for result in futures::future::join_all(handshakes).await {
+            result.unwrap();
+        }
+        writer.await.unwrap();
+        assert_eq!(roster_endpoint(&server_state, 2).as_deref(), Some("10.0.0.2:9000"));
+    }
+
+    /// Serve an empty state under `limits`; returns the address.
+    async fn serve(limits: P2pLimits) -> SocketAddr {
+        let shared: SharedState = Arc::new(Mutex::new(PartyStateFile::new(EPOCH, 1)));
+        let listener = bind_p2p("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_p2p(listener, shared, limits));
+        addr
+    }
+
+    /// Whether the server closes `conn` within a second without answering.
+    async fn closed_unanswered(conn: &mut TcpStream) -> bool {
+        let mut buf = [0u8; 2];
+        let read = tokio::time::timeout(Duration::from_secs(1), conn.read(&mut buf)).await;
+        matches!(read, Ok(Ok(0)) | Ok(Err(_)))
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_cap_are_closed_and_the_server_stays_responsive() {
+        let limits = P2pLimits { max_connections: 4, ..P2pLimits::default() };
+        let addr = serve(limits).await;
+        // Connect and send nothing, holding a slot each.
+        let mut stalled = Vec::new();
+        for _ in 0..4 {
+            stalled.push(TcpStream::connect(addr).await.unwrap());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut flood = Vec::new();
+        for _ in 0..16 {
+            flood.push(TcpStream::connect(addr).await.unwrap());
+        }
+        for conn in &mut flood {
+            assert!(closed_unanswered(conn).await, "served over the cap");
+        }
+        // Freed slots are reused at once.
+        drop(stalled);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        connect_and_handshake(&addr.to_string(), 2, 1000).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_per_ip_rate_are_closed() {
+        let limits = P2pLimits { ip_rate_burst: 3, ip_rate_per_sec: 0.001, ..P2pLimits::default() };
+        let addr = serve(limits).await.to_string();
+        for pid in 2..5 {
+            connect_and_handshake(&addr, pid, 1000).await.unwrap();
+        }
+        let mut conn = TcpStream::connect(&addr).await.unwrap();
+        assert!(closed_unanswered(&mut conn).await, "served over the rate");
+    }
+
+    #[tokio::test]
+    async fn silent_peers_are_dropped_at_the_handshake_deadline() {
+        let limits = P2pLimits {
+            max_connections: 1,
+            handshake_timeout: Duration::from_millis(200),
+            ..P2pLimits::default()
+        };
+        let addr = serve(limits).await;
+        let start = Instant::now();
+        let mut silent = TcpStream::connect(addr).await.unwrap();
+        assert!(closed_unanswered(&mut silent).await, "silent peer kept");
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        // Its slot, the only one, is free again.
+        connect_and_handshake(&addr.to_string(), 2, 1000).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_server_that_never_acks_times_out_the_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                held.push(socket);
+            }
+        });
+        let start = Instant::now();
+        let err = connect_and_handshake(&addr, 2, 200).await.unwrap_err();
+        assert!(err.to_string().contains("handshake timeout"), "{err}");
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,252 @@
+use crate::client::{verify_party_record, verify_prr_signatures, verify_receipt, WatchtowerClient};
+use crate::keys::PartyKeys;
+use crate::state::PartyStateFile;
+use anyhow::{anyhow, Context, Result};
+use common::crypto::{sign_struct, Ed25519, SignatureScheme, CTX_PRR, CTX_ROTATION};
+use common::types::{
+    Endpoint, ErrorCode, KeyRotation, PartyRegistrationRecord, RegistrationMessage,
+    REGISTRATION_MSG_VERSION,
+};
+use ed25519_dalek::VerifyingKey;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Register `keys` at `endpoint` under the party's next seq, verify the receipt, and
+/// record it (and the snapshot it came with) in `st`. If the watchtower rejects the seq as
+/// already used, `next_seq` is resynced from its record of this party and the registration
+/// is tried once more.
+pub async fn register_self(
+    wt: &WatchtowerClient,
+    pk_w: &VerifyingKey,
+    keys: &PartyKeys,
+    st: &mut PartyStateFile,
+    endpoint: String,
+) -> Result<()> {
+    submit_registration(wt, pk_w, keys, st, |st| registration_message(keys, st, endpoint.clone()))
+        .await
+}
+
+/// Register under `new_keys`, with `old_keys` endorsing the rotation.
+pub async fn rotate_self(
+    wt: &WatchtowerClient,
+    pk_w: &VerifyingKey,
+    old_keys: &PartyKeys,
+    new_keys: &PartyKeys,
+    st: &mut PartyStateFile,
+    endpoint: String,
+) -> Result<()> {
+    submit_registration(wt, pk_w, new_keys, st, |st| {
+        let mut msg = registration_message(new_keys, st, endpoint.clone())?;
+        let old_pk = old_keys.pk.to_bytes();
+        let sig_old = sign_struct(&old_keys.sk, CTX_ROTATION, &msg.rotation_message(old_pk))?;
+        msg.rotation = Some(KeyRotation { old_pk, sig_old });
+        Ok(msg)
+    })
+    .await
+}
+
+/// The (unsigned) registration message for `keys` at `endpoint`, using `st`'s next seq.
+pub fn registration_message(
+    keys: &PartyKeys,
+    st: &PartyStateFile,
+    endpoint: String,
+) -> Result<RegistrationMessage> {
+    let seq = st.next_seq;
+    let endpoint = Endpoint { addr: endpoint };
+    endpoint.validate()?;
+
+    let mut nonce = [0u8; 16];
+    OsRng.fill_bytes(&mut nonce);
+
+    let msg = RegistrationMessage {
+        version: REGISTRATION_MSG_VERSION,
+        epoch: st.epoch,
+        party_id: st.party_id,
+        endpoint,
+        scheme: Ed25519::TAG,
+        pk_party: keys.pk.to_bytes(),
+        seq,
+        nonce,
+        created_at_unix: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        rotation: None,
+    };
+    Ok(msg)
+}
+
+/// Sign `msg` with `keys` and check the record's signatures as the watchtower would.
+pub fn sign_registration(
+    keys: &PartyKeys,
+    msg: RegistrationMessage,
+) -> Result<PartyRegistrationRecord> {
+    let sig_party = sign_struct(&keys.sk, CTX_PRR, &msg)?;
+    let prr = PartyRegistrationRecord { msg, sig_party };
+    verify_prr_signatures(&prr)?;
+    Ok(prr)
+}
+
+/// Raise `st.next_seq` past the seq of the watchtower's latest record for this party,
+/// after checking that record's signatures and proofs. Returns the record's seq, or `None`
+/// (leaving `next_seq` alone) if the watchtower has no record for the party.
+pub async fn resync_next_seq(
+    wt: &WatchtowerClient,
+    pk_w: &VerifyingKey,
+    st: &mut PartyStateFile,
+) -> Result<Option<u64>> {
+    let Some(resp) = wt.party(st.party_id).await? else {
+        return Ok(None);
+    };
+    verify_party_record(pk_w, &resp)?;
+    let msg = &resp.prr.msg;
+    if msg.epoch != st.epoch || msg.party_id != st.party_id {
+        return Err(anyhow!(
+            "asked for party_id={} in epoch={}, got a record for party_id={} in epoch={}",
+            st.party_id,
+            st.epoch,
+            msg.party_id,
+            msg.epoch
+        ));
+    }
+    st.next_seq = st.next_seq.max(msg.seq.saturating_add(1));
+    Ok(Some(msg.seq))
+}
+
+/// Sign and submit the message `build` makes from `st`. On a SEQ_NOT_INCREASING rejection,
+/// resync `next_seq` and submit a rebuilt message once more.
+async fn submit_registration(
+    wt: &WatchtowerClient,
+    pk_w: &VerifyingKey,
+    keys: &PartyKeys,
+    st: &mut PartyStateFile,
+    build: impl Fn(&PartyStateFile) -> Result<RegistrationMessage>,
+) -> Result<()> {
+    let mut prr = sign_registration(keys, build(st)?)?;
+
+    let resp = match wt.register(prr.clone()).await {
+        Ok(resp) => resp,
+        Err(e) if e.watchtower_error().is_some_and(|we| we.code == ErrorCode::SeqNotIncreasing) => {
+            // The watchtower already has this seq from us: the state file was lost or
+            // restored from a backup, or an accepted registration's response never arrived.
+            let rejected = prr.msg.seq;
+            let resynced = resync_next_seq(wt, pk_w, st).await.and_then(|last| {
+                last.ok_or_else(|| anyhow!("the watchtower has no record for this party"))
+            });
+            let last = match resynced {
+                Ok(last) => last,
+                Err(resync) => {
+                    return Err(anyhow::Error::from(e).context(format!(
+                        "registration with seq={rejected} rejected, and resyncing next_seq \
+                         failed: {resync:#}"
+                    )))
+                }
+            };
+            warn!(
+                "registration with seq={rejected} rejected; the watchtower has seq={last}, \
+                 retrying with seq={}",
+                st.next_seq
+            );
+            prr = sign_registration(keys, build(st)?)?;
+            wt.register(prr.clone()).await.with_context(|| {
+                format!("registration retried with seq={} failed", prr.msg.seq)
+            })?
+        }
+        Err(e) => {
+            let context = format!("registration with seq={} failed", prr.msg.seq);
+            return Err(anyhow::Error::from(e).context(context));
+        }
+    };
+    verify_receipt(pk_w, &prr, &resp.srs, &resp.receipt)?;
+    let index = resp.receipt.receipt.assigned_index;
+    info!("registration accepted at log index {index}");
+    st.current_srs = Some(resp.srs);
+    st.my_last_index = Some(index);
+    st.last_receipt = Some(resp.receipt);
+
+    // Advance sequence for next re-register/update.
+    st.next_seq = st.next_seq.saturating_add(1);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::RetryPolicy;
+    use crate::testutil::{entry, party_key, watchtower_key, FakeWatchtower, EPOCH};
+    use std::time::Duration;
+
+    /// A client for `wt` (serving /party only if `lookup`) that doesn't retry.
+    async fn serve(wt: &FakeWatchtower, lookup: bool) -> WatchtowerClient {
+        let retry = RetryPolicy {
+            max_attempts: 1,
+            base_delay: Duration::from_millis(1),
+            jitter: Duration::ZERO,
+        };
+        WatchtowerClient::new_with_retry(wt.serve(lookup).await, retry).unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_seq_the_watchtower_already_has_is_resynced_and_retried() {
+        // Registered up to seq=3 from elsewhere; this state file only knows seq=1.
+        let log = (1..=3).map(|seq| entry(&party_key(1), 1, seq)).collect();
+        let fake = FakeWatchtower::with_log(log);
+        let wt = serve(&fake, true).await;
+        let keys = PartyKeys::from_seed(&[1; 32]);
+        let pk_w = watchtower_key().verifying_key();
+        let mut st = PartyStateFile::new(EPOCH, 1);
+        st.next_seq = 2;
+
+        register_self(&wt, &pk_w, &keys, &mut st, "10.0.0.1:9001".into()).await.unwrap();
+        let last = fake.log.lock().unwrap().last().unwrap().seq();
+        assert_eq!((last, st.next_seq), (4, 5));
+        assert_eq!(st.my_last_index, Some(4));
+    }
+
+    #[tokio::test]
+    async fn a_failed_resync_keeps_both_reasons_and_next_seq() {
+        // The watchtower refuses the seq, but can't be asked for its record of the party.
+        let fake = FakeWatchtower::with_log(vec![entry(&party_key(2), 2, 1)]);
+        let wt = serve(&fake, false).await;
+        let keys = PartyKeys::from_seed(&[2; 32]);
+        let pk_w = watchtower_key().verifying_key();
+        let mut st = PartyStateFile::new(EPOCH, 2);
+
+        let err = register_self(&wt, &pk_w, &keys, &mut st, "10.0.0.2:9000".into()).await;
+        let err = format!("{:#}", err.unwrap_err());
+        assert!(err.contains("rejected, and resyncing next_seq failed"), "{err}");
+        assert!(err.contains("no record for this party"), "{err}");
+        assert!(err.contains("SEQ_NOT_INCREASING"), "{err}");
+        assert_eq!(st.next_seq, 1);
+        assert_eq!(fake.log.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn resync_corrects_a_stale_state_file_so_the_next_register_succeeds() {
+        let log = (1..=3).map(|seq| entry(&party_key(1), 1, seq)).collect();
+        let fake = FakeWatchtower::with_log(log);
+        let pk_w = watchtower_key().verifying_key();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let path = path.to_str().unwrap();
+        PartyStateFile::new(EPOCH, 1).save(path).unwrap();
+
+        let mut st = PartyStateFile::load_or_init(path, EPOCH, 1, false).unwrap();
+        let wt = serve(&fake, true).await;
+        assert_eq!(resync_next_seq(&wt, &pk_w, &mut st).await.unwrap(), Some(3));
+        st.save(path).unwrap();
+        let mut st = PartyStateFile::load_or_init(path, EPOCH, 1, false).unwrap();
+        assert_eq!(st.next_seq, 4);
+
+        // Accepted first time: this watchtower couldn't be asked to resync again.
+        let keys = PartyKeys::from_seed(&[1; 32]);
+        let wt = serve(&fake, false).await;
+        register_self(&wt, &pk_w, &keys, &mut st, "10.0.0.1:9000".into()).await.unwrap();
+        assert_eq!(fake.log.lock().unwrap().last().unwrap().seq(), 4);
+
+        // A party the watchtower has never seen keeps its next_seq.
+        let wt = serve(&fake, true).await;
+        let mut other = PartyStateFile::new(EPOCH, 9);
+        assert_eq!(resync_next_seq(&wt, &pk_w, &mut other).await.unwrap(), None);
+        assert_eq!(other.next_seq, 1);
+    }
+}
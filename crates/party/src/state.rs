@@ -1,17 +1,116 @@
-use anyhow::Result;
-use common::types::{PartyRegistrationRecord, SignedRosterSnapshot};
+use anyhow::{anyhow, Result};
+use common::types::{
+    LogEntry, PartyRegistrationRecord, RosterResponse, SignedRegistrationReceipt,
+    SignedRosterSnapshot, SnapshotMessage, SNAPSHOT_MSG_VERSION,
+};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs;
+use std::sync::{Arc, Mutex};
+use tracing::warn;
 use base64::Engine as _;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RosterEntry {
+    /// Also the entry's key in `PartyStateFile::roster`. Missing from state saved before
+    /// it was recorded; loading fills it in from the key.
+    #[serde(default)]
+    pub party_id: u64,
     pub endpoint: String,
     pub pk_party_b64: String,
     pub seq: u64,
+    /// When the party signed its latest registration (unix seconds).
+    #[serde(default)]
+    pub created_at_unix: u64,
+    /// When the watchtower received the party's latest record (unix seconds), as its
+    /// unsigned /roster reports it. Advisory; `None` until a sync fetched it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen_unix: Option<u64>,
+    /// How many of the party's registrations the log holds, superseded and compacted ones
+    /// included. A high count can mean churn, or someone re-registering it. 0 in state
+    /// saved before this was counted, until the next sync.
+    #[serde(default)]
+    pub history_len: u64,
+}
+
+impl RosterEntry {
+    /// When the party was last heard from: the watchtower's receive time if known,
+    /// otherwise when the party signed its latest record.
+    pub fn last_seen_or_created(&self) -> u64 {
+        self.last_seen_unix.unwrap_or(self.created_at_unix)
+    }
+}
+
+/// What a light sync checks the watchtower against: the accepted snapshot and this party's
+/// latest record in it, at 1-indexed log position `index`.
+#[derive(Debug, Clone)]
+pub struct LightBase {
+    pub srs: SignedRosterSnapshot,
+    pub party_id: u64,
+    pub index: u64,
+    pub own: RosterEntry,
+}
+
+/// A roster delta produced by `apply_prrs`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RosterChange {
+    Added { party_id: u64, endpoint: String, seq: u64 },
+    EndpointChanged { party_id: u64, old: String, new: String, seq: u64 },
+    /// Newer record with the same endpoint (e.g. a refresh or key rotation).
+    SeqAdvanced { party_id: u64, seq: u64 },
+    /// The party no longer appears in the watchtower's log.
+    Removed { party_id: u64 },
+}
+
+impl fmt::Display for RosterChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RosterChange::Added { party_id, endpoint, seq } => {
+                write!(f, "party_id={party_id} joined at {endpoint} (seq={seq})")
+            }
+            RosterChange::EndpointChanged { party_id, old, new, seq } => {
+                write!(f, "party_id={party_id} moved {old} -> {new} (seq={seq})")
+            }
+            RosterChange::SeqAdvanced { party_id, seq } => {
+                write!(f, "party_id={party_id} re-registered (seq={seq})")
+            }
+            RosterChange::Removed { party_id } => write!(f, "party_id={party_id} left the roster"),
+        }
+    }
 }
 
+/// Two snapshots the watchtower signed for one epoch, where the one served later commits
+/// to a shorter log than the one the party had already accepted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RollbackEvidence {
+    pub accepted: SignedRosterSnapshot,
+    pub served: SignedRosterSnapshot,
+}
+
+impl fmt::Display for RollbackEvidence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ROLLBACK DETECTED: epoch={}, accepted log_len={}, served log_len={}. \
+             Both signed snapshots are kept in the state file as rollback_evidence.",
+            self.accepted.msg.epoch, self.accepted.msg.log_len, self.served.msg.log_len
+        )
+    }
+}
+
+/// A signed snapshot with the full log it commits to, for running from a pre-distributed
+/// roster (`--roster-file`) instead of a live watchtower. `fetch-roster --save` writes one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RosterFile {
+    pub srs: SignedRosterSnapshot,
+    pub entries: Vec<LogEntry>,
+}
+
+/// Party state shared between the `Run` loop and the servers it spawns. The loop is the
+/// only writer and the only one that saves it.
+pub type SharedState = Arc<Mutex<PartyStateFile>>;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PartyStateFile {
     pub epoch: u64,
@@ -20,7 +119,9 @@ pub struct PartyStateFile {
     /// Next seq to use if this party registers/updates again.
     pub next_seq: u64,
 
-    /// Last seen watchtower snapshot.
+    /// Last seen watchtower snapshot. Dropped on load if it is in a layout this build
+    /// can't verify, so the next sync starts over from the watchtower's current one.
+    #[serde(deserialize_with = "stale_as_none")]
     pub current_srs: Option<SignedRosterSnapshot>,
 
     /// Local cached log length.
@@ -31,6 +132,28 @@ pub struct PartyStateFile {
 
     /// For debugging: last fetched PRRs count.
     pub last_entries_count: usize,
+
+    /// For debugging: how many of the last fetched PRRs were superseded by a later record
+    /// of the same party (or repeated).
+    #[serde(default)]
+    pub last_superseded_count: usize,
+
+    /// Watchtower pubkey (base64) pinned on first use.
+    #[serde(default)]
+    pub pinned_watchtower_pk_b64: Option<String>,
+
+    /// Receipt for this party's latest accepted registration.
+    #[serde(default, deserialize_with = "stale_as_none")]
+    pub last_receipt: Option<SignedRegistrationReceipt>,
+
+    /// 1-indexed log position of this party's latest accepted registration, for fetching
+    /// its /merkle_proof. Refreshed by each full sync, so it follows log compactions.
+    #[serde(default)]
+    pub my_last_index: Option<u64>,
+
+    /// The first log rollback seen from the watchtower, if any.
+    #[serde(default, deserialize_with = "stale_as_none")]
+    pub rollback_evidence: Option<RollbackEvidence>,
 }
 
 impl PartyStateFile {
@@ -43,29 +166,238 @@ impl PartyStateFile {
             last_log_len: 0,
             roster: HashMap::new(),
             last_entries_count: 0,
+            last_superseded_count: 0,
+            pinned_watchtower_pk_b64: None,
+            last_receipt: None,
+            my_last_index: None,
+            rollback_evidence: None,
         }
     }
 
-    pub fn load_or_init(path: &str, epoch: u64, party_id: u64) -> Result<Self> {
+    /// Load the state file at `path`, or start fresh if it doesn't exist.
+    ///
+    /// If the file belongs to a different epoch/party_id, this is an error unless
+    /// `reset` is set, in which case a fresh state is returned that keeps the old
+    /// `next_seq` as a floor so sequence numbers never go backwards.
+    pub fn load_or_init(path: &str, epoch: u64, party_id: u64, reset: bool) -> Result<Self> {
         if let Ok(data) = fs::read_to_string(path) {
             let mut st: PartyStateFile = serde_json::from_str(&data)?;
-            // If user changes epoch/party_id, reset the state to avoid confusion.
+            for (pid, entry) in st.roster.iter_mut() {
+                entry.party_id = *pid;
+            }
             if st.epoch != epoch || st.party_id != party_id {
-                st = Self::new(epoch, party_id);
+                if !reset {
+                    return Err(anyhow!(
+                        "state file {path} is for epoch={} party_id={}, but got epoch={epoch} party_id={party_id}. \
+                         Pass --reset to start a fresh state (next_seq is preserved).",
+                        st.epoch,
+                        st.party_id
+                    ));
+                }
+                let mut fresh = Self::new(epoch, party_id);
+                fresh.next_seq = fresh.next_seq.max(st.next_seq);
+                fresh.pinned_watchtower_pk_b64 = st.pinned_watchtower_pk_b64;
+                return Ok(fresh);
             }
-            Ok(st)
+            Ok(st.without_stale_snapshots())
         } else {
             Ok(Self::new(epoch, party_id))
         }
     }
 
+    /// Drop a saved snapshot or receipt signed in an older snapshot layout: it can't be
+    /// verified or extended any more. The next sync fetches the watchtower's current one.
+    fn without_stale_snapshots(mut self) -> Self {
+        let stale = |msg: &SnapshotMessage| msg.version != SNAPSHOT_MSG_VERSION;
+        if self.current_srs.as_ref().is_some_and(|srs| stale(&srs.msg)) {
+            warn!("dropping saved snapshot in an older layout; the next sync starts over");
+            self.current_srs = None;
+        }
+        if self.last_receipt.as_ref().is_some_and(|r| stale(&r.receipt.snapshot_after)) {
+            self.last_receipt = None;
+        }
+        self
+    }
+
+    /// Describe how `other` differs from `self` in epoch, log length, snapshot root and
+    /// roster entries, one line per difference. Empty if the views agree.
+    pub fn diff(&self, other: &PartyStateFile) -> Vec<String> {
+        let b64 = |v: &[u8]| base64::engine::general_purpose::STANDARD.encode(v);
+        let mut out = Vec::new();
+        if self.epoch != other.epoch {
+            out.push(format!("epoch: a={} b={}", self.epoch, other.epoch));
+        }
+        if self.last_log_len != other.last_log_len {
+            out.push(format!("log_len: a={} b={}", self.last_log_len, other.last_log_len));
+        }
+        let root = |st: &PartyStateFile| match &st.current_srs {
+            Some(srs) => b64(&srs.msg.merkle_root),
+            None => "none".to_string(),
+        };
+        let (root_a, root_b) = (root(self), root(other));
+        if root_a != root_b {
+            out.push(format!("snapshot root: a={root_a} b={root_b}"));
+        }
+
+        let mut pids: Vec<u64> = self.roster.keys().chain(other.roster.keys()).copied().collect();
+        pids.sort_unstable();
+        pids.dedup();
+        for pid in pids {
+            match (self.roster.get(&pid), other.roster.get(&pid)) {
+                (Some(_), None) => out.push(format!("party_id={pid}: only in a")),
+                (None, Some(_)) => out.push(format!("party_id={pid}: only in b")),
+                (Some(a), Some(b)) => {
+                    if a.endpoint != b.endpoint {
+                        out.push(format!(
+                            "party_id={pid} endpoint: a={} b={}",
+                            a.endpoint, b.endpoint
+                        ));
+                    }
+                    if a.seq != b.seq {
+                        out.push(format!("party_id={pid} seq: a={} b={}", a.seq, b.seq));
+                    }
+                    if a.pk_party_b64 != b.pk_party_b64 {
+                        out.push(format!(
+                            "party_id={pid} pk_party_b64: a={} b={}",
+                            a.pk_party_b64, b.pk_party_b64
+                        ));
+                    }
+                }
+                (None, None) => {}
+            }
+        }
+        out
+    }
+
+    /// Write the state to `path` atomically (temp file + rename), so an interrupted save
+    /// leaves the previous file intact.
     pub fn save(&self, path: &str) -> Result<()> {
-        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        let tmp = format!("{path}.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp, path)?;
         Ok(())
     }
 
-    pub fn apply_prrs(&mut self, prrs: &[PartyRegistrationRecord]) {
-        for prr in prrs {
+    /// Refuse a verified snapshot that rolls the log back: same epoch as the accepted
+    /// snapshot but a shorter log_len. The first rollback is recorded in
+    /// `rollback_evidence`, also when `allow_rollback` lets it through.
+    pub fn check_rollback(
+        &mut self,
+        srs: &SignedRosterSnapshot,
+        allow_rollback: bool,
+    ) -> Result<()> {
+        let Some(accepted) = &self.current_srs else {
+            return Ok(());
+        };
+        if accepted.msg.epoch != srs.msg.epoch || srs.msg.log_len >= accepted.msg.log_len {
+            return Ok(());
+        }
+        let evidence = RollbackEvidence { accepted: accepted.clone(), served: srs.clone() };
+        let report = evidence.to_string();
+        self.rollback_evidence.get_or_insert(evidence);
+        if allow_rollback {
+            warn!("{report} Accepting it (--allow-rollback).");
+            return Ok(());
+        }
+        Err(anyhow!("{report} Pass --allow-rollback to accept it."))
+    }
+
+    /// Take each party's last-seen time from a watchtower /roster response, where it lists
+    /// the same latest record (seq) as the verified roster. Advisory: only used to hide
+    /// stale parties.
+    pub fn apply_last_seen(&mut self, resp: &RosterResponse) {
+        for p in &resp.parties {
+            if let Some(entry) = self.roster.get_mut(&p.party_id).filter(|e| e.seq == p.seq) {
+                entry.last_seen_unix = Some(p.last_seen_unix);
+            }
+        }
+    }
+
+    /// Adopt a verified snapshot and the full log it commits to; returns the roster changes.
+    pub fn apply_verified(
+        &mut self,
+        srs: SignedRosterSnapshot,
+        entries: &[LogEntry],
+    ) -> Vec<RosterChange> {
+        self.last_log_len = srs.msg.log_len;
+        self.current_srs = Some(srs);
+        if let Some(i) = entries.iter().rposition(|entry| entry.party_id() == self.party_id) {
+            self.my_last_index = Some(i as u64 + 1);
+        }
+        self.apply_prrs(entries)
+    }
+
+    /// What a light sync checks the watchtower against, or `None` before a full sync that
+    /// included this party.
+    pub fn light_base(&self) -> Option<LightBase> {
+        Some(LightBase {
+            srs: self.current_srs.clone()?,
+            party_id: self.party_id,
+            index: self.my_last_index?,
+            own: self.roster.get(&self.party_id)?.clone(),
+        })
+    }
+
+    /// Adopt a snapshot verified to extend the current one (a light sync), with the records
+    /// appended since, verified against it; returns the roster changes. An append-only log
+    /// never drops a party, so nobody is removed.
+    pub fn apply_appended(
+        &mut self,
+        srs: SignedRosterSnapshot,
+        appended: &[LogEntry],
+    ) -> Vec<RosterChange> {
+        if let Some(i) = appended.iter().rposition(|entry| entry.party_id() == self.party_id) {
+            self.my_last_index = Some(self.last_log_len + i as u64 + 1);
+        }
+        self.last_log_len = srs.msg.log_len;
+        self.current_srs = Some(srs);
+        let changes = self.fold_entries(appended);
+        for (pid, n) in history_lens(appended) {
+            if let Some(e) = self.roster.get_mut(&pid) {
+                e.history_len += n;
+            }
+        }
+        self.last_entries_count += appended.len();
+        self.last_superseded_count = self.last_entries_count.saturating_sub(self.roster.len());
+        changes
+    }
+
+    /// Fold the full verified log into the roster and report what changed. Parties in
+    /// the roster but absent from `entries` are dropped. `entries` may be in any order.
+    pub fn apply_prrs(&mut self, entries: &[LogEntry]) -> Vec<RosterChange> {
+        let mut changes = self.fold_entries(entries);
+        let history = history_lens(entries);
+        for (pid, e) in self.roster.iter_mut() {
+            e.history_len = history.get(pid).copied().unwrap_or(0);
+        }
+
+        let present: HashSet<u64> = entries.iter().map(LogEntry::party_id).collect();
+        let mut gone: Vec<u64> =
+            self.roster.keys().filter(|pid| !present.contains(pid)).copied().collect();
+        gone.sort_unstable();
+        for pid in gone {
+            self.roster.remove(&pid);
+            changes.push(RosterChange::Removed { party_id: pid });
+        }
+
+        self.last_entries_count = entries.len();
+        self.last_superseded_count = entries.len() - present.len();
+        changes
+    }
+
+    /// Apply the records among `entries` to the roster on top of what it holds; returns the
+    /// changes.
+    fn fold_entries(&mut self, entries: &[LogEntry]) -> Vec<RosterChange> {
+        // Replaying each party's records by increasing seq gives the same roster as log
+        // order (rotations chain the same way), however the batch was put together.
+        // Tombstones only stand for superseded records, so they change nothing here.
+        let mut ordered: Vec<&PartyRegistrationRecord> =
+            entries.iter().filter_map(LogEntry::record).collect();
+        ordered.sort_by_key(|prr| (prr.msg.party_id, prr.msg.seq));
+        ordered.dedup_by_key(|prr| (prr.msg.party_id, prr.msg.seq));
+
+        let mut changes = Vec::new();
+        for prr in ordered {
             let pid = prr.msg.party_id;
             let seq = prr.msg.seq;
             let endpoint = prr.msg.endpoint.addr.clone();
@@ -73,20 +405,397 @@ impl PartyStateFile {
 
             let should_update = match self.roster.get(&pid) {
                 None => true,
-                Some(existing) => seq > existing.seq,
+                Some(existing) if seq <= existing.seq => false,
+                // A key change must be a rotation endorsed by the key we currently know.
+                Some(existing) if existing.pk_party_b64 != pk_b64 => {
+                    let chained = prr.msg.rotation.as_ref().is_some_and(|r| {
+                        base64::engine::general_purpose::STANDARD.encode(r.old_pk) == existing.pk_party_b64
+                    });
+                    if !chained {
+                        warn!(
+                            "ignoring party_id={} seq={}: key changed without a rotation from the known key",
+                            pid, seq
+                        );
+                    }
+                    chained
+                }
+                Some(_) => true,
             };
 
             if should_update {
-                self.roster.insert(
-                    pid,
-                    RosterEntry {
-                        endpoint,
-                        pk_party_b64: pk_b64,
+                let entry = RosterEntry {
+                    party_id: pid,
+                    endpoint: endpoint.clone(),
+                    pk_party_b64: pk_b64,
+                    seq,
+                    created_at_unix: prr.msg.created_at_unix,
+                    last_seen_unix: None,
+                    history_len: self.roster.get(&pid).map_or(0, |e| e.history_len),
+                };
+                changes.push(match self.roster.insert(pid, entry) {
+                    None => RosterChange::Added { party_id: pid, endpoint, seq },
+                    Some(old) if old.endpoint != endpoint => RosterChange::EndpointChanged {
+                        party_id: pid,
+                        old: old.endpoint,
+                        new: endpoint,
                         seq,
                     },
-                );
+                    Some(_) => RosterChange::SeqAdvanced { party_id: pid, seq },
+                });
             }
         }
-        self.last_entries_count = prrs.len();
+        changes
+    }
+}
+
+/// A field saved by an older build in a layout this one can't read (e.g. a snapshot from
+/// before `watchtower_pk` was signed) reads as `None` instead of failing the whole load.
+fn stale_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: serde::de::DeserializeOwned,
+{
+    let value = Option::<serde_json::Value>::deserialize(deserializer)?;
+    Ok(value.and_then(|v| match serde_json::from_value(v) {
+        Ok(parsed) => Some(parsed),
+        Err(e) => {
+            warn!("dropping unreadable saved field: {e}");
+            None
+        }
+    }))
+}
+
+/// Registrations per party_id among `entries`, records and tombstones alike, each
+/// (party_id, seq) counted once.
+fn history_lens(entries: &[LogEntry]) -> HashMap<u64, u64> {
+    let distinct: HashSet<(u64, u64)> = entries.iter().map(|e| (e.party_id(), e.seq())).collect();
+    let mut lens = HashMap::new();
+    for (pid, _) in distinct {
+        *lens.entry(pid).or_insert(0) += 1;
+    }
+    lens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{entry, party_key, prr, snapshot_of, watchtower_key, EPOCH};
+    use common::crypto::{sign_struct, CTX_PRR};
+    use common::types::{KeyRotation, PartyRegistrationRecord, RosterParty, Tombstone};
+    use ed25519_dalek::SigningKey;
+
+    #[test]
+    fn mismatched_state_is_refused_unless_reset() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let path = path.to_str().unwrap();
+        assert_eq!(PartyStateFile::load_or_init(path, 7, 1, false).unwrap().next_seq, 1);
+
+        let mut st = PartyStateFile::new(7, 1);
+        let entry = RosterEntry {
+            party_id: 1,
+            endpoint: "10.0.0.1:9000".into(),
+            pk_party_b64: String::new(),
+            seq: 4,
+            created_at_unix: 0,
+            last_seen_unix: None,
+            history_len: 1,
+        };
+        st.roster.insert(1, entry);
+        st.next_seq = 5;
+        st.save(path).unwrap();
+        let saved = std::fs::read_to_string(path).unwrap();
+
+        for (epoch, party_id) in [(8, 1), (7, 2)] {
+            let err = PartyStateFile::load_or_init(path, epoch, party_id, false).unwrap_err();
+            assert!(err.to_string().contains("is for epoch=7 party_id=1"), "{err}");
+            assert!(err.to_string().contains("--reset"), "{err}");
+            assert_eq!(std::fs::read_to_string(path).unwrap(), saved);
+
+            // A reset starts over, but keeps seq monotonic.
+            let fresh = PartyStateFile::load_or_init(path, epoch, party_id, true).unwrap();
+            assert_eq!((fresh.epoch, fresh.party_id), (epoch, party_id));
+            assert_eq!(fresh.next_seq, 5);
+            assert!(fresh.roster.is_empty());
+            assert!(fresh.current_srs.is_none());
+        }
+
+        // The matching epoch and party_id load as saved, with or without --reset.
+        for reset in [false, true] {
+            let loaded = PartyStateFile::load_or_init(path, 7, 1, reset).unwrap();
+            assert_eq!(loaded.next_seq, 5);
+            assert_eq!(loaded.roster.len(), 1);
+        }
+    }
+
+    #[test]
+    fn a_state_file_with_a_pre_watchtower_pk_snapshot_loads_and_resyncs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let path = path.to_str().unwrap();
+        let log: Vec<_> = (1..=2).map(|n| entry(&party_key(n), n.into(), 1)).collect();
+        let mut st = PartyStateFile::new(EPOCH, 1);
+        st.apply_verified(snapshot_of(&watchtower_key(), &log), &log);
+        st.next_seq = 2;
+
+        // As saved before snapshots carried watchtower_pk (and, earlier, hash_alg).
+        let mut json = serde_json::to_value(&st).unwrap();
+        let msg = json["current_srs"]["msg"].as_object_mut().unwrap();
+        msg.remove("watchtower_pk");
+        msg.remove("hash_alg");
+        msg.insert("version".into(), 4.into());
+        std::fs::write(path, serde_json::to_string(&json).unwrap()).unwrap();
+
+        // The stale snapshot is dropped, so the next sync is a full one; the rest stays.
+        let loaded = PartyStateFile::load_or_init(path, EPOCH, 1, false).unwrap();
+        assert!(loaded.current_srs.is_none());
+        assert_eq!((loaded.next_seq, loaded.last_log_len, loaded.roster.len()), (2, 2, 2));
+        assert!(loaded.light_base().is_none());
+        let fresh = PartyStateFile::load_or_init(path, EPOCH + 1, 1, true).unwrap();
+        assert_eq!(fresh.next_seq, 2);
+
+        // A snapshot that still parses but has an older version is dropped too.
+        let mut old = st.clone();
+        old.current_srs.as_mut().unwrap().msg.version = SNAPSHOT_MSG_VERSION - 1;
+        old.save(path).unwrap();
+        let loaded = PartyStateFile::load_or_init(path, EPOCH, 1, false).unwrap();
+        assert!(loaded.current_srs.is_none());
+        st.save(path).unwrap();
+        let loaded = PartyStateFile::load_or_init(path, EPOCH, 1, false).unwrap();
+        assert_eq!(loaded.current_srs, st.current_srs);
+    }
+
+    /// Party 1's record at `seq` under `new`, rotating from `old`. The rotation signature
+    /// is left empty: the roster only follows the chain, signatures are checked on fetch.
+    fn rotated(old: &SigningKey, new: &SigningKey, seq: u64) -> LogEntry {
+        let mut msg = prr(new, 1, seq).msg;
+        let old_pk = old.verifying_key().to_bytes();
+        msg.rotation = Some(KeyRotation { old_pk, sig_old: [0; 64] });
+        let sig_party = sign_struct(new, CTX_PRR, &msg).unwrap();
+        PartyRegistrationRecord { msg, sig_party }.into()
+    }
+
+    #[test]
+    fn roster_follows_only_key_changes_chained_from_the_known_key() {
+        let b64 = |sk: &SigningKey| {
+            base64::engine::general_purpose::STANDARD.encode(sk.verifying_key().to_bytes())
+        };
+        let (old, new, stranger) = (party_key(1), party_key(2), party_key(3));
+        let mut st = PartyStateFile::new(EPOCH, 2);
+        let mut log = vec![entry(&old, 1, 1)];
+        st.apply_prrs(&log);
+
+        // The new key alone, and a rotation from a key the roster never had, are ignored.
+        for bogus in [entry(&new, 1, 2), rotated(&stranger, &new, 2)] {
+            let mut with = log.clone();
+            with.push(bogus);
+            let mut after = st.clone();
+            after.apply_prrs(&with);
+            assert_eq!(after.roster[&1].seq, 1);
+            assert_eq!(after.roster[&1].pk_party_b64, b64(&old));
+        }
+
+        log.push(rotated(&old, &new, 2));
+        st.apply_prrs(&log);
+        assert_eq!(st.roster[&1].seq, 2);
+        assert_eq!(st.roster[&1].pk_party_b64, b64(&new));
+        // Back to the old key is a key change like any other.
+        log.push(entry(&old, 1, 3));
+        st.apply_prrs(&log);
+        assert_eq!(st.roster[&1].seq, 2);
+        assert_eq!(st.roster[&1].pk_party_b64, b64(&new));
+    }
+
+    #[test]
+    fn each_batch_reports_the_changes_since_the_last() {
+        let mut moved = prr(&party_key(2), 2, 2);
+        moved.msg.endpoint.addr = "10.0.0.22:9000".into();
+        moved.sig_party = sign_struct(&party_key(2), CTX_PRR, &moved.msg).unwrap();
+
+        let mut st = PartyStateFile::new(EPOCH, 1);
+        let first = [entry(&party_key(1), 1, 1), entry(&party_key(2), 2, 1)];
+        assert_eq!(
+            st.apply_prrs(&first),
+            [
+                RosterChange::Added { party_id: 1, endpoint: "10.0.0.1:9000".into(), seq: 1 },
+                RosterChange::Added { party_id: 2, endpoint: "10.0.0.2:9000".into(), seq: 1 },
+            ]
+        );
+
+        let second = [&first[..], &[entry(&party_key(1), 1, 2), moved.clone().into()]].concat();
+        let second = [second, vec![entry(&party_key(3), 3, 1)]].concat();
+        assert_eq!(
+            st.apply_prrs(&second),
+            [
+                RosterChange::SeqAdvanced { party_id: 1, seq: 2 },
+                RosterChange::EndpointChanged {
+                    party_id: 2,
+                    old: "10.0.0.2:9000".into(),
+                    new: "10.0.0.22:9000".into(),
+                    seq: 2,
+                },
+                RosterChange::Added { party_id: 3, endpoint: "10.0.0.3:9000".into(), seq: 1 },
+            ]
+        );
+
+        // Party 2 is gone from the next log; the one after changes nothing.
+        let third = [entry(&party_key(1), 1, 2), entry(&party_key(3), 3, 1)];
+        assert_eq!(st.apply_prrs(&third), [RosterChange::Removed { party_id: 2 }]);
+        assert_eq!(st.apply_prrs(&third), []);
+    }
+
+    #[test]
+    fn a_shuffled_batch_gives_the_in_order_roster() {
+        use rand::{seq::SliceRandom, SeedableRng};
+        let log: Vec<LogEntry> = (1..=3)
+            .flat_map(|seq| (1..=4).map(move |n| entry(&party_key(n), n.into(), seq)))
+            .collect();
+        let mut in_order = PartyStateFile::new(EPOCH, 1);
+        in_order.apply_prrs(&log);
+        let roster = |st: &PartyStateFile| serde_json::to_value(&st.roster).unwrap();
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        for _ in 0..8 {
+            let mut shuffled = log.clone();
+            shuffled.shuffle(&mut rng);
+            // Duplicates too, as overlapping chunks would deliver them.
+            shuffled.extend_from_slice(&log[..4]);
+            let mut st = PartyStateFile::new(EPOCH, 1);
+            st.apply_prrs(&shuffled);
+            assert_eq!(roster(&st), roster(&in_order));
+            assert!(st.roster.values().all(|e| e.seq == 3));
+            assert_eq!(st.last_superseded_count, shuffled.len() - 4);
+        }
+
+        // Across calls too: an older batch after a newer one changes nothing.
+        let mut st = PartyStateFile::new(EPOCH, 1);
+        st.apply_prrs(&log);
+        assert_eq!(st.apply_prrs(&log[..8]), []);
+        assert!(st.roster.values().all(|e| e.seq == 3));
+    }
+
+    #[test]
+    fn a_shorter_log_is_refused_and_kept_as_evidence() {
+        let sk_w = watchtower_key();
+        let log: Vec<_> = (1..=3).map(|n| entry(&party_key(n), n.into(), 1)).collect();
+        let mut st = PartyStateFile::new(EPOCH, 1);
+        st.apply_verified(snapshot_of(&sk_w, &log[..2]), &log[..2]);
+
+        // Advancing is fine and leaves no evidence.
+        st.check_rollback(&snapshot_of(&sk_w, &log), false).unwrap();
+        st.check_rollback(&snapshot_of(&sk_w, &log[..2]), false).unwrap();
+        assert!(st.rollback_evidence.is_none());
+
+        let err = st.check_rollback(&snapshot_of(&sk_w, &log[..1]), false).unwrap_err();
+        assert!(err.to_string().contains("--allow-rollback"), "{err}");
+        let evidence = st.rollback_evidence.clone().unwrap();
+        assert_eq!((evidence.accepted.msg.log_len, evidence.served.msg.log_len), (2, 1));
+
+        // Allowed, it goes through, and the first rollback stays the recorded one.
+        st.check_rollback(&snapshot_of(&sk_w, &log[..0]), true).unwrap();
+        assert_eq!(st.rollback_evidence.unwrap().served.msg.log_len, 1);
+    }
+
+    #[test]
+    fn appended_records_give_the_full_sync_roster() {
+        let sk_w = watchtower_key();
+        let mut log: Vec<_> = (1..=3).map(|n| entry(&party_key(n), n.into(), 1)).collect();
+        let mut light = PartyStateFile::new(EPOCH, 1);
+        light.apply_verified(snapshot_of(&sk_w, &log), &log);
+
+        let appended = vec![
+            entry(&party_key(2), 2, 2),
+            entry(&party_key(4), 4, 1),
+            entry(&party_key(1), 1, 2),
+        ];
+        log.extend(appended.clone());
+        let srs = snapshot_of(&sk_w, &log);
+        let changes = light.apply_appended(srs.clone(), &appended);
+        assert_eq!(changes.len(), 3);
+        assert!(changes.contains(&RosterChange::Added {
+            party_id: 4,
+            endpoint: "10.0.0.4:9000".into(),
+            seq: 1
+        }));
+
+        let mut full = PartyStateFile::new(EPOCH, 1);
+        full.apply_verified(srs, &log);
+        assert_eq!(light.last_log_len, 6);
+        assert_eq!(light.my_last_index, Some(6));
+        assert_eq!(light.my_last_index, full.my_last_index);
+        assert_eq!(light.last_entries_count, full.last_entries_count);
+        assert_eq!(light.last_superseded_count, full.last_superseded_count);
+        let roster = |st: &PartyStateFile| {
+            let mut r: Vec<_> = st
+                .roster
+                .iter()
+                .map(|(pid, e)| (*pid, e.seq, e.endpoint.clone(), e.history_len))
+                .collect();
+            r.sort();
+            r
+        };
+        assert_eq!(roster(&light), roster(&full));
+    }
+
+    #[test]
+    fn history_len_counts_every_registration_of_a_party() {
+        let (sk, other) = (party_key(1), party_key(2));
+        let mut log: Vec<_> = (1..=3).map(|seq| entry(&sk, 1, seq)).collect();
+        log.push(entry(&other, 2, 1));
+        let mut st = PartyStateFile::new(EPOCH, 2);
+        st.apply_prrs(&log);
+        assert_eq!(st.roster[&1].history_len, 3);
+        assert_eq!(st.roster[&2].history_len, 1);
+
+        // Compacted records still count, duplicates delivered twice don't.
+        let tombstone = Tombstone { party_id: 1, seq: 1, leaf: [0; 32], nonce: [1; 16] };
+        log[0] = LogEntry::Tombstone { tombstone };
+        log.push(log[1].clone());
+        st.apply_prrs(&log);
+        assert_eq!(st.roster[&1].history_len, 3);
+    }
+
+    #[test]
+    fn light_base_needs_a_sync_that_included_this_party() {
+        let sk_w = watchtower_key();
+        let log: Vec<_> = (2..=3).map(|n| entry(&party_key(n), n.into(), 1)).collect();
+        let mut st = PartyStateFile::new(EPOCH, 1);
+        assert!(st.light_base().is_none());
+        st.apply_verified(snapshot_of(&sk_w, &log), &log);
+        assert!(st.light_base().is_none());
+
+        let log = [log, vec![entry(&party_key(1), 1, 1)]].concat();
+        st.apply_verified(snapshot_of(&sk_w, &log), &log);
+        let base = st.light_base().unwrap();
+        assert_eq!((base.party_id, base.index, base.own.seq), (1, 3, 1));
+        assert_eq!(base.srs.msg.log_len, 3);
+    }
+
+    #[test]
+    fn last_seen_is_taken_only_for_the_verified_record() {
+        let sk_w = watchtower_key();
+        let mut log = vec![entry(&party_key(1), 1, 1), entry(&party_key(2), 2, 1)];
+        let mut st = PartyStateFile::new(EPOCH, 1);
+        st.apply_verified(snapshot_of(&sk_w, &log), &log);
+        let party = |party_id, seq, last_seen_unix| RosterParty {
+            party_id,
+            endpoint: String::new(),
+            seq,
+            pk_party_b64: String::new(),
+            last_seen_unix,
+        };
+        // The watchtower's roster is ahead for party 2: that time is for another record.
+        let parties = vec![party(1, 1, 5_000), party(2, 2, 6_000)];
+        let resp = RosterResponse { log_len: 3, parties };
+        st.apply_last_seen(&resp);
+        assert_eq!(st.roster[&1].last_seen_unix, Some(5_000));
+        assert_eq!(st.roster[&1].last_seen_or_created(), 5_000);
+        assert_eq!(st.roster[&2].last_seen_unix, None);
+        assert_eq!(st.roster[&2].last_seen_or_created(), st.roster[&2].created_at_unix);
+
+        // A newer record's receive time isn't known until fetched again.
+        log.push(entry(&party_key(1), 1, 2));
+        st.apply_verified(snapshot_of(&sk_w, &log), &log);
+        assert_eq!(st.roster[&1].last_seen_unix, None);
     }
 }
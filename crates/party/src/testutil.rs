@@ -0,0 +1,187 @@
+//! Helpers shared by the tests (the library's, and with the `testutil` feature the
+//! binary's and the integration tests'): signed registrations, the snapshots a watchtower
+//! would sign over them, and a stand-in watchtower serving them.
+
+use crate::client::{log_leaves, log_root, log_smt_root};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use common::crypto::{
+    enc, sign_struct, Ed25519, Hasher, SignatureScheme, CTX_PRR, CTX_RECEIPT, CTX_SNAPSHOT,
+};
+use common::merkle::{inclusion_proof, leaf_hash};
+use common::smt::smt_proof;
+use common::types::{
+    Endpoint, ErrorCode, LogEntry, PartyRegistrationRecord, PartyResponse, RegisterRequest,
+    RegisterResponse, RegistrationMessage, RegistrationReceipt, SignedRegistrationReceipt,
+    SignedRosterSnapshot, SnapshotMessage, SnapshotResponse, WatchtowerError,
+    RECEIPT_MSG_VERSION, REGISTRATION_MSG_VERSION, SNAPSHOT_MSG_VERSION,
+};
+use ed25519_dalek::SigningKey;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+pub const EPOCH: u64 = 7;
+
+/// Deterministic key for party `n`.
+pub fn party_key(n: u8) -> SigningKey {
+    SigningKey::from_bytes(&[n; 32])
+}
+
+pub fn watchtower_key() -> SigningKey {
+    SigningKey::from_bytes(&[0xee; 32])
+}
+
+/// A registration of `party_id` under `sk` with `seq`, signed and otherwise unremarkable.
+pub fn prr(sk: &SigningKey, party_id: u64, seq: u64) -> PartyRegistrationRecord {
+    let msg = RegistrationMessage {
+        version: REGISTRATION_MSG_VERSION,
+        epoch: EPOCH,
+        party_id,
+        endpoint: Endpoint { addr: format!("10.0.0.{party_id}:9000") },
+        scheme: Ed25519::TAG,
+        pk_party: sk.verifying_key().to_bytes(),
+        seq,
+        nonce: [seq as u8; 16],
+        created_at_unix: 1_700_000_000 + seq,
+        rotation: None,
+    };
+    let sig_party = sign_struct(sk, CTX_PRR, &msg).unwrap();
+    PartyRegistrationRecord { msg, sig_party }
+}
+
+/// `prr(sk, party_id, seq)` as a log entry.
+pub fn entry(sk: &SigningKey, party_id: u64, seq: u64) -> LogEntry {
+    prr(sk, party_id, seq).into()
+}
+
+/// `log` as a watchtower signing with `sk_w` would snapshot it.
+pub fn snapshot_of(sk_w: &SigningKey, log: &[LogEntry]) -> SignedRosterSnapshot {
+    let h = Hasher::Sha256;
+    let msg = SnapshotMessage {
+        version: SNAPSHOT_MSG_VERSION,
+        epoch: EPOCH,
+        log_len: log.len() as u64,
+        merkle_root: log_root(h, log).unwrap(),
+        smt_root: log_smt_root(h, log).unwrap(),
+        hash_alg: h.tag(),
+        watchtower_pk: sk_w.verifying_key().to_bytes(),
+    };
+    sign_snapshot(sk_w, msg)
+}
+
+/// Sign `msg` with `sk_w`, e.g. after a test changed it.
+pub fn sign_snapshot(sk_w: &SigningKey, msg: SnapshotMessage) -> SignedRosterSnapshot {
+    let sig_watchtower = sign_struct(sk_w, CTX_SNAPSHOT, &msg).unwrap();
+    SignedRosterSnapshot { msg, sig_watchtower }
+}
+
+/// Just enough of a watchtower to register with and sync from, signing with
+/// `watchtower_key()`. /register accepts a record only if its seq is above its party's
+/// latest, as the watchtower does; /snapshot and /entries serve the log, and /party (if
+/// asked for) each party's latest record with its proofs.
+#[derive(Clone, Default)]
+pub struct FakeWatchtower {
+    pub log: Arc<Mutex<Vec<LogEntry>>>,
+}
+
+impl FakeWatchtower {
+    /// A watchtower whose log already holds `log`.
+    pub fn with_log(log: Vec<LogEntry>) -> Self {
+        Self { log: Arc::new(Mutex::new(log)) }
+    }
+
+    /// Its routes; /party only if `lookup`.
+    pub fn router(&self, lookup: bool) -> Router {
+        let mut app = Router::new()
+            .route("/register", post(fake_register))
+            .route("/snapshot", get(fake_snapshot))
+            .route("/entries", get(fake_entries));
+        if lookup {
+            app = app.route("/party/:party_id", get(fake_party));
+        }
+        app.with_state(self.clone())
+    }
+
+    /// Serve `router(lookup)` on a local port; returns its URL.
+    pub async fn serve(&self, lookup: bool) -> String {
+        let app = self.router(lookup);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+}
+
+async fn fake_register(
+    State(wt): State<FakeWatchtower>,
+    Json(req): Json<RegisterRequest>,
+) -> Response {
+    let mut log = wt.log.lock().unwrap();
+    let (party_id, seq) = (req.prr.msg.party_id, req.prr.msg.seq);
+    let last = log.iter().filter(|e| e.party_id() == party_id).map(LogEntry::seq).max();
+    if last.is_some_and(|last| seq <= last) {
+        let err = WatchtowerError::new(ErrorCode::SeqNotIncreasing, "seq must increase");
+        return (StatusCode::BAD_REQUEST, Json(err)).into_response();
+    }
+    let prr_leaf = leaf_hash(Hasher::Sha256, &enc(&req.prr).unwrap());
+    log.push(req.prr.into());
+    let srs = snapshot_of(&watchtower_key(), &log);
+    let receipt = RegistrationReceipt {
+        version: RECEIPT_MSG_VERSION,
+        party_id,
+        seq,
+        assigned_index: srs.msg.log_len,
+        prr_leaf,
+        snapshot_after: srs.msg.clone(),
+    };
+    let sig_watchtower = sign_struct(&watchtower_key(), CTX_RECEIPT, &receipt).unwrap();
+    let receipt = SignedRegistrationReceipt { receipt, sig_watchtower };
+    Json(RegisterResponse { srs, receipt }).into_response()
+}
+
+async fn fake_snapshot(State(wt): State<FakeWatchtower>) -> Json<SnapshotResponse> {
+    let srs = snapshot_of(&watchtower_key(), &wt.log.lock().unwrap());
+    Json(SnapshotResponse { srs, finalized: false })
+}
+
+async fn fake_entries(
+    State(wt): State<FakeWatchtower>,
+    Query(q): Query<BTreeMap<String, usize>>,
+) -> String {
+    let log = wt.log.lock().unwrap();
+    let range = &log[q["from"] - 1..q["to"]];
+    range.iter().map(|entry| serde_json::to_string(entry).unwrap() + "\n").collect()
+}
+
+/// The party's latest record with its proofs, or 404.
+async fn fake_party(State(wt): State<FakeWatchtower>, Path(party_id): Path<u64>) -> Response {
+    match party_response(&wt.log.lock().unwrap(), party_id) {
+        Some(resp) => Json(resp).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// `party_id`'s latest record in `log` with its proofs, as /party serves it.
+pub fn party_response(log: &[LogEntry], party_id: u64) -> Option<PartyResponse> {
+    let h = Hasher::Sha256;
+    let leaves = log_leaves(h, log).unwrap();
+    let mut latest = BTreeMap::new();
+    let mut index = None;
+    for (i, (entry, leaf)) in log.iter().zip(&leaves).enumerate() {
+        latest.insert(entry.party_id(), *leaf);
+        if entry.party_id() == party_id {
+            index = Some(i);
+        }
+    }
+    let i = index?;
+    Some(PartyResponse {
+        prr: log[i].record().unwrap().clone(),
+        index: i as u64 + 1,
+        proof: inclusion_proof(h, &leaves, i as u64).unwrap(),
+        smt_proof: smt_proof(h, &latest, party_id),
+        srs: snapshot_of(&watchtower_key(), log),
+    })
+}
@@ -0,0 +1,110 @@
+//! Registers two parties and syncs one of them using only the `party` library, against
+//! the in-process stand-in for the watchtower (`testutil::FakeWatchtower`), over plain
+//! HTTP and over TLS.
+
+use ed25519_dalek::SigningKey;
+use axum_server::tls_rustls::RustlsConfig;
+use party::client::{ClientError, RetryPolicy, WatchtowerClient, WatchtowerClientConfig};
+use party::keys::PartyKeys;
+use party::registration::register_self;
+use party::state::PartyStateFile;
+use party::testutil::{watchtower_key, FakeWatchtower, EPOCH};
+use base64::Engine as _;
+
+/// Serve a fresh watchtower on a local port; returns its URL and signing key.
+async fn serve() -> (String, SigningKey) {
+    (FakeWatchtower::default().serve(false).await, watchtower_key())
+}
+
+/// Serve a fresh watchtower over HTTPS the way the watchtower binary does, with a
+/// certificate for `localhost` issued by a new self-signed root; returns its URL, signing
+/// key and the root's PEM certificate.
+async fn serve_tls() -> (String, SigningKey, String) {
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let ca_key = rcgen::KeyPair::generate().unwrap();
+    let mut ca_params = rcgen::CertificateParams::new(Vec::new()).unwrap();
+    ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    ca_params.distinguished_name.push(rcgen::DnType::CommonName, "test root");
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+    let key = rcgen::KeyPair::generate().unwrap();
+    let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+    params.distinguished_name.push(rcgen::DnType::CommonName, "localhost");
+    let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+    let tls = RustlsConfig::from_pem(cert.pem().into_bytes(), key.serialize_pem().into_bytes())
+        .await
+        .unwrap();
+
+    let (app, sk_w) = (FakeWatchtower::default().router(false), watchtower_key());
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("https://localhost:{}", listener.local_addr().unwrap().port());
+    let server = axum_server::from_tcp_rustls(listener, tls);
+    tokio::spawn(async move { server.serve(app.into_make_service()).await });
+    (url, sk_w, ca.pem())
+}
+
+#[tokio::test]
+async fn register_and_sync_through_the_library() {
+    let (url, sk_w) = serve().await;
+    let pk_w = sk_w.verifying_key();
+    let wt = WatchtowerClient::new(url).unwrap();
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut states = Vec::new();
+    for party_id in 1..=2u64 {
+        let key_file = dir.path().join(format!("party{party_id}_key.json"));
+        let keys = PartyKeys::load_or_create(key_file.to_str().unwrap(), None).unwrap();
+        let mut st = PartyStateFile::new(EPOCH, party_id);
+        let endpoint = format!("127.0.0.1:{}", 9000 + party_id);
+        register_self(&wt, &pk_w, &keys, &mut st, endpoint).await.unwrap();
+        assert_eq!(st.my_last_index, Some(party_id));
+        assert_eq!(st.next_seq, 2);
+        states.push((keys, st));
+    }
+
+    // Party 1 registered first, so it learns of party 2 only by syncing.
+    let (keys, mut st) = states.remove(0);
+    assert_eq!(st.current_srs.as_ref().unwrap().msg.log_len, 1);
+    let changes = wt.sync_state(&pk_w, &mut st, false).await.unwrap();
+    assert_eq!(changes.len(), 2, "{changes:?}");
+    assert_eq!(st.last_log_len, 2);
+    assert_eq!(st.roster[&1].endpoint, "127.0.0.1:9001");
+    assert_eq!(st.roster[&2].endpoint, "127.0.0.1:9002");
+    let pk_b64 = base64::engine::general_purpose::STANDARD.encode(keys.pk.to_bytes());
+    assert_eq!(st.roster[&1].pk_party_b64, pk_b64);
+
+    // What was synced survives a save and reload.
+    let path = dir.path().join("party1_state.json");
+    let path = path.to_str().unwrap();
+    st.save(path).unwrap();
+    let reloaded = PartyStateFile::load_or_init(path, EPOCH, 1, false).unwrap();
+    assert_eq!(serde_json::to_value(reloaded).unwrap(), serde_json::to_value(st).unwrap());
+}
+
+#[tokio::test]
+async fn register_and_snapshot_over_tls_with_a_private_root() {
+    let (url, sk_w, cert_pem) = serve_tls().await;
+    let pk_w = sk_w.verifying_key();
+    let dir = tempfile::tempdir().unwrap();
+    let ca_file = dir.path().join("ca.pem");
+    std::fs::write(&ca_file, cert_pem).unwrap();
+    let no_retry = RetryPolicy { max_attempts: 1, ..Default::default() };
+
+    // The root isn't a built-in one.
+    let untrusting = WatchtowerClient::new_with_retry(url.clone(), no_retry.clone()).unwrap();
+    assert!(matches!(untrusting.snapshot().await, Err(ClientError::Transport(_))));
+
+    let config = WatchtowerClientConfig {
+        ca_cert_file: Some(ca_file.to_str().unwrap().to_string()),
+        retry: no_retry,
+        ..Default::default()
+    };
+    let wt = WatchtowerClient::new_with_config(url, config).unwrap();
+    let key_file = dir.path().join("party1_key.json");
+    let keys = PartyKeys::load_or_create(key_file.to_str().unwrap(), None).unwrap();
+    let mut st = PartyStateFile::new(EPOCH, 1);
+    register_self(&wt, &pk_w, &keys, &mut st, "127.0.0.1:9001".into()).await.unwrap();
+    assert_eq!(st.my_last_index, Some(1));
+    let srs = wt.snapshot().await.unwrap();
+    assert_eq!(srs.msg.log_len, 1);
+    assert_eq!(Some(&srs.msg), st.current_srs.as_ref().map(|srs| &srs.msg));
+}
@@ -1,63 +1,1935 @@
-use crate::state::WatchtowerState;
+use crate::state::{authenticate, WatchtowerState};
 use axum::{
-    extract::{Query, State},
-    http::StatusCode,
-    response::IntoResponse,
+    body::{Body, Bytes},
+    extract::{
+        ConnectInfo, DefaultBodyLimit, FromRequest, FromRequestParts, Path, Query, Request,
+        State,
+    },
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE, ETAG, IF_NONE_MATCH, RETRY_AFTER},
+        request::Parts,
+        HeaderMap, StatusCode,
+    },
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     routing::{get, post},
     Json, Router,
 };
-use common::types::{EntriesResponse, RegisterRequest, SnapshotResponse};
+use axum_server::tls_rustls::RustlsConfig;
+use common::ratelimit::RateLimiter;
+use common::types::{
+    CheckpointsResponse, CompactResponse, EntriesError, ErrorCode, HealthResponse, LogEntry,
+    RegisterRequest, SnapshotMessage, SnapshotResponse, WatchtowerError, ENTRIES_CONTENT_TYPE,
+    NEXT_FROM_HEADER, SNAPSHOT_ID_HEADER,
+};
+use futures::{stream, Stream, StreamExt};
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+use tracing::{field, info, warn, Span};
 use base64::Engine as _;
 
 #[derive(Clone)]
 pub struct AppState {
+    /// One exclusive lock for reads and registrations alike: a registration's checks, log
+    /// append and signed snapshot must happen as one step, or concurrent registrations
+    /// could interleave between the seq check and the append.
     pub inner: Arc<Mutex<WatchtowerState>>,
+    /// Process start time, for /healthz uptime.
+    pub started_at: Instant,
+    /// Set once the watchtower state has been fully loaded; gates /readyz.
+    pub ready: Arc<AtomicBool>,
+    /// Upper bound on entries returned per /entries request.
+    pub max_entries_limit: u64,
+    /// Request bodies larger than this are refused with 413.
+    pub max_body_bytes: usize,
+    /// Bearer token guarding mutating endpoints; `None` leaves them open.
+    pub operator_token: Option<Arc<str>>,
+    /// /register throttling, keyed by claimed party_id and by client IP. The IP limiter
+    /// also covers /snapshot_at, which costs a signature too.
+    pub party_limiter: Arc<Mutex<RateLimiter<u64>>>,
+    pub ip_limiter: Arc<Mutex<RateLimiter<IpAddr>>>,
+    /// Each epoch's latest snapshot for /snapshot/subscribe streams, published once per
+    /// change (registration, compaction, finalization). Also poked on shutdown, to end
+    /// the streams.
+    pub snapshots: Arc<watch::Sender<SnapshotFeed>>,
+    /// Permits for open /snapshot/subscribe streams; none left means new ones get 429.
+    pub subscribers: Arc<Semaphore>,
+    /// Serve reads only: /register, /finalize and /compact answer 405.
+    pub read_only: bool,
+}
+
+/// Latest published snapshot per epoch.
+pub type SnapshotFeed = BTreeMap<u64, PublishedSnapshot>;
+
+/// A snapshot as /snapshot/subscribe sends it: serialized once, shared by every stream.
+#[derive(Debug, Clone)]
+pub struct PublishedSnapshot {
+    pub etag: String,
+    pub json: Arc<str>,
+}
+
+impl PublishedSnapshot {
+    pub fn new(resp: &SnapshotResponse) -> anyhow::Result<Self> {
+        let etag = snapshot_etag(&resp.srs.msg, resp.finalized);
+        Ok(Self { etag, json: serde_json::to_string(resp)?.into() })
+    }
+}
+
+impl AppState {
+    /// Hand `resp` to every /snapshot/subscribe stream of its epoch. Callers publish while
+    /// still holding the state lock, so snapshots of one epoch go out in order.
+    pub fn publish_snapshot(&self, resp: &SnapshotResponse) {
+        match PublishedSnapshot::new(resp) {
+            Ok(published) => self.snapshots.send_modify(|feed| {
+                feed.insert(resp.srs.msg.epoch, published);
+            }),
+            Err(e) => warn!("not publishing snapshot for epoch={}: {e:#}", resp.srs.msg.epoch),
+        }
+    }
+
+    /// Catch a read-only replica up with the log files it follows, publishing the new
+    /// snapshot of every epoch that changed, also when an error cut the catch-up short.
+    /// The files are read off the runtime and without the state lock, which is only taken
+    /// to apply what was read. Passes must not overlap: the follower task runs them.
+    pub async fn catch_up(&self) -> anyhow::Result<()> {
+        let Some(followed) = self.inner.lock().unwrap().followed_logs() else {
+            return Ok(());
+        };
+        let reads = tokio::task::spawn_blocking(move || followed.read()).await?;
+        let mut guard = self.inner.lock().unwrap();
+        let caught_up = guard.apply_log_reads(reads);
+        for epoch in caught_up.changed {
+            match current_snapshot(&guard, epoch) {
+                Ok(resp) => self.publish_snapshot(&resp),
+                Err(e) => warn!("not publishing snapshot for epoch={epoch}: {e:#}"),
+            }
+        }
+        caught_up.error.map_or(Ok(()), Err)
+    }
+
+    /// Wake every /snapshot/subscribe stream without a new snapshot, so they notice the
+    /// watchtower going not-ready and end.
+    pub fn close_subscriptions(&self) {
+        self.snapshots.send_modify(|_| {});
+    }
+}
+
+/// Selects the epoch a request is about; the watchtower's default epoch if unset.
+#[derive(Debug, Deserialize)]
+pub struct EpochQuery {
+    pub epoch: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct EntriesQuery {
+    pub epoch: Option<u64>,
     pub from: u64,
-    pub to: u64,
+    /// Defaults to the current log length.
+    pub to: Option<u64>,
+    /// Maximum number of entries to return; defaults to (and may not exceed) the server max.
+    pub limit: Option<u64>,
+}
+
+/// Span for one request, so everything logged while serving it carries its request_id.
+/// The epoch comes from `?epoch=` when given; handlers record party_id (and a
+/// registration's epoch) once they know it.
+pub fn request_span<B>(req: &axum::http::Request<B>) -> Span {
+    static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+    let epoch = req.uri().query().and_then(|q| {
+        q.split('&').find_map(|kv| kv.strip_prefix("epoch=").and_then(|v| v.parse::<u64>().ok()))
+    });
+    tracing::info_span!(
+        "request",
+        request_id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+        method = %req.method(),
+        path = req.uri().path(),
+        party_id = field::Empty,
+        epoch,
+    )
 }
 
 pub fn router(state: AppState) -> Router {
+    let protected = if state.read_only {
+        Router::new()
+            .route("/register", post(read_only))
+            .route("/finalize", post(read_only))
+            .route("/compact", post(read_only))
+    } else {
+        Router::new()
+            .route("/register", post(register))
+            .route("/finalize", post(finalize))
+            .route("/compact", post(compact))
+            .route_layer(middleware::from_fn_with_state(state.clone(), require_operator_token))
+    };
+
     Router::new()
-        .route("/register", post(register))
+        .merge(protected)
+        .route("/verify", post(verify))
         .route("/snapshot", get(snapshot))
+        .route("/snapshot/subscribe", get(snapshot_subscribe))
+        .route("/snapshot_at", get(snapshot_at))
         .route("/entries", get(entries))
+        .route("/party/:party_id", get(party))
+        .route("/checkpoints", get(checkpoints))
+        .route("/consistency", get(consistency))
+        .route("/merkle_proof", get(merkle_proof))
+        .route("/roster", get(roster))
         .route("/watchtower_pubkey", get(watchtower_pubkey))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .layer(DefaultBodyLimit::max(state.max_body_bytes))
         .with_state(state)
 }
 
-async fn register(State(st): State<AppState>, Json(req): Json<RegisterRequest>) -> impl IntoResponse {
+/// Serve `app` over plain HTTP until `shutdown` resolves; then stop accepting connections and
+/// wait for in-flight requests to finish.
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    app: Router,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()> {
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+/// The HTTPS config for a PEM certificate chain and private key.
+pub async fn tls_config(cert: &str, key: &str) -> std::io::Result<RustlsConfig> {
+    // Same crypto backend as the party's reqwest/rustls client.
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    RustlsConfig::from_pem_file(cert, key).await
+}
+
+/// `serve`, over HTTPS.
+pub async fn serve_tls(
+    listener: std::net::TcpListener,
+    tls: RustlsConfig,
+    app: Router,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()> {
+    let handle = axum_server::Handle::new();
+    let drain = handle.clone();
+    tokio::spawn(async move {
+        shutdown.await;
+        drain.graceful_shutdown(None);
+    });
+    axum_server::from_tcp_rustls(listener, tls)
+        .handle(handle)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+}
+
+/// Reject requests lacking `Authorization: Bearer <operator_token>` when a token is configured.
+async fn require_operator_token(State(st): State<AppState>, req: Request, next: Next) -> Response {
+    let Some(expected) = st.operator_token.as_deref() else {
+        return next.run(req).await;
+    };
+    let provided = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match provided {
+        Some(token) if ct_eq(token.as_bytes(), expected.as_bytes()) => next.run(req).await,
+        _ => error_response(
+            StatusCode::UNAUTHORIZED,
+            WatchtowerError::new(ErrorCode::Unauthorized, "missing or invalid bearer token"),
+        ),
+    }
+}
+
+/// What a read-only replica answers on every mutating endpoint.
+async fn read_only() -> Response {
+    let msg = "read-only watchtower replica: send writes to the primary";
+    error_response(StatusCode::METHOD_NOT_ALLOWED, WatchtowerError::new(ErrorCode::ReadOnly, msg))
+}
+
+/// JSON error response with `err` as the body.
+fn error_response(status: StatusCode, err: WatchtowerError) -> Response {
+    (status, Json(err)).into_response()
+}
+
+/// Error response for a failed state operation: keeps the code of a `WatchtowerError`
+/// raised along the way, otherwise picks a generic one from `status`.
+///
+/// Some codes fix the status whatever the endpoint's default: a well-formed request for
+/// something that isn't there (`NOT_FOUND`, `OUT_OF_RANGE`) is 404, and a fault on the
+/// watchtower's side (`INTERNAL`) is 500, and a registration for a finalized epoch
+/// (`EPOCH_FINALIZED`) is 409. Everything else is the caller's `status`.
+fn api_error(status: StatusCode, e: anyhow::Error) -> Response {
+    let err = match e.downcast::<WatchtowerError>() {
+        Ok(err) => err,
+        Err(e) => {
+            let code = if status.is_server_error() {
+                ErrorCode::Internal
+            } else {
+                ErrorCode::BadRequest
+            };
+            WatchtowerError::new(code, e.to_string())
+        }
+    };
+    let status = match err.code {
+        ErrorCode::NotFound | ErrorCode::OutOfRange => StatusCode::NOT_FOUND,
+        ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        ErrorCode::EpochFinalized => StatusCode::CONFLICT,
+        _ => status,
+    };
+    error_response(status, err)
+}
+
+/// Error response for a request axum's extractors refused before it reached a handler,
+/// keeping the status axum picked for it.
+fn rejection_response(status: StatusCode, message: String) -> Response {
+    let code = match status {
+        StatusCode::PAYLOAD_TOO_LARGE => ErrorCode::BodyTooLarge,
+        _ => ErrorCode::BadRequest,
+    };
+    error_response(status, WatchtowerError::new(code, message))
+}
+
+/// `Json`, `Query` and `Path` with their rejections (a malformed body or query, a body
+/// over `max_body_bytes`) answered as `WatchtowerError` bodies like every other error.
+struct ApiJson<T>(T);
+struct ApiQuery<T>(T);
+struct ApiPath<T>(T);
+
+#[axum::async_trait]
+impl<S: Send + Sync, T: DeserializeOwned> FromRequest<S> for ApiJson<T> {
+    type Rejection = Response;
+
+    async fn from_request(req: Request, st: &S) -> Result<Self, Response> {
+        match Json::<T>::from_request(req, st).await {
+            Ok(Json(t)) => Ok(ApiJson(t)),
+            Err(r) => Err(rejection_response(r.status(), r.body_text())),
+        }
+    }
+}
+
+#[axum::async_trait]
+impl<S: Send + Sync, T: DeserializeOwned> FromRequestParts<S> for ApiQuery<T> {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, st: &S) -> Result<Self, Response> {
+        match Query::<T>::from_request_parts(parts, st).await {
+            Ok(Query(t)) => Ok(ApiQuery(t)),
+            Err(r) => Err(rejection_response(r.status(), r.body_text())),
+        }
+    }
+}
+
+#[axum::async_trait]
+impl<S: Send + Sync, T: DeserializeOwned + Send> FromRequestParts<S> for ApiPath<T> {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, st: &S) -> Result<Self, Response> {
+        match Path::<T>::from_request_parts(parts, st).await {
+            Ok(Path(t)) => Ok(ApiPath(t)),
+            Err(r) => Err(rejection_response(r.status(), r.body_text())),
+        }
+    }
+}
+
+/// Constant-time byte comparison (length is not secret).
+fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn register(
+    State(st): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    ApiJson(req): ApiJson<RegisterRequest>,
+) -> impl IntoResponse {
+    let limited = st.ip_limiter.lock().unwrap().check(peer.ip());
+    if let Err(wait) = limited {
+        return rate_limited(wait, &format!("too many registrations from ip {}", peer.ip()));
+    }
+    // The party_id is only a claim until its signature checks out; charging its bucket
+    // before that would let anyone exhaust another party's budget with forged records.
+    if let Err(e) = authenticate(&req.prr) {
+        return api_error(StatusCode::BAD_REQUEST, e);
+    }
+    let pid = req.prr.msg.party_id;
+    if let Err(wait) = st.party_limiter.lock().unwrap().check(pid) {
+        return rate_limited(wait, &format!("too many registrations from party_id={pid}"));
+    }
+
+    let span = Span::current();
+    span.record("party_id", req.prr.msg.party_id);
+    span.record("epoch", req.prr.msg.epoch);
+    let seq = req.prr.msg.seq;
     let mut guard = st.inner.lock().unwrap();
     match guard.register(req.prr) {
-        Ok(srs) => (StatusCode::OK, Json(SnapshotResponse { srs })).into_response(),
-        Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        Ok(resp) => {
+            st.publish_snapshot(&SnapshotResponse { srs: resp.srs.clone(), finalized: false });
+            drop(guard);
+            info!(
+                seq,
+                index = resp.receipt.receipt.assigned_index,
+                snapshot_id = %resp.srs.msg.snapshot_id_hex(),
+                "registration accepted"
+            );
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Err(e) => {
+            warn!(seq, "registration rejected: {e:#}");
+            api_error(StatusCode::BAD_REQUEST, e)
+        }
+    }
+}
+
+/// Report which of the /register checks a record passes, without logging it. Checking a
+/// signature costs as much as a registration, so it draws on the same per-IP budget.
+async fn verify(
+    State(st): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    ApiJson(req): ApiJson<RegisterRequest>,
+) -> Response {
+    let limited = st.ip_limiter.lock().unwrap().check(peer.ip());
+    if let Err(wait) = limited {
+        return rate_limited(wait, &format!("too many registrations from ip {}", peer.ip()));
     }
+    let span = Span::current();
+    span.record("party_id", req.prr.msg.party_id);
+    span.record("epoch", req.prr.msg.epoch);
+    let report = st.inner.lock().unwrap().verify(&req.prr);
+    Json(report).into_response()
 }
 
-async fn snapshot(State(st): State<AppState>) -> impl IntoResponse {
+/// 429 with a Retry-After of `wait`, rounded up to whole seconds.
+fn rate_limited(wait: Duration, what: &str) -> Response {
+    let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+    let msg = format!("rate limited: {what}; retry in {retry_after}s");
+    (
+        [(RETRY_AFTER, retry_after.to_string())],
+        error_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            WatchtowerError::new(ErrorCode::RateLimited, msg),
+        ),
+    )
+        .into_response()
+}
+
+async fn party(
+    State(st): State<AppState>,
+    ApiPath(party_id): ApiPath<u64>,
+    ApiQuery(q): ApiQuery<EpochQuery>,
+) -> impl IntoResponse {
+    Span::current().record("party_id", party_id);
     let guard = st.inner.lock().unwrap();
-    match guard.snapshot() {
-        Ok(srs) => (StatusCode::OK, Json(SnapshotResponse { srs })).into_response(),
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    let guard = match guard.epoch(q.epoch) {
+        Ok(epoch) => epoch,
+        Err(e) => return api_error(StatusCode::NOT_FOUND, e),
+    };
+    match guard.party(party_id) {
+        Ok(Some(resp)) => (StatusCode::OK, Json(resp)).into_response(),
+        Ok(None) => error_response(
+            StatusCode::NOT_FOUND,
+            WatchtowerError::new(ErrorCode::NotFound, format!("unknown party_id={party_id}")),
+        ),
+        Err(e) => api_error(StatusCode::INTERNAL_SERVER_ERROR, e),
     }
 }
 
-async fn entries(State(st): State<AppState>, Query(q): Query<EntriesQuery>) -> impl IntoResponse {
+async fn checkpoints(State(st): State<AppState>, ApiQuery(q): ApiQuery<EpochQuery>) -> Response {
     let guard = st.inner.lock().unwrap();
-    match guard.entries(q.from, q.to) {
-        Ok(entries) => (StatusCode::OK, Json(EntriesResponse { entries })).into_response(),
-        Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    match guard.epoch(q.epoch) {
+        Ok(epoch) => Json(CheckpointsResponse { checkpoints: epoch.checkpoints.clone() })
+            .into_response(),
+        Err(e) => api_error(StatusCode::NOT_FOUND, e),
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ConsistencyQuery {
+    pub epoch: Option<u64>,
+    pub from: u64,
+    /// Defaults to the current log length.
+    pub to: Option<u64>,
+}
+
+async fn consistency(
+    State(st): State<AppState>,
+    ApiQuery(q): ApiQuery<ConsistencyQuery>,
+) -> impl IntoResponse {
+    let guard = st.inner.lock().unwrap();
+    let guard = match guard.epoch(q.epoch) {
+        Ok(epoch) => epoch,
+        Err(e) => return api_error(StatusCode::NOT_FOUND, e),
+    };
+    match guard.consistency(q.from, q.to) {
+        Ok(proof) => (StatusCode::OK, Json(proof)).into_response(),
+        Err(e) => api_error(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MerkleProofQuery {
+    pub epoch: Option<u64>,
+    /// 1-indexed log position.
+    pub index: u64,
+}
+
+async fn merkle_proof(
+    State(st): State<AppState>,
+    ApiQuery(q): ApiQuery<MerkleProofQuery>,
+) -> impl IntoResponse {
+    let guard = st.inner.lock().unwrap();
+    let guard = match guard.epoch(q.epoch) {
+        Ok(epoch) => epoch,
+        Err(e) => return api_error(StatusCode::NOT_FOUND, e),
+    };
+    match guard.merkle_proof(q.index) {
+        Ok(resp) => (StatusCode::OK, Json(resp)).into_response(),
+        Err(e) => api_error(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RosterQuery {
+    pub epoch: Option<u64>,
+    /// Leave out parties whose latest record is older than this many seconds.
+    pub stale_after_secs: Option<u64>,
+}
+
+/// Advisory roster with each party's last-seen time; stale parties stay in the log.
+async fn roster(
+    State(st): State<AppState>,
+    ApiQuery(q): ApiQuery<RosterQuery>,
+) -> impl IntoResponse {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let guard = st.inner.lock().unwrap();
+    match guard.epoch(q.epoch) {
+        Ok(epoch) => Json(epoch.roster(q.stale_after_secs, now)).into_response(),
+        Err(e) => api_error(StatusCode::NOT_FOUND, e),
+    }
+}
+
+async fn compact(
+    State(st): State<AppState>,
+    ApiQuery(q): ApiQuery<EpochQuery>,
+) -> impl IntoResponse {
+    let mut guard = st.inner.lock().unwrap();
+    let guard = match guard.epoch_mut(q.epoch) {
+        Ok(epoch) => epoch,
+        Err(e) => return api_error(StatusCode::NOT_FOUND, e),
+    };
+    let compacted = guard.compact().and_then(|tombstoned| Ok((tombstoned, guard.snapshot()?)));
+    match compacted {
+        Ok((tombstoned, srs)) => {
+            st.publish_snapshot(&SnapshotResponse { srs: srs.clone(), finalized: false });
+            info!(
+                snapshot_id = %srs.msg.snapshot_id_hex(),
+                "log compacted: {tombstoned} superseded records of {} replaced by tombstones",
+                srs.msg.log_len,
+            );
+            (StatusCode::OK, Json(CompactResponse { tombstoned, srs })).into_response()
+        }
+        Err(e) => api_error(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+async fn finalize(
+    State(st): State<AppState>,
+    ApiQuery(q): ApiQuery<EpochQuery>,
+) -> impl IntoResponse {
+    let mut guard = st.inner.lock().unwrap();
+    let guard = match guard.epoch_mut(q.epoch) {
+        Ok(epoch) => epoch,
+        Err(e) => return api_error(StatusCode::NOT_FOUND, e),
+    };
+    match guard.finalize() {
+        Ok(srs) => {
+            let resp = SnapshotResponse { srs, finalized: true };
+            st.publish_snapshot(&resp);
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Err(e) => api_error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+async fn snapshot(
+    State(st): State<AppState>,
+    ApiQuery(q): ApiQuery<EpochQuery>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let guard = st.inner.lock().unwrap();
+    let guard = match guard.epoch(q.epoch) {
+        Ok(epoch) => epoch,
+        Err(e) => return api_error(StatusCode::NOT_FOUND, e),
+    };
+    let finalized = guard.finalized.is_some();
+    let msg = match &guard.finalized {
+        Some(srs) => srs.msg.clone(),
+        None => guard.snapshot_message(),
+    };
+
+    // Check the ETag before signing, so an unchanged snapshot costs no signature.
+    let etag = snapshot_etag(&msg, finalized);
+    let etag_header = [(ETAG, etag.clone())];
+    if etag_matches(&headers, &etag) {
+        return (StatusCode::NOT_MODIFIED, etag_header).into_response();
+    }
+
+    let srs = match &guard.finalized {
+        Some(srs) => Ok(srs.clone()),
+        None => guard.sign_snapshot(msg),
+    };
+    match srs {
+        Ok(srs) => {
+            let id = srs.msg.snapshot_id_hex();
+            let mut resp = (StatusCode::OK, etag_header, Json(SnapshotResponse { srs, finalized }))
+                .into_response();
+            resp.headers_mut().insert(SNAPSHOT_ID_HEADER, id.parse().unwrap());
+            resp
+        }
+        Err(e) => api_error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// Server-Sent Events: a `snapshot` event carrying the epoch's current `SnapshotResponse`
+/// right away, then another each time it changes. Bursts of changes may be coalesced into
+/// one event. The event id is the snapshot's ETag. The stream ends when the watchtower
+/// shuts down. At most `subscribers` streams are open at once.
+async fn snapshot_subscribe(
+    State(st): State<AppState>,
+    ApiQuery(q): ApiQuery<EpochQuery>,
+) -> Response {
+    let Ok(permit) = Arc::clone(&st.subscribers).try_acquire_owned() else {
+        return rate_limited(Duration::from_secs(1), "too many snapshot subscribers");
+    };
+    let (epoch, first) = {
+        let guard = st.inner.lock().unwrap();
+        let epoch = match guard.epoch(q.epoch) {
+            Ok(epoch) => epoch.epoch,
+            Err(e) => return api_error(StatusCode::NOT_FOUND, e),
+        };
+        let published = st.snapshots.borrow().get(&epoch).cloned();
+        let first = match published {
+            Some(p) => p,
+            None => {
+                // Nothing published for the epoch since startup: publish it now, without
+                // waking anyone, so later subscribers reuse it.
+                match current_snapshot(&guard, epoch).and_then(|r| PublishedSnapshot::new(&r)) {
+                    Ok(p) => {
+                        st.snapshots.send_if_modified(|feed| {
+                            feed.entry(epoch).or_insert_with(|| p.clone());
+                            false
+                        });
+                        p
+                    }
+                    Err(e) => return api_error(StatusCode::INTERNAL_SERVER_ERROR, e),
+                }
+            }
+        };
+        (epoch, first)
+    };
+    let events = snapshot_events(st, epoch, first, permit);
+    Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+}
+
+/// `first`, then each snapshot published for `epoch` with a different ETag. Holds
+/// `permit` for as long as the stream is open.
+fn snapshot_events(
+    st: AppState,
+    epoch: u64,
+    first: PublishedSnapshot,
+    permit: OwnedSemaphorePermit,
+) -> impl Stream<Item = Result<Event, axum::Error>> {
+    let mut feed = st.snapshots.subscribe();
+    feed.mark_unchanged();
+    let state = (st, feed, first.etag.clone(), permit);
+    let rest = stream::unfold(state, move |(st, mut feed, last, permit)| async move {
+        loop {
+            feed.changed().await.ok()?;
+            if !st.ready.load(Ordering::Acquire) {
+                return None;
+            }
+            let next = feed.borrow_and_update().get(&epoch).cloned();
+            if let Some(p) = next.filter(|p| p.etag != last) {
+                return Some((Ok(snapshot_event(&p)), (st, feed, p.etag, permit)));
+            }
+        }
+    });
+    stream::once(async move { Ok(snapshot_event(&first)) }).chain(rest)
+}
+
+fn snapshot_event(p: &PublishedSnapshot) -> Event {
+    Event::default().event("snapshot").id(p.etag.clone()).data(&*p.json)
+}
+
+/// `epoch`'s current snapshot: the final one once finalized.
+fn current_snapshot(state: &WatchtowerState, epoch: u64) -> anyhow::Result<SnapshotResponse> {
+    let guard = state.epoch(Some(epoch))?;
+    Ok(match &guard.finalized {
+        Some(srs) => SnapshotResponse { srs: srs.clone(), finalized: true },
+        None => SnapshotResponse { srs: guard.snapshot()?, finalized: false },
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SnapshotAtQuery {
+    pub epoch: Option<u64>,
+    pub log_len: u64,
+}
+
+/// A signed snapshot over the first `log_len` entries of the log. Signing one costs a
+/// Merkle root over the prefix, so this counts against the client IP's rate limit.
+async fn snapshot_at(
+    State(st): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    ApiQuery(q): ApiQuery<SnapshotAtQuery>,
+) -> impl IntoResponse {
+    if let Err(wait) = st.ip_limiter.lock().unwrap().check(peer.ip()) {
+        return rate_limited(wait, &format!("too many requests from ip {}", peer.ip()));
+    }
+    let guard = st.inner.lock().unwrap();
+    let guard = match guard.epoch(q.epoch) {
+        Ok(epoch) => epoch,
+        Err(e) => return api_error(StatusCode::NOT_FOUND, e),
+    };
+    match guard.snapshot_at(q.log_len) {
+        Ok(srs) => {
+            let id = srs.msg.snapshot_id_hex();
+            let mut resp = (StatusCode::OK, Json(srs)).into_response();
+            resp.headers_mut().insert(SNAPSHOT_ID_HEADER, id.parse().unwrap());
+            resp
+        }
+        Err(e) => api_error(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+/// Strong ETag for a /snapshot response: (epoch, log_len, merkle_root) pin the message,
+/// plus whether it is final.
+fn snapshot_etag(msg: &SnapshotMessage, finalized: bool) -> String {
+    let root: String = msg.merkle_root.iter().map(|b| format!("{b:02x}")).collect();
+    let suffix = if finalized { "-final" } else { "" };
+    format!("\"{}-{}-{root}{suffix}\"", msg.epoch, msg.log_len)
+}
+
+/// Whether `If-None-Match` lists `etag` (or is `*`).
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+async fn entries(
+    State(st): State<AppState>,
+    ApiQuery(q): ApiQuery<EntriesQuery>,
+) -> impl IntoResponse {
+    let limit = q.limit.unwrap_or(st.max_entries_limit);
+    if limit == 0 || limit > st.max_entries_limit {
+        let msg = format!("invalid limit={limit} (must be 1..={})", st.max_entries_limit);
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            WatchtowerError::new(ErrorCode::BadRequest, msg),
+        );
+    }
+
+    let (epoch, generation, end, next_from) = {
+        let guard = st.inner.lock().unwrap();
+        let guard = match guard.epoch(q.epoch) {
+            Ok(epoch) => epoch,
+            Err(e) => return api_error(StatusCode::NOT_FOUND, e),
+        };
+        let to = q.to.unwrap_or(guard.log.len() as u64);
+        // Truncate to at most `limit` entries, handing back a cursor for the rest.
+        let end = to.min(q.from.saturating_add(limit - 1));
+        if let Err(e) = guard.check_range(q.from, end) {
+            return api_error(StatusCode::BAD_REQUEST, e);
+        }
+        (guard.epoch, guard.generation, end, (end < to).then(|| end + 1))
+    };
+
+    // Stream the range as NDJSON, taking the lock per batch so neither the whole
+    // slice nor the whole body is ever held in memory. If a compaction lands between
+    // batches, the stream ends with an error line rather than mixing two logs.
+    let inner = st.inner.clone();
+    let body = stream::unfold(Some(q.from), move |cur| {
+        let inner = inner.clone();
+        async move {
+            let cur = cur.filter(|&cur| cur <= end)?;
+            let batch_end = end.min(cur.saturating_add(ENTRIES_STREAM_BATCH - 1));
+            let batch = {
+                let guard = inner.lock().unwrap();
+                guard.epoch(Some(epoch)).and_then(|st| {
+                    if st.generation != generation {
+                        return Ok(None);
+                    }
+                    st.entries(cur, batch_end).map(Some)
+                })
+            };
+            match batch {
+                Ok(Some(batch)) => Some((ndjson_lines(batch), Some(batch_end + 1))),
+                Ok(None) => Some((log_changed_line(cur), None)),
+                Err(e) => Some((Err(e), None)),
+            }
+        }
+    });
+
+    let mut resp = Response::new(Body::from_stream(body));
+    resp.headers_mut().insert(CONTENT_TYPE, ENTRIES_CONTENT_TYPE.parse().unwrap());
+    if let Some(next) = next_from {
+        resp.headers_mut().insert(NEXT_FROM_HEADER, next.into());
+    }
+    resp
+}
+
+/// Records per lock acquisition while streaming /entries.
+const ENTRIES_STREAM_BATCH: u64 = 256;
+
+/// The `EntriesError` line ending a stream whose log was compacted before `from`.
+fn log_changed_line(from: u64) -> anyhow::Result<Bytes> {
+    let msg = format!("the log was compacted while streaming from={from}; retry the request");
+    let line = EntriesError { error: WatchtowerError::new(ErrorCode::LogChanged, msg) };
+    let mut buf = serde_json::to_vec(&line)?;
+    buf.push(b'\n');
+    Ok(Bytes::from(buf))
+}
+
+fn ndjson_lines(entries: Vec<LogEntry>) -> anyhow::Result<Bytes> {
+    let mut buf = Vec::new();
+    for entry in &entries {
+        serde_json::to_writer(&mut buf, entry)?;
+        buf.push(b'\n');
+    }
+    Ok(Bytes::from(buf))
+}
+
 async fn watchtower_pubkey(State(st): State<AppState>) -> impl IntoResponse {
     let guard = st.inner.lock().unwrap();
     let pk = guard.watchtower_pubkey_bytes();
     let pk_b64 = base64::engine::general_purpose::STANDARD.encode(pk);
     (StatusCode::OK, pk_b64)
 }
+
+async fn healthz(State(st): State<AppState>) -> impl IntoResponse {
+    let guard = st.inner.lock().unwrap();
+    let default = guard.epoch(None).expect("default epoch");
+    let resp = HealthResponse {
+        epoch: default.epoch,
+        log_len: default.log.len() as u64,
+        uptime_secs: st.started_at.elapsed().as_secs(),
+    };
+    (StatusCode::OK, Json(resp))
+}
+
+async fn readyz(State(st): State<AppState>) -> impl IntoResponse {
+    if st.ready.load(Ordering::Acquire) {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not ready")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, call, get, party_key, post_json, prr, EPOCH};
+    use crate::state::EpochSettings;
+    use axum::body::Body;
+    use http_body_util::BodyExt as _;
+    use tower::ServiceExt as _;
+    use axum::http::Request;
+    use common::crypto::{enc, verify_struct, Hasher, CTX_RECEIPT, CTX_SNAPSHOT};
+    use common::merkle::{empty_root, leaf_hash, merkle_root, verify_inclusion};
+    use common::smt::verify_smt_proof;
+    use common::types::{
+        MerkleProofResponse, PartyRegistrationRecord, PartyResponse, RegisterResponse,
+        RegistrationMessage, SignedRosterSnapshot, VerifyResponse,
+    };
+    use ed25519_dalek::SigningKey;
+
+    #[tokio::test]
+    async fn healthz_reports_the_epoch_and_log_length() {
+        let st = testutil::app_state(testutil::state());
+        let req = RegisterRequest { prr: prr(&party_key(1), 1, 1) };
+        assert_eq!(call(&st, post_json("/register", &req)).await.0, StatusCode::OK);
+        let (status, body) = call(&st, testutil::get("/healthz")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["epoch"], EPOCH);
+        assert_eq!(body["log_len"], 1);
+        assert!(body["uptime_secs"].as_u64().is_some(), "{body}");
+    }
+
+    #[tokio::test]
+    async fn readyz_follows_the_ready_flag() {
+        let st = testutil::app_state(testutil::state());
+        st.ready.store(false, Ordering::Release);
+        let (status, _) = call(&st, testutil::get("/readyz")).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        // Liveness doesn't wait for readiness.
+        assert_eq!(call(&st, testutil::get("/healthz")).await.0, StatusCode::OK);
+
+        st.ready.store(true, Ordering::Release);
+        let (status, _) = call(&st, testutil::get("/readyz")).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn read_only_replicas_serve_snapshots_but_refuse_registration() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wt.log");
+        let path = path.to_str().unwrap();
+        let mut primary = testutil::state();
+        primary.open_log(path).unwrap();
+        let primary = testutil::app_state(primary);
+        for party in 1..=3u8 {
+            let req = RegisterRequest { prr: prr(&party_key(party), party.into(), 1) };
+            assert_eq!(call(&primary, post_json("/register", &req)).await.0, StatusCode::OK);
+        }
+        let written = std::fs::read(path).unwrap();
+
+        let mut replica = testutil::state();
+        replica.load_log_read_only(path).unwrap();
+        let mut replica = testutil::app_state(replica);
+        replica.read_only = true;
+        let (status, body) = call(&replica, testutil::get("/snapshot")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, expected) = call(&primary, testutil::get("/snapshot")).await;
+        assert_eq!(body["srs"]["msg"], expected["srs"]["msg"]);
+        assert_eq!(body["srs"]["msg"]["log_len"], 3);
+
+        let req = RegisterRequest { prr: prr(&party_key(4), 4, 1) };
+        let (status, body) = call(&replica, post_json("/register", &req)).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(body["code"], "READ_ONLY");
+        for uri in ["/finalize", "/compact"] {
+            let (status, _) = call(&replica, Request::post(uri).body(Body::empty()).unwrap()).await;
+            assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED, "{uri}");
+        }
+        assert_eq!(replica.inner.lock().unwrap().epoch(None).unwrap().log.len(), 3);
+        assert_eq!(std::fs::read(path).unwrap(), written);
+
+        // Snapshots are signed with the replica's own key: given the primary's, they verify
+        // under the watchtower_pk parties pinned; under any other key they don't.
+        let pk_w = testutil::watchtower_key().verifying_key();
+        let own_key = EpochSettings::new(SigningKey::from_bytes(&[0xaa; 32]));
+        let mut other = WatchtowerState::new(EPOCH, own_key, false);
+        other.load_log_read_only(path).unwrap();
+        for (st, pinned) in [(&replica, true), (&testutil::app_state(other), false)] {
+            let (_, body) = call(st, get("/snapshot")).await;
+            assert_eq!(body["srs"]["msg"]["root"], expected["srs"]["msg"]["root"]);
+            let srs: SignedRosterSnapshot = serde_json::from_value(body["srs"].clone()).unwrap();
+            let verified = verify_struct(&pk_w, CTX_SNAPSHOT, &srs.msg, &srs.sig_watchtower);
+            assert_eq!(verified.is_ok(), pinned);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_failed_catch_up_still_publishes_the_epochs_it_applied() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wt.log");
+        let path = path.to_str().unwrap();
+        let mut primary = WatchtowerState::new(EPOCH, testutil::settings(), true);
+        primary.open_log(path).unwrap();
+        primary.register(prr(&party_key(1), 1, 1)).unwrap();
+        primary.register(prr_in_epoch(EPOCH + 1, 1, 1)).unwrap();
+        let mut replica = WatchtowerState::new(EPOCH, testutil::settings(), true);
+        replica.load_log_read_only(path).unwrap();
+        let replica = testutil::app_state(replica);
+        replica.catch_up().await.unwrap();
+
+        // The default epoch grows; the next one's file is rewritten into garbage.
+        primary.register(prr(&party_key(2), 2, 1)).unwrap();
+        std::fs::write(format!("{path}.epoch-{}", EPOCH + 1), "not a log\n").unwrap();
+        let mut feed = replica.snapshots.subscribe();
+        assert!(replica.catch_up().await.is_err());
+        assert!(feed.has_changed().unwrap());
+        let published = feed.borrow_and_update()[&EPOCH].json.clone();
+        let published: SnapshotResponse = serde_json::from_str(&published).unwrap();
+        assert_eq!(published.srs.msg.log_len, 2);
+        assert_eq!(replica.inner.lock().unwrap().epoch(None).unwrap().log.len(), 2);
+        // The lock is free again, and the broken epoch still serves what it had.
+        let (status, body) = call(&replica, get(&format!("/snapshot?epoch={}", EPOCH + 1))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["srs"]["msg"]["log_len"], 1);
+    }
+
+    #[tokio::test]
+    async fn finalized_epochs_refuse_registrations_and_keep_their_root() {
+        let st = testutil::app_state(testutil::state());
+        for party in 1..=2u8 {
+            let req = RegisterRequest { prr: prr(&party_key(party), party.into(), 1) };
+            assert_eq!(call(&st, post_json("/register", &req)).await.0, StatusCode::OK);
+        }
+        let finalize = || Request::post("/finalize").body(Body::empty()).unwrap();
+        let (status, first) = call(&st, finalize()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(first["finalized"], true);
+        assert_eq!(first["srs"]["msg"]["log_len"], 2);
+
+        for (party, seq) in [(1, 2), (3, 1)] {
+            let req = RegisterRequest { prr: prr(&party_key(party), party.into(), seq) };
+            let (status, body) = call(&st, post_json("/register", &req)).await;
+            assert_eq!(status, StatusCode::CONFLICT);
+            assert_eq!(body["code"], "EPOCH_FINALIZED", "{body}");
+        }
+        let err = st.inner.lock().unwrap().register(prr(&party_key(3), 3, 1)).unwrap_err();
+        assert!(err.to_string().contains("epoch finalized"), "{err}");
+        assert_eq!(st.inner.lock().unwrap().epoch(None).unwrap().log.len(), 2);
+
+        // Finalizing again, and reading the snapshot, give the very same final snapshot.
+        let (status, again) = call(&st, finalize()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(again, first);
+        let (_, snapshot) = call(&st, testutil::get("/snapshot")).await;
+        assert_eq!(snapshot, first);
+    }
+
+    /// POST `path` (with a registration of party 1 at `seq` for /register) and the given
+    /// Authorization header.
+    fn operator_request(path: &str, seq: u64, auth: Option<&str>) -> Request<Body> {
+        let mut req = if path == "/register" {
+            post_json(path, &RegisterRequest { prr: prr(&party_key(1), 1, seq) })
+        } else {
+            Request::post(path).body(Body::empty()).unwrap()
+        };
+        if let Some(auth) = auth {
+            req.headers_mut().insert(AUTHORIZATION, auth.parse().unwrap());
+        }
+        req
+    }
+
+    #[tokio::test]
+    async fn operator_endpoints_need_the_token_when_one_is_set() {
+        let mut st = testutil::app_state(testutil::state());
+        st.operator_token = Some("s3cret".into());
+        // /finalize last: it ends registration.
+        for (seq, path) in [(1, "/register"), (2, "/finalize")] {
+            for auth in [None, Some("Bearer wrong"), Some("s3cret"), Some("Bearer s3cret ")] {
+                let (status, _) = call(&st, operator_request(path, seq, auth)).await;
+                assert_eq!(status, StatusCode::UNAUTHORIZED, "{path} {auth:?}");
+            }
+            assert!(st.inner.lock().unwrap().epoch(None).unwrap().log.len() < seq as usize);
+            let (status, body) = call(&st, operator_request(path, seq, Some("Bearer s3cret"))).await;
+            assert_eq!(status, StatusCode::OK, "{path}: {body}");
+        }
+        {
+            let wt = st.inner.lock().unwrap();
+            assert_eq!(wt.epoch(None).unwrap().log.len(), 1);
+            assert!(wt.epoch(None).unwrap().finalized.is_some());
+        }
+
+        // Reads stay open.
+        let (status, _) = call(&st, testutil::get("/snapshot")).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn operator_endpoints_are_open_without_a_token() {
+        let st = testutil::app_state(testutil::state());
+        // A header nobody asked for is ignored.
+        let (status, body) = call(&st, operator_request("/register", 1, Some("Bearer x"))).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let (status, body) = call(&st, operator_request("/finalize", 2, None)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+    }
+
+    #[tokio::test]
+    async fn forged_registrations_do_not_spend_the_party_budget() {
+        let mut st = testutil::app_state(testutil::state());
+        st.party_limiter = Arc::new(Mutex::new(RateLimiter::new(1, 0.001)));
+        let victim = party_key(1);
+        let mut forged = prr(&victim, 1, 1);
+        forged.sig_party = prr(&party_key(2), 1, 1).sig_party;
+        for _ in 0..3 {
+            let req = RegisterRequest { prr: forged.clone() };
+            let (status, _) = call(&st, post_json("/register", &req)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+        let req = RegisterRequest { prr: prr(&victim, 1, 1) };
+        let (status, _) = call(&st, post_json("/register", &req)).await;
+        assert_eq!(status, StatusCode::OK);
+        // The genuine party's own records still count against it.
+        let req = RegisterRequest { prr: prr(&victim, 1, 2) };
+        let (status, _) = call(&st, post_json("/register", &req)).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn roster_leaves_out_parties_not_seen_within_the_window() {
+        let st = testutil::app_state(testutil::state());
+        for n in 1..=2 {
+            let req = RegisterRequest { prr: prr(&party_key(n), n.into(), 1) };
+            assert_eq!(call(&st, post_json("/register", &req)).await.0, StatusCode::OK);
+        }
+        // Party 1 was last heard from an hour ago, party 2 just now.
+        {
+            let mut wt = st.inner.lock().unwrap();
+            let es = wt.epoch_mut(None).unwrap();
+            *es.last_seen.get_mut(&1).unwrap() -= 3600;
+        }
+        let ids = |body: &serde_json::Value| -> Vec<u64> {
+            let parties = body["parties"].as_array().unwrap();
+            parties.iter().map(|p| p["party_id"].as_u64().unwrap()).collect()
+        };
+
+        let (status, body) = call(&st, get("/roster?stale_after_secs=60")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ids(&body), [2]);
+        // Advisory only: the log still holds both, and without a window both are listed.
+        assert_eq!(body["log_len"], 2);
+        let (_, body) = call(&st, get("/roster")).await;
+        assert_eq!(ids(&body), [1, 2]);
+        let (_, body) = call(&st, get("/roster?stale_after_secs=7200")).await;
+        assert_eq!(ids(&body), [1, 2]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_registrations_all_land_once_and_in_seq_order() {
+        const PARTIES: u8 = 8;
+        const SEQS: u64 = 3;
+        let mut st = testutil::app_state(testutil::state());
+        st.ip_limiter = Arc::new(Mutex::new(RateLimiter::new(10_000, 10_000.0)));
+        // Fixed up front, so the serial run below registers the very same bytes.
+        let records: Vec<Vec<_>> = (1..=PARTIES)
+            .map(|p| (1..=SEQS).map(|seq| prr(&party_key(p), p.into(), seq)).collect())
+            .collect();
+
+        // Each party registers its seqs in order, every record sent by two tasks at once
+        // (as a client retrying a lost response would); the parties race each other.
+        let mut tasks = Vec::new();
+        for party_records in &records {
+            for _ in 0..2 {
+                let (st, party_records) = (st.clone(), party_records.clone());
+                tasks.push(tokio::spawn(async move {
+                    let mut indices = Vec::new();
+                    for prr in party_records {
+                        let req = RegisterRequest { prr };
+                        let (status, body) = call(&st, post_json("/register", &req)).await;
+                        // The twin may be a record ahead already.
+                        if status == StatusCode::OK {
+                            indices.push(body["receipt"]["receipt"]["assigned_index"].as_u64());
+                        } else {
+                            assert_eq!(body["code"], "SEQ_NOT_INCREASING", "{body}");
+                        }
+                    }
+                    indices
+                }));
+            }
+        }
+        for task in tasks {
+            for index in task.await.unwrap() {
+                assert!(index.is_some_and(|i| (1..=PARTIES as u64 * SEQS).contains(&i)));
+            }
+        }
+
+        let wt = st.inner.lock().unwrap();
+        let es = wt.epoch(None).unwrap();
+        assert_eq!(es.log.len() as u64, PARTIES as u64 * SEQS);
+        for p in 1..=PARTIES as u64 {
+            assert_eq!(es.last_seq[&p], SEQS);
+            let seqs: Vec<u64> =
+                es.log.iter().filter(|e| e.party_id() == p).map(|e| e.seq()).collect();
+            assert_eq!(seqs, (1..=SEQS).collect::<Vec<_>>());
+        }
+        // The log root is over whatever order the races gave, so replay that order; the
+        // parties' latest records are the same whatever it was.
+        let mut serial = testutil::state();
+        for entry in &es.log {
+            serial.register(entry.record().unwrap().clone()).unwrap();
+        }
+        assert_eq!(serial.epoch(None).unwrap().snapshot_message(), es.snapshot_message());
+        let mut by_party = testutil::state();
+        for prr in records.into_iter().flatten() {
+            by_party.register(prr).unwrap();
+        }
+        let by_party = by_party.epoch(None).unwrap().snapshot_message();
+        assert_eq!(by_party.smt_root, es.snapshot_message().smt_root);
+    }
+
+    #[tokio::test]
+    async fn rapid_registrations_get_429_once_the_burst_is_spent() {
+        let mut st = testutil::app_state(testutil::state());
+        st.party_limiter = Arc::new(Mutex::new(RateLimiter::new(3, 0.001)));
+        st.ip_limiter = Arc::new(Mutex::new(RateLimiter::new(5, 0.001)));
+        let register = |n: u8, seq: u64| {
+            let req = RegisterRequest { prr: prr(&party_key(n), n.into(), seq) };
+            call(&st, post_json("/register", &req))
+        };
+
+        // Party 1 spends its own burst...
+        for seq in 1..=3 {
+            assert_eq!(register(1, seq).await.0, StatusCode::OK);
+        }
+        assert_eq!(register(1, 4).await.0, StatusCode::TOO_MANY_REQUESTS);
+        // ...while others from the same IP get on until the IP's burst is spent too.
+        assert_eq!(register(2, 1).await.0, StatusCode::OK);
+        assert_eq!(register(3, 1).await.0, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(st.inner.lock().unwrap().epoch(None).unwrap().log.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn receipts_name_the_index_their_record_landed_at() {
+        let st = testutil::app_state(testutil::state());
+        let pk_w = testutil::watchtower_key().verifying_key();
+        let records: Vec<_> =
+            [(1, 1), (2, 1), (1, 2)].map(|(p, seq)| prr(&party_key(p), p.into(), seq)).into();
+        for (i, record) in records.iter().enumerate() {
+            let req = RegisterRequest { prr: record.clone() };
+            let (status, body) = call(&st, post_json("/register", &req)).await;
+            assert_eq!(status, StatusCode::OK);
+            let resp: RegisterResponse = serde_json::from_value(body).unwrap();
+            let receipt = &resp.receipt.receipt;
+            assert_eq!(receipt.assigned_index, i as u64 + 1);
+            assert_eq!((receipt.party_id, receipt.seq), (record.msg.party_id, record.msg.seq));
+            assert_eq!(receipt.prr_leaf, leaf_hash(Hasher::Sha256, &enc(record).unwrap()));
+            assert_eq!(receipt.snapshot_after, resp.srs.msg);
+            verify_struct(&pk_w, CTX_RECEIPT, receipt, &resp.receipt.sig_watchtower).unwrap();
+
+            let mut forged = receipt.clone();
+            forged.assigned_index += 1;
+            assert!(verify_struct(&pk_w, CTX_RECEIPT, &forged, &resp.receipt.sig_watchtower)
+                .is_err());
+        }
+        let wt = st.inner.lock().unwrap();
+        let log = &wt.epoch(None).unwrap().log;
+        for (entry, record) in log.iter().zip(&records) {
+            assert_eq!(entry.record(), Some(record));
+        }
+    }
+
+    #[tokio::test]
+    async fn bodies_over_the_cap_are_refused() {
+        let mut st = testutil::app_state(testutil::state());
+        st.max_body_bytes = 4096;
+        let body = serde_json::to_vec(&RegisterRequest { prr: prr(&party_key(1), 1, 1) }).unwrap();
+        let padded = |len: usize| {
+            let mut padded = body.clone();
+            padded.resize(len, b' ');
+            Request::post("/register")
+                .header("content-type", "application/json")
+                .body(Body::from(padded))
+                .unwrap()
+        };
+
+        let (status, json) = call(&st, padded(4097)).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(json["code"], "BODY_TOO_LARGE", "{json}");
+        assert!(json["message"].as_str().is_some_and(|m| !m.is_empty()), "{json}");
+        assert_eq!(st.inner.lock().unwrap().epoch(None).unwrap().log.len(), 0);
+
+        let (status, json) = call(&st, padded(4096)).await;
+        assert_eq!(status, StatusCode::OK, "{json}");
+        assert_eq!(st.inner.lock().unwrap().epoch(None).unwrap().log.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn party_serves_the_latest_record_with_proofs() {
+        let st = testutil::app_state(testutil::state());
+        for (party, seq) in [(1, 1), (2, 1), (1, 2)] {
+            let req = RegisterRequest { prr: prr(&party_key(party), party.into(), seq) };
+            assert_eq!(call(&st, post_json("/register", &req)).await.0, StatusCode::OK);
+        }
+        let pk_w = testutil::watchtower_key().verifying_key();
+        for (party_id, seq, index) in [(1, 2, 3), (2, 1, 2)] {
+            let (status, body) = call(&st, get(&format!("/party/{party_id}"))).await;
+            assert_eq!(status, StatusCode::OK);
+            let resp: PartyResponse = serde_json::from_value(body).unwrap();
+            assert_eq!((resp.prr.msg.party_id, resp.prr.msg.seq), (party_id, seq));
+            assert_eq!(resp.index, index);
+            let msg = &resp.srs.msg;
+            assert_eq!(msg.log_len, 3);
+            verify_struct(&pk_w, CTX_SNAPSHOT, msg, &resp.srs.sig_watchtower).unwrap();
+            let leaf = leaf_hash(Hasher::Sha256, &enc(&resp.prr).unwrap());
+            assert_eq!(resp.proof.leaf_index, index - 1);
+            verify_inclusion(Hasher::Sha256, &msg.merkle_root, &leaf, &resp.proof).unwrap();
+            let smt_root = &msg.smt_root;
+            verify_smt_proof(Hasher::Sha256, smt_root, party_id, Some(&leaf), &resp.smt_proof)
+                .unwrap();
+        }
+
+        let (status, body) = call(&st, get("/party/9")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "NOT_FOUND");
+    }
+
+    #[tokio::test]
+    async fn merkle_proofs_place_each_record_at_its_index_and_nowhere_else() {
+        let st = testutil::app_state(testutil::state());
+        let mut receipts = Vec::new();
+        for (party, seq) in [(1, 1), (2, 1), (1, 2)] {
+            let req = RegisterRequest { prr: prr(&party_key(party), party.into(), seq) };
+            let (status, body) = call(&st, post_json("/register", &req)).await;
+            assert_eq!(status, StatusCode::OK);
+            let resp: RegisterResponse = serde_json::from_value(body).unwrap();
+            receipts.push((req.prr, resp.receipt.receipt.assigned_index));
+        }
+        let h = Hasher::Sha256;
+        let pk_w = testutil::watchtower_key().verifying_key();
+        for (registered, index) in receipts {
+            let (status, body) = call(&st, get(&format!("/merkle_proof?index={index}"))).await;
+            assert_eq!(status, StatusCode::OK);
+            let mp: MerkleProofResponse = serde_json::from_value(body).unwrap();
+            assert_eq!((&mp.prr, mp.index), (&registered, index));
+            let msg = &mp.srs.msg;
+            verify_struct(&pk_w, CTX_SNAPSHOT, msg, &mp.srs.sig_watchtower).unwrap();
+            let leaf = leaf_hash(h, &enc(&mp.prr).unwrap());
+            verify_inclusion(h, &msg.merkle_root, &leaf, &mp.proof).unwrap();
+
+            let mut tampered = mp.proof.clone();
+            tampered.siblings[0][0] ^= 1;
+            assert!(verify_inclusion(h, &msg.merkle_root, &leaf, &tampered).is_err());
+            let mut moved = mp.proof.clone();
+            moved.leaf_index = index % 3;
+            assert!(verify_inclusion(h, &msg.merkle_root, &leaf, &moved).is_err());
+            let other = leaf_hash(h, &enc(&prr(&party_key(9), 9, 1)).unwrap());
+            assert!(verify_inclusion(h, &msg.merkle_root, &other, &mp.proof).is_err());
+        }
+        let (status, body) = call(&st, get("/merkle_proof?index=0")).await;
+        assert_eq!((status, body["code"].as_str()), (StatusCode::BAD_REQUEST, Some("BAD_REQUEST")));
+    }
+
+    /// GET /snapshot with the given `If-None-Match`: the status, the ETag and the body.
+    async fn snapshot_if_none_match(
+        st: &AppState,
+        inm: Option<&str>,
+    ) -> (StatusCode, String, axum::body::Bytes) {
+        let mut req = get("/snapshot");
+        if let Some(inm) = inm {
+            req.headers_mut().insert(IF_NONE_MATCH, inm.parse().unwrap());
+        }
+        let resp = router(st.clone()).oneshot(req).await.unwrap();
+        let status = resp.status();
+        let etag = resp.headers()[ETAG].to_str().unwrap().to_string();
+        (status, etag, resp.into_body().collect().await.unwrap().to_bytes())
+    }
+
+    #[tokio::test]
+    async fn snapshot_is_not_resent_while_its_etag_matches() {
+        let st = testutil::app_state(testutil::state());
+        let (status, etag, body) = snapshot_if_none_match(&st, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!body.is_empty());
+        for inm in [etag.clone(), format!("\"x\", {etag}"), "*".to_string()] {
+            let (status, again, body) = snapshot_if_none_match(&st, Some(&inm)).await;
+            assert_eq!(status, StatusCode::NOT_MODIFIED, "{inm}");
+            assert_eq!(again, etag);
+            assert!(body.is_empty());
+        }
+
+        // A new record changes the snapshot, and so its ETag.
+        let req = RegisterRequest { prr: prr(&party_key(1), 1, 1) };
+        assert_eq!(call(&st, post_json("/register", &req)).await.0, StatusCode::OK);
+        let (status, newer, body) = snapshot_if_none_match(&st, Some(&etag)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_ne!(newer, etag);
+        let resp: SnapshotResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(resp.srs.msg.log_len, 1);
+        let (status, ..) = snapshot_if_none_match(&st, Some(&newer)).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn snapshots_carry_their_snapshot_id() {
+        let st = testutil::app_state(testutil::state());
+        for party in 1..=2u8 {
+            let req = RegisterRequest { prr: prr(&party_key(party), party.into(), 1) };
+            assert_eq!(call(&st, post_json("/register", &req)).await.0, StatusCode::OK);
+        }
+        for uri in ["/snapshot", "/snapshot_at?log_len=1"] {
+            let mut req = get(uri);
+            let peer: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+            req.extensions_mut().insert(ConnectInfo(peer));
+            let resp = router(st.clone()).oneshot(req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK, "{uri}");
+            let id = resp.headers()[SNAPSHOT_ID_HEADER].to_str().unwrap().to_string();
+            let body = resp.into_body().collect().await.unwrap().to_bytes();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let msg: SnapshotMessage =
+                serde_json::from_value(body.get("srs").unwrap_or(&body)["msg"].clone()).unwrap();
+            assert_eq!(id, msg.snapshot_id_hex(), "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn consecutive_snapshots_are_byte_identical_until_the_log_grows() {
+        let st = testutil::app_state(testutil::state());
+        let snapshot = || async {
+            let resp = router(st.clone()).oneshot(get("/snapshot")).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            let etag = resp.headers()[ETAG].clone();
+            (etag, resp.into_body().collect().await.unwrap().to_bytes())
+        };
+        let req = RegisterRequest { prr: prr(&party_key(1), 1, 1) };
+        assert_eq!(call(&st, post_json("/register", &req)).await.0, StatusCode::OK);
+        let first = snapshot().await;
+        assert_eq!(snapshot().await, first);
+        assert_eq!(snapshot().await, first);
+
+        let req = RegisterRequest { prr: prr(&party_key(2), 2, 1) };
+        assert_eq!(call(&st, post_json("/register", &req)).await.0, StatusCode::OK);
+        let second = snapshot().await;
+        assert_ne!(second.1, first.1);
+        assert_eq!(snapshot().await, second);
+    }
+
+    /// POST /register with `prr`; the status and the error code, if any.
+    async fn register_code(st: &AppState, prr: PartyRegistrationRecord) -> (StatusCode, String) {
+        let (status, body) = call(st, post_json("/register", &RegisterRequest { prr })).await;
+        (status, body["code"].as_str().unwrap_or_default().to_string())
+    }
+
+    #[tokio::test]
+    async fn each_rejection_has_its_own_code() {
+        let bad = StatusCode::BAD_REQUEST;
+        let st = testutil::app_state(testutil::state());
+        let other_epoch = prr_in_epoch(EPOCH + 1, 1, 1);
+        assert_eq!(register_code(&st, other_epoch).await, (bad, "EPOCH_MISMATCH".into()));
+        let mut wt = testutil::state();
+        wt.epoch_window_fwd = 1;
+        let st = testutil::app_state(wt);
+        let far = prr_in_epoch(EPOCH + 5, 1, 1);
+        assert_eq!(register_code(&st, far).await, (bad, "EPOCH_OUT_OF_WINDOW".into()));
+        let mut settings = testutil::settings();
+        settings.max_parties = Some(2);
+        settings.max_log_len = Some(4);
+        let st = testutil::app_state(WatchtowerState::new(EPOCH, settings, false));
+        let (a, b) = (party_key(1), party_key(2));
+        assert_eq!(register_code(&st, prr(&a, 1, 3)).await.0, StatusCode::OK);
+        let mut forged = prr(&a, 1, 4);
+        forged.sig_party = prr(&b, 1, 4).sig_party;
+        assert_eq!(register_code(&st, forged).await, (bad, "BAD_SIGNATURE".into()));
+        let mut msg = prr(&a, 1, 4).msg;
+        msg.pk_party = [0; 32];
+        msg.pk_party[0] = 1; // The identity point.
+        let weak = testutil::sign(&a, msg);
+        assert_eq!(register_code(&st, weak).await, (bad, "BAD_PUBKEY".into()));
+        let stale = prr(&a, 1, 2);
+        assert_eq!(register_code(&st, stale).await, (bad, "SEQ_NOT_INCREASING".into()));
+        let mut msg = prr(&a, 1, 4).msg;
+        msg.nonce = [3; 16];
+        let replayed = testutil::sign(&a, msg);
+        assert_eq!(register_code(&st, replayed).await, (bad, "NONCE_REUSED".into()));
+
+        assert_eq!(register_code(&st, prr(&b, 2, 1)).await.0, StatusCode::OK);
+        let third = prr(&party_key(3), 3, 1);
+        assert_eq!(register_code(&st, third).await, (bad, "PARTY_CAP_REACHED".into()));
+        for seq in [2, 3] {
+            assert_eq!(register_code(&st, prr(&b, 2, seq)).await.0, StatusCode::OK);
+        }
+        assert_eq!(register_code(&st, prr(&b, 2, 4)).await, (bad, "LOG_FULL".into()));
+
+        for (uri, code) in [
+            ("/party/9", "NOT_FOUND"),
+            ("/snapshot?epoch=99", "NOT_FOUND"),
+            ("/merkle_proof?index=9", "OUT_OF_RANGE"),
+            ("/snapshot_at?log_len=9", "OUT_OF_RANGE"),
+            ("/consistency?from=1&to=9", "OUT_OF_RANGE"),
+            ("/entries?from=9", "OUT_OF_RANGE"),
+        ] {
+            let (status, body) = call(&st, get(uri)).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{uri}");
+            assert_eq!(body["code"], code, "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_the_extractors_refuse_still_get_a_coded_json_error() {
+        let st = testutil::app_state(testutil::state());
+        let malformed = Request::post("/register")
+            .header("content-type", "application/json")
+            .body(Body::from("{\"prr\":"))
+            .unwrap();
+        let untyped = Request::post("/register").body(Body::from("{}")).unwrap();
+        for (req, status) in [
+            (get("/entries?from=one"), StatusCode::BAD_REQUEST),
+            (get("/entries?from=1&limit=-1"), StatusCode::BAD_REQUEST),
+            (get("/party/nine"), StatusCode::BAD_REQUEST),
+            (malformed, StatusCode::BAD_REQUEST),
+            (post_json("/register", &serde_json::json!({})), StatusCode::UNPROCESSABLE_ENTITY),
+            (untyped, StatusCode::UNSUPPORTED_MEDIA_TYPE),
+        ] {
+            let uri = req.uri().clone();
+            let (got, body) = call(&st, req).await;
+            assert_eq!(got, status, "{uri}");
+            assert_eq!(body["code"], "BAD_REQUEST", "{uri}: {body}");
+            assert!(body["message"].as_str().is_some_and(|m| !m.is_empty()), "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn a_resubmitted_registration_gets_its_original_answer_and_logs_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wt.log");
+        let path = path.to_str().unwrap();
+        let mut wt = testutil::state();
+        wt.open_log(path).unwrap();
+        let st = testutil::app_state(wt);
+        let register = |prr: PartyRegistrationRecord| {
+            let st = st.clone();
+            async move { call(&st, post_json("/register", &RegisterRequest { prr })).await }
+        };
+
+        let first = prr(&party_key(1), 1, 1);
+        let (status, original) = register(first.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(register(prr(&party_key(2), 2, 1)).await.0, StatusCode::OK);
+        // The response was lost; the party sends the very same record again.
+        let (status, again) = register(first.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(again, original);
+        let resp: RegisterResponse = serde_json::from_value(again).unwrap();
+        let pk_w = testutil::watchtower_key().verifying_key();
+        let receipt = &resp.receipt;
+        verify_struct(&pk_w, CTX_RECEIPT, &receipt.receipt, &receipt.sig_watchtower).unwrap();
+        assert_eq!(receipt.receipt.assigned_index, 1);
+
+        let mut msg = first.msg.clone();
+        msg.endpoint.addr = "10.0.0.9:9000".into();
+        let (status, body) = register(testutil::sign(&party_key(1), msg)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "SEQ_NOT_INCREASING");
+
+        // Neither the resubmission nor the refused record reached the log file.
+        st.inner.lock().unwrap().flush().unwrap();
+        let mut replayed = testutil::state();
+        replayed.open_log(path).unwrap();
+        assert_eq!(replayed.epoch(None).unwrap().log.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn log_write_failures_are_internal_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wt.log");
+        let mut wt = testutil::state();
+        wt.open_log(path.to_str().unwrap()).unwrap();
+        let st = testutil::app_state(wt);
+        for seq in 1..=2 {
+            let req = RegisterRequest { prr: prr(&party_key(1), 1, seq) };
+            assert_eq!(call(&st, post_json("/register", &req)).await.0, StatusCode::OK);
+        }
+        // The compaction's temp file can't be created where a directory stands.
+        std::fs::create_dir(dir.path().join("wt.log.tmp")).unwrap();
+        let compact = Request::post("/compact").body(Body::empty()).unwrap();
+        let (status, body) = call(&st, compact).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body["code"], "INTERNAL");
+        // Nothing was compacted in memory either.
+        assert!(st.inner.lock().unwrap().epoch(None).unwrap().log[0].record().is_some());
+    }
+
+    /// Open /snapshot/subscribe for the default epoch; the response body is the stream.
+    async fn subscribe(st: &AppState) -> Response {
+        let req = get("/snapshot/subscribe");
+        router(st.clone()).oneshot(req).await.unwrap()
+    }
+
+    /// The next SSE event's data line, parsed.
+    async fn next_event(body: &mut Body) -> serde_json::Value {
+        loop {
+            let frame = body.frame().await.unwrap().unwrap();
+            let Ok(data) = frame.into_data() else { continue };
+            let text = String::from_utf8(data.to_vec()).unwrap();
+            if let Some(json) = text.lines().find_map(|l| l.strip_prefix("data: ")) {
+                return serde_json::from_str(json).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribers_get_each_published_snapshot() {
+        let st = testutil::app_state(testutil::state());
+        let resp = subscribe(&st).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let mut body = resp.into_body();
+        assert_eq!(next_event(&mut body).await["srs"]["msg"]["log_len"], 0);
+
+        for (n, party) in [(1, 1), (2, 2)] {
+            let req = RegisterRequest { prr: prr(&party_key(party), party.into(), 1) };
+            let (status, _) = call(&st, post_json("/register", &req)).await;
+            assert_eq!(status, StatusCode::OK);
+            let event = next_event(&mut body).await;
+            assert_eq!(event["srs"]["msg"]["log_len"], n);
+            // What the stream sent is what was published, serialized once.
+            let published = st.snapshots.borrow()[&EPOCH].json.clone();
+            assert_eq!(event, serde_json::from_str::<serde_json::Value>(&published).unwrap());
+        }
+
+        let (status, _) = call(&st, Request::post("/finalize").body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(next_event(&mut body).await["finalized"], true);
+    }
+
+    #[tokio::test]
+    async fn subscribers_are_capped() {
+        let mut st = testutil::app_state(testutil::state());
+        st.subscribers = Arc::new(Semaphore::new(1));
+        let first = subscribe(&st).await;
+        assert_eq!(first.status(), StatusCode::OK);
+        let (status, body) = call(&st, get("/snapshot/subscribe")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body["code"], "RATE_LIMITED");
+
+        // Closing a stream frees its slot.
+        drop(first);
+        assert_eq!(subscribe(&st).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn other_epochs_are_served_once_created() {
+        let mut wt = WatchtowerState::new(EPOCH, testutil::settings(), true);
+        wt.max_epochs = Some(2);
+        let st = testutil::app_state(wt);
+        let (status, _) = call(&st, get(&format!("/snapshot?epoch={}", EPOCH + 1))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let mut msg = prr(&party_key(1), 1, 1).msg;
+        msg.epoch = EPOCH + 1;
+        let req = RegisterRequest { prr: testutil::sign(&party_key(1), msg.clone()) };
+        let (status, _) = call(&st, post_json("/register", &req)).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = call(&st, get(&format!("/snapshot?epoch={}", EPOCH + 1))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["srs"]["msg"]["log_len"], 1);
+        let (_, body) = call(&st, get("/snapshot")).await;
+        assert_eq!(body["srs"]["msg"]["log_len"], 0);
+
+        msg.epoch = EPOCH + 2;
+        let req = RegisterRequest { prr: testutil::sign(&party_key(1), msg) };
+        let (status, body) = call(&st, post_json("/register", &req)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["message"].as_str().unwrap().contains("epoch cap"), "{body}");
+    }
+
+    /// POST /verify with `prr`; the failed checks with their codes, and whether the record
+    /// would be accepted.
+    async fn verify_failures(st: &AppState, prr: PartyRegistrationRecord) -> (Vec<String>, bool) {
+        let (status, body) = call(st, post_json("/verify", &RegisterRequest { prr })).await;
+        assert_eq!(status, StatusCode::OK);
+        let report: VerifyResponse = serde_json::from_value(body).unwrap();
+        let failed = report.checks.iter().filter(|c| !c.passed);
+        let failed = failed.map(|c| format!("{}: {}", c.check, c.error.as_ref().unwrap().code));
+        (failed.collect(), report.accepted)
+    }
+
+    #[tokio::test]
+    async fn verify_reports_each_failing_check_without_logging_anything() {
+        let st = testutil::app_state(testutil::state());
+        let (a, b) = (party_key(1), party_key(2));
+        let log_len = |st: &AppState| st.inner.lock().unwrap().epoch(None).unwrap().log.len();
+        let forge = |mut prr: PartyRegistrationRecord| {
+            prr.sig_party = testutil::sign(&b, prr.msg.clone()).sig_party;
+            prr
+        };
+
+        // A valid record passes every check, and is not logged.
+        let valid = prr(&a, 1, 2);
+        let req = RegisterRequest { prr: valid.clone() };
+        let (status, body) = call(&st, post_json("/verify", &req)).await;
+        assert_eq!(status, StatusCode::OK);
+        let report: VerifyResponse = serde_json::from_value(body).unwrap();
+        assert!(report.accepted && !report.resubmission);
+        let checks: Vec<&str> = report.checks.iter().map(|c| c.check.as_str()).collect();
+        let all = ["version", "epoch", "endpoint", "pubkey", "signature", "created_at", "seq"];
+        assert_eq!(checks, [&all[..], &["nonce", "key_binding", "caps"]].concat());
+        assert!(report.checks.iter().all(|c| c.passed && c.error.is_none()));
+        assert_eq!(log_len(&st), 0);
+        assert_eq!(register_code(&st, valid.clone()).await.0, StatusCode::OK);
+
+        // Each failure is reported on its own check, with the code /register answers.
+        let (failed, accepted) = verify_failures(&st, prr_in_epoch(EPOCH + 1, 1, 3)).await;
+        assert_eq!((failed, accepted), (vec!["epoch: EPOCH_MISMATCH".to_string()], false));
+        let (failed, _) = verify_failures(&st, forge(prr(&a, 1, 3))).await;
+        assert_eq!(failed, ["signature: BAD_SIGNATURE"]);
+        let (failed, _) = verify_failures(&st, prr(&a, 1, 1)).await;
+        assert_eq!(failed, ["seq: SEQ_NOT_INCREASING"]);
+        // Unlike /register, every check runs: a record failing several reports them all.
+        let (failed, _) = verify_failures(&st, forge(prr(&a, 1, 1))).await;
+        assert_eq!(failed, ["signature: BAD_SIGNATURE", "seq: SEQ_NOT_INCREASING"]);
+
+        // One record per remaining check, each failing only that one.
+        {
+            let mut guard = st.inner.lock().unwrap();
+            let epoch = guard.epoch_mut(None).unwrap();
+            epoch.max_future_skew_secs = Some(60);
+            epoch.max_parties = Some(1);
+        }
+        let changed = |party: u8, seq, change: &dyn Fn(&mut RegistrationMessage)| {
+            let mut msg = prr(&party_key(party), party.into(), seq).msg;
+            change(&mut msg);
+            testutil::sign(&party_key(party), msg)
+        };
+        let cases = [
+            (changed(1, 3, &|m| m.version += 1), "version: BAD_REQUEST"),
+            (changed(1, 3, &|m| m.endpoint.addr = "nowhere".into()), "endpoint: BAD_REQUEST"),
+            (changed(1, 3, &|m| m.created_at_unix += 3600), "created_at: BAD_REQUEST"),
+            (changed(1, 3, &|m| m.nonce = [2; 16]), "nonce: NONCE_REUSED"),
+            (prr(&b, 1, 3), "key_binding: BAD_REQUEST"),
+            (prr(&party_key(3), 3, 1), "caps: PARTY_CAP_REACHED"),
+        ];
+        for (prr, expected) in cases {
+            assert_eq!(verify_failures(&st, prr).await, (vec![expected.to_string()], false));
+        }
+        // A degenerate key also fails the signature and the party's bound key.
+        let weak = changed(1, 3, &|m| {
+            m.pk_party = [0; 32];
+            m.pk_party[0] = 1; // The identity point.
+        });
+        let (failed, _) = verify_failures(&st, weak).await;
+        let expected = ["pubkey: BAD_PUBKEY", "signature: BAD_SIGNATURE"];
+        assert_eq!(failed, [&expected[..], &["key_binding: BAD_REQUEST"]].concat());
+
+        // An exact resubmission would get its original answer.
+        let (_, body) = call(&st, post_json("/verify", &RegisterRequest { prr: valid })).await;
+        assert_eq!((&body["accepted"], &body["resubmission"]), (&true.into(), &true.into()));
+        assert_eq!(log_len(&st), 1);
+    }
+
+    /// `prr(party_key(party), party, seq)`, for `epoch` instead.
+    fn prr_in_epoch(epoch: u64, party: u8, seq: u64) -> PartyRegistrationRecord {
+        let mut msg = prr(&party_key(party), party.into(), seq).msg;
+        msg.epoch = epoch;
+        testutil::sign(&party_key(party), msg)
+    }
+
+    #[tokio::test]
+    async fn snapshot_at_commits_to_the_entries_up_to_it() {
+        let st = testutil::app_state(testutil::state());
+        for (party, seq) in [(1, 1), (2, 1), (1, 2), (3, 1), (2, 2)] {
+            let req = RegisterRequest { prr: prr(&party_key(party), party.into(), seq) };
+            assert_eq!(call(&st, post_json("/register", &req)).await.0, StatusCode::OK);
+        }
+        let pk_w = testutil::watchtower_key().verifying_key();
+        let h = Hasher::Sha256;
+        for k in 1..=5u64 {
+            let (status, body) = call(&st, get(&format!("/snapshot_at?log_len={k}"))).await;
+            assert_eq!(status, StatusCode::OK);
+            let srs: SignedRosterSnapshot = serde_json::from_value(body).unwrap();
+            verify_struct(&pk_w, CTX_SNAPSHOT, &srs.msg, &srs.sig_watchtower).unwrap();
+            assert_eq!(srs.msg.log_len, k);
+
+            let req = get(&format!("/entries?from=1&to={k}"));
+            let lines = entries_lines(router(st.clone()).oneshot(req).await.unwrap()).await;
+            let entries: Vec<LogEntry> =
+                lines.into_iter().map(|l| serde_json::from_value(l).unwrap()).collect();
+            assert_eq!(entries.len() as u64, k);
+            let leaves = entries.iter().map(|e| e.leaf(h).unwrap()).collect();
+            assert_eq!(srs.msg.merkle_root, merkle_root(h, leaves), "k={k}");
+        }
+        let (_, body) = call(&st, get("/snapshot_at?log_len=0")).await;
+        assert_eq!(body["msg"]["merkle_root"], serde_json::json!(empty_root(h)));
+    }
+
+    /// The NDJSON lines of an /entries response, parsed.
+    async fn entries_lines(resp: Response) -> Vec<serde_json::Value> {
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        text.lines().map(|l| serde_json::from_str(l).unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn entries_streams_end_with_an_error_if_the_log_is_compacted() {
+        let st = testutil::app_state(testutil::state());
+        for (party, seq) in [(1, 1), (2, 1), (1, 2)] {
+            let req = RegisterRequest { prr: prr(&party_key(party), party.into(), seq) };
+            assert_eq!(call(&st, post_json("/register", &req)).await.0, StatusCode::OK);
+        }
+
+        // The stream is only read once the response is; compact in between.
+        let resp = router(st.clone()).oneshot(get("/entries?from=1")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let compact = Request::post("/compact").body(Body::empty()).unwrap();
+        let (status, body) = call(&st, compact).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["tombstoned"], 1);
+        let lines = entries_lines(resp).await;
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["error"]["code"], "LOG_CHANGED");
+
+        // A stream begun after the compaction serves the tombstone in place.
+        let resp = router(st.clone()).oneshot(get("/entries?from=1")).await.unwrap();
+        let lines = entries_lines(resp).await;
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["tombstone"]["party_id"], 1);
+        assert_eq!(lines[1]["msg"]["party_id"], 2);
+    }
+
+    #[tokio::test]
+    async fn entries_streams_a_large_range_one_batch_at_a_time() {
+        const N: u64 = 10_000;
+        let template = prr(&party_key(1), 1, 1);
+        let with_seq = |seq| {
+            let mut prr = template.clone();
+            prr.msg.seq = seq;
+            LogEntry::from(prr)
+        };
+        let mut st = testutil::app_state(testutil::state());
+        st.max_entries_limit = N;
+        {
+            // Straight into the log: /entries serves it as is, unverified.
+            let mut wt = st.inner.lock().unwrap();
+            wt.epoch_mut(None).unwrap().log.extend((1..=N).map(with_seq));
+        }
+
+        let resp = router(st).oneshot(get("/entries?from=1")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get(NEXT_FROM_HEADER).is_none());
+        let mut body = resp.into_body();
+        let (mut frames, mut largest, mut seqs) = (0, 0, Vec::new());
+        while let Some(frame) = body.frame().await {
+            let data = frame.unwrap().into_data().unwrap();
+            frames += 1;
+            largest = largest.max(data.len());
+            let text = std::str::from_utf8(&data).unwrap();
+            assert!(text.ends_with('\n'), "a frame split a line");
+            for line in text.lines() {
+                let entry: LogEntry = serde_json::from_str(line).unwrap();
+                seqs.push(entry.seq());
+            }
+        }
+        assert_eq!(seqs, (1..=N).collect::<Vec<_>>());
+        // Never more than one batch of lines is held at a time.
+        assert_eq!(frames, N.div_ceil(ENTRIES_STREAM_BATCH));
+        let line_len = serde_json::to_vec(&with_seq(N)).unwrap().len();
+        assert!(largest <= (line_len + 1) * ENTRIES_STREAM_BATCH as usize, "{largest} bytes");
+    }
+
+    #[tokio::test]
+    async fn entries_pages_stop_at_the_limit_and_point_at_the_rest() {
+        let mut st = testutil::app_state(testutil::state());
+        st.max_entries_limit = 2;
+        for party in 1..=5 {
+            let req = RegisterRequest { prr: prr(&party_key(party), party.into(), 1) };
+            assert_eq!(call(&st, post_json("/register", &req)).await.0, StatusCode::OK);
+        }
+
+        let page = |uri: &'static str| {
+            let st = st.clone();
+            async move {
+                let resp = router(st).oneshot(get(uri)).await.unwrap();
+                assert_eq!(resp.status(), StatusCode::OK, "{uri}");
+                let next = resp.headers().get(NEXT_FROM_HEADER).map(|v| v.to_str().unwrap());
+                let next = next.map(|v| v.parse::<u64>().unwrap());
+                let lines = entries_lines(resp).await;
+                let ids = lines.iter().map(|l| l["msg"]["party_id"].as_u64().unwrap());
+                (ids.collect::<Vec<_>>(), next)
+            }
+        };
+        // Unasked, the page is the server's maximum.
+        assert_eq!(page("/entries?from=1").await, (vec![1, 2], Some(3)));
+        assert_eq!(page("/entries?from=3&limit=1").await, (vec![3], Some(4)));
+        assert_eq!(page("/entries?from=4").await, (vec![4, 5], None));
+        // A page cut short by `to` rather than the limit has nothing after it.
+        assert_eq!(page("/entries?from=2&to=3").await, (vec![2, 3], None));
+        assert_eq!(page("/entries?from=2&to=4").await, (vec![2, 3], Some(4)));
+
+        for uri in ["/entries?from=1&limit=3", "/entries?from=1&limit=0"] {
+            let (status, body) = call(&st, get(uri)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{uri}");
+            assert_eq!(body["code"], "BAD_REQUEST", "{uri}");
+            assert!(body["message"].as_str().unwrap().contains("must be 1..=2"), "{body}");
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_refuses_new_connections_but_finishes_in_flight_ones() {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+        use tokio::net::{TcpListener, TcpStream};
+
+        let started = Arc::new(tokio::sync::Notify::new());
+        let slow = {
+            let started = started.clone();
+            axum::routing::get(move || async move {
+                started.notify_one();
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                "done"
+            })
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, Router::new().route("/slow", slow), async {
+            let _ = stopped.await;
+        }));
+
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        conn.write_all(b"GET /slow HTTP/1.1\r\nhost: x\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        started.notified().await;
+        stop.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(TcpStream::connect(addr).await.is_err(), "still accepting after shutdown");
+
+        let mut resp = String::new();
+        conn.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200"), "{resp}");
+        assert!(resp.ends_with("done"), "{resp}");
+        tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_https_with_the_configured_certificate() {
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let mut ca_params = rcgen::CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        ca_params.distinguished_name.push(rcgen::DnType::CommonName, "test root");
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let key = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params.distinguished_name.push(rcgen::DnType::CommonName, "localhost");
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        std::fs::write(path("cert.pem"), cert.pem()).unwrap();
+        std::fs::write(path("key.pem"), key.serialize_pem()).unwrap();
+        assert!(tls_config(&path("cert.pem"), &path("missing.pem")).await.is_err());
+        let tls = tls_config(&path("cert.pem"), &path("key.pem")).await.unwrap();
+
+        let st = testutil::app_state(testutil::state());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("https://localhost:{}/snapshot", listener.local_addr().unwrap().port());
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve_tls(listener, tls, router(st.clone()), async {
+            let _ = stopped.await;
+        }));
+
+        // The root isn't a built-in one.
+        let err = reqwest::get(&url).await.unwrap_err();
+        assert!(err.is_connect(), "{err}");
+        let root = reqwest::Certificate::from_pem(ca.pem().as_bytes()).unwrap();
+        let client = reqwest::Client::builder().add_root_certificate(root).build().unwrap();
+        let resp = client.get(&url).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let sr: SnapshotResponse = resp.json().await.unwrap();
+        let expected = st.inner.lock().unwrap().epoch(None).unwrap().snapshot().unwrap();
+        assert_eq!(sr.srs, expected);
+
+        stop.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn snapshot_at_is_rate_limited_per_ip() {
+        let mut st = testutil::app_state(testutil::state());
+        for party in 1..=2u8 {
+            let req = RegisterRequest { prr: prr(&party_key(party), party.into(), 1) };
+            assert_eq!(call(&st, post_json("/register", &req)).await.0, StatusCode::OK);
+        }
+        st.ip_limiter = Arc::new(Mutex::new(RateLimiter::new(2, 0.001)));
+        let (status, first) = call(&st, get("/snapshot_at?log_len=1")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(first["msg"]["log_len"], 1);
+        let (status, again) = call(&st, get("/snapshot_at?log_len=1")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(again, first);
+        let (status, body) = call(&st, get("/snapshot_at?log_len=1")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body["code"], "RATE_LIMITED");
+    }
+}
@@ -1,4 +1,6 @@
 use clap::Parser;
+use common::crypto::Hasher;
+use common::logging::LogFormat;
 
 #[derive(Debug, Parser)]
 pub struct Config {
@@ -6,11 +8,155 @@ pub struct Config {
     #[arg(long, default_value = "0.0.0.0:7000")]
     pub bind: String,
 
-    /// Epoch/session id.
+    /// PEM certificate chain for HTTPS. Requires --tls-key; plain HTTP if neither is set.
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<String>,
+
+    /// PEM private key for HTTPS. Requires --tls-cert.
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<String>,
+
+    /// Epoch/session id. With --multi-epoch, the epoch served to requests that don't name one.
     #[arg(long, default_value_t = 1)]
     pub epoch: u64,
 
+    /// Also accept registrations for other epochs, each with its own log (and log file,
+    /// `<log-file>.epoch-<n>`), created on its first registration. Requests select an
+    /// epoch with `?epoch=`.
+    #[arg(long)]
+    pub multi_epoch: bool,
+
+    /// Also accept registrations for up to this many epochs before --epoch (e.g. parties
+    /// lagging behind a rollover), each into its own log as with --multi-epoch.
+    #[arg(long, default_value_t = 0, conflicts_with = "multi_epoch")]
+    pub epoch_window_back: u64,
+
+    /// Also accept registrations for up to this many epochs after --epoch.
+    #[arg(long, default_value_t = 0, conflicts_with = "multi_epoch")]
+    pub epoch_window_fwd: u64,
+
+    /// Most epochs held at once, the default one included. A registration that would
+    /// create another (with --multi-epoch or an epoch window) is refused.
+    #[arg(long, default_value_t = 64)]
+    pub max_epochs: u64,
+
     /// Watchtower key file path (JSON). Generated if missing.
     #[arg(long, default_value = "watchtower_key.json")]
     pub key_file: String,
+
+    /// Passphrase for encrypting/decrypting the key file. Plaintext key file if unset.
+    #[arg(long, env = "WATCHTOWER_KEY_PASSPHRASE", hide_env_values = true)]
+    pub key_passphrase: Option<String>,
+
+    /// Read the key seed (base64) from this environment variable instead of --key-file.
+    #[arg(long, conflicts_with = "key_stdin")]
+    pub key_env: Option<String>,
+
+    /// Read the key seed (base64) from stdin instead of --key-file.
+    #[arg(long)]
+    pub key_stdin: bool,
+
+    /// Hash for the Merkle trees: sha256 or blake3. Recorded in every snapshot; a log file
+    /// must be reopened with the hash it was written with.
+    #[arg(long, default_value_t = Hasher::Sha256)]
+    pub hash_alg: Hasher,
+
+    /// Append-only log file for accepted registrations. In-memory only if unset.
+    #[arg(long)]
+    pub log_file: Option<String>,
+
+    /// Run as a read-only replica: serve /snapshot, /entries and the other reads from
+    /// --log-file (which another watchtower appends to) without ever writing to it;
+    /// /register, /finalize and /compact answer 405. Replicas are eventually consistent:
+    /// they serve the log as of their last read of the file, which may trail the primary's.
+    ///
+    /// NOTE: a replica signs its snapshots with its OWN key (--key-file, --key-env or
+    /// --key-stdin). Parties pinning the primary's watchtower_pk refuse snapshots signed
+    /// with any other key, so give the replica the primary's key; otherwise its clients
+    /// must pin the replica's key instead.
+    #[arg(long, requires = "log_file")]
+    pub read_only: bool,
+
+    /// How often a --read-only replica checks --log-file for new records (ms).
+    #[arg(long, default_value_t = 1000, requires = "read_only")]
+    pub follow_interval_ms: u64,
+
+    /// Bearer token required on mutating endpoints (/register, /finalize, /compact). Open if unset.
+    #[arg(long, env = "WATCHTOWER_OPERATOR_TOKEN", hide_env_values = true)]
+    pub operator_token: Option<String>,
+
+    /// Maximum number of entries returned by a single /entries request.
+    #[arg(long, default_value_t = 1000)]
+    pub max_entries_limit: u64,
+
+    /// Most /snapshot/subscribe streams open at once; further subscribers get 429.
+    #[arg(long, default_value_t = 1024)]
+    pub max_subscribers: usize,
+
+    /// Largest request body accepted (bytes); bigger ones get 413. A registration is a
+    /// few KiB of JSON.
+    #[arg(long, default_value_t = 64 * 1024)]
+    pub max_body_bytes: usize,
+
+    /// Reject registrations whose created_at_unix is more than this many seconds in the future.
+    /// Unset means no check.
+    #[arg(long)]
+    pub max_future_skew_secs: Option<u64>,
+
+    /// Refuse registrations once the log holds this many records. Unset means unbounded.
+    /// Records replayed from --log-file are always loaded; a cap below the replayed length
+    /// just closes registrations.
+    #[arg(long)]
+    pub max_log_len: Option<u64>,
+
+    /// Refuse registrations from new party_ids once this many distinct parties are known.
+    /// Known parties can still re-register (up to --max-log-len). Unset means unbounded.
+    #[arg(long)]
+    pub max_parties: Option<u64>,
+
+    /// Refuse any registration at a party's last accepted seq. By default a byte-identical
+    /// resubmission is answered with its original snapshot and receipt, so a party that
+    /// retries after a lost response (at-least-once delivery) still gets its receipt; in
+    /// strict mode that retry is refused and the party must re-register with a higher seq.
+    #[arg(long)]
+    pub strict_seq: bool,
+
+    /// Sign and persist a checkpoint snapshot every this many log entries
+    /// (served on /checkpoints). Disabled if unset.
+    #[arg(long)]
+    pub checkpoint_interval: Option<u64>,
+
+    /// /register burst allowed per party_id before throttling with 429.
+    #[arg(long, default_value_t = 20)]
+    pub party_rate_burst: u32,
+
+    /// Sustained /register rate per party_id, in requests per second.
+    #[arg(long, default_value_t = 1.0)]
+    pub party_rate_per_sec: f64,
+
+    /// /register burst allowed per client IP before throttling with 429.
+    /// Behind a reverse proxy all clients share the proxy's IP; size this accordingly.
+    #[arg(long, default_value_t = 200)]
+    pub ip_rate_burst: u32,
+
+    /// Sustained /register rate per client IP, in requests per second.
+    #[arg(long, default_value_t = 20.0)]
+    pub ip_rate_per_sec: f64,
+
+    /// Log output: text, or json (one object per line, each carrying its request_id).
+    #[arg(long, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_only_needs_a_log_file() {
+        let err = Config::try_parse_from(["watchtower", "--read-only"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::MissingRequiredArgument);
+        let cfg = Config::try_parse_from(["watchtower", "--read-only", "--log-file", "wt.log"]);
+        assert!(cfg.unwrap().read_only);
+    }
 }
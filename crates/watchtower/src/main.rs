@@ -1,36 +1,136 @@
 mod api;
 mod config;
+mod persist;
 mod state;
+#[cfg(test)]
+mod testutil;
 
-use crate::{api::AppState, config::Config, state::WatchtowerState};
+use crate::{
+    api::AppState,
+    config::Config,
+    state::{watchtower_key, EpochSettings, WatchtowerState},
+};
 use axum::Router;
 use clap::Parser;
+use common::keyfile::seed_from_env_or_stdin;
+use common::logging;
+use common::ratelimit::RateLimiter;
+use common::shutdown;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, Semaphore};
 use tower_http::trace::TraceLayer;
-use tracing::info;
+use tracing::{info, warn};
 use base64::Engine as _;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt().init();
     let cfg = Config::parse();
+    logging::init(cfg.log_format);
+    let started_at = Instant::now();
+    // Not ready until the watchtower state has been loaded.
+    let ready = Arc::new(AtomicBool::new(false));
 
-    let wt_state = WatchtowerState::load_or_create(cfg.epoch, &cfg.key_file)?;
+    let seed = seed_from_env_or_stdin(
+        cfg.key_env.as_deref(),
+        cfg.key_stdin,
+        |var| std::env::var(var),
+        std::io::stdin(),
+    )?;
+    let sk_w = watchtower_key(seed, &cfg.key_file, cfg.key_passphrase.as_deref())?;
+    let mut settings = EpochSettings::new(sk_w);
+    settings.max_future_skew_secs = cfg.max_future_skew_secs;
+    settings.max_log_len = cfg.max_log_len;
+    settings.max_parties = cfg.max_parties;
+    settings.checkpoint_interval = cfg.checkpoint_interval.filter(|n| *n > 0);
+    settings.strict_seq = cfg.strict_seq;
+    settings.hasher = cfg.hash_alg;
+    let mut wt_state = WatchtowerState::new(cfg.epoch, settings, cfg.multi_epoch);
+    wt_state.epoch_window_back = cfg.epoch_window_back;
+    wt_state.epoch_window_fwd = cfg.epoch_window_fwd;
+    wt_state.max_epochs = Some(cfg.max_epochs);
+    if let Some(path) = &cfg.log_file {
+        if cfg.read_only {
+            wt_state.load_log_read_only(path)?;
+            info!("log file = {} (read-only replica)", path);
+        } else {
+            wt_state.open_log(path)?;
+            info!("log file = {}", path);
+        }
+        for (epoch, st) in &wt_state.epochs {
+            info!("epoch {epoch}: replayed {} entries", st.log.len());
+        }
+    }
+    ready.store(true, Ordering::Release);
     let pk_b64 = base64::engine::general_purpose::STANDARD.encode(wt_state.watchtower_pubkey_bytes());
 
     info!("Watchtower starting on {}", cfg.bind);
-    info!("epoch = {}", cfg.epoch);
+    info!("epoch = {}{}", cfg.epoch, if cfg.multi_epoch { " (default; multi-epoch)" } else { "" });
     info!("watchtower_pubkey_b64 = {}", pk_b64);
+    if cfg.read_only {
+        warn!("read-only replica: snapshots are signed with {pk_b64}, which parties must pin");
+    }
 
+    let inner = Arc::new(Mutex::new(wt_state));
     let shared = AppState {
-        inner: Arc::new(Mutex::new(wt_state)),
+        inner: inner.clone(),
+        started_at,
+        ready: ready.clone(),
+        max_entries_limit: cfg.max_entries_limit,
+        max_body_bytes: cfg.max_body_bytes,
+        operator_token: cfg.operator_token.as_deref().map(Arc::from),
+        party_limiter: Arc::new(Mutex::new(RateLimiter::new(
+            cfg.party_rate_burst,
+            cfg.party_rate_per_sec,
+        ))),
+        ip_limiter: Arc::new(Mutex::new(RateLimiter::new(cfg.ip_rate_burst, cfg.ip_rate_per_sec))),
+        snapshots: Arc::new(watch::channel(Default::default()).0),
+        subscribers: Arc::new(Semaphore::new(cfg.max_subscribers)),
+        read_only: cfg.read_only,
     };
+    let subscriptions = shared.clone();
+    if cfg.read_only && cfg.log_file.is_some() {
+        let follower = shared.clone();
+        let every = Duration::from_millis(cfg.follow_interval_ms.max(1));
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(every);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticks.tick().await;
+                if let Err(e) = follower.catch_up().await {
+                    warn!("failed to catch up with the log file: {e:#}");
+                }
+            }
+        });
+    }
 
-    let app: Router = api::router(shared).layer(TraceLayer::new_for_http());
+    let app: Router =
+        api::router(shared).layer(TraceLayer::new_for_http().make_span_with(api::request_span));
+
+    // On SIGINT/SIGTERM: stop accepting, report not-ready, let in-flight requests finish.
+    // Snapshot subscriptions never finish on their own, so they are woken to end.
+    let shutdown = async move {
+        shutdown::signal().await;
+        info!("shutdown requested; draining in-flight requests");
+        ready.store(false, Ordering::Release);
+        subscriptions.close_subscriptions();
+    };
 
     let addr: SocketAddr = cfg.bind.parse()?;
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    if let (Some(cert), Some(key)) = (&cfg.tls_cert, &cfg.tls_key) {
+        let tls = api::tls_config(cert, key).await?;
+        info!("serving HTTPS (cert = {})", cert);
+        api::serve_tls(std::net::TcpListener::bind(addr)?, tls, app, shutdown).await?;
+    } else {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        api::serve(listener, app, shutdown).await?;
+    }
+
+    let mut st = inner.lock().unwrap();
+    st.flush()?;
+    let log_len = st.epoch(None)?.log.len();
+    info!("watchtower stopped cleanly (log_len = {log_len})");
     Ok(())
 }
@@ -0,0 +1,181 @@
+use anyhow::{anyhow, Result};
+use common::types::{PartyRegistrationRecord, SignedRosterSnapshot, Tombstone};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use tracing::warn;
+
+/// One record of the append-only watchtower log file (one JSON object per line).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum LogRecord {
+    /// First record of every log file: the `Hasher` tag its leaves and snapshots use.
+    Header { hash_alg: u8 },
+    /// An accepted registration and when the watchtower received it (unix seconds), in
+    /// log order.
+    Accepted { prr: PartyRegistrationRecord, received_at_unix: u64 },
+    /// The epoch was finalized with this snapshot; no registrations follow.
+    Finalized(SignedRosterSnapshot),
+    /// A checkpoint snapshot signed when the log reached this length.
+    Checkpoint(SignedRosterSnapshot),
+    /// A superseded registration compacted away, in its place in log order.
+    Tombstone(Tombstone),
+}
+
+/// Append-only, line-delimited JSON log of accepted records.
+#[derive(Debug)]
+pub struct LogFile {
+    path: String,
+    file: File,
+}
+
+impl LogFile {
+    /// Open (creating if missing) the log at `path` and return the records it holds.
+    /// A torn final line (crash mid-append) is dropped and truncated away.
+    pub fn open(path: &str) -> Result<(Self, Vec<LogRecord>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        let mut data = String::new();
+        file.read_to_string(&mut data)?;
+
+        let (records, good_len) = parse_records(path, &data, 1)?;
+        if good_len < data.len() {
+            warn!("dropping torn record at end of log file {} (line {})", path, records.len() + 1);
+            file.set_len(good_len as u64)?;
+        }
+
+        Ok((Self { path: path.to_string(), file }, records))
+    }
+
+    /// Atomically replace the whole log with `records` (write a temp file, fsync, rename).
+    pub fn rewrite(&mut self, records: &[LogRecord]) -> Result<()> {
+        let tmp = format!("{}.tmp", self.path);
+        let mut data = String::new();
+        for rec in records {
+            data.push_str(&serde_json::to_string(rec)?);
+            data.push('\n');
+        }
+        let mut f = File::create(&tmp)?;
+        f.write_all(data.as_bytes())?;
+        f.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+
+    /// Append one record and flush it to disk before returning.
+    pub fn append(&mut self, rec: &LogRecord) -> Result<()> {
+        let mut line = serde_json::to_string(rec)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Flush file data and metadata to disk.
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Follows a log file another watchtower appends to, without writing to it: each poll
+/// returns the records completed since the last one.
+#[derive(Debug, Clone)]
+pub struct LogTail {
+    path: String,
+    /// Bytes consumed so far; always ends on a record boundary.
+    offset: u64,
+    /// Line number of the next record.
+    line: usize,
+    /// Identity of the file followed, to notice it being replaced (rewritten by a
+    /// compaction) rather than appended to.
+    file_id: Option<u64>,
+    /// Set when the records handed out could not be applied: only a reload recovers.
+    broken: bool,
+}
+
+/// What a `LogTail` found since its last poll.
+#[derive(Debug)]
+pub enum TailRead {
+    /// These records were appended; possibly none.
+    Appended(Vec<LogRecord>),
+    /// The file was rewritten or truncated (or marked broken): read it from the start.
+    Replaced,
+}
+
+impl LogTail {
+    /// Follow `path` from its start.
+    pub fn new(path: &str) -> Self {
+        Self { path: path.to_string(), offset: 0, line: 1, file_id: None, broken: false }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Records appended since the last poll. A final line still being written is left for
+    /// a later poll. The file must exist.
+    pub fn poll(&mut self) -> Result<TailRead> {
+        if self.broken {
+            return Ok(TailRead::Replaced);
+        }
+        let path = &self.path;
+        let mut file =
+            File::open(path).map_err(|e| anyhow!("failed to open log file {path}: {e}"))?;
+        let meta = file.metadata()?;
+        let id = file_id(&meta);
+        if self.offset > 0 && (meta.len() < self.offset || id != self.file_id) {
+            return Ok(TailRead::Replaced);
+        }
+        self.file_id = id;
+
+        let mut data = Vec::new();
+        file.seek(SeekFrom::Start(self.offset))?;
+        file.read_to_end(&mut data)?;
+        // Only whole lines: a torn one may end mid-way through a UTF-8 sequence.
+        let whole = data.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+        let data = std::str::from_utf8(&data[..whole])
+            .map_err(|e| anyhow!("corrupt log file {path}: {e}"))?;
+        let (records, good_len) = parse_records(path, data, self.line)?;
+        self.offset += good_len as u64;
+        self.line += records.len();
+        Ok(TailRead::Appended(records))
+    }
+
+    /// Make every later poll report the file replaced.
+    pub fn mark_broken(&mut self) {
+        self.broken = true;
+    }
+}
+
+#[cfg(unix)]
+fn file_id(meta: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(meta.ino())
+}
+
+#[cfg(not(unix))]
+fn file_id(_meta: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// The complete records in `data`, whose first line is line `first_line` of the file,
+/// and the length of the prefix they span. A final line without its newline is left out.
+fn parse_records(path: &str, data: &str, first_line: usize) -> Result<(Vec<LogRecord>, usize)> {
+    let mut records = Vec::new();
+    let mut good_len = 0usize;
+    for (i, line) in data.split_inclusive('\n').enumerate() {
+        if !line.ends_with('\n') {
+            break;
+        }
+        let rec: LogRecord = serde_json::from_str(line.trim_end())
+            .map_err(|e| anyhow!("corrupt log file {path} at line {}: {e}", first_line + i))?;
+        records.push(rec);
+        good_len += line.len();
+    }
+    Ok((records, good_len))
+}
@@ -1,132 +1,1807 @@
+use crate::persist::{LogFile, LogRecord, LogTail, TailRead};
 use anyhow::{anyhow, Result};
 use common::{
-    crypto::{sign_struct, verify_struct, verifying_key_from_bytes, enc},
-    merkle::{leaf_hash, merkle_root},
-    types::{PartyRegistrationRecord, SignedRosterSnapshot, SnapshotMessage},
+    crypto::{
+        check_pubkey_tagged, enc_canonical, sign_struct, verify_bytes_tagged, verify_struct_tagged,
+        Hasher, CTX_PRR, CTX_RECEIPT, CTX_ROTATION, CTX_SNAPSHOT,
+    },
+    keyfile::KeyFile,
+    merkle::{consistency_proof, inclusion_proof, leaf_hash, merkle_root_par, ConsistencyProof},
+    smt::{smt_proof, smt_root},
+    types::{
+        ErrorCode, LogEntry, MerkleProofResponse, PartyRegistrationRecord, PartyResponse,
+        RegisterResponse, RegistrationReceipt, RosterParty, RosterResponse,
+        SignedRegistrationReceipt, SignedRosterSnapshot, SnapshotMessage, Tombstone,
+        VerifyCheck, VerifyResponse, WatchtowerError, RECEIPT_MSG_VERSION,
+        SNAPSHOT_MSG_VERSION,
+    },
 };
-use ed25519_dalek::{SigningKey, VerifyingKey};
+use ed25519_dalek::SigningKey;
 use rand::rngs::OsRng;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
 use base64::Engine as _;
 
-#[derive(Debug)]
-pub struct WatchtowerState {
-    pub epoch: u64,
-    pub log: Vec<PartyRegistrationRecord>, // 1-indexed conceptually
-    pub last_seq: HashMap<u64, u64>,        // party_id -> last seq accepted
-    pub sk_w: SigningKey,
-    pub pk_w: VerifyingKey,
+/// How many signed snapshots of earlier log prefixes each epoch keeps for /snapshot_at.
+pub const HISTORICAL_SNAPSHOT_CACHE: usize = 256;
+
+/// How many of each party's latest nonces are remembered for reuse detection.
+pub const RECENT_NONCES_PER_PARTY: usize = 64;
+
+/// Signing key and registration policy every epoch is created with.
+#[derive(Debug, Clone)]
+pub struct EpochSettings {
+    /// Shared by every epoch rather than copied into each.
+    pub sk_w: Arc<SigningKey>,
+    /// If set, reject PRRs timestamped further than this into the future.
+    pub max_future_skew_secs: Option<u64>,
+    /// If set, registrations are refused once an epoch's log holds this many records.
+    pub max_log_len: Option<u64>,
+    /// If set, registrations from new party_ids are refused once an epoch knows this many.
+    pub max_parties: Option<u64>,
+    /// If set, a checkpoint snapshot is signed every this many log entries.
+    pub checkpoint_interval: Option<u64>,
+    /// Refuse every registration at a party's last seq, even a byte-identical resubmission.
+    pub strict_seq: bool,
+    /// Hash for leaves and both Merkle trees.
+    pub hasher: Hasher,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct KeyFile {
-    /// raw 32-byte signing key seed (ed25519)
-    sk_seed_b64: String,
+impl EpochSettings {
+    /// Default policy (no caps, no checkpoints, SHA-256), signing with `sk_w`.
+    pub fn new(sk_w: SigningKey) -> Self {
+        Self {
+            sk_w: Arc::new(sk_w),
+            max_future_skew_secs: None,
+            max_log_len: None,
+            max_parties: None,
+            checkpoint_interval: None,
+            strict_seq: false,
+            hasher: Hasher::default(),
+        }
+    }
+}
+
+/// Load the watchtower key from `key_file`, generating and saving one if it is missing.
+pub fn load_or_create_key(key_file: &str, passphrase: Option<&str>) -> Result<SigningKey> {
+    match KeyFile::load(key_file)? {
+        Some(kf) => Ok(SigningKey::from_bytes(&kf.seed(passphrase)?)),
+        None => {
+            let sk = SigningKey::generate(&mut OsRng);
+            KeyFile::new(&sk.to_bytes(), passphrase)?.save(key_file)?;
+            Ok(sk)
+        }
+    }
+}
+
+/// The watchtower key: from `seed` (given with --key-env or --key-stdin) if there is one,
+/// otherwise loaded from (or created at) `key_file`.
+pub fn watchtower_key(
+    seed: Option<[u8; 32]>,
+    key_file: &str,
+    passphrase: Option<&str>,
+) -> Result<SigningKey> {
+    match seed {
+        Some(seed) => Ok(SigningKey::from_bytes(&seed)),
+        None => load_or_create_key(key_file, passphrase),
+    }
+}
+
+/// Every epoch one watchtower serves. Each has its own log, roots and registration state;
+/// only the signing key and policy are shared.
+#[derive(Debug)]
+pub struct WatchtowerState {
+    /// Epoch used when a request doesn't name one. Always present in `epochs`.
+    pub default_epoch: u64,
+    /// Accept registrations for other epochs, creating each on its first registration.
+    pub multi_epoch: bool,
+    /// Without `multi_epoch`, registrations are accepted for epochs in
+    /// `[default_epoch - back, default_epoch + fwd]` (just the default epoch if both are 0).
+    pub epoch_window_back: u64,
+    pub epoch_window_fwd: u64,
+    /// If set, a registration that would create an epoch beyond this many is refused.
+    pub max_epochs: Option<u64>,
+    pub epochs: BTreeMap<u64, EpochState>,
+    settings: EpochSettings,
+    /// Log file of the default epoch; other epochs log to `<path>.epoch-<n>` beside it.
+    log_file: Option<String>,
+    /// Like `log_file`, for a read-only replica following another watchtower's logs.
+    followed_log: Option<String>,
 }
 
 impl WatchtowerState {
-    pub fn load_or_create(epoch: u64, key_file: &str) -> Result<Self> {
-        let (sk_w, pk_w) = if let Ok(data) = fs::read_to_string(key_file) {
-            let kf: KeyFile = serde_json::from_str(&data)?;
-            let seed = base64::Engine::decode(&base64::engine::general_purpose::STANDARD, kf.sk_seed_b64)?;
-            if seed.len() != 32 {
-                return Err(anyhow!("watchtower key seed must be 32 bytes"));
+    pub fn new(default_epoch: u64, settings: EpochSettings, multi_epoch: bool) -> Self {
+        let epochs = BTreeMap::from([(default_epoch, EpochState::new(default_epoch, &settings))]);
+        Self {
+            default_epoch,
+            multi_epoch,
+            epoch_window_back: 0,
+            epoch_window_fwd: 0,
+            max_epochs: None,
+            epochs,
+            settings,
+            log_file: None,
+            followed_log: None,
+        }
+    }
+
+    /// Attach the default epoch's log file at `path`, replaying it, and load every other
+    /// epoch logged beside it.
+    pub fn open_log(&mut self, path: &str) -> Result<()> {
+        self.attach_logs(path, false)
+    }
+
+    /// Load the default epoch's log at `path`, and every other epoch logged beside it,
+    /// without writing to any of them: for a read-only replica of the watchtower that
+    /// appends to them. Later registrations are neither accepted nor logged here.
+    pub fn load_log_read_only(&mut self, path: &str) -> Result<()> {
+        self.attach_logs(path, true)
+    }
+
+    fn attach_logs(&mut self, path: &str, read_only: bool) -> Result<()> {
+        let open = |st: &mut EpochState, path: &str| {
+            if read_only {
+                st.load_log(path)
+            } else {
+                st.open_log(path)
+            }
+        };
+        open(self.epochs.get_mut(&self.default_epoch).expect("default epoch"), path)?;
+        for epoch in logged_epochs(path)? {
+            if epoch == self.default_epoch {
+                continue;
             }
-            let mut seed32 = [0u8; 32];
-            seed32.copy_from_slice(&seed);
-            let sk = SigningKey::from_bytes(&seed32);
-            let pk = sk.verifying_key();
-            (sk, pk)
+            let mut st = EpochState::new(epoch, &self.settings);
+            open(&mut st, &epoch_log_path(path, epoch))?;
+            self.epochs.insert(epoch, st);
+        }
+        if read_only {
+            self.followed_log = Some(path.to_string());
         } else {
-            let sk = SigningKey::generate(&mut OsRng);
-            let pk = sk.verifying_key();
+            self.log_file = Some(path.to_string());
+        }
+        Ok(())
+    }
+
+    /// How far each epoch has read the log files this replica follows, for catching up
+    /// with them without holding the state: `FollowedLogs::read` replays what was
+    /// appended since, reloads an epoch whose file was rewritten (compacted), and loads
+    /// epochs logged since. `None` for a watchtower that writes its own log.
+    pub fn followed_logs(&self) -> Option<FollowedLogs> {
+        let path = self.followed_log.clone()?;
+        let epochs =
+            self.epochs.iter().map(|(&epoch, st)| (epoch, st.tail.clone(), st.generation));
+        Some(FollowedLogs {
+            path,
+            default_epoch: self.default_epoch,
+            settings: self.settings.clone(),
+            epochs: epochs.collect(),
+        })
+    }
+
+    /// Apply what `FollowedLogs::read` found. Records that fail to replay leave their
+    /// epoch to be reloaded by the next catch-up.
+    pub fn apply_log_reads(&mut self, reads: LogReads) -> CaughtUp {
+        let mut changed = Vec::new();
+        for (epoch, read) in reads.epochs {
+            match read {
+                EpochRead::Appended(tail, records) => {
+                    let Some(st) = self.epochs.get_mut(&epoch) else {
+                        continue;
+                    };
+                    let path = tail.path().to_string();
+                    match st.follow_appended(tail, records) {
+                        Ok(0) => continue,
+                        Ok(_) => {}
+                        Err(e) => warn!("replaying log file {path}: {e:#}; it will be reloaded"),
+                    }
+                }
+                EpochRead::Loaded(st) => {
+                    self.epochs.insert(epoch, *st);
+                }
+            }
+            changed.push(epoch);
+        }
+        CaughtUp { changed, error: reads.error }
+    }
+
+    /// The state of `epoch` (the default epoch if `None`).
+    pub fn epoch(&self, epoch: Option<u64>) -> Result<&EpochState> {
+        let epoch = epoch.unwrap_or(self.default_epoch);
+        self.epochs.get(&epoch).ok_or_else(|| unknown_epoch(epoch))
+    }
+
+    pub fn epoch_mut(&mut self, epoch: Option<u64>) -> Result<&mut EpochState> {
+        let epoch = epoch.unwrap_or(self.default_epoch);
+        self.epochs.get_mut(&epoch).ok_or_else(|| unknown_epoch(epoch))
+    }
+
+    /// Check that registrations for `epoch` are accepted: any epoch with `multi_epoch`,
+    /// otherwise only those in the epoch window.
+    pub fn check_epoch_window(&self, epoch: u64) -> Result<()> {
+        if self.multi_epoch {
+            return Ok(());
+        }
+        let lo = self.default_epoch.saturating_sub(self.epoch_window_back);
+        let hi = self.default_epoch.saturating_add(self.epoch_window_fwd);
+        if (lo..=hi).contains(&epoch) {
+            return Ok(());
+        }
+        let err = if lo == hi {
+            WatchtowerError::new(
+                ErrorCode::EpochMismatch,
+                format!("epoch mismatch: watchtower epoch={}, got={epoch}", self.default_epoch),
+            )
+        } else {
+            WatchtowerError::new(
+                ErrorCode::EpochOutOfWindow,
+                format!("epoch={epoch} is outside the accepted window [{lo}, {hi}]"),
+            )
+        };
+        Err(err.into())
+    }
+
+    /// Check that an epoch not known yet may be created for `epoch`'s first registration.
+    pub fn check_epoch_cap(&self, epoch: u64) -> Result<()> {
+        let known = self.epochs.len() as u64;
+        match self.max_epochs {
+            Some(max) if !self.epochs.contains_key(&epoch) && known >= max => Err(anyhow!(
+                "epoch cap reached: {known} epochs known (max_epochs={max}); epoch={epoch} \
+                 rejected"
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Register `prr` in its epoch's log. An accepted epoch that isn't known yet is created
+    /// for it, and dropped again if the registration is rejected.
+    pub fn register(&mut self, prr: PartyRegistrationRecord) -> Result<RegisterResponse> {
+        let epoch = prr.msg.epoch;
+        self.check_epoch_window(epoch)?;
+        self.check_epoch_cap(epoch)?;
+        let created = !self.epochs.contains_key(&epoch);
+        if created {
+            let mut st = EpochState::new(epoch, &self.settings);
+            if let Some(path) = &self.log_file {
+                st.open_log(&epoch_log_path(path, epoch))?;
+            }
+            self.epochs.insert(epoch, st);
+        }
+        let resp = self.epochs.get_mut(&epoch).expect("present").register(prr);
+        if resp.is_err() && created {
+            self.epochs.remove(&epoch);
+            if let Some(path) = &self.log_file {
+                let _ = std::fs::remove_file(epoch_log_path(path, epoch));
+            }
+        }
+        resp
+    }
+
+    /// Run every check `register` would on `prr` without logging it or creating its
+    /// epoch. An epoch not known yet is checked as the empty one it would start as.
+    pub fn verify(&self, prr: &PartyRegistrationRecord) -> VerifyResponse {
+        let epoch = prr.msg.epoch;
+        let admitted = self.check_epoch_window(epoch).and_then(|()| self.check_epoch_cap(epoch));
+        match self.epochs.get(&epoch) {
+            Some(st) => st.verify(prr, admitted),
+            None => EpochState::new(epoch, &self.settings).verify(prr, admitted),
+        }
+    }
+
+    /// Flush every epoch's log file.
+    pub fn flush(&mut self) -> Result<()> {
+        for st in self.epochs.values_mut() {
+            st.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn watchtower_pubkey_bytes(&self) -> [u8; 32] {
+        self.settings.sk_w.verifying_key().to_bytes()
+    }
+}
 
-            let seed32 = sk.to_bytes();
-            let kf = KeyFile {
-                sk_seed_b64: base64::engine::general_purpose::STANDARD.encode(seed32),
+fn epoch_log_path(path: &str, epoch: u64) -> String {
+    format!("{path}.epoch-{epoch}")
+}
+
+/// The log files a read-only replica follows, and how far each of its epochs has read
+/// them: see `WatchtowerState::followed_logs`.
+pub struct FollowedLogs {
+    path: String,
+    default_epoch: u64,
+    settings: EpochSettings,
+    /// Each epoch's tail and generation.
+    epochs: Vec<(u64, Option<LogTail>, u64)>,
+}
+
+/// What `FollowedLogs::read` found, for `WatchtowerState::apply_log_reads`.
+pub struct LogReads {
+    epochs: Vec<(u64, EpochRead)>,
+    /// What stopped the read early; what was read before it still applies.
+    error: Option<anyhow::Error>,
+}
+
+enum EpochRead {
+    /// Records appended to the epoch's file, and its tail past them.
+    Appended(LogTail, Vec<LogRecord>),
+    /// The epoch loaded afresh, from a rewritten file or one logged since.
+    Loaded(Box<EpochState>),
+}
+
+/// Epochs one catch-up changed, in the order applied, and the error that cut it short.
+#[derive(Debug)]
+pub struct CaughtUp {
+    pub changed: Vec<u64>,
+    pub error: Option<anyhow::Error>,
+}
+
+impl FollowedLogs {
+    /// Read what was appended to each followed file, and load every rewritten or new one.
+    /// Touches only the files, so it can run without the state lock.
+    pub fn read(self) -> LogReads {
+        let mut reads = LogReads { epochs: Vec::new(), error: None };
+        if let Err(e) = self.read_into(&mut reads.epochs) {
+            reads.error = Some(e);
+        }
+        reads
+    }
+
+    fn read_into(&self, reads: &mut Vec<(u64, EpochRead)>) -> Result<()> {
+        let epoch_path = |epoch: u64| {
+            if epoch == self.default_epoch {
+                self.path.clone()
+            } else {
+                epoch_log_path(&self.path, epoch)
+            }
+        };
+        let load = |epoch: u64, generation: u64| -> Result<EpochRead> {
+            let mut st = EpochState::new(epoch, &self.settings);
+            st.generation = generation;
+            st.load_log(&epoch_path(epoch))?;
+            Ok(EpochRead::Loaded(Box::new(st)))
+        };
+        for (epoch, tail, generation) in &self.epochs {
+            let Some(mut tail) = tail.clone() else {
+                continue;
             };
-            fs::write(key_file, serde_json::to_string_pretty(&kf)?)?;
-            (sk, pk)
+            match tail.poll() {
+                Ok(TailRead::Appended(records)) => {
+                    reads.push((*epoch, EpochRead::Appended(tail, records)));
+                    continue;
+                }
+                Ok(TailRead::Replaced) => {
+                    info!("log file {} was rewritten; reloading it", epoch_path(*epoch));
+                }
+                Err(e) => warn!("reloading log file {}: {e:#}", epoch_path(*epoch)),
+            }
+            // Readers paging through the old log must notice it was replaced.
+            reads.push((*epoch, load(*epoch, generation + 1)?));
+        }
+        for epoch in logged_epochs(&self.path)? {
+            if !self.epochs.iter().any(|(known, ..)| *known == epoch) {
+                reads.push((epoch, load(epoch, 0)?));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The epochs other than the default one logged beside the log file at `path`.
+fn logged_epochs(path: &str) -> Result<Vec<u64>> {
+    let prefix = format!("{}.epoch-", file_name(path));
+    let dir = match std::path::Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => std::path::PathBuf::from("."),
+    };
+    let mut epochs = Vec::new();
+    for entry in std::fs::read_dir(&dir)? {
+        let name = entry?.file_name();
+        let Some(epoch) = name.to_str().and_then(|n| n.strip_prefix(&prefix)) else {
+            continue;
         };
+        if let Ok(epoch) = epoch.parse::<u64>() {
+            epochs.push(epoch);
+        }
+    }
+    Ok(epochs)
+}
+
+fn file_name(path: &str) -> &str {
+    std::path::Path::new(path).file_name().and_then(|n| n.to_str()).unwrap_or(path)
+}
 
-        Ok(Self {
+fn unknown_epoch(epoch: u64) -> anyhow::Error {
+    WatchtowerError::new(ErrorCode::NotFound, format!("unknown epoch={epoch}")).into()
+}
+
+/// One epoch's log, roots and registration state.
+#[derive(Debug)]
+pub struct EpochState {
+    pub epoch: u64,
+    /// Accepted records in log order (1-indexed conceptually); superseded ones may have
+    /// been compacted into tombstones.
+    pub log: Vec<LogEntry>,
+    /// leaf_hash(enc(prr)) per log entry, computed once from the exact bytes accepted.
+    pub leaves: Vec<[u8; 32]>,
+    /// party_id -> leaf of its latest record; the sparse Merkle tree's contents.
+    pub latest_leaf: BTreeMap<u64, [u8; 32]>,
+    /// party_id -> 1-indexed log position of its latest record.
+    pub latest_index: HashMap<u64, u64>,
+    pub last_seq: HashMap<u64, u64>,        // party_id -> last seq accepted
+    /// party_id -> nonces of its last `RECENT_NONCES_PER_PARTY` records, oldest first.
+    pub recent_nonces: HashMap<u64, VecDeque<[u8; 16]>>,
+    pub bound_pk: HashMap<u64, [u8; 32]>,   // party_id -> current key (changes only via rotation)
+    /// party_id -> when its latest record was received (unix seconds), as logged with it.
+    pub last_seen: HashMap<u64, u64>,
+    pub sk_w: Arc<SigningKey>,
+    /// If set, reject PRRs timestamped further than this into the future.
+    pub max_future_skew_secs: Option<u64>,
+    /// If set, registrations are refused once the log holds this many records.
+    pub max_log_len: Option<u64>,
+    /// If set, registrations from new party_ids are refused once this many are known.
+    pub max_parties: Option<u64>,
+    /// Final snapshot once the epoch has been finalized; registrations are closed after that.
+    pub finalized: Option<SignedRosterSnapshot>,
+    /// If set, a checkpoint snapshot is signed every this many log entries.
+    pub checkpoint_interval: Option<u64>,
+    /// Refuse a byte-identical resubmission at a party's last seq instead of answering it
+    /// with the original response.
+    pub strict_seq: bool,
+    /// Checkpoint snapshots of the current log, in log_len order.
+    pub checkpoints: Vec<SignedRosterSnapshot>,
+    /// Bumped by every compaction that changed the log, so a reader working through it in
+    /// batches (/entries) can tell it changed underneath.
+    pub generation: u64,
+    /// Optional append-only persistence of accepted records.
+    pub log_file: Option<LogFile>,
+    /// Log file another watchtower appends to, followed by a read-only replica instead
+    /// of `log_file`.
+    pub tail: Option<LogTail>,
+    /// Hash for leaves and both Merkle trees. Must be set before `open_log`, since
+    /// replayed records are hashed with it.
+    pub hasher: Hasher,
+    /// Last signed snapshot, reused while the message is unchanged so repeated
+    /// /snapshot calls return identical bytes without re-signing.
+    last_snapshot: Mutex<Option<SignedRosterSnapshot>>,
+    /// Signed snapshots of log prefixes by log_len, at most `HISTORICAL_SNAPSHOT_CACHE`.
+    /// Compaction keeps every leaf, so a prefix's snapshot never changes.
+    historical: Mutex<BTreeMap<u64, SignedRosterSnapshot>>,
+}
+
+impl EpochState {
+    /// A fresh, empty epoch under `settings`.
+    pub fn new(epoch: u64, settings: &EpochSettings) -> Self {
+        Self {
             epoch,
             log: Vec::new(),
+            leaves: Vec::new(),
+            latest_leaf: BTreeMap::new(),
+            latest_index: HashMap::new(),
             last_seq: HashMap::new(),
-            sk_w,
-            pk_w,
-        })
+            recent_nonces: HashMap::new(),
+            bound_pk: HashMap::new(),
+            last_seen: HashMap::new(),
+            sk_w: Arc::clone(&settings.sk_w),
+            max_future_skew_secs: settings.max_future_skew_secs,
+            max_log_len: settings.max_log_len,
+            max_parties: settings.max_parties,
+            finalized: None,
+            checkpoint_interval: settings.checkpoint_interval,
+            strict_seq: settings.strict_seq,
+            checkpoints: Vec::new(),
+            generation: 0,
+            log_file: None,
+            tail: None,
+            hasher: settings.hasher,
+            last_snapshot: Mutex::new(None),
+            historical: Mutex::new(BTreeMap::new()),
+        }
     }
 
-    pub fn watchtower_pubkey_bytes(&self) -> [u8; 32] {
-        self.pk_w.to_bytes()
+    /// Attach an append-only log file, replaying any records it already holds. A new file
+    /// starts with a header; an existing one must start with one.
+    pub fn open_log(&mut self, path: &str) -> Result<()> {
+        let (mut log_file, records) = LogFile::open(path)?;
+        match records.first() {
+            Some(LogRecord::Header { .. }) => {}
+            Some(_) => return Err(anyhow!("log file {path} does not start with a header")),
+            None => log_file.append(&LogRecord::Header { hash_alg: self.hasher.tag() })?,
+        }
+        self.replay(path, records)?;
+        self.log_file = Some(log_file);
+        Ok(())
+    }
+
+    /// Replay the log at `path` and follow it for `catch_up`, without writing to it. It
+    /// must already exist.
+    pub fn load_log(&mut self, path: &str) -> Result<()> {
+        self.tail = Some(LogTail::new(path));
+        match self.catch_up()? {
+            Some(_) => Ok(()),
+            None => Err(anyhow!("log file {path} was replaced while loading it")),
+        }
+    }
+
+    /// Replay the records appended to the followed log file since the last call, and
+    /// return how many there were; `None` if the file was rewritten and has to be loaded
+    /// into a fresh state. If they can't be replayed, every later call returns `None`.
+    pub fn catch_up(&mut self) -> Result<Option<usize>> {
+        let Some(mut tail) = self.tail.clone() else {
+            return Ok(Some(0));
+        };
+        match tail.poll()? {
+            TailRead::Appended(records) => self.follow_appended(tail, records).map(Some),
+            TailRead::Replaced => Ok(None),
+        }
+    }
+
+    /// Replay `records`, read by `tail` (polled on from this epoch's), and follow the
+    /// file from where `tail` stopped. If they can't be replayed, the file counts as
+    /// rewritten from then on.
+    fn follow_appended(&mut self, tail: LogTail, records: Vec<LogRecord>) -> Result<usize> {
+        let (path, n) = (tail.path().to_string(), records.len());
+        self.tail = Some(tail);
+        if let Err(e) = self.replay(&path, records) {
+            self.tail.as_mut().expect("followed log").mark_broken();
+            return Err(e);
+        }
+        Ok(n)
+    }
+
+    fn replay(&mut self, path: &str, records: Vec<LogRecord>) -> Result<()> {
+        for rec in records {
+            match rec {
+                LogRecord::Accepted { prr, received_at_unix } => {
+                    self.replay_registration(path, prr, received_at_unix)?;
+                }
+                LogRecord::Header { hash_alg } => {
+                    if hash_alg != self.hasher.tag() {
+                        return Err(anyhow!(
+                            "log file {path} was written with hash_alg={hash_alg}, but \
+                             watchtower hash is {}",
+                            self.hasher
+                        ));
+                    }
+                }
+                LogRecord::Finalized(srs) => {
+                    self.check_snapshot(path, &srs)?;
+                    self.finalized = Some(srs);
+                }
+                LogRecord::Checkpoint(srs) => {
+                    self.check_snapshot(path, &srs)?;
+                    self.checkpoints.push(srs);
+                }
+                LogRecord::Tombstone(tombstone) => {
+                    self.remember_nonce(tombstone.party_id, tombstone.nonce);
+                    self.leaves.push(tombstone.leaf);
+                    self.log.push(LogEntry::Tombstone { tombstone });
+                }
+            }
+        }
+        Ok(())
     }
 
-    pub fn register(&mut self, prr: PartyRegistrationRecord) -> Result<SignedRosterSnapshot> {
-        // Epoch must match
+    /// Replay one accepted registration, received at `received_at_unix`.
+    fn replay_registration(
+        &mut self,
+        path: &str,
+        prr: PartyRegistrationRecord,
+        received_at_unix: u64,
+    ) -> Result<()> {
         if prr.msg.epoch != self.epoch {
             return Err(anyhow!(
-                "epoch mismatch: watchtower epoch={}, got={}",
-                self.epoch,
-                prr.msg.epoch
+                "log file {path} holds epoch={} records, but watchtower epoch={}",
+                prr.msg.epoch,
+                self.epoch
             ));
         }
+        self.last_seen.insert(prr.msg.party_id, received_at_unix);
+        self.append(prr)
+    }
 
-        // Verify party signature
-        let pk_party = verifying_key_from_bytes(&prr.msg.pk_party)?;
-        verify_struct(&pk_party, &prr.msg, &prr.sig_party)?;
+    /// Snapshots in the log file must be at the current layout, and commit to roots under
+    /// its hash: replaying it under another would silently change every root.
+    fn check_snapshot(&self, path: &str, srs: &SignedRosterSnapshot) -> Result<()> {
+        srs.msg.check_version().map_err(|e| anyhow!("log file {path}: {e}"))?;
+        if srs.msg.hash_alg != self.hasher.tag() {
+            return Err(anyhow!(
+                "log file {path} holds snapshots with hash_alg={}, but watchtower hash is {}",
+                srs.msg.hash_alg,
+                self.hasher
+            ));
+        }
+        Ok(())
+    }
 
-        // Enforce seq monotonicity
-        let pid = prr.msg.party_id;
-        let seq = prr.msg.seq;
-        if let Some(last) = self.last_seq.get(&pid) {
-            if seq <= *last {
+    /// Make sure everything written to the log file is on disk. Appends already sync
+    /// as they go; this is the last step before exiting.
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(f) = self.log_file.as_mut() {
+            f.sync()?;
+        }
+        Ok(())
+    }
+
+    fn persist(&mut self, rec: &LogRecord) -> Result<()> {
+        if let Some(f) = self.log_file.as_mut() {
+            f.append(rec).map_err(internal)?;
+        }
+        Ok(())
+    }
+
+    /// Freeze the log and return the final snapshot. Idempotent.
+    pub fn finalize(&mut self) -> Result<SignedRosterSnapshot> {
+        if let Some(srs) = &self.finalized {
+            return Ok(srs.clone());
+        }
+        let srs = self.snapshot()?;
+        self.persist(&LogRecord::Finalized(srs.clone()))?;
+        self.finalized = Some(srs.clone());
+        Ok(srs)
+    }
+
+    /// Replace every record superseded by a later one of the same party with a tombstone
+    /// that keeps its leaf, and rewrite the log file to match; returns how many records
+    /// were dropped. The root is recomputed over the compacted log and must come out the
+    /// same, so indices and every snapshot signed so far stay valid. The snapshot of the
+    /// log just before is kept as a checkpoint (in the log file too) for auditors.
+    pub fn compact(&mut self) -> Result<u64> {
+        // Seqs only increase, so a party's last entry is its latest record.
+        let latest: HashMap<u64, usize> =
+            self.log.iter().enumerate().map(|(i, entry)| (entry.party_id(), i)).collect();
+        let superseded: Vec<usize> = self
+            .log
+            .iter()
+            .enumerate()
+            .filter(|(i, entry)| entry.record().is_some() && latest[&entry.party_id()] != *i)
+            .map(|(i, _)| i)
+            .collect();
+        if superseded.is_empty() {
+            return Ok(0);
+        }
+        let mut log = self.log.clone();
+        for &i in &superseded {
+            let Some(prr) = log[i].record() else { continue };
+            let tombstone = Tombstone {
+                party_id: prr.msg.party_id,
+                seq: prr.msg.seq,
+                leaf: self.leaves[i],
+                nonce: prr.msg.nonce,
+            };
+            log[i] = LogEntry::Tombstone { tombstone };
+        }
+        let leaves = log.iter().map(|entry| entry.leaf(self.hasher)).collect::<Result<_>>()?;
+        let before = self.snapshot()?;
+        if merkle_root_par(self.hasher, leaves) != before.msg.merkle_root {
+            return Err(internal(anyhow!("the compacted log has another merkle root")));
+        }
+        let mut checkpoints = self.checkpoints.clone();
+        if checkpoints.last().map(|cp| cp.msg.log_len) != Some(before.msg.log_len) {
+            checkpoints.push(before);
+        }
+
+        // Rewrite the file before touching memory, so a failure leaves both as they were.
+        if let Some(f) = self.log_file.as_mut() {
+            let header = LogRecord::Header { hash_alg: self.hasher.tag() };
+            // Every record left is its party's latest, so last_seen is its receive time.
+            let entries = log.iter().cloned().map(|entry| match entry {
+                LogEntry::Record(prr) => {
+                    let received_at_unix = self.last_seen[&prr.msg.party_id];
+                    LogRecord::Accepted { prr: *prr, received_at_unix }
+                }
+                LogEntry::Tombstone { tombstone } => LogRecord::Tombstone(tombstone),
+            });
+            let records: Vec<LogRecord> = std::iter::once(header)
+                .chain(entries)
+                .chain(checkpoints.iter().cloned().map(LogRecord::Checkpoint))
+                .chain(self.finalized.iter().cloned().map(LogRecord::Finalized))
+                .collect();
+            f.rewrite(&records).map_err(internal)?;
+        }
+        self.log = log;
+        self.checkpoints = checkpoints;
+        self.generation += 1;
+        Ok(superseded.len() as u64)
+    }
+
+    pub fn register(&mut self, prr: PartyRegistrationRecord) -> Result<RegisterResponse> {
+        prr.msg.check_version()?;
+        self.check_open()?;
+        self.check_epoch(&prr)?;
+        prr.msg.endpoint.validate()?;
+        authenticate(&prr)?;
+        self.check_created_at(&prr)?;
+        if self.check_seq(&prr)? {
+            // An exact resubmit (e.g. after a lost response) gets its original answer.
+            return self.resubmission(&prr);
+        }
+        self.check_nonce(&prr)?;
+        self.check_key_binding(&prr)?;
+        self.check_caps(prr.msg.party_id)?;
+
+        // Write-ahead: only accept once the record is durable. Nothing after the write
+        // may fail before the record is applied, or the file and memory would disagree.
+        let leaf = leaf_hash(self.hasher, &enc_canonical(&prr)?);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        self.persist(&LogRecord::Accepted { prr: prr.clone(), received_at_unix: now })?;
+        let (party_id, seq) = (prr.msg.party_id, prr.msg.seq);
+        self.append_leaf(prr, leaf);
+        self.last_seen.insert(party_id, now);
+
+        let srs = self.snapshot()?;
+        let receipt = self.receipt(party_id, seq, &srs)?;
+        if let Some(n) = self.checkpoint_interval {
+            if srs.msg.log_len % n == 0 {
+                // The registration is already durable; a lost checkpoint only costs an anchor.
+                if let Err(e) = self.persist(&LogRecord::Checkpoint(srs.clone())) {
+                    warn!("failed to persist checkpoint at log_len={}: {e}", srs.msg.log_len);
+                }
+                self.checkpoints.push(srs.clone());
+            }
+        }
+        Ok(RegisterResponse { srs, receipt })
+    }
+
+    /// Run every check `register` would on `prr` without logging it. `admitted` is the
+    /// outcome of the watchtower-wide checks on its epoch (window and cap).
+    pub fn verify(&self, prr: &PartyRegistrationRecord, admitted: Result<()>) -> VerifyResponse {
+        let epoch = admitted.and_then(|()| self.check_open()).and_then(|()| self.check_epoch(prr));
+        let mut checks = vec![
+            verify_check("version", prr.msg.check_version()),
+            verify_check("epoch", epoch),
+            verify_check("endpoint", prr.msg.endpoint.validate()),
+            verify_check("pubkey", check_pubkey(prr)),
+            verify_check("signature", check_signature(prr)),
+            verify_check("created_at", self.check_created_at(prr)),
+        ];
+        let seq = self.check_seq(prr);
+        let resubmission = matches!(seq, Ok(true));
+        checks.push(verify_check("seq", seq.map(|_| ())));
+        if !resubmission {
+            checks.push(verify_check("nonce", self.check_nonce(prr)));
+            checks.push(verify_check("key_binding", self.check_key_binding(prr)));
+            checks.push(verify_check("caps", self.check_caps(prr.msg.party_id)));
+        }
+        let accepted = checks.iter().all(|c| c.passed);
+        VerifyResponse { accepted, resubmission, checks }
+    }
+
+    fn check_open(&self) -> Result<()> {
+        if self.finalized.is_some() {
+            return Err(WatchtowerError::new(
+                ErrorCode::EpochFinalized,
+                format!("epoch finalized: epoch={} is closed to registrations", self.epoch),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    fn check_epoch(&self, prr: &PartyRegistrationRecord) -> Result<()> {
+        if prr.msg.epoch != self.epoch {
+            return Err(WatchtowerError::new(
+                ErrorCode::EpochMismatch,
+                format!("epoch mismatch: watchtower epoch={}, got={}", self.epoch, prr.msg.epoch),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Reject timestamps too far in the future.
+    fn check_created_at(&self, prr: &PartyRegistrationRecord) -> Result<()> {
+        if let Some(skew) = self.max_future_skew_secs {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            if prr.msg.created_at_unix > now.saturating_add(skew) {
                 return Err(anyhow!(
-                    "seq must increase for party_id={pid}. last={last}, got={seq}"
+                    "created_at_unix too far in the future: got={}, now={now}, max_skew={skew}s",
+                    prr.msg.created_at_unix
                 ));
             }
         }
+        Ok(())
+    }
+
+    /// Enforce seq monotonicity. `Ok(true)` if `prr` is byte-for-byte the party's latest
+    /// record, resubmitted at its seq.
+    fn check_seq(&self, prr: &PartyRegistrationRecord) -> Result<bool> {
+        let pid = prr.msg.party_id;
+        let seq = prr.msg.seq;
+        let Some(&last) = self.last_seq.get(&pid) else {
+            return Ok(false);
+        };
+        if seq == last && self.strict_seq {
+            return Err(WatchtowerError::new(
+                ErrorCode::SeqNotIncreasing,
+                format!("party_id={pid} already registered seq={seq} (strict seq)"),
+            )
+            .into());
+        }
+        if seq == last {
+            if self.is_latest(prr)? {
+                return Ok(true);
+            }
+            return Err(WatchtowerError::new(
+                ErrorCode::SeqNotIncreasing,
+                format!("party_id={pid} already registered a different record at seq={seq}"),
+            )
+            .into());
+        }
+        if seq < last {
+            return Err(WatchtowerError::new(
+                ErrorCode::SeqNotIncreasing,
+                format!("seq must increase for party_id={pid}. last={last}, got={seq}"),
+            )
+            .into());
+        }
+        Ok(false)
+    }
 
-        self.last_seq.insert(pid, seq);
-        self.log.push(prr);
+    fn check_nonce(&self, prr: &PartyRegistrationRecord) -> Result<()> {
+        let pid = prr.msg.party_id;
+        if self.recent_nonces.get(&pid).is_some_and(|seen| seen.contains(&prr.msg.nonce)) {
+            return Err(WatchtowerError::new(
+                ErrorCode::NonceReused,
+                format!("nonce reused by party_id={pid} (seq={})", prr.msg.seq),
+            )
+            .into());
+        }
+        Ok(())
+    }
 
-        Ok(self.snapshot()?)
+    /// Whether `prr` is byte-for-byte the latest record accepted from its party.
+    fn is_latest(&self, prr: &PartyRegistrationRecord) -> Result<bool> {
+        let Some(&index) = self.latest_index.get(&prr.msg.party_id) else {
+            return Ok(false);
+        };
+        // Compaction never tombstones a party's latest record.
+        let Some(latest) = self.log[(index - 1) as usize].record() else {
+            return Ok(false);
+        };
+        Ok(enc_canonical(latest)? == enc_canonical(prr)?)
     }
 
-    pub fn snapshot(&self) -> Result<SignedRosterSnapshot> {
+    /// The response the party's latest record `prr` got when it was accepted: the
+    /// snapshot right after its append and a receipt for it.
+    fn resubmission(&self, prr: &PartyRegistrationRecord) -> Result<RegisterResponse> {
+        let index = self.latest_index[&prr.msg.party_id];
+        let srs = self.snapshot_at(index)?;
+        let receipt = self.receipt(prr.msg.party_id, prr.msg.seq, &srs)?;
+        Ok(RegisterResponse { srs, receipt })
+    }
+
+    /// Signed receipt for the record at `srs.msg.log_len`, the last one `srs` commits to.
+    fn receipt(
+        &self,
+        party_id: u64,
+        seq: u64,
+        srs: &SignedRosterSnapshot,
+    ) -> Result<SignedRegistrationReceipt> {
+        let index = srs.msg.log_len;
+        let receipt = RegistrationReceipt {
+            version: RECEIPT_MSG_VERSION,
+            party_id,
+            seq,
+            assigned_index: index,
+            prr_leaf: self.leaves[(index - 1) as usize],
+            snapshot_after: srs.msg.clone(),
+        };
+        let sig_watchtower = sign_struct(&self.sk_w, CTX_RECEIPT, &receipt)?;
+        Ok(SignedRegistrationReceipt { receipt, sig_watchtower })
+    }
+
+    /// Each party's latest record and when it was last seen, by party_id. With
+    /// `stale_after_secs`, parties not seen in that many seconds before `now` are left out.
+    pub fn roster(&self, stale_after_secs: Option<u64>, now: u64) -> RosterResponse {
+        let cutoff = stale_after_secs.map(|secs| now.saturating_sub(secs));
+        let mut parties: Vec<RosterParty> = self
+            .latest_index
+            .iter()
+            .filter_map(|(&party_id, &index)| {
+                let last_seen_unix = self.last_seen[&party_id];
+                if cutoff.is_some_and(|cutoff| last_seen_unix < cutoff) {
+                    return None;
+                }
+                // A party's latest record is never compacted away.
+                let prr = self.log[(index - 1) as usize].record()?;
+                Some(RosterParty {
+                    party_id,
+                    endpoint: prr.msg.endpoint.addr.clone(),
+                    seq: prr.msg.seq,
+                    pk_party_b64: base64::engine::general_purpose::STANDARD
+                        .encode(prr.msg.pk_party),
+                    last_seen_unix,
+                })
+            })
+            .collect();
+        parties.sort_by_key(|p| p.party_id);
+        RosterResponse { log_len: self.log.len() as u64, parties }
+    }
+
+    /// Proof that the current log extends its first `old_size` entries (up to `new_size`,
+    /// default the whole log).
+    pub fn consistency(&self, old_size: u64, new_size: Option<u64>) -> Result<ConsistencyProof> {
         let k = self.log.len() as u64;
+        let new_size = new_size.unwrap_or(k);
+        if new_size > k {
+            return Err(out_of_range(new_size, k));
+        }
+        consistency_proof(self.hasher, &self.leaves[..new_size as usize], old_size)
+    }
 
-        // Build Merkle root over leaf hashes of serialized PRRs
-        let mut leaves = Vec::with_capacity(self.log.len());
-        for prr in &self.log {
-            let bytes = enc(prr)?;
-            leaves.push(leaf_hash(&bytes));
+    /// A party's first registration binds its key; later ones must reuse it or
+    /// carry a rotation endorsed by the currently bound key.
+    fn check_key_binding(&self, prr: &PartyRegistrationRecord) -> Result<()> {
+        let pid = prr.msg.party_id;
+        let new_pk = prr.msg.pk_party;
+        match (self.bound_pk.get(&pid), &prr.msg.rotation) {
+            (None, None) => Ok(()),
+            (None, Some(_)) => Err(anyhow!("key rotation for unregistered party_id={pid}")),
+            (Some(bound), None) if *bound == new_pk => Ok(()),
+            (Some(_), None) => Err(anyhow!(
+                "pk_party for party_id={pid} differs from its bound key; submit a key rotation"
+            )),
+            (Some(bound), Some(rot)) => {
+                if rot.old_pk != *bound {
+                    return Err(anyhow!("key rotation for party_id={pid} does not chain from its bound key"));
+                }
+                if new_pk == *bound {
+                    return Err(anyhow!("key rotation for party_id={pid} does not change the key"));
+                }
+                let rot_msg = prr.msg.rotation_message(rot.old_pk);
+                verify_struct_tagged(prr.msg.scheme, bound, CTX_ROTATION, &rot_msg, &rot.sig_old)
+                    .map_err(|e| {
+                        WatchtowerError::new(
+                            ErrorCode::BadSignature,
+                            format!("key rotation not endorsed by old key: {e}"),
+                        )
+                        .into()
+                    })
+            }
         }
-        let root = merkle_root(leaves);
+    }
+
+    fn check_caps(&self, pid: u64) -> Result<()> {
+        if let Some(max) = self.max_parties {
+            let known = self.last_seq.len() as u64;
+            if !self.last_seq.contains_key(&pid) && known >= max {
+                return Err(WatchtowerError::new(
+                    ErrorCode::PartyCapReached,
+                    format!(
+                        "party cap reached: {known} parties registered (max_parties={max}); \
+                         new party_id={pid} rejected"
+                    ),
+                )
+                .into());
+            }
+        }
+        if let Some(max) = self.max_log_len {
+            let len = self.log.len() as u64;
+            if len >= max {
+                return Err(WatchtowerError::new(
+                    ErrorCode::LogFull,
+                    format!("log full: log_len={len} (max_log_len={max})"),
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply an already-validated record to the in-memory state.
+    fn append(&mut self, prr: PartyRegistrationRecord) -> Result<()> {
+        let leaf = leaf_hash(self.hasher, &enc_canonical(&prr)?);
+        self.append_leaf(prr, leaf);
+        Ok(())
+    }
+
+    /// `append` with the record's leaf hash already computed; can't fail.
+    fn append_leaf(&mut self, prr: PartyRegistrationRecord, leaf: [u8; 32]) {
+        self.last_seq.insert(prr.msg.party_id, prr.msg.seq);
+        self.remember_nonce(prr.msg.party_id, prr.msg.nonce);
+        self.bound_pk.insert(prr.msg.party_id, prr.msg.pk_party);
+        self.latest_leaf.insert(prr.msg.party_id, leaf);
+        self.latest_index.insert(prr.msg.party_id, self.log.len() as u64 + 1);
+        self.log.push(prr.into());
+        self.leaves.push(leaf);
+    }
+
+    /// Add `nonce` to `party_id`'s recent nonces, forgetting the oldest past
+    /// `RECENT_NONCES_PER_PARTY`. Called in log order, for tombstones too.
+    fn remember_nonce(&mut self, party_id: u64, nonce: [u8; 16]) {
+        let nonces = self.recent_nonces.entry(party_id).or_default();
+        if nonces.len() >= RECENT_NONCES_PER_PARTY {
+            nonces.pop_front();
+        }
+        nonces.push_back(nonce);
+    }
 
-        let msg = SnapshotMessage {
+    pub fn snapshot(&self) -> Result<SignedRosterSnapshot> {
+        self.sign_snapshot(self.snapshot_message())
+    }
+
+    /// The (unsigned) snapshot message over the current log.
+    pub fn snapshot_message(&self) -> SnapshotMessage {
+        let k = self.log.len() as u64;
+
+        // Merkle root over the leaf hashes cached at accept time
+        let root = merkle_root_par(self.hasher, self.leaves.clone());
+
+        SnapshotMessage {
+            version: SNAPSHOT_MSG_VERSION,
             epoch: self.epoch,
             log_len: k,
             merkle_root: root,
+            smt_root: smt_root(self.hasher, &self.latest_leaf),
+            hash_alg: self.hasher.tag(),
+            watchtower_pk: self.sk_w.verifying_key().to_bytes(),
+        }
+    }
+
+    /// The snapshot message over the first `log_len` entries of the current log.
+    pub fn snapshot_message_at(&self, log_len: u64) -> SnapshotMessage {
+        let n = log_len as usize;
+        let mut latest_leaf = BTreeMap::new();
+        for (entry, leaf) in self.log[..n].iter().zip(&self.leaves[..n]) {
+            latest_leaf.insert(entry.party_id(), *leaf);
+        }
+        SnapshotMessage {
+            version: SNAPSHOT_MSG_VERSION,
+            epoch: self.epoch,
+            log_len,
+            merkle_root: merkle_root_par(self.hasher, self.leaves[..n].to_vec()),
+            smt_root: smt_root(self.hasher, &latest_leaf),
+            hash_alg: self.hasher.tag(),
+            watchtower_pk: self.sk_w.verifying_key().to_bytes(),
+        }
+    }
+
+    /// Signed snapshot over the first `log_len` entries. Doesn't replace the cached
+    /// current snapshot; earlier prefixes are signed once and then served from a cache.
+    pub fn snapshot_at(&self, log_len: u64) -> Result<SignedRosterSnapshot> {
+        let k = self.log.len() as u64;
+        if log_len > k {
+            return Err(WatchtowerError::new(
+                ErrorCode::OutOfRange,
+                format!("log_len={log_len} is beyond the current log_len={k}"),
+            )
+            .into());
+        }
+        if log_len == k {
+            return self.snapshot();
+        }
+        if let Some(srs) = self.historical.lock().unwrap().get(&log_len) {
+            return Ok(srs.clone());
+        }
+        let msg = self.snapshot_message_at(log_len);
+        let sig_watchtower = sign_struct(&self.sk_w, CTX_SNAPSHOT, &msg)?;
+        let srs = SignedRosterSnapshot { msg, sig_watchtower };
+        let mut cache = self.historical.lock().unwrap();
+        if cache.len() >= HISTORICAL_SNAPSHOT_CACHE {
+            cache.pop_first();
+        }
+        cache.insert(log_len, srs.clone());
+        Ok(srs)
+    }
+
+    /// Sign `msg`, or hand back the cached signed snapshot if it is for the same message.
+    pub fn sign_snapshot(&self, msg: SnapshotMessage) -> Result<SignedRosterSnapshot> {
+        let mut last = self.last_snapshot.lock().unwrap();
+        if let Some(srs) = last.as_ref().filter(|srs| srs.msg == msg) {
+            return Ok(srs.clone());
+        }
+        let sig_watchtower = sign_struct(&self.sk_w, CTX_SNAPSHOT, &msg)?;
+        let srs = SignedRosterSnapshot { msg, sig_watchtower };
+        *last = Some(srs.clone());
+        Ok(srs)
+    }
+
+    /// `party_id`'s latest record with its inclusion and SMT proofs against the current
+    /// (or final) snapshot; `None` if the party has never registered.
+    pub fn party(&self, party_id: u64) -> Result<Option<PartyResponse>> {
+        let Some(&index) = self.latest_index.get(&party_id) else {
+            return Ok(None);
+        };
+        let srs = match &self.finalized {
+            Some(srs) => srs.clone(),
+            None => self.snapshot()?,
         };
-        let sig_watchtower = sign_struct(&self.sk_w, &msg)?;
+        // Compaction only tombstones superseded records, never a party's latest.
+        let prr = self.log[(index - 1) as usize]
+            .record()
+            .cloned()
+            .ok_or_else(|| anyhow!("record at index {index} was compacted"))?;
+        Ok(Some(PartyResponse {
+            prr,
+            index,
+            proof: inclusion_proof(self.hasher, &self.leaves, index - 1)?,
+            smt_proof: smt_proof(self.hasher, &self.latest_leaf, party_id),
+            srs,
+        }))
+    }
 
-        Ok(SignedRosterSnapshot { msg, sig_watchtower })
+    /// The record at `index` (1-based) with its inclusion proof against the current
+    /// snapshot (the final one once finalized).
+    pub fn merkle_proof(&self, index: u64) -> Result<MerkleProofResponse> {
+        let k = self.log.len() as u64;
+        if index == 0 {
+            return Err(anyhow!("invalid index: 0 (must be 1-indexed)"));
+        }
+        if index > k {
+            return Err(WatchtowerError::new(
+                ErrorCode::OutOfRange,
+                format!("index out of bounds: index={index} > log_len={k}"),
+            )
+            .into());
+        }
+        let srs = match &self.finalized {
+            Some(srs) => srs.clone(),
+            None => self.snapshot()?,
+        };
+        let prr = self.log[(index - 1) as usize].record().cloned().ok_or_else(|| compacted(index))?;
+        Ok(MerkleProofResponse {
+            prr,
+            index,
+            proof: inclusion_proof(self.hasher, &self.leaves, index - 1)?,
+            srs,
+        })
     }
 
-    pub fn entries(&self, from: u64, to: u64) -> Result<Vec<PartyRegistrationRecord>> {
+    /// Check that `from..=to` is a valid 1-indexed range within the current log.
+    pub fn check_range(&self, from: u64, to: u64) -> Result<()> {
         let k = self.log.len() as u64;
-        if from == 0 || to == 0 || from > to {
-            return Err(anyhow!("invalid range: from={from} to={to} (must be 1-indexed, from<=to)"));
+        if from == 0 || to == 0 {
+            return Err(anyhow!("invalid range: from={from} to={to} (must be 1-indexed)"));
+        }
+        // A range starting past the log is well-formed, just not there (yet).
+        if from > k {
+            return Err(out_of_range(from, k));
+        }
+        if from > to {
+            return Err(anyhow!("invalid range: from={from} > to={to}"));
         }
         if to > k {
-            return Err(anyhow!("range out of bounds: to={to} > log_len={k}"));
+            return Err(out_of_range(to, k));
         }
+        Ok(())
+    }
+
+    pub fn entries(&self, from: u64, to: u64) -> Result<Vec<LogEntry>> {
+        self.check_range(from, to)?;
         // Convert to 0-indexed slice.
         let start = (from - 1) as usize;
         let end = to as usize;
         Ok(self.log[start..end].to_vec())
     }
 }
+
+/// Check that `prr` is signed by the key it carries. Needs no state, so callers can run it
+/// before spending anything on the record, such as its party_id's rate-limit budget.
+pub fn authenticate(prr: &PartyRegistrationRecord) -> Result<()> {
+    check_pubkey(prr)?;
+    check_signature(prr)
+}
+
+/// A degenerate key would "verify" signatures nobody made; reject it before trusting
+/// the signature check.
+fn check_pubkey(prr: &PartyRegistrationRecord) -> Result<()> {
+    check_pubkey_tagged(prr.msg.scheme, &prr.msg.pk_party).map_err(|e| {
+        WatchtowerError::new(ErrorCode::BadPubkey, format!("bad pk_party: {e}")).into()
+    })
+}
+
+/// Verify the party signature over the exact canonical bytes the log commits to.
+fn check_signature(prr: &PartyRegistrationRecord) -> Result<()> {
+    let msg_bytes = enc_canonical(&prr.msg)?;
+    verify_bytes_tagged(prr.msg.scheme, &prr.msg.pk_party, CTX_PRR, &msg_bytes, &prr.sig_party)
+        .map_err(|e| WatchtowerError::new(ErrorCode::BadSignature, e.to_string()).into())
+}
+
+/// The outcome of one of the checks run by `EpochState::verify`, with the code /register
+/// would answer a failure with.
+fn verify_check(check: &str, outcome: Result<()>) -> VerifyCheck {
+    let error = outcome.err().map(|e| match e.downcast::<WatchtowerError>() {
+        Ok(err) => err,
+        Err(e) => WatchtowerError::new(ErrorCode::BadRequest, e.to_string()),
+    });
+    VerifyCheck { check: check.to_string(), passed: error.is_none(), error }
+}
+
+/// A failure on the watchtower's side (e.g. writing the log file), not in the request.
+fn internal(e: anyhow::Error) -> anyhow::Error {
+    WatchtowerError::new(ErrorCode::Internal, format!("{e:#}")).into()
+}
+
+fn compacted(index: u64) -> anyhow::Error {
+    WatchtowerError::new(
+        ErrorCode::NotFound,
+        format!("the record at index={index} was superseded and compacted away"),
+    )
+    .into()
+}
+
+fn out_of_range(index: u64, log_len: u64) -> anyhow::Error {
+    WatchtowerError::new(
+        ErrorCode::OutOfRange,
+        format!("range out of bounds: {index} > log_len={log_len}"),
+    )
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, party_key, prr, EPOCH};
+    use common::crypto::verify_struct;
+    use common::types::{KeyRotation, RegistrationMessage};
+
+    #[test]
+    fn the_key_comes_from_env_or_stdin_before_the_key_file() {
+        use common::keyfile::seed_from_env_or_stdin;
+        let dir = tempfile::tempdir().unwrap();
+        let key_file = dir.path().join("wt_key.json");
+        let key_file = key_file.to_str().unwrap();
+        let from_file = load_or_create_key(key_file, None).unwrap().verifying_key();
+        let seed_b64 = base64::engine::general_purpose::STANDARD.encode([6; 32]);
+        let given = SigningKey::from_bytes(&[6; 32]).verifying_key();
+        let key = |env: Option<&str>, stdin: bool, input: &str| {
+            let lookup = |var: &str| match var {
+                "WT_TEST_KEY_SEED" => Ok(seed_b64.clone()),
+                _ => Err(std::env::VarError::NotPresent),
+            };
+            let seed = seed_from_env_or_stdin(env, stdin, lookup, input.as_bytes()).unwrap();
+            watchtower_key(seed, key_file, None).unwrap().verifying_key()
+        };
+        assert_eq!(key(Some("WT_TEST_KEY_SEED"), false, ""), given);
+        assert_eq!(key(None, true, &format!("{seed_b64}\n")), given);
+        assert_eq!(key(None, false, &seed_b64), from_file);
+
+        // With no key file, one is made and kept for next time.
+        let fresh = dir.path().join("fresh.json");
+        let made = watchtower_key(None, fresh.to_str().unwrap(), None).unwrap();
+        assert_eq!(load_or_create_key(fresh.to_str().unwrap(), None).unwrap(), made);
+    }
+
+    fn epoch_state() -> EpochState {
+        EpochState::new(EPOCH, &testutil::settings())
+    }
+
+    #[test]
+    fn timestamps_past_the_future_skew_are_refused() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let at = |n: u8, created_at_unix: u64| {
+            let sk = party_key(n);
+            let msg = RegistrationMessage { created_at_unix, ..prr(&sk, n.into(), 1).msg };
+            testutil::sign(&sk, msg)
+        };
+        let mut st = epoch_state();
+        // Unchecked unless a skew is configured.
+        st.register(at(1, now + 86_400)).unwrap();
+        st.max_future_skew_secs = Some(60);
+        st.register(at(2, now + 30)).unwrap();
+        st.register(at(3, now - 3600)).unwrap();
+        let err = st.register(at(4, now + 3600)).unwrap_err();
+        assert!(err.to_string().contains("too far in the future"), "{err}");
+        assert_eq!(st.log.len(), 3);
+    }
+
+    #[test]
+    fn registrations_of_an_unknown_version_are_refused() {
+        let mut st = epoch_state();
+        let sk = party_key(1);
+        let mut msg = prr(&sk, 1, 1).msg;
+        msg.version += 1;
+        let err = st.register(testutil::sign(&sk, msg)).unwrap_err();
+        assert!(err.to_string().contains("unsupported RegistrationMessage version"), "{err}");
+        assert!(st.log.is_empty());
+        st.register(prr(&sk, 1, 1)).unwrap();
+    }
+
+    /// Party `party_id`'s record at `seq` under `new`, rotating from `old`, with the
+    /// rotation signed by `endorser`.
+    fn rotation(
+        old: &SigningKey,
+        new: &SigningKey,
+        endorser: &SigningKey,
+        party_id: u64,
+        seq: u64,
+    ) -> PartyRegistrationRecord {
+        let mut msg = prr(new, party_id, seq).msg;
+        let old_pk = old.verifying_key().to_bytes();
+        let sig_old = sign_struct(endorser, CTX_ROTATION, &msg.rotation_message(old_pk)).unwrap();
+        msg.rotation = Some(KeyRotation { old_pk, sig_old });
+        testutil::sign(new, msg)
+    }
+
+    #[test]
+    fn key_changes_need_a_rotation_endorsed_by_the_bound_key() {
+        let (old, new) = (party_key(1), party_key(2));
+        let mut st = epoch_state();
+        st.register(prr(&old, 1, 1)).unwrap();
+
+        // Signed only by the new key: no rotation, or one it endorsed itself.
+        let err = st.register(prr(&new, 1, 2)).unwrap_err();
+        assert!(err.to_string().contains("submit a key rotation"), "{err}");
+        let err = st.register(rotation(&old, &new, &new, 1, 2)).unwrap_err();
+        assert!(err.to_string().contains("not endorsed by old key"), "{err}");
+        let err = st.register(rotation(&party_key(3), &new, &party_key(3), 1, 2)).unwrap_err();
+        assert!(err.to_string().contains("does not chain from its bound key"), "{err}");
+        assert_eq!(st.bound_pk[&1], old.verifying_key().to_bytes());
+
+        st.register(rotation(&old, &new, &old, 1, 2)).unwrap();
+        assert_eq!(st.bound_pk[&1], new.verifying_key().to_bytes());
+        // The old key is now the stranger.
+        assert!(st.register(prr(&old, 1, 3)).is_err());
+        st.register(prr(&new, 1, 3)).unwrap();
+    }
+
+    #[test]
+    fn same_seq_resubmissions_follow_the_seq_mode() {
+        let sk = party_key(1);
+        let first = prr(&sk, 1, 1);
+        let mut conflicting = first.msg.clone();
+        conflicting.endpoint.addr = "10.0.0.9:9000".into();
+        let conflicting = testutil::sign(&sk, conflicting);
+
+        // Lenient: the identical record gets its original answer, appending nothing.
+        let mut st = epoch_state();
+        let accepted = st.register(first.clone()).unwrap();
+        st.register(prr(&party_key(2), 2, 1)).unwrap();
+        let again = st.register(first.clone()).unwrap();
+        assert_eq!(again.srs, accepted.srs);
+        assert_eq!(again.receipt.receipt, accepted.receipt.receipt);
+        assert_eq!(st.log.len(), 2);
+        let err = st.register(conflicting.clone()).unwrap_err();
+        assert!(err.to_string().contains("a different record at seq=1"), "{err}");
+        assert_eq!(error_code(err), ErrorCode::SeqNotIncreasing);
+
+        // Strict: both are refused.
+        let mut st = epoch_state();
+        st.strict_seq = true;
+        st.register(first.clone()).unwrap();
+        for resubmit in [first, conflicting] {
+            let err = st.register(resubmit).unwrap_err();
+            assert!(err.to_string().contains("already registered seq=1 (strict seq)"), "{err}");
+            assert_eq!(error_code(err), ErrorCode::SeqNotIncreasing);
+        }
+        assert_eq!(st.log.len(), 1);
+    }
+
+    #[test]
+    fn registrations_with_a_malformed_endpoint_are_refused() {
+        let mut st = epoch_state();
+        let at = |n: u8, addr: &str| {
+            let sk = party_key(n);
+            let mut msg = prr(&sk, n.into(), 1).msg;
+            msg.endpoint.addr = addr.into();
+            testutil::sign(&sk, msg)
+        };
+        st.register(at(1, "[::1]:9000")).unwrap();
+        st.register(at(2, "party-2.example.com:9000")).unwrap();
+        let err = st.register(at(3, "::1:9000")).unwrap_err();
+        assert!(err.to_string().contains("invalid endpoint"), "{err}");
+        assert_eq!(st.log.len(), 2);
+    }
+
+    fn error_code(err: anyhow::Error) -> ErrorCode {
+        err.downcast_ref::<WatchtowerError>().expect("a WatchtowerError").code
+    }
+
+    #[test]
+    fn party_and_log_caps_refuse_with_their_own_codes() {
+        let mut st = epoch_state();
+        st.max_parties = Some(2);
+        st.max_log_len = Some(3);
+        st.register(prr(&party_key(1), 1, 1)).unwrap();
+        st.register(prr(&party_key(2), 2, 1)).unwrap();
+        let err = st.register(prr(&party_key(3), 3, 1)).unwrap_err();
+        assert_eq!(error_code(err), ErrorCode::PartyCapReached);
+
+        // Known parties may still update, up to the log cap.
+        st.register(prr(&party_key(1), 1, 2)).unwrap();
+        let err = st.register(prr(&party_key(2), 2, 2)).unwrap_err();
+        assert_eq!(error_code(err), ErrorCode::LogFull);
+        assert_eq!(st.log.len(), 3);
+        assert_eq!(st.last_seq.len(), 2);
+    }
+
+    /// `prr(sk, party_id, seq)` carrying `nonce` instead.
+    fn prr_with_nonce(sk: &SigningKey, party_id: u64, seq: u64, nonce: u8) -> PartyRegistrationRecord {
+        let msg = RegistrationMessage { nonce: [nonce; 16], ..prr(sk, party_id, seq).msg };
+        testutil::sign(sk, msg)
+    }
+
+    #[test]
+    fn recent_nonces_are_refused_per_party() {
+        let mut st = epoch_state();
+        let sk = party_key(1);
+        st.register(prr(&sk, 1, 1)).unwrap();
+        st.register(prr(&sk, 1, 2)).unwrap();
+        let err = st.register(prr_with_nonce(&sk, 1, 3, 1)).unwrap_err();
+        assert_eq!(error_code(err), ErrorCode::NonceReused);
+        // Another party may use the same nonce.
+        st.register(prr_with_nonce(&party_key(2), 2, 1, 1)).unwrap();
+
+        // Only the last RECENT_NONCES_PER_PARTY nonces are remembered.
+        let window = RECENT_NONCES_PER_PARTY as u64;
+        for seq in 3..=window + 1 {
+            st.register(prr_with_nonce(&sk, 1, seq, seq as u8 + 100)).unwrap();
+        }
+        let err = st.register(prr_with_nonce(&sk, 1, window + 2, 2)).unwrap_err();
+        assert_eq!(error_code(err), ErrorCode::NonceReused);
+        st.register(prr_with_nonce(&sk, 1, window + 2, 1)).unwrap();
+    }
+
+    #[test]
+    fn nonces_of_compacted_records_are_still_refused_after_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wt.log");
+        let path = path.to_str().unwrap();
+        let sk = party_key(1);
+        let mut st = epoch_state();
+        st.open_log(path).unwrap();
+        st.register(prr(&sk, 1, 1)).unwrap();
+        st.register(prr(&sk, 1, 2)).unwrap();
+        assert_eq!(st.compact().unwrap(), 1);
+
+        let mut replayed = epoch_state();
+        replayed.open_log(path).unwrap();
+        assert_eq!(replayed.recent_nonces, st.recent_nonces);
+        for st in [&mut st, &mut replayed] {
+            let err = st.register(prr_with_nonce(&sk, 1, 3, 1)).unwrap_err();
+            assert_eq!(error_code(err), ErrorCode::NonceReused);
+        }
+    }
+
+    /// Three parties, the first two of which re-registered.
+    fn churned_state() -> EpochState {
+        let mut st = epoch_state();
+        for (party, seq) in [(1, 1), (2, 1), (1, 2), (3, 1), (2, 2), (1, 3)] {
+            st.register(prr(&party_key(party), party.into(), seq)).unwrap();
+        }
+        st
+    }
+
+    #[test]
+    fn compaction_keeps_indices_and_roots() {
+        let mut st = churned_state();
+        let before = st.snapshot().unwrap();
+        let roster = serde_json::to_value(st.roster(None, 0)).unwrap();
+
+        assert_eq!(st.compact().unwrap(), 3);
+        assert_eq!(serde_json::to_value(st.roster(None, 0)).unwrap(), roster);
+        // A freshly signed snapshot over the recomputed root matches the one kept.
+        *st.last_snapshot.lock().unwrap() = None;
+        assert_eq!(st.snapshot().unwrap(), before);
+        assert_eq!(st.checkpoints, [before]);
+        let kinds: Vec<bool> = st.log.iter().map(|e| e.record().is_some()).collect();
+        assert_eq!(kinds, [false, false, false, true, true, true]);
+        for (entry, leaf) in st.log.iter().zip(&st.leaves) {
+            assert_eq!(entry.leaf(st.hasher).unwrap(), *leaf);
+        }
+        // Nothing left to drop.
+        assert_eq!(st.compact().unwrap(), 0);
+
+        // New registrations extend the same log.
+        st.register(prr(&party_key(3), 3, 2)).unwrap();
+        assert_eq!(st.snapshot().unwrap().msg.log_len, 7);
+    }
+
+    #[test]
+    fn checkpoints_are_signed_every_interval_and_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wt.log");
+        let path = path.to_str().unwrap();
+        let mut st = epoch_state();
+        st.checkpoint_interval = Some(10);
+        st.open_log(path).unwrap();
+        for party in 1..=25u8 {
+            st.register(prr(&party_key(party), party.into(), 1)).unwrap();
+        }
+
+        let lens: Vec<u64> = st.checkpoints.iter().map(|srs| srs.msg.log_len).collect();
+        assert_eq!(lens, [10, 20]);
+        let pk_w = testutil::watchtower_key().verifying_key();
+        let current = st.snapshot().unwrap();
+        for cp in &st.checkpoints {
+            verify_struct(&pk_w, CTX_SNAPSHOT, &cp.msg, &cp.sig_watchtower).unwrap();
+            let prefix = st.leaves[..cp.msg.log_len as usize].to_vec();
+            assert_eq!(cp.msg.merkle_root, common::merkle::merkle_root(st.hasher, prefix));
+            // Each is an anchor the current log provably extends.
+            let proof = st.consistency(cp.msg.log_len, None).unwrap();
+            let (old, new) = (&cp.msg.merkle_root, &current.msg.merkle_root);
+            common::merkle::verify_consistency(st.hasher, &proof, old, new).unwrap();
+        }
+
+        let mut replayed = epoch_state();
+        replayed.checkpoint_interval = Some(10);
+        replayed.open_log(path).unwrap();
+        assert_eq!(replayed.checkpoints, st.checkpoints);
+    }
+
+    #[test]
+    fn compacted_log_replays_to_the_same_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wt.log");
+        let path = path.to_str().unwrap();
+        let mut st = epoch_state();
+        st.checkpoint_interval = Some(2);
+        st.open_log(path).unwrap();
+        for (party, seq) in [(1, 1), (2, 1), (1, 2)] {
+            st.register(prr(&party_key(party), party.into(), seq)).unwrap();
+        }
+        st.compact().unwrap();
+        let data = std::fs::read_to_string(path).unwrap();
+        let lines: Vec<serde_json::Value> =
+            data.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert!(lines[1].get("Tombstone").is_some(), "{}", lines[1]);
+        assert!(lines.last().unwrap().get("Checkpoint").is_some());
+
+        let mut replayed = epoch_state();
+        replayed.open_log(path).unwrap();
+        assert_eq!(replayed.log, st.log);
+        assert_eq!(replayed.leaves, st.leaves);
+        assert_eq!(replayed.checkpoints, st.checkpoints);
+        assert_eq!(replayed.snapshot().unwrap(), st.snapshot().unwrap());
+    }
+
+    fn log_lines(path: &std::path::Path) -> Vec<serde_json::Value> {
+        let data = std::fs::read_to_string(path).unwrap();
+        data.lines().map(|l| serde_json::from_str(l).unwrap()).collect()
+    }
+
+    /// `prr(sk, party_id, 1)`, for `epoch` instead.
+    fn prr_in(epoch: u64, sk: &SigningKey, party_id: u64) -> PartyRegistrationRecord {
+        let mut msg = prr(sk, party_id, 1).msg;
+        msg.epoch = epoch;
+        testutil::sign(sk, msg)
+    }
+
+    #[test]
+    fn epochs_are_created_up_to_the_cap() {
+        let mut wt = WatchtowerState::new(EPOCH, testutil::settings(), true);
+        wt.max_epochs = Some(2);
+        wt.register(prr_in(EPOCH + 1, &party_key(1), 1)).unwrap();
+        let err = wt.register(prr_in(EPOCH + 2, &party_key(1), 1)).unwrap_err();
+        assert!(err.to_string().contains("epoch cap reached"), "{err}");
+        assert_eq!(wt.epochs.keys().copied().collect::<Vec<_>>(), [EPOCH, EPOCH + 1]);
+
+        // Known epochs keep accepting registrations at the cap.
+        wt.register(prr_in(EPOCH + 1, &party_key(2), 2)).unwrap();
+        wt.register(prr_in(EPOCH, &party_key(3), 3)).unwrap();
+
+        // One signing key, shared rather than copied per epoch.
+        let (a, b) = (&wt.epochs[&EPOCH].sk_w, &wt.epochs[&(EPOCH + 1)].sk_w);
+        assert!(Arc::ptr_eq(a, b));
+    }
+
+    /// Epochs `replica` changed catching up, which must succeed.
+    fn caught_up(replica: &mut WatchtowerState) -> Vec<u64> {
+        let followed = replica.followed_logs().unwrap();
+        let caught_up = replica.apply_log_reads(followed.read());
+        assert!(caught_up.error.is_none(), "{:?}", caught_up.error);
+        caught_up.changed
+    }
+
+    #[test]
+    fn read_only_replicas_catch_up_with_the_primarys_log_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wt.log");
+        let path = path.to_str().unwrap();
+        let mut primary = WatchtowerState::new(EPOCH, testutil::settings(), true);
+        primary.open_log(path).unwrap();
+        primary.register(prr(&party_key(1), 1, 1)).unwrap();
+        let mut replica = WatchtowerState::new(EPOCH, testutil::settings(), true);
+        replica.load_log_read_only(path).unwrap();
+        let msg = |wt: &WatchtowerState, epoch| wt.epochs[&epoch].snapshot_message();
+        assert_eq!(msg(&replica, EPOCH), msg(&primary, EPOCH));
+        assert!(caught_up(&mut replica).is_empty());
+
+        for party in 2..=4u8 {
+            primary.register(prr(&party_key(party), party.into(), 1)).unwrap();
+        }
+        primary.register(prr_in(EPOCH + 1, &party_key(1), 1)).unwrap();
+        assert_eq!(caught_up(&mut replica), [EPOCH, EPOCH + 1]);
+        assert_eq!(msg(&replica, EPOCH).log_len, 4);
+        for epoch in [EPOCH, EPOCH + 1] {
+            assert_eq!(msg(&replica, epoch), msg(&primary, epoch));
+        }
+
+        // A record caught mid-append is left until its line is complete.
+        let line = {
+            let mut line = serde_json::to_string(&LogRecord::Accepted {
+                prr: prr(&party_key(5), 5, 1),
+                received_at_unix: 1,
+            })
+            .unwrap();
+            line.push('\n');
+            line
+        };
+        let (head, rest) = line.split_at(line.len() / 2);
+        let append = |bytes: &str| {
+            let mut f = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+            std::io::Write::write_all(&mut f, bytes.as_bytes()).unwrap();
+        };
+        append(head);
+        assert!(caught_up(&mut replica).is_empty());
+        assert_eq!(msg(&replica, EPOCH).log_len, 4);
+        append(rest);
+        assert_eq!(caught_up(&mut replica), [EPOCH]);
+        assert_eq!(msg(&replica, EPOCH).log_len, 5);
+        assert_eq!(replica.epochs[&EPOCH].latest_index[&5], 5);
+
+        // A compaction rewrites the file; the replica reloads it and sees the same root.
+        let mut primary = WatchtowerState::new(EPOCH, testutil::settings(), true);
+        primary.open_log(path).unwrap();
+        primary.register(prr(&party_key(1), 1, 2)).unwrap();
+        let generation = replica.epochs[&EPOCH].generation;
+        assert_eq!(primary.epoch_mut(None).unwrap().compact().unwrap(), 1);
+        assert_eq!(caught_up(&mut replica), [EPOCH]);
+        assert_eq!(msg(&replica, EPOCH), msg(&primary, EPOCH));
+        assert!(replica.epochs[&EPOCH].log[0].record().is_none());
+        assert!(replica.epochs[&EPOCH].generation > generation);
+    }
+
+    #[test]
+    fn rejected_registration_does_not_leave_an_epoch_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wt.log");
+        let mut wt = WatchtowerState::new(EPOCH, testutil::settings(), true);
+        wt.open_log(path.to_str().unwrap()).unwrap();
+        let mut bad = prr_in(EPOCH + 1, &party_key(1), 1);
+        bad.sig_party = [0; 64];
+        assert!(wt.register(bad).is_err());
+        assert!(!wt.epochs.contains_key(&(EPOCH + 1)));
+        assert!(!dir.path().join(format!("wt.log.epoch-{}", EPOCH + 1)).exists());
+    }
+
+    #[test]
+    fn epochs_inside_the_window_are_accepted_each_into_its_own_log() {
+        let mut wt = testutil::state();
+        (wt.epoch_window_back, wt.epoch_window_fwd) = (1, 2);
+        for (n, epoch) in (EPOCH - 1..=EPOCH + 2).enumerate() {
+            let party = n as u8 + 1;
+            let resp = wt.register(prr_in(epoch, &party_key(party), party.into())).unwrap();
+            assert_eq!((resp.srs.msg.epoch, resp.srs.msg.log_len), (epoch, 1));
+        }
+        let epochs: Vec<u64> = wt.epochs.keys().copied().collect();
+        assert_eq!(epochs, (EPOCH - 1..=EPOCH + 2).collect::<Vec<_>>());
+
+        for epoch in [EPOCH - 2, EPOCH + 3] {
+            let err = wt.register(prr_in(epoch, &party_key(9), 9)).unwrap_err();
+            assert!(err.to_string().contains(&format!("[{}, {}]", EPOCH - 1, EPOCH + 2)), "{err}");
+            assert_eq!(error_code(err), ErrorCode::EpochOutOfWindow);
+            assert!(!wt.epochs.contains_key(&epoch));
+        }
+    }
+
+    #[test]
+    fn new_log_starts_with_a_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wt.log");
+        let mut st = epoch_state();
+        st.open_log(path.to_str().unwrap()).unwrap();
+        st.register(prr(&party_key(1), 1, 1)).unwrap();
+        let lines = log_lines(&path);
+        assert_eq!(lines[0], serde_json::json!({ "Header": { "hash_alg": 0 } }));
+        assert!(lines[1]["Accepted"]["received_at_unix"].is_u64());
+    }
+
+    #[test]
+    fn log_with_another_hash_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wt.log");
+        let path = path.to_str().unwrap();
+        epoch_state().open_log(path).unwrap();
+
+        let mut st = epoch_state();
+        st.hasher = Hasher::Blake3;
+        let err = st.open_log(path).unwrap_err();
+        assert!(err.to_string().contains("written with hash_alg=0"), "{err}");
+    }
+
+    #[test]
+    fn log_without_a_header_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wt.log");
+        let line = serde_json::json!({
+            "Accepted": { "prr": prr(&party_key(1), 1, 1), "received_at_unix": 1 }
+        });
+        std::fs::write(&path, format!("{line}\n")).unwrap();
+        let err = epoch_state().open_log(path.to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("does not start with a header"), "{err}");
+        assert_eq!(log_lines(&path), [line]);
+    }
+
+    #[test]
+    fn log_with_a_snapshot_of_another_layout_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wt.log");
+        let path = path.to_str().unwrap();
+        let mut st = epoch_state();
+        st.open_log(path).unwrap();
+        st.register(prr(&party_key(1), 1, 1)).unwrap();
+        let mut srs = st.snapshot().unwrap();
+        srs.msg.version = SNAPSHOT_MSG_VERSION + 1;
+        let line = serde_json::json!({ "Checkpoint": srs });
+        let mut data = std::fs::read_to_string(path).unwrap();
+        data.push_str(&format!("{line}\n"));
+        std::fs::write(path, data).unwrap();
+        let err = epoch_state().open_log(path).unwrap_err();
+        assert!(err.to_string().contains("unsupported SnapshotMessage version"), "{err}");
+    }
+
+    #[test]
+    fn historical_snapshots_are_cached() {
+        let mut es = epoch_state();
+        for seq in 1..=3 {
+            es.register(prr(&party_key(1), 1, seq)).unwrap();
+        }
+        let first = es.snapshot_at(1).unwrap();
+        assert_eq!(es.historical.lock().unwrap().len(), 1);
+        assert_eq!(es.snapshot_at(1).unwrap(), first);
+        assert_eq!(es.historical.lock().unwrap().len(), 1);
+        // The current log_len is the live snapshot, not a cache entry.
+        es.snapshot_at(3).unwrap();
+        assert_eq!(es.historical.lock().unwrap().len(), 1);
+
+        // Compaction keeps every leaf, so cached prefixes stay right.
+        es.compact().unwrap();
+        assert_eq!(es.snapshot_message_at(1), first.msg);
+    }
+
+    /// `prr(sk, party_id, seq)`, signed a day before it's sent.
+    fn day_old_prr(sk: &SigningKey, party_id: u64, seq: u64) -> PartyRegistrationRecord {
+        let mut msg = prr(sk, party_id, seq).msg;
+        msg.created_at_unix -= 86_400;
+        testutil::sign(sk, msg)
+    }
+
+    /// The party_ids seen in the hour before `now`.
+    fn seen_within_the_hour(es: &EpochState, now: u64) -> Vec<u64> {
+        es.roster(Some(3600), now).parties.iter().map(|p| p.party_id).collect()
+    }
+
+    #[test]
+    fn last_seen_is_the_logged_receive_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wt.log");
+        let path = path.to_str().unwrap();
+        let mut es = epoch_state();
+        es.open_log(path).unwrap();
+        for (party, seq) in [(1, 1), (1, 2), (2, 1)] {
+            es.register(day_old_prr(&party_key(party), party.into(), seq)).unwrap();
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert_eq!(seen_within_the_hour(&es, now), [1, 2]);
+        assert!(seen_within_the_hour(&es, now + 7200).is_empty());
+
+        // Replayed, and replayed again after compaction rewrote the file.
+        let reopened = |path: &str| {
+            let mut es = epoch_state();
+            es.open_log(path).unwrap();
+            es
+        };
+        assert_eq!(reopened(path).last_seen, es.last_seen);
+        assert_eq!(es.compact().unwrap(), 1);
+        assert_eq!(reopened(path).last_seen, es.last_seen);
+        assert_eq!(seen_within_the_hour(&reopened(path), now), [1, 2]);
+    }
+
+    #[test]
+    fn historical_snapshot_cache_is_bounded() {
+        let mut es = epoch_state();
+        es.register(prr(&party_key(1), 1, 1)).unwrap();
+        let filler = es.snapshot_at(1).unwrap();
+        es.historical.lock().unwrap().extend(
+            (1000..).take(HISTORICAL_SNAPSHOT_CACHE).map(|log_len| (log_len, filler.clone())),
+        );
+        let srs = es.snapshot_at(0).unwrap();
+        let cache = es.historical.lock().unwrap();
+        assert_eq!(cache.len(), HISTORICAL_SNAPSHOT_CACHE);
+        assert_eq!(cache.get(&0), Some(&srs));
+    }
+}
@@ -0,0 +1,101 @@
+//! Helpers shared by the unit tests: signed registrations and in-memory watchtowers.
+
+use crate::api::AppState;
+use crate::state::{EpochSettings, WatchtowerState};
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::http::{Request, StatusCode};
+use common::crypto::{sign_struct, Ed25519, SignatureScheme, CTX_PRR};
+use common::ratelimit::RateLimiter;
+use common::types::{
+    Endpoint, PartyRegistrationRecord, RegistrationMessage, REGISTRATION_MSG_VERSION,
+};
+use ed25519_dalek::SigningKey;
+use http_body_util::BodyExt as _;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{watch, Semaphore};
+use tower::ServiceExt as _;
+
+pub const EPOCH: u64 = 7;
+
+/// Deterministic key for party `n`.
+pub fn party_key(n: u8) -> SigningKey {
+    SigningKey::from_bytes(&[n; 32])
+}
+
+pub fn watchtower_key() -> SigningKey {
+    SigningKey::from_bytes(&[0xee; 32])
+}
+
+/// A registration of `party_id` under `sk` with `seq`, signed and otherwise unremarkable.
+pub fn prr(sk: &SigningKey, party_id: u64, seq: u64) -> PartyRegistrationRecord {
+    let msg = RegistrationMessage {
+        version: REGISTRATION_MSG_VERSION,
+        epoch: EPOCH,
+        party_id,
+        endpoint: Endpoint { addr: format!("10.0.0.{party_id}:9000") },
+        scheme: Ed25519::TAG,
+        pk_party: sk.verifying_key().to_bytes(),
+        seq,
+        nonce: [seq as u8; 16],
+        created_at_unix: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        rotation: None,
+    };
+    sign(sk, msg)
+}
+
+/// Re-sign `msg` with `sk`, e.g. after a test changed it.
+pub fn sign(sk: &SigningKey, msg: RegistrationMessage) -> PartyRegistrationRecord {
+    let sig_party = sign_struct(sk, CTX_PRR, &msg).unwrap();
+    PartyRegistrationRecord { msg, sig_party }
+}
+
+pub fn settings() -> EpochSettings {
+    EpochSettings::new(watchtower_key())
+}
+
+pub fn state() -> WatchtowerState {
+    WatchtowerState::new(EPOCH, settings(), false)
+}
+
+/// Serving state over `wt` with no token.
+pub fn app_state(wt: WatchtowerState) -> AppState {
+    AppState {
+        inner: Arc::new(Mutex::new(wt)),
+        started_at: Instant::now(),
+        ready: Arc::new(AtomicBool::new(true)),
+        max_entries_limit: 1000,
+        max_body_bytes: 64 * 1024,
+        operator_token: None,
+        party_limiter: Arc::new(Mutex::new(RateLimiter::new(100, 100.0))),
+        ip_limiter: Arc::new(Mutex::new(RateLimiter::new(100, 100.0))),
+        snapshots: Arc::new(watch::channel(Default::default()).0),
+        subscribers: Arc::new(Semaphore::new(16)),
+        read_only: false,
+    }
+}
+
+/// Send one request to the router over `st`, as if from 127.0.0.1.
+pub async fn call(st: &AppState, mut req: Request<Body>) -> (StatusCode, serde_json::Value) {
+    let peer: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    req.extensions_mut().insert(ConnectInfo(peer));
+    let resp = crate::api::router(st.clone()).oneshot(req).await.unwrap();
+    let status = resp.status();
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    let json = serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null);
+    (status, json)
+}
+
+pub fn get(uri: &str) -> Request<Body> {
+    Request::get(uri).body(Body::empty()).unwrap()
+}
+
+pub fn post_json(uri: &str, body: &impl serde::Serialize) -> Request<Body> {
+    Request::post(uri)
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_vec(body).unwrap()))
+        .unwrap()
+}